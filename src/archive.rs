@@ -1,10 +1,12 @@
 use serde::{Deserialize, Serialize};
 
-use crate::DockerInputType;
+use crate::{plugin::PluginInput, service::Service, skip::SkipIf, stream::CompressionOptions, DockerInputType, ShellTask};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) enum ArchiveInput {
     Docker(DockerInputType),
+    /// staged by an external executable
+    Plugin(PluginInput),
     // Directory {
     //     path: PathBuf,
     //     prepare: Vec<ShellTask>,
@@ -17,4 +19,181 @@ pub(crate) struct ArchiveOptions {
     // output: OutputType,
     // mode: ArchiveMode,
     pub(crate) name: String,
+    /// maximum time in seconds the archive's dump is allowed to run
+    #[serde(default)]
+    pub(crate) timeout: Option<u64>,
+    /// zstd compression of the dump, only applies to archives staged in the intermediate directory
+    #[serde(default)]
+    pub(crate) compression: Option<CompressionOptions>,
+    /// maximum rate in bytes per second at which the dump is written, overrides the global `io_limit`
+    #[serde(default)]
+    pub(crate) io_limit: Option<u64>,
+    /// how many more times a failed dump is attempted
+    #[serde(default)]
+    pub(crate) retries: u32,
+    /// seconds to wait before the first retry, doubled after every further failure
+    #[serde(default = "ArchiveOptions::default_retry_delay")]
+    pub(crate) retry_delay: u64,
+    /// keep the previous dump untouched if the new one has the same content
+    #[serde(default)]
+    pub(crate) skip_unchanged: bool,
+    /// checks run once the archive is restored, so that a restore is validated rather than assumed good
+    #[serde(default)]
+    pub(crate) post_restore: Vec<PostRestoreTask>,
+    /// what the dump must look like to count as one, only applies to archives dumped from stdout
+    #[serde(default)]
+    pub(crate) dump_check: DumpCheck,
+    /// when the compose service of a dump isn't running, run its task in a new container of the
+    /// service with `docker compose run` rather than failing the preflight checks
+    #[serde(default)]
+    pub(crate) run_if_stopped: bool,
+    /// whether the archive missing from the host, like a volume that disappeared, fails it rather
+    /// than only being logged
+    #[serde(default = "ArchiveOptions::default_required")]
+    pub(crate) required: bool,
+    /// whether the archive is backed up, keeping a parked archive configured
+    #[serde(default = "Service::default_enabled")]
+    pub(crate) enabled: bool,
+    /// when the archive is left out of a run
+    #[serde(default)]
+    pub(crate) skip_if: Option<SkipIf>,
+    /// archives of the service staged before this one, like the database whose dump has to be
+    /// taken before its uploads are copied
+    #[serde(default)]
+    pub(crate) depends_on: Vec<String>,
+}
+
+/// Checks run on a finished dump, since a dump task failing to authenticate may print nothing
+/// and exit successfully.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct DumpCheck {
+    /// dumps with fewer bytes fail, like the header-only ones of an empty database; 0 disables the
+    /// check
+    #[serde(default = "DumpCheck::default_min_size")]
+    pub(crate) min_size: u64,
+    /// format whose magic bytes the dump must start with, guessed from the extension if unset
+    #[serde(default)]
+    pub(crate) format: Option<DumpFormat>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DumpFormat {
+    Gzip,
+    Zstd,
+    /// pg_dump's custom format, as written by `pg_dump -Fc`
+    PgCustom,
+    /// a single bson document, whose length is its first 4 bytes
+    Bson,
+    /// no magic bytes are checked
+    Any,
+}
+
+/// Bytes of the start of a dump kept to check its magic bytes.
+pub(crate) static DUMP_HEAD_LEN: usize = 8;
+
+impl Default for DumpCheck {
+    fn default() -> Self {
+        Self { min_size: Self::default_min_size(), format: None }
+    }
+}
+
+impl DumpCheck {
+    fn default_min_size() -> u64 {
+        1
+    }
+
+    /// Fails a dump of `size` bytes starting with `head`, for an archive with the extension `ext`.
+    pub(crate) fn check(&self, ext: &str, size: u64, head: &[u8]) -> Result<(), String> {
+        if size < self.min_size {
+            return Err(match size {
+                0 => "the dump is empty, did the task fail without an error?".to_owned(),
+                _ => format!("the dump is only {} bytes, {} are expected at least", size, self.min_size),
+            });
+        }
+        let format = self.format.or_else(|| DumpFormat::from_ext(ext)).unwrap_or(DumpFormat::Any);
+        if size > 0 && !format.matches(size, head) {
+            return Err(format!("the dump doesn't start with the magic bytes of {:?} (it starts with {:02x?})", format, head));
+        }
+        Ok(())
+    }
+}
+
+impl DumpFormat {
+    fn from_ext(ext: &str) -> Option<Self> {
+        match ext.rsplit('.').next()? {
+            "gz" | "tgz" => Some(Self::Gzip),
+            "zst" => Some(Self::Zstd),
+            "bson" => Some(Self::Bson),
+            _ => None,
+        }
+    }
+
+    fn matches(self, size: u64, head: &[u8]) -> bool {
+        match self {
+            Self::Gzip => head.starts_with(&[0x1f, 0x8b]),
+            Self::Zstd => head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]),
+            Self::PgCustom => head.starts_with(b"PGDMP"),
+            Self::Bson => head.get(..4)
+                .map(|len| i32::from_le_bytes(len.try_into().expect("4 bytes")))
+                .is_some_and(|len| len >= 5 && len as u64 <= size),
+            Self::Any => true,
+        }
+    }
+}
+
+/// A task run inside a compose service after a restore, failing the archive's restore if it fails.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct PostRestoreTask {
+    /// compose service the task runs in
+    pub(crate) service: String,
+    pub(crate) task: ShellTask,
+}
+
+impl ArchiveOptions {
+    /// An archive with its options set as if it was loaded from yaml.
+    pub(crate) fn new(name: impl ToString, input: ArchiveInput) -> Self {
+        Self {
+            input,
+            name: name.to_string(),
+            timeout: None,
+            compression: None,
+            io_limit: None,
+            retries: 0,
+            retry_delay: Self::default_retry_delay(),
+            skip_unchanged: false,
+            post_restore: vec![],
+            dump_check: DumpCheck::default(),
+            run_if_stopped: false,
+            required: Self::default_required(),
+            enabled: Service::default_enabled(),
+            skip_if: None,
+            depends_on: vec![],
+        }
+    }
+
+    pub(crate) fn default_retry_delay() -> u64 {
+        10
+    }
+
+    pub(crate) fn default_required() -> bool {
+        true
+    }
+}
+
+#[test]
+fn test_dump_check() {
+    let check = DumpCheck::default();
+    assert!(check.check("sql", 0, &[]).is_err());
+    assert!(check.check("sql", 3, b"abc").is_ok());
+    assert!(check.check("sql.gz", 3, b"abc").is_err());
+    assert!(check.check("sql.gz", 3, &[0x1f, 0x8b, 0x08]).is_ok());
+    assert!(check.check("bson", 5, &[5, 0, 0, 0, 0]).is_ok());
+    assert!(check.check("bson", 5, &[9, 0, 0, 0, 0]).is_err());
+    let check = DumpCheck { min_size: 64, format: Some(DumpFormat::PgCustom) };
+    assert!(check.check("dump", 32, b"PGDMP").is_err());
+    assert!(check.check("dump", 128, b"PGDMP").is_ok());
+    assert!(check.check("dump", 128, b"--\nSET").is_err());
+    let check = DumpCheck { min_size: 0, format: Some(DumpFormat::Any) };
+    assert!(check.check("gz", 0, &[]).is_ok());
 }
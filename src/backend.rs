@@ -0,0 +1,485 @@
+use std::{cmp::Reverse, collections::{BTreeMap, BTreeSet}, path::{Path, PathBuf}, process::Stdio, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local, Utc};
+use indicatif::ProgressBar;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{io::{AsyncRead, AsyncWriteExt}, process::Command};
+
+use crate::{audit::Audited, borg::Borg, config::Config, docker::PathExclude, kopia::Kopia, mirror::Mirror, rclone::Rclone, restic::Restic, rsync::Rsync, service::Service, tarball::Tarball, DockerBinding, DockerSubcommand, SerializableError};
+
+/// Tag of every snapshot taken by hoarder.
+pub(crate) static HOARDER_TAG: &str = "hoarder";
+
+/// Which tool backs up the staged data, and to what kind of repository.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum BackendType {
+    #[default]
+    Restic,
+    Borg,
+    Kopia,
+    Rclone,
+    Tarball,
+    Mirror,
+    Rsync,
+}
+
+impl BackendType {
+    /// Name of the backend in logs and run records.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            BackendType::Restic => "restic",
+            BackendType::Borg => "borg",
+            BackendType::Kopia => "kopia",
+            BackendType::Rclone => "rclone",
+            BackendType::Tarball => "tarball",
+            BackendType::Mirror => "mirror",
+            BackendType::Rsync => "rsync",
+        }
+    }
+}
+
+/// Opens the backend selected by the configuration.
+pub(crate) fn open(config: &Config) -> Box<dyn BackupBackend> {
+    open_type(config.backend())
+}
+
+/// The backend the snapshots of `service` are read from, to restore or check it: the first one it
+/// is backed up to.
+pub(crate) fn primary(config: &Config, service: &Service) -> BackendType {
+    service.backends(config).first().copied().unwrap_or(config.backend())
+}
+
+/// Every backend `services` are backed up to, the configured one first when it is used or when
+/// there are no services.
+pub(crate) fn used(config: &Config, services: &[Service]) -> Vec<BackendType> {
+    let mut used = vec![];
+    for backend in services.iter().flat_map(|s| s.backends(config)) {
+        if !used.contains(&backend) {
+            used.push(backend);
+        }
+    }
+    if used.is_empty() || used.contains(&config.backend()) {
+        used.retain(|b| *b != config.backend());
+        used.insert(0, config.backend());
+    }
+    used
+}
+
+pub(crate) fn open_type(backend: BackendType) -> Box<dyn BackupBackend> {
+    match backend {
+        BackendType::Restic => Box::new(Restic),
+        BackendType::Borg => Box::new(Borg),
+        BackendType::Kopia => Box::new(Kopia),
+        BackendType::Rclone => Box::new(Rclone),
+        BackendType::Tarball => Box::new(Tarball),
+        BackendType::Mirror => Box::new(Mirror),
+        BackendType::Rsync => Box::new(Rsync),
+    }
+}
+
+/// Starts the long running container `name` of `image` holding `mounts`, for the backups to be run
+/// in with `docker exec`. A leftover container with the same name is stopped first.
+pub(crate) async fn start_container(
+    config: &Config,
+    name: &str,
+    image: &str,
+    mounts: Vec<DockerBinding>,
+    options: Vec<String>,
+    args: Vec<&str>,
+) -> Result<(), SerializableError> {
+    start_container_with_env(config, name, image, mounts, options, args, vec![]).await
+}
+
+/// Like [`start_container`], setting `env` on the docker client for options passing variables by
+/// name.
+pub(crate) async fn start_container_with_env(
+    config: &Config,
+    name: &str,
+    image: &str,
+    mounts: Vec<DockerBinding>,
+    options: Vec<String>,
+    args: Vec<&str>,
+    env: Vec<(String, String)>,
+) -> Result<(), SerializableError> {
+    if stop_container(config, name).await? {
+        warn!("another container with the name {} has been found and stopped", name);
+        warn!("waiting 1 second for letting the daemon delete it...");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    let options = ["--rm", "-d", "--name", name]
+        .into_iter()
+        .map(str::to_owned)
+        .chain(options)
+        .collect();
+    let mut command = config.docker_command_with_context(DockerSubcommand::run(image, mounts, options, args)).into_command();
+    if !command.envs(env).audited_spawn()?.wait().await?.success()
+    {
+        error!("failed to start container {}", name);
+        return Err(SerializableError::backend(format!("failed to start container {}", name)));
+    }
+    Ok(())
+}
+
+/// Stops the container `name`, returning whether it was running.
+pub(crate) async fn stop_container(config: &Config, name: &str) -> Result<bool, SerializableError> {
+    Ok(config.docker_command_with_context(DockerSubcommand::stop(name, Vec::<String>::new()))
+        .spawn_and_wait().await?
+        .success())
+}
+
+/// Runs `command` to completion, feeding it `input`, returning its stdout. `what` names the task in
+/// the error it fails with.
+pub(crate) async fn run(what: &str, mut command: Command, input: Option<Box<dyn AsyncRead + Send + Unpin>>) -> Result<String, SerializableError> {
+    command
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    debug!("running {} task: {:?}", what, command.as_std().get_args().collect::<Vec<_>>());
+    let mut handle = command.audited_spawn()
+        .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+    let stdin = handle.stdin.take();
+    let feed = async {
+        if let (Some(mut input), Some(mut stdin)) = (input, stdin) {
+            tokio::io::copy(&mut input, &mut stdin).await?;
+            stdin.shutdown().await?;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let (fed, output) = tokio::join!(feed, handle.wait_with_output());
+    let output = output?;
+    if !output.status.success() {
+        return Err(SerializableError::backend(format!(
+            "{} task {}: {}",
+            what,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )));
+    }
+    fed?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// A repository the staged services are backed up to, and restored from.
+#[async_trait]
+pub(crate) trait BackupBackend: Send + Sync {
+    /// Starts whatever the backups run in, with `mounts` holding the volumes and the intermediate
+    /// directory under the backup root.
+    async fn prepare(&self, config: &Config, mounts: Vec<DockerBinding>) -> Result<(), SerializableError>;
+
+    /// Fails if the repository can't be reached.
+    async fn check(&self, config: &Config) -> Result<(), SerializableError> {
+        self.snapshots(config).await.map(|_| ())
+    }
+
+    /// Checks that the repository is reachable and the credentials are valid, with what
+    /// [`BackupBackend::prepare`] started, so that a typo fails the run before anything is dumped.
+    async fn probe(&self, config: &Config) -> Result<(), SerializableError> {
+        self.check(config).await
+    }
+
+    /// The paths under `roots` that can't be read from what [`BackupBackend::prepare`] started,
+    /// like a postgres data directory owned by its container's user. Backends that can't tell
+    /// report none.
+    async fn unreadable(&self, _config: &Config, _roots: &[PathBuf]) -> Result<Vec<String>, SerializableError> {
+        Ok(vec![])
+    }
+
+    /// Stops what [`BackupBackend::prepare`] started, returning whether there was anything to stop.
+    async fn teardown(&self, config: &Config) -> Result<bool, SerializableError>;
+
+    /// Whether the backend matches [`BackupRequest::iexcludes`] regardless of case.
+    fn ignores_case(&self) -> bool {
+        false
+    }
+
+    /// Whether the backend implements [`BackupBackend::deep_check`].
+    fn deep_checks(&self) -> bool {
+        false
+    }
+
+    /// Backs up a directory under the backup root as one snapshot, reporting its progress on `bar`.
+    /// `previous` is how long the last backup of the same directory took, to estimate the time left.
+    async fn backup(
+        &self,
+        config: &Config,
+        request: BackupRequest,
+        bar: &ProgressBar,
+        previous: Option<Duration>,
+    ) -> Result<BackupSummary, SerializableError>;
+
+    /// The commands [`BackupBackend::backup`] runs for `request`, for the plan. The ones following
+    /// up on the outcome of the backup, like reading the size of what was uploaded, are left out.
+    fn plan_commands(&self, config: &Config, request: &BackupRequest) -> Result<Vec<Command>, SerializableError>;
+
+    /// Removes the snapshots that `retention` doesn't keep.
+    async fn forget(&self, config: &Config, retention: &Retention) -> Result<(), SerializableError>;
+
+    /// The commands [`BackupBackend::forget`] runs, for the plan. Backends picking what to remove
+    /// from the snapshots in the repository can't tell in advance and plan none.
+    fn plan_forget(&self, _config: &Config, _retention: &Retention) -> Result<Vec<Command>, SerializableError> {
+        Ok(vec![])
+    }
+
+    /// Every snapshot taken by hoarder from this host.
+    async fn snapshots(&self, config: &Config) -> Result<Vec<Snapshot>, SerializableError>;
+
+    /// Restores `path` from `snapshot` into the `target` host directory or volume, which can only
+    /// be missing for a dry run. With `dry_run`, nothing is written and the files that would be are
+    /// returned, one per line.
+    async fn restore(
+        &self,
+        config: &Config,
+        snapshot: &str,
+        path: &Path,
+        target: Option<&str>,
+        dry_run: bool,
+    ) -> Result<String, SerializableError>;
+
+    /// Builds a command writing the file `path` of `snapshot` to its stdout.
+    fn dump(&self, config: &Config, snapshot: &str, path: &Path) -> Result<Command, SerializableError>;
+
+    /// Size and contents of the repository.
+    async fn stats(&self, config: &Config) -> Result<RepositoryStats, SerializableError>;
+
+    /// Checks the integrity of the repository, reading back `read_data_subset` of its data or all
+    /// of it, in what [`BackupBackend::prepare`] started.
+    async fn deep_check(&self, _config: &Config, _read_data_subset: Option<&str>) -> Result<(), SerializableError> {
+        Err(SerializableError::config("deep checks aren't supported by this backend"))
+    }
+
+    /// The commands [`BackupBackend::deep_check`] runs, for the plan.
+    fn plan_check(&self, _config: &Config, _read_data_subset: Option<&str>) -> Result<Vec<Command>, SerializableError> {
+        Ok(vec![])
+    }
+}
+
+/// A directory under the backup root to back up as one snapshot.
+#[derive(Debug, Clone)]
+pub(crate) struct BackupRequest {
+    pub(crate) path: PathBuf,
+    /// exclude string globs
+    pub(crate) excludes: Vec<String>,
+    /// exclude string globs, matched regardless of case
+    pub(crate) iexcludes: Vec<String>,
+    /// tags added next to the `hoarder` one
+    pub(crate) tags: Vec<String>,
+    /// how long in seconds to wait for a locked repository
+    pub(crate) retry_lock: Option<u64>,
+    /// list of the paths backed up instead of `path`, as seen by the backup container
+    pub(crate) files_from: Option<PathBuf>,
+    /// whether the backup stays on the file system of each path it starts from
+    pub(crate) one_file_system: bool,
+}
+
+impl BackupRequest {
+    /// A request for `path`, leaving out `excludes`, already resolved under it.
+    pub(crate) fn with_excludes(path: PathBuf, excludes: Vec<PathExclude>) -> Self {
+        let (sensitive, insensitive): (Vec<_>, Vec<_>) = excludes.into_iter().map(|pe| (pe.exclude, pe.iexclude)).unzip();
+        let strings = |patterns: Vec<Vec<PathBuf>>| patterns.into_iter().flatten().map(|p| p.to_string_lossy().to_string()).collect();
+        Self {
+            excludes: strings(sensitive),
+            iexcludes: strings(insensitive),
+            tags: vec![],
+            retry_lock: None,
+            files_from: None,
+            one_file_system: false,
+            path,
+        }
+    }
+
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self {
+            excludes: vec![],
+            iexcludes: vec![],
+            tags: vec![],
+            retry_lock: None,
+            files_from: None,
+            one_file_system: false,
+            path,
+        }
+    }
+
+    /// Matches the case-insensitive excludes like the others, for `backend` which can't ignore the
+    /// case.
+    pub(crate) fn case_sensitive(mut self, backend: BackendType) -> Self {
+        if !self.iexcludes.is_empty() {
+            warn!("{}: the {} backend can't ignore the case of excludes, matching them as they are", self.path.display(), backend.name());
+            self.excludes.append(&mut self.iexcludes);
+        }
+        self
+    }
+
+    pub(crate) fn with_retry_lock(mut self, secs: Option<u64>) -> Self {
+        self.retry_lock = secs;
+        self
+    }
+
+    /// Backs up the paths listed in `list` instead of the whole `path`, staying on the file
+    /// system of each one if `one_file_system`.
+    pub(crate) fn with_files_from(mut self, list: Option<PathBuf>, one_file_system: bool) -> Self {
+        self.files_from = list;
+        self.one_file_system = one_file_system;
+        self
+    }
+
+    pub(crate) fn with_tag(mut self, tag: impl ToString) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+}
+
+/// What a backend reports about a finished backup.
+#[derive(Debug, Default)]
+pub(crate) struct BackupSummary {
+    pub(crate) snapshot_id: Option<String>,
+    /// bytes added to the repository
+    pub(crate) data_added: Option<u64>,
+}
+
+/// A snapshot in the repository.
+#[derive(Deserialize, Debug)]
+pub(crate) struct Snapshot {
+    pub(crate) id: String,
+    pub(crate) time: DateTime<Utc>,
+    #[serde(default)]
+    pub(crate) paths: Vec<String>,
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+}
+
+impl Snapshot {
+    /// Whether the snapshot is of `path`, backed up whole or through paths listed under it.
+    pub(crate) fn covers(&self, path: &Path) -> bool {
+        self.paths.iter().any(|p| Path::new(p).starts_with(path))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RepositoryStats {
+    pub(crate) snapshots: u64,
+    /// bytes stored in the repository, after deduplication and compression
+    pub(crate) stored_bytes: u64,
+}
+
+/// Which snapshots are kept when old ones are forgotten, each rule keeping the latest snapshot of
+/// as many hours, days, ... back.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub(crate) struct Retention {
+    #[serde(default)]
+    pub(crate) keep_last: Option<u32>,
+    #[serde(default)]
+    pub(crate) keep_hourly: Option<u32>,
+    #[serde(default)]
+    pub(crate) keep_daily: Option<u32>,
+    #[serde(default)]
+    pub(crate) keep_weekly: Option<u32>,
+    #[serde(default)]
+    pub(crate) keep_monthly: Option<u32>,
+    #[serde(default)]
+    pub(crate) keep_yearly: Option<u32>,
+}
+
+/// A deep check of the repository, run after a backup once due, under `check_schedule`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RepositoryCheck {
+    /// seconds between two checks
+    pub(crate) every: u64,
+    /// share of the data read back, like `5%` or `1/10`, all of it if unset
+    #[serde(default)]
+    pub(crate) read_data_subset: Option<String>,
+}
+
+impl RepositoryCheck {
+    /// Identifies the check in the state, along with the backend.
+    pub(crate) fn key(&self, backend: BackendType) -> String {
+        match &self.read_data_subset {
+            Some(subset) => format!("{}/read-data-subset={}", backend.name(), subset),
+            None => format!("{}/read-data", backend.name()),
+        }
+    }
+}
+
+/// The checks of `schedule` due for `backend`, given when each last ran. When a full check is due it
+/// is the only one run, as it covers the others, which are returned as done along with it.
+pub(crate) fn due_checks<'a>(
+    schedule: &'a [RepositoryCheck],
+    backend: BackendType,
+    last: &BTreeMap<String, DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Vec<(&'a RepositoryCheck, Vec<String>)> {
+    let due = schedule
+        .iter()
+        .filter(|c| last.get(&c.key(backend)).is_none_or(|l| (now - *l).num_seconds() >= c.every as i64))
+        .collect::<Vec<_>>();
+    match due.iter().find(|c| c.read_data_subset.is_none()) {
+        Some(full) => vec![(*full, due.iter().map(|c| c.key(backend)).collect())],
+        None => due.into_iter().map(|c| (c, vec![c.key(backend)])).collect(),
+    }
+}
+
+impl Retention {
+    /// The rules that are set, with the name restic and borg use for them.
+    pub(crate) fn rules(&self) -> Vec<(&'static str, u32)> {
+        [
+            ("last", self.keep_last),
+            ("hourly", self.keep_hourly),
+            ("daily", self.keep_daily),
+            ("weekly", self.keep_weekly),
+            ("monthly", self.keep_monthly),
+            ("yearly", self.keep_yearly),
+        ]
+            .into_iter()
+            .filter_map(|(rule, keep)| keep.map(|k| (rule, k)))
+            .collect()
+    }
+    /// Which of `times` the rules keep, as indexes into it, for backends without a retention of
+    /// their own: like restic, each rule keeps the newest of `times` in as many of the latest
+    /// hours, days, ... having one.
+    pub(crate) fn keep(&self, times: &[DateTime<Utc>]) -> BTreeSet<usize> {
+        let mut newest_first = (0..times.len()).collect::<Vec<_>>();
+        newest_first.sort_by_key(|&i| Reverse(times[i]));
+        let mut kept = BTreeSet::new();
+        for (rule, count) in self.rules() {
+            let mut periods = BTreeSet::new();
+            for &i in &newest_first {
+                if periods.len() == count as usize {
+                    break;
+                }
+                let time = times[i].with_timezone(&Local);
+                let period = match rule {
+                    "last" => i.to_string(),
+                    "hourly" => time.format("%Y-%m-%d %H").to_string(),
+                    "daily" => time.format("%Y-%m-%d").to_string(),
+                    "weekly" => time.format("%G-%V").to_string(),
+                    "monthly" => time.format("%Y-%m").to_string(),
+                    _ => time.format("%Y").to_string(),
+                };
+                if periods.insert(period) {
+                    kept.insert(i);
+                }
+            }
+        }
+        kept
+    }
+}
+
+#[test]
+fn test_due_checks() {
+    let schedule: Vec<RepositoryCheck> = serde_yaml::from_str("[{every: 604800, read_data_subset: 5%}, {every: 2592000}]").unwrap();
+    let now = Utc::now();
+    let days = |d| now - chrono::Duration::days(d);
+    let keys = |due: Vec<(&RepositoryCheck, Vec<String>)>| due.into_iter().map(|(c, done)| (c.key(BackendType::Restic), done)).collect::<Vec<_>>();
+    // never run: the full check covers the subset
+    assert_eq!(keys(due_checks(&schedule, BackendType::Restic, &BTreeMap::new(), now)), [
+        ("restic/read-data".to_owned(), vec!["restic/read-data-subset=5%".to_owned(), "restic/read-data".to_owned()]),
+    ]);
+    let last = BTreeMap::from([("restic/read-data-subset=5%".to_owned(), days(8)), ("restic/read-data".to_owned(), days(10))]);
+    assert_eq!(keys(due_checks(&schedule, BackendType::Restic, &last, now)), [
+        ("restic/read-data-subset=5%".to_owned(), vec!["restic/read-data-subset=5%".to_owned()]),
+    ]);
+    // tracked per backend
+    assert_eq!(due_checks(&schedule, BackendType::Borg, &last, now).len(), 1);
+}
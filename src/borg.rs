@@ -0,0 +1,433 @@
+use std::{collections::BTreeSet, path::{Path, PathBuf}, process::Stdio, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use log::{debug, info, warn};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader}, process::Command};
+
+use crate::{
+    audit::Audited,
+    backend::{self, BackendType, BackupBackend, BackupRequest, BackupSummary, RepositoryStats, Retention, Snapshot, HOARDER_TAG},
+    config::Config,
+    credentials::Credentials,
+    DockerBinding,
+    DockerSubcommand,
+    SerializableError,
+    ShellTask,
+};
+
+static BORG_IMAGE: &str = "borg";
+static BORG_CONTAINER_NAME: &str = "hoarder-borg";
+/// Where the passphrase file is mounted inside borg containers.
+static PASSPHRASE_MOUNT: &str = "/borg_passphrase";
+/// Where the ssh key is mounted inside borg containers.
+static SSH_KEY_MOUNT: &str = "/borg_ssh_key";
+/// Where the restore target is mounted inside borg containers, and where borg extracts.
+static RESTORE_MOUNT: &str = "/restore";
+/// Separates the parts of archive names, it can't appear in host, tag or service names.
+static SEPARATOR: char = '+';
+static TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Settings of the borg backend.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct BorgConfig {
+    /// the borg repository, as passed to BORG_REPO
+    pub(crate) repository: String,
+    /// file holding the repository's passphrase
+    pub(crate) passphrase_file: Option<String>,
+    /// ssh private key used to reach remote repositories
+    pub(crate) ssh_key: Option<String>,
+    /// image providing borg, the backups run in a long running container of it
+    pub(crate) image: Option<String>,
+    /// name of the long running borg container
+    pub(crate) container_name: Option<String>,
+}
+
+impl BorgConfig {
+    fn image(&self) -> String {
+        self.image.clone().unwrap_or(BORG_IMAGE.to_owned())
+    }
+
+    fn container_name(&self) -> String {
+        self.container_name.clone().unwrap_or(BORG_CONTAINER_NAME.to_owned())
+    }
+}
+
+/// A line of `borg create --log-json --progress` output on stderr.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BorgLogMessage {
+    ArchiveProgress {
+        #[serde(default)]
+        original_size: u64,
+    },
+    LogMessage {
+        #[serde(default)]
+        levelname: String,
+        #[serde(default)]
+        message: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// The `borg create --json` output.
+#[derive(Deserialize, Debug)]
+struct BorgCreateOutput {
+    archive: BorgArchive,
+}
+
+#[derive(Deserialize, Debug)]
+struct BorgArchive {
+    name: String,
+    #[serde(default)]
+    stats: Option<BorgArchiveStats>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BorgArchiveStats {
+    deduplicated_size: u64,
+}
+
+/// Backs up to a borg repository, from a long running borg container holding every mount.
+/// Archives are named `host+tags+directory+time`, borg archives having no tags or host.
+pub(crate) struct Borg;
+
+impl Borg {
+    fn settings(config: &Config) -> Result<&BorgConfig, SerializableError> {
+        config.borg().ok_or(SerializableError::config("the borg backend needs a borg section"))
+    }
+
+    /// Environment of the borg containers, as `docker run` options along with the credentials they
+    /// pass by name, adding the passphrase and key files to `mounts`.
+    fn environment(config: &Config, mounts: &mut Vec<DockerBinding>) -> Result<(Vec<String>, Credentials), SerializableError> {
+        let settings = Self::settings(config)?;
+        let mut env = vec![("BORG_REPO".to_owned(), settings.repository.clone())];
+        if let Some(file) = &settings.passphrase_file {
+            env.push(("BORG_PASSCOMMAND".to_owned(), format!("cat {}", PASSPHRASE_MOUNT)));
+            mounts.push(DockerBinding::new_ro(file.clone(), PathBuf::from(PASSPHRASE_MOUNT)));
+        }
+        if let Some(key) = &settings.ssh_key {
+            env.push(("BORG_RSH".to_owned(), format!("ssh -i {} -o StrictHostKeyChecking=accept-new", SSH_KEY_MOUNT)));
+            mounts.push(DockerBinding::new_ro(key.clone(), PathBuf::from(SSH_KEY_MOUNT)));
+        }
+        let credentials = Credentials::new(config, BackendType::Borg, &["BORG_"])
+            .without(&env.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>());
+        let options = env.into_iter()
+            .flat_map(|(k, v)| ["--env".to_owned(), format!("{}={}", k, v)])
+            .chain(credentials.options())
+            .collect();
+        Ok((options, credentials))
+    }
+
+    /// Builds a command running `task` in a throwaway borg container, with `mounts` and `options`
+    /// passed to `docker run`.
+    fn command(config: &Config, mut mounts: Vec<DockerBinding>, options: Vec<String>, task: ShellTask) -> Result<Command, SerializableError> {
+        let (env, credentials) = Self::environment(config, &mut mounts)?;
+        let mut command = config.docker_command_with_context(DockerSubcommand::run(
+            Self::settings(config)?.image(),
+            mounts,
+            std::iter::once("--rm".to_owned()).chain(env).chain(options).collect(),
+            task.get_args().into_iter().collect(),
+        )).into_command();
+        command.envs(credentials.vars());
+        Ok(command)
+    }
+
+    /// Runs `task` in a throwaway borg container, returning its stdout.
+    async fn query(config: &Config, mounts: Vec<DockerBinding>, options: Vec<String>, task: ShellTask) -> Result<String, SerializableError> {
+        let mut command = Self::command(config, mounts, options, task)?;
+        command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        debug!("querying borg: {:?}", command.as_std().get_args().collect::<Vec<_>>());
+        let output = command.audited_output().await
+            .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+        if !output.status.success() {
+            return Err(SerializableError::backend(format!(
+                "borg {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim(),
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn archive_name(config: &Config, tags: &[String], path: &Path) -> Result<String, SerializableError> {
+        let tags = std::iter::once(HOARDER_TAG.to_owned()).chain(tags.iter().cloned()).collect::<Vec<_>>();
+        Ok(format!(
+            "{host}{SEPARATOR}{tags}{SEPARATOR}{dir}{SEPARATOR}{time}",
+            host = config.restic_host()?,
+            tags = tags.join(","),
+            dir = path.file_name().unwrap_or_default().to_string_lossy(),
+            time = Utc::now().format(TIME_FORMAT),
+        ))
+    }
+
+    /// Reads a snapshot back from an archive name, None for archives hoarder didn't name.
+    fn parse_archive_name(config: &Config, name: &str) -> Option<Snapshot> {
+        let mut parts = name.splitn(4, SEPARATOR);
+        let (host, tags, dir, time) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if host != config.restic_host().ok()? {
+            return None;
+        }
+        Some(Snapshot {
+            id: name.to_owned(),
+            time: NaiveDateTime::parse_from_str(time, TIME_FORMAT).ok()?.and_utc(),
+            paths: vec![Path::new(&config.restic_root()).join(dir).display().to_string()],
+            tags: tags.split(',').map(str::to_owned).collect(),
+        })
+    }
+
+    fn lock_wait(config: &Config, task: &mut ShellTask) {
+        if let Some(lock) = config.lock() {
+            task.arg("--lock-wait").arg(lock.wait);
+        }
+    }
+
+    /// Starts a `borg create` task, with a json summary unless it is a dry run: borg refuses both.
+    fn create_task(config: &Config) -> ShellTask {
+        let mut task = ShellTask::new("borg");
+        task.args(["create", "--log-json", "--progress"]);
+        match config.dry_run() {
+            true => task.arg("--dry-run"),
+            false => task.arg("--json"),
+        };
+        task
+    }
+
+    /// The `borg create` task backing up `request`.
+    fn backup_task(config: &Config, request: &BackupRequest) -> Result<ShellTask, SerializableError> {
+        let mut task = Self::create_task(config);
+        if let Some(secs) = request.retry_lock {
+            task.arg("--lock-wait").arg(secs);
+        }
+        for exclude in &request.excludes {
+            task.arg("--exclude").arg(exclude);
+        }
+        task.arg(format!("::{}", Self::archive_name(config, &request.tags, &request.path)?))
+            .arg(request.path.display());
+        Ok(task)
+    }
+
+    /// Builds the command running a `borg create` task in the borg container.
+    fn create_command(config: &Config, task: ShellTask, stdin: bool) -> Result<Command, SerializableError> {
+        Ok(config.docker_command_with_context(DockerSubcommand::exec(
+            Self::settings(config)?.container_name(),
+            task,
+            if stdin { vec!["-i"] } else { vec![] },
+        )).into_command())
+    }
+
+    /// Runs a `borg create` task in the borg container, following its json log on stderr.
+    async fn create(
+        config: &Config,
+        task: ShellTask,
+        input: Option<Box<dyn AsyncRead + Send + Unpin>>,
+        bar: &ProgressBar,
+    ) -> Result<BackupSummary, SerializableError> {
+        if config.dry_run() {
+            warn!("running in dry run mode, not actually uploading");
+        }
+        let mut command = Self::create_command(config, task, input.is_some())?;
+        command
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        info!("running borg create: {:?}", command.as_std().get_args().collect::<Vec<_>>());
+
+        let mut handle = command.audited_spawn()?;
+        let stdin = handle.stdin.take();
+        let feed = async {
+            if let (Some(mut input), Some(mut stdin)) = (input, stdin) {
+                tokio::io::copy(&mut input, &mut stdin).await?;
+                stdin.shutdown().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        let stderr = handle.stderr.take()
+            .ok_or(SerializableError::backend("no stderr found in borg output"))?;
+        let log = async {
+            let mut errors = vec![];
+            let mut lines = BufReader::new(stderr).lines();
+            while let Some(line) = lines.next_line().await? {
+                match serde_json::from_str::<BorgLogMessage>(&line) {
+                    Ok(BorgLogMessage::ArchiveProgress { original_size }) => {
+                        bar.set_position(original_size);
+                        bar.set_length(bar.length().unwrap_or_default().max(original_size));
+                    }
+                    Ok(BorgLogMessage::LogMessage { levelname, message }) if matches!(levelname.as_str(), "ERROR" | "CRITICAL") => {
+                        errors.push(message);
+                    }
+                    Ok(BorgLogMessage::LogMessage { message, .. }) => debug!("borg: {}", message),
+                    Ok(BorgLogMessage::Other) => {},
+                    Err(_) => debug!("borg: {}", line),
+                }
+            }
+            Ok::<_, std::io::Error>(errors)
+        };
+        let mut stdout = handle.stdout.take()
+            .ok_or(SerializableError::backend("no stdout found in borg output"))?;
+        let read = async {
+            let mut output = String::new();
+            tokio::io::AsyncReadExt::read_to_string(&mut stdout, &mut output).await?;
+            Ok::<_, std::io::Error>(output)
+        };
+        let (fed, errors, output) = tokio::join!(feed, log, read);
+        let exit = handle.wait().await?;
+        // borg exits with 1 on warnings, like files that vanished while being read
+        if !exit.success() && exit.code() != Some(1) {
+            return Err(SerializableError::backend(format!("borg create failed: {}: {}", exit, errors?.join("; "))));
+        }
+        fed?;
+        if config.dry_run() {
+            return Ok(BackupSummary::default());
+        }
+        let output: BorgCreateOutput = serde_json::from_str(&output?)?;
+        Ok(BackupSummary {
+            snapshot_id: Some(output.archive.name),
+            data_added: output.archive.stats.map(|s| s.deduplicated_size),
+        })
+    }
+}
+
+#[async_trait]
+impl BackupBackend for Borg {
+    async fn prepare(&self, config: &Config, mut mounts: Vec<DockerBinding>) -> Result<(), SerializableError> {
+        let settings = Self::settings(config)?;
+        let (env, credentials) = Self::environment(config, &mut mounts)?;
+        let options = std::iter::once("--init".to_owned()).chain(env).collect();
+        backend::start_container_with_env(config, &settings.container_name(), &settings.image(), mounts, options, vec!["sleep", "infinity"], credentials.into_vars()).await
+    }
+
+    async fn teardown(&self, config: &Config) -> Result<bool, SerializableError> {
+        backend::stop_container(config, &Self::settings(config)?.container_name()).await
+    }
+
+    async fn backup(
+        &self,
+        config: &Config,
+        request: BackupRequest,
+        bar: &ProgressBar,
+        _previous: Option<Duration>,
+    ) -> Result<BackupSummary, SerializableError> {
+        Self::create(config, Self::backup_task(config, &request)?, None, bar).await
+    }
+
+    fn plan_commands(&self, config: &Config, request: &BackupRequest) -> Result<Vec<Command>, SerializableError> {
+        Ok(vec![Self::create_command(config, Self::backup_task(config, request)?, false)?])
+    }
+
+    async fn forget(&self, config: &Config, retention: &Retention) -> Result<(), SerializableError> {
+        let rules = retention.rules();
+        if rules.is_empty() {
+            return Err(SerializableError::config("retention must keep something, set at least one keep_* rule"));
+        }
+        // prune every directory on its own, borg only groups archives by name
+        let prefixes = self.snapshots(config).await?
+            .iter()
+            .filter_map(|s| s.id.rsplit_once(SEPARATOR).map(|(prefix, _)| prefix.to_owned()))
+            .collect::<BTreeSet<_>>();
+        for prefix in prefixes {
+            let mut task = ShellTask::new("borg");
+            task.args(["prune", "--glob-archives"]).arg(format!("{}{}*", prefix, SEPARATOR));
+            Self::lock_wait(config, &mut task);
+            for (rule, keep) in &rules {
+                task.arg(format!("--keep-{}", rule)).arg(keep);
+            }
+            if config.dry_run() {
+                task.arg("--dry-run");
+            }
+            Self::query(config, vec![], vec![], task).await?;
+        }
+        if !config.dry_run() {
+            let mut task = ShellTask::new("borg");
+            task.arg("compact");
+            Self::lock_wait(config, &mut task);
+            Self::query(config, vec![], vec![], task).await?;
+        }
+        Ok(())
+    }
+
+    async fn snapshots(&self, config: &Config) -> Result<Vec<Snapshot>, SerializableError> {
+        #[derive(Deserialize)]
+        struct List {
+            archives: Vec<Entry>,
+        }
+        #[derive(Deserialize)]
+        struct Entry {
+            name: String,
+        }
+        let mut task = ShellTask::new("borg");
+        task.args(["list", "--json", "--glob-archives"])
+            .arg(format!("{}{}*", config.restic_host()?, SEPARATOR));
+        let list: List = serde_json::from_str(&Self::query(config, vec![], vec![], task).await?)?;
+        Ok(list.archives
+            .iter()
+            .filter_map(|a| Self::parse_archive_name(config, &a.name))
+            .filter(|s| s.tags.iter().any(|t| t == HOARDER_TAG))
+            .collect())
+    }
+
+    async fn restore(
+        &self,
+        config: &Config,
+        snapshot: &str,
+        path: &Path,
+        target: Option<&str>,
+        dry_run: bool,
+    ) -> Result<String, SerializableError> {
+        let mounts = target
+            .iter()
+            .map(|t| match dry_run {
+                true => DockerBinding::new_ro(t.to_string(), PathBuf::from(RESTORE_MOUNT)),
+                false => DockerBinding::new_rw(t.to_string(), PathBuf::from(RESTORE_MOUNT)),
+            })
+            .collect();
+        // borg stores paths without their leading slash, and extracts them whole into the working
+        // directory
+        let stored = path.strip_prefix("/").unwrap_or(path);
+        let mut task = ShellTask::new("borg");
+        task.arg("extract")
+            .arg("--strip-components")
+            .arg(stored.components().count());
+        if dry_run {
+            task.args(["--dry-run", "--list"]);
+        }
+        task.arg(format!("::{}", snapshot)).arg(stored.display());
+        Self::query(config, mounts, vec!["-w".to_owned(), RESTORE_MOUNT.to_owned()], task).await
+    }
+
+    fn dump(&self, config: &Config, snapshot: &str, path: &Path) -> Result<Command, SerializableError> {
+        let mut task = ShellTask::new("borg");
+        task.args(["extract", "--stdout"])
+            .arg(format!("::{}", snapshot))
+            .arg(path.strip_prefix("/").unwrap_or(path).display());
+        Self::command(config, vec![], vec![], task)
+    }
+
+    async fn stats(&self, config: &Config) -> Result<RepositoryStats, SerializableError> {
+        #[derive(Deserialize)]
+        struct Info {
+            cache: Cache,
+        }
+        #[derive(Deserialize)]
+        struct Cache {
+            stats: CacheStats,
+        }
+        #[derive(Deserialize)]
+        struct CacheStats {
+            unique_csize: u64,
+        }
+        let mut task = ShellTask::new("borg");
+        task.args(["info", "--json"]);
+        let info: Info = serde_json::from_str(&Self::query(config, vec![], vec![], task).await?)?;
+        Ok(RepositoryStats {
+            snapshots: self.snapshots(config).await?.len() as u64,
+            stored_bytes: info.cache.stats.unique_csize,
+        })
+    }
+}
@@ -0,0 +1,39 @@
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+
+use crate::SerializableError;
+
+/// Removes dumps staged in the intermediate directory once they are safely in the repository,
+/// along with their service directory if it ends up empty. Anything that doesn't resolve to a
+/// path strictly inside `intermediate_root` is left alone.
+pub(crate) fn remove_staged(intermediate_root: &Path, staged: &[PathBuf]) -> Result<(), SerializableError> {
+    let root = intermediate_root.canonicalize()?;
+    for file in staged {
+        let file = match file.canonicalize() {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if !is_strictly_inside(&file, &root) {
+            warn!("refusing to remove {}: it is not inside {}", file.display(), root.display());
+            continue;
+        }
+        debug!("removing staged dump {}", file.display());
+        std::fs::remove_file(&file)?;
+
+        if let Some(dir) = file.parent()
+            && is_strictly_inside(dir, &root)
+            && std::fs::read_dir(dir)?.next().is_none()
+        {
+            debug!("removing empty directory {}", dir.display());
+            std::fs::remove_dir(dir)?;
+        }
+    }
+    info!("removed {} staged dump(s) from {}", staged.len(), root.display());
+    Ok(())
+}
+
+fn is_strictly_inside(path: &Path, root: &Path) -> bool {
+    path != root && path.starts_with(root)
+}
@@ -0,0 +1,268 @@
+use std::path::PathBuf;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::{Args, Parser, Subcommand};
+
+use crate::{config::DryRun, dr_plan::DrPlanFormat, events::OutputFormat, export::parse_archive, restore::{AlternateTarget, SnapshotSelector}, ExitCode};
+
+/// Backs up docker compose services with restic.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub(crate) struct Cli {
+    /// path of the configuration file
+    #[arg(short, long, default_value = "config.yaml")]
+    pub(crate) config: PathBuf,
+    /// read the restic password from the first line of stdin, over its file or command
+    #[arg(long, global = true)]
+    pub(crate) password_from_stdin: bool,
+    /// ask for the restic password on the terminal, over its file or command
+    #[arg(long, global = true, conflicts_with = "password_from_stdin")]
+    pub(crate) ask_password: bool,
+    /// remember the restic password in the OS keyring, asking for it when it isn't there yet, or
+    /// every time with --ask-password to replace it
+    #[arg(long, global = true, conflicts_with = "password_from_stdin")]
+    pub(crate) remember_password: bool,
+    /// never color the output, like setting NO_COLOR
+    #[arg(long, global = true)]
+    pub(crate) no_color: bool,
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// back up every configured service, the default when no subcommand is given
+    Backup {
+        /// skip the archives that the previous, interrupted run already completed
+        #[arg(long)]
+        resume: bool,
+        /// only go through part of the backup, over the configured dry run level: `plan` prints
+        /// what would be done, `stage` runs the dumps but not the backends, `restic-dry-run` (the
+        /// default) runs everything with the backends in their dry run mode
+        #[arg(long, num_args = 0..=1, default_missing_value = "restic-dry-run", value_name = "LEVEL")]
+        dry_run: Option<DryRun>,
+        /// show the plan and ask before running it, and again before forgetting snapshots
+        #[arg(long, short)]
+        interactive: bool,
+        /// how the progress is shown: `ndjson` prints one json event per line on stdout
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+        /// show a desktop notification with the outcome once the run is over, when run from a
+        /// desktop session
+        #[arg(long)]
+        notify_desktop: bool,
+    },
+    /// print what a backup would do, every mount, dump and backend, without doing it
+    Plan {
+        /// print the plan as json
+        #[arg(long)]
+        json: bool,
+    },
+    /// flag the parts of the configuration that likely don't do what was meant, exiting with the
+    /// partial exit code if there are any
+    Lint {
+        /// don't ask docker for the size of the volumes
+        #[arg(long)]
+        offline: bool,
+    },
+    /// rewrite the configuration file in the current schema version, keeping the original next to it
+    MigrateConfig {
+        /// print the migrated configuration instead of rewriting the file
+        #[arg(long)]
+        print: bool,
+    },
+    /// print a service definition for a running compose project, to bootstrap its configuration
+    Import {
+        /// compose project to inspect
+        project: String,
+        /// docker context the project runs in, no configuration is read
+        #[arg(long)]
+        context: Option<String>,
+    },
+    /// look for databases that are copied raw or not backed up at all instead of being dumped,
+    /// printing the archives to add and exiting with the partial exit code if any is found
+    Suggest {
+        /// look at every compose project, not only the configured ones
+        #[arg(long)]
+        all: bool,
+    },
+    /// show past runs recorded in the state directory
+    History {
+        /// only show this service
+        #[arg(short, long)]
+        service: Option<String>,
+        /// only show runs started on or after this day (YYYY-MM-DD)
+        #[arg(long)]
+        since: Option<NaiveDate>,
+        /// only show runs started on or before this day (YYYY-MM-DD)
+        #[arg(long)]
+        until: Option<NaiveDate>,
+    },
+    /// show an overview of each service's backups, exiting with the partial exit code if any of
+    /// them wasn't backed up for longer than its max_age, or has fewer or older snapshots in the
+    /// repository than expected
+    Status {
+        /// run the partial hook with the stale services
+        #[arg(long)]
+        hook: bool,
+        /// don't query the repository for snapshots
+        #[arg(long)]
+        offline: bool,
+    },
+    /// restore the archives of a service from the repository
+    Restore {
+        /// service to restore
+        #[arg(required_unless_present_any = ["interactive", "all"])]
+        service: Option<String>,
+        /// archives to restore, every archive of the service if none is given
+        #[arg(conflicts_with = "interactive")]
+        archives: Vec<String>,
+        #[command(flatten)]
+        snapshot: SnapshotArgs,
+        #[command(flatten)]
+        target: Box<TargetArgs>,
+        /// give the restored files to this owner, as UID:GID
+        #[arg(long)]
+        owner: Option<String>,
+        /// print the files that would be written or overwritten, without touching anything
+        #[arg(long)]
+        dry_run: bool,
+        /// pick the service, archives and snapshot from lists, then follow the restore live
+        #[arg(long, short, conflicts_with_all = ["service", "snapshot", "at", "run", "latest"])]
+        interactive: bool,
+        /// restore every configured service, in the order of the configuration
+        #[arg(long, conflicts_with_all = ["service", "archives", "interactive", "snapshot", "target_volume"])]
+        all: bool,
+        /// bring the compose projects up from their compose_file once their volumes are restored,
+        /// before the dumps that need the services running
+        #[arg(long)]
+        up: bool,
+    },
+    /// restore a sample of files of a random archive from its latest snapshot and compare them with
+    /// the live data, exiting with the partial exit code if they don't match
+    Verify {
+        /// only pick an archive of this service
+        #[arg(short, long)]
+        service: Option<String>,
+        /// only test this archive
+        #[arg(short, long, requires = "service")]
+        archive: Option<String>,
+        /// how many files to compare, the configured number by default
+        #[arg(long)]
+        files: Option<usize>,
+        /// run the hooks with the outcome
+        #[arg(long)]
+        hook: bool,
+    },
+    /// copy a single archive to a local file, running its dump or taring its volume, without any
+    /// backend
+    Export {
+        /// the archive, as service:archive
+        #[arg(value_parser = parse_archive)]
+        archive: (String, String),
+        /// file to write, `<service>-<archive>.<ext>` in the current directory by default
+        #[arg(long)]
+        to: Option<PathBuf>,
+    },
+    /// print a disaster recovery plan for every service, from the latest manifest
+    DrPlan {
+        #[arg(long, value_enum, default_value = "markdown")]
+        format: DrPlanFormat,
+    },
+    /// run restic with the repository's credentials, as in `hoarder restic -- snapshots`, exiting
+    /// with its exit code
+    Restic {
+        /// use restic from the host instead of the restic container
+        #[arg(long)]
+        native: bool,
+        /// arguments handed to restic
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// mount the snapshots on a host directory to browse them, until interrupted
+    Mount {
+        /// host directory to mount the snapshots on
+        mountpoint: PathBuf,
+        /// use restic and FUSE from the host instead of the restic container
+        #[arg(long)]
+        native: bool,
+    },
+}
+
+/// Which snapshot to use, the latest one of the service by default.
+#[derive(Args, Debug)]
+#[group(multiple = false)]
+pub(crate) struct SnapshotArgs {
+    /// restic snapshot id
+    #[arg(long)]
+    snapshot: Option<String>,
+    /// latest snapshot taken at or before this time (YYYY-MM-DD HH:MM), or taken on this day
+    /// (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_at)]
+    at: Option<SnapshotSelector>,
+    /// snapshot taken by this hoarder run, as shown by `hoarder history`
+    #[arg(long)]
+    run: Option<String>,
+    /// latest snapshot
+    #[arg(long)]
+    latest: bool,
+}
+
+/// Where to restore, where the archives were backed up from by default.
+#[derive(Args, Debug)]
+#[group(multiple = false)]
+pub(crate) struct TargetArgs {
+    /// restore into this host directory instead, one subdirectory per volume and one file per dump
+    #[arg(long)]
+    target: Option<PathBuf>,
+    /// restore into the volumes and services of this compose project instead
+    #[arg(long)]
+    target_project: Option<String>,
+    /// restore into this named volume instead, for a single volume archive
+    #[arg(long)]
+    target_volume: Option<String>,
+}
+
+impl TargetArgs {
+    pub(crate) fn target(self) -> AlternateTarget {
+        self.target.map(AlternateTarget::Directory)
+            .or(self.target_project.map(AlternateTarget::Project))
+            .or(self.target_volume.map(AlternateTarget::Volume))
+            .unwrap_or(AlternateTarget::Original)
+    }
+}
+
+impl SnapshotArgs {
+    pub(crate) fn selector(self) -> SnapshotSelector {
+        self.snapshot.map(SnapshotSelector::Id)
+            .or(self.at)
+            .or(self.run.map(SnapshotSelector::Run))
+            .unwrap_or(SnapshotSelector::Latest)
+    }
+}
+
+fn parse_at(value: &str) -> Result<SnapshotSelector, String> {
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(value, format) {
+            return Ok(SnapshotSelector::At(time));
+        }
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(SnapshotSelector::Day)
+        .map_err(|_| "expected YYYY-MM-DD or YYYY-MM-DD HH:MM".to_owned())
+}
+
+impl Cli {
+    /// Parses the command line, exiting with the config exit code on usage errors: clap's own
+    /// exit code would clash with [`ExitCode::Partial`].
+    pub(crate) fn parse_or_exit() -> Self {
+        Self::try_parse().unwrap_or_else(|e| {
+            if !e.use_stderr() {
+                // --help and --version
+                e.exit();
+            }
+            let _ = e.print();
+            ExitCode::Config.exit();
+        })
+    }
+}
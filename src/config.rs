@@ -1,25 +1,38 @@
+use std::{collections::{BTreeMap, HashSet}, path::PathBuf};
+
 use serde::{Deserialize, Serialize};
 
-use crate::{hooks::HookConfig, service::Service, DockerCommand, DockerSubcommand, SerializableError};
+use crate::{archive::ArchiveInput, audit, backend::{BackendType, RepositoryCheck, Retention}, image::ImageVerification, borg::BorgConfig, notify::NotifyConfig, kopia::KopiaConfig, restic::{ResticHardening, ResticPassword}, secret::{Secret, VaultConfig}, lock::LockConfig, migrate::CONFIG_VERSION, mirror::MirrorConfig, order, rclone::RcloneConfig, rsync::RsyncConfig, service::Service, tarball::TarballConfig, theme::{ColorMode, Theme}, unmanaged::{UnmanagedConfig, UNMANAGED_SERVICE}, verify::VerifyConfig, DockerCommand, DockerInputType, DockerSubcommand, SerializableError};
 
 static RESTIC_ROOT: &str = "/restic";
 static RESTIC_IMAGE: &str = "test";
 static RESTIC_CONTAINER_NAME: &str = "hoarder-restic";
+static IO_BUFFER_SIZE: usize = 1 << 20;
+static HISTORY_SIZE: usize = 100;
+static AUDIT_LOG: &str = "audit.jsonl";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct FullConfig {
+    /// version of the configuration schema, see [`crate::migrate`]
+    pub(crate) version: u32,
     pub(crate) services: Vec<Service>,
-    pub(crate) hooks: HookConfig,
+    #[serde(default)]
+    pub(crate) hooks: NotifyConfig,
     #[serde(flatten)]
     pub(crate) config: Config,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub(crate) struct Config {
     /// where temporary data will be stored/mounted inside the restic container
     restic_root: Option<String>,
     /// the restic image to use
     restic_image: Option<String>,
+    /// checks the restic image is the expected one before running it
+    restic_image_verify: Option<ImageVerification>,
+    /// how the restic containers are locked down, every protection being on by default
+    #[serde(default)]
+    restic_hardening: ResticHardening,
     /// the restic path to back up once inside the container
     intermediate_path: Option<String>,
     /// directory to mount in restic container.
@@ -29,15 +42,303 @@ pub(crate) struct Config {
     intermediate_mount_override: Option<String>,
     /// the restic password file to use
     restic_password_file: Option<String>,
+    /// command printing the restic password, run by restic wherever it runs, instead of a file
+    restic_password_command: Option<String>,
+    /// the restic password itself, usually a secret reference, over the command and the file
+    restic_password: Option<Secret>,
+    /// more environment of the restic containers, usually secret references, passed by name so
+    /// that the values stay off the command lines
+    #[serde(default)]
+    restic_env: BTreeMap<String, Secret>,
+    /// how secret references to Vault are resolved
+    #[allow(dead_code)] // read by secret::resolve, before the configuration is loaded
+    vault: Option<VaultConfig>,
     /// restic host to use
     restic_host: Option<String>,
     /// the restic container name/id to use
     restic_container_name: Option<String>,
-    /// whether to run in dry run mode
+    /// how much of a backup a dry run goes through, `true` standing for `restic-dry-run`
+    #[serde(default, deserialize_with = "DryRun::deserialize_compat")]
+    dry_run: DryRun,
+    /// whether to ask before the destructive steps of a run, only set from the command line
+    #[serde(skip)]
+    interactive: bool,
+    /// whether to delete the staged dumps once they have been backed up
+    #[serde(default)]
+    cleanup_intermediate: bool,
+    /// whether to back up this configuration, with secrets redacted, along with the run manifest
+    #[serde(default)]
+    backup_config: bool,
+    /// skip scanning the mounted volumes for paths the backup can't read before dumping
+    #[serde(default)]
+    skip_readability_scan: bool,
+    /// when the terminal output is colored: `auto`, `always` or `never`
+    #[serde(default)]
+    color: ColorMode,
+    /// colors of the terminal output: `default`, `colorblind` or `mono`
+    #[serde(default)]
+    theme: Theme,
+    /// size in bytes of the buffer used to copy dumps to the intermediate directory
+    io_buffer_size: Option<usize>,
+    /// minimum free space in bytes required on the intermediate filesystem before each dump,
+    /// defaults to the size of the archive's previous dump
+    min_free_space: Option<u64>,
+    /// free space in bytes on the intermediate filesystem under which the dump being written is
+    /// aborted and the remaining ones are skipped, the staged ones still being backed up
+    low_water_mark: Option<u64>,
+    /// default maximum rate in bytes per second at which dumps are written
+    io_limit: Option<u64>,
+    /// where state is persisted between runs, defaults to `.hoarder` inside the intermediate path
+    state_dir: Option<String>,
+    /// how many runs are kept in the history inside the state directory
+    history_size: Option<usize>,
+    /// lock shared with other hoarder instances using the same repository
+    lock: Option<LockConfig>,
+    /// backs up the named volumes no service covers under a catch-all service
+    unmanaged: Option<UnmanagedConfig>,
+    /// whether to back up the archives declared with `hoarder.archive.*` labels on the containers
+    /// of every compose project
+    #[serde(default)]
+    docker_labels: bool,
+    /// directory where metrics are written for the node_exporter textfile collector
+    metrics_dir: Option<String>,
+    /// scheduled restore tests, none are run if unset
+    verify: Option<VerifyConfig>,
+    /// file every external command run is appended to, as json lines, `audit.jsonl` in the state
+    /// directory if unset
+    audit_log: Option<String>,
+    /// the tool and repository the services are backed up to, restic by default
+    backend: Option<BackendType>,
+    /// backend the services are backed up to instead of the configured one when its repository
+    /// can't be reached over the network, the run being reported as degraded. Any other error of
+    /// the repository, like a wrong password, still fails the run
+    fallback: Option<BackendType>,
+    /// old snapshots to forget after every successful run, none are if unset
+    retention: Option<Retention>,
+    /// deep checks of the repository, each run after a backup once due
     #[serde(default)]
-    dry_run: bool,
+    check_schedule: Vec<RepositoryCheck>,
+    /// settings of the borg backend
+    borg: Option<BorgConfig>,
+    /// settings of the kopia backend
+    kopia: Option<KopiaConfig>,
+    /// settings of the rclone backend
+    rclone: Option<RcloneConfig>,
+    /// settings of the tarball backend
+    tarball: Option<TarballConfig>,
+    /// settings of the mirror backend
+    mirror: Option<MirrorConfig>,
+    /// settings of the rsync backend
+    rsync: Option<RsyncConfig>,
+    /// environment of each backend's containers, usually secret references: a backend listed here
+    /// gets these variables only, instead of the matching ones hoarder was given
+    #[serde(default)]
+    credentials: BTreeMap<BackendType, BTreeMap<String, Secret>>,
     #[serde(default)]
     pub(crate) docker_context: Option<String>,
+    /// docker cli every docker command is run with, `docker` from the PATH if unset
+    #[serde(default)]
+    docker_binary: Option<String>,
+}
+
+impl FullConfig {
+    /// Checks the names that end up in paths and docker arguments, and the settings that can be
+    /// checked offline, reporting every problem at once.
+    pub(crate) fn validate(&self) -> Result<(), SerializableError> {
+        let mut problems = vec![];
+        let mut service_names = HashSet::new();
+        for service in &self.services {
+            if let Err(e) = check_name("service name", &service.name) {
+                problems.push(e);
+            } else if !service_names.insert(&service.name) {
+                problems.push(format!("service {} is defined more than once", service.name));
+            } else if service.name == UNMANAGED_SERVICE && self.config.unmanaged().is_some() {
+                problems.push(format!("service name {} is used by the unmanaged volumes", UNMANAGED_SERVICE));
+            }
+            if let Some(project) = &service.compose_project
+                && let Err(e) = check_name("compose project", project)
+            {
+                problems.push(format!("{}: {}", service.name, e));
+            }
+            let mut archive_names = HashSet::new();
+            for archive in &service.archives {
+                if let Err(e) = check_name("archive name", &archive.name) {
+                    problems.push(format!("{}: {}", service.name, e));
+                } else if !archive_names.insert(&archive.name) {
+                    problems.push(format!("{}: archive {} is defined more than once", service.name, archive.name));
+                }
+                // plugins name their own things
+                let ArchiveInput::Docker(input) = &archive.input else {
+                    continue;
+                };
+                let names = match input {
+                    DockerInputType::ExecStdout { service, ext, .. } => vec![("compose service", service), ("extension", ext)],
+                    DockerInputType::ComposeNamedVolume { name, .. } | DockerInputType::Volume { name, .. } => vec![("volume name", name)],
+                    DockerInputType::ComposeBoundVolume { service, .. } => vec![("compose service", service)],
+                };
+                for (what, name) in names {
+                    if let Err(e) = check_name(what, name) {
+                        problems.push(format!("{}: {}: {}", service.name, archive.name, e));
+                    }
+                }
+            }
+        }
+        for service in &self.services {
+            let listed = service.archives.iter().any(|a| matches!(
+                &a.input,
+                ArchiveInput::Docker(input) if input.filter().is_some_and(|f| !f.files_from.is_empty()),
+            ));
+            let backends = service.backends(&self.config);
+            if (listed || service.one_file_system) && backends.iter().any(|b| *b != BackendType::Restic) {
+                problems.push(format!("{}: files_from and one_file_system are only supported by the restic backend", service.name));
+            }
+        }
+        problems.extend(order::problems(&self.services));
+        if let Some(verification) = self.config.restic_image_verify() {
+            problems.extend(verification.problems());
+        }
+        for backend in self.config.credentials.keys() {
+            if matches!(backend, BackendType::Mirror | BackendType::Rsync) {
+                problems.push(format!("credentials: the {} backend doesn't take any", backend.name()));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(SerializableError::config(format!("invalid config: {}", problems.join("; "))))
+        }
+    }
+}
+
+/// Puts a configuration together in code rather than from yaml, checking it like a loaded one.
+#[derive(Default)]
+pub(crate) struct ConfigBuilder {
+    config: Config,
+    services: Vec<Service>,
+    hooks: NotifyConfig,
+}
+
+#[allow(dead_code)]
+impl ConfigBuilder {
+    pub(crate) fn new(intermediate_path: impl ToString, restic_host: impl ToString) -> Self {
+        let mut builder = Self::default();
+        builder.config.intermediate_path = Some(intermediate_path.to_string());
+        builder.config.restic_host = Some(restic_host.to_string());
+        builder
+    }
+
+    pub(crate) fn restic_root(mut self, restic_root: impl ToString) -> Self {
+        self.config.restic_root = Some(restic_root.to_string());
+        self
+    }
+
+    pub(crate) fn restic_image(mut self, restic_image: impl ToString) -> Self {
+        self.config.restic_image = Some(restic_image.to_string());
+        self
+    }
+
+    pub(crate) fn state_dir(mut self, state_dir: impl ToString) -> Self {
+        self.config.state_dir = Some(state_dir.to_string());
+        self
+    }
+
+    pub(crate) fn dry_run(mut self, dry_run: DryRun) -> Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    pub(crate) fn backend(mut self, backend: BackendType) -> Self {
+        self.config.backend = Some(backend);
+        self
+    }
+
+    pub(crate) fn fallback(mut self, fallback: BackendType) -> Self {
+        self.config.fallback = Some(fallback);
+        self
+    }
+
+    pub(crate) fn retention(mut self, retention: Retention) -> Self {
+        self.config.retention = Some(retention);
+        self
+    }
+
+    pub(crate) fn borg(mut self, borg: BorgConfig) -> Self {
+        self.config.borg = Some(borg);
+        self
+    }
+
+    pub(crate) fn kopia(mut self, kopia: KopiaConfig) -> Self {
+        self.config.kopia = Some(kopia);
+        self
+    }
+
+    pub(crate) fn rclone(mut self, rclone: RcloneConfig) -> Self {
+        self.config.rclone = Some(rclone);
+        self
+    }
+
+    pub(crate) fn tarball(mut self, tarball: TarballConfig) -> Self {
+        self.config.tarball = Some(tarball);
+        self
+    }
+
+    pub(crate) fn unmanaged(mut self, unmanaged: UnmanagedConfig) -> Self {
+        self.config.unmanaged = Some(unmanaged);
+        self
+    }
+
+    pub(crate) fn docker_binary(mut self, docker_binary: impl ToString) -> Self {
+        self.config.docker_binary = Some(docker_binary.to_string());
+        self
+    }
+
+    pub(crate) fn docker_labels(mut self, docker_labels: bool) -> Self {
+        self.config.docker_labels = docker_labels;
+        self
+    }
+
+    pub(crate) fn mirror(mut self, mirror: MirrorConfig) -> Self {
+        self.config.mirror = Some(mirror);
+        self
+    }
+
+    pub(crate) fn rsync(mut self, rsync: RsyncConfig) -> Self {
+        self.config.rsync = Some(rsync);
+        self
+    }
+
+    pub(crate) fn hooks(mut self, hooks: NotifyConfig) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    pub(crate) fn service(mut self, service: Service) -> Self {
+        self.services.push(service);
+        self
+    }
+
+    /// Checks the configuration like [`FullConfig::validate`] does for a loaded one.
+    pub(crate) fn build(self) -> Result<FullConfig, SerializableError> {
+        let full_config = FullConfig { version: CONFIG_VERSION, services: self.services, hooks: self.hooks, config: self.config };
+        full_config.validate()?;
+        Ok(full_config)
+    }
+}
+
+/// Names are used as path components and docker arguments: only allow characters that are safe
+/// in both, and nothing that could be mistaken for a relative path or a command line flag.
+pub(crate) fn check_name(what: &str, name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err(format!("{} can't be empty", what));
+    }
+    if name.starts_with('.') || name.starts_with('-') {
+        return Err(format!("{} {:?} can't start with '{}'", what, name, &name[..1]));
+    }
+    if let Some(c) = name.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))) {
+        return Err(format!("{} {:?} contains {:?}, only letters, digits, '_', '-' and '.' are allowed", what, name, c));
+    }
+    Ok(())
 }
 
 impl Config {
@@ -59,21 +360,75 @@ impl Config {
             .unwrap_or(RESTIC_ROOT.to_string())
     }
 
+    /// The restic image, by digest when it is pinned to one.
     pub fn restic_image(&self) -> String {
-        self._get_env("RESTIC_IMAGE")
+        let image = self._get_env("RESTIC_IMAGE")
             .or_else(|| self.restic_image.clone())
-            .unwrap_or(RESTIC_IMAGE.to_string())
+            .unwrap_or(RESTIC_IMAGE.to_string());
+        match self.restic_image_verify.as_ref().and_then(|v| v.digest.as_ref()) {
+            Some(digest) => format!("{}@{}", image.split_once('@').map_or(image.as_str(), |(name, _)| name), digest),
+            None => image,
+        }
+    }
+
+    pub(crate) fn restic_hardening(&self) -> &ResticHardening {
+        &self.restic_hardening
+    }
+
+    pub(crate) fn restic_image_verify(&self) -> Option<&ImageVerification> {
+        self.restic_image_verify.as_ref()
     }
 
     pub fn restic_password_file(&self) -> Result<String, SerializableError> {
         self._get_env("RESTIC_PASSWORD_FILE")
-            .ok_or(SerializableError::new("restic_password_file must be set"))
+            .or_else(|| self.restic_password_file.clone())
+            .ok_or(SerializableError::config("restic_password_file must be set"))
+    }
+
+    pub fn restic_password_command(&self) -> Option<String> {
+        self._get_env("RESTIC_PASSWORD_COMMAND")
+            .or_else(|| self.restic_password_command.clone())
+    }
+
+    /// Where restic gets the repository password from: the one given at runtime or in the
+    /// configuration, the command or the file, in that order.
+    pub(crate) fn restic_password(&self) -> Result<ResticPassword, SerializableError> {
+        if let Some(Secret(password)) = &self.restic_password {
+            return Ok(ResticPassword::Value(password.clone()));
+        }
+        if let Some(command) = self.restic_password_command() {
+            return Ok(ResticPassword::Command(command));
+        }
+        self.restic_password_file()
+            .map(ResticPassword::File)
+            .map_err(|_| SerializableError::config("one of restic_password_file and restic_password_command must be set"))
+    }
+
+    pub(crate) fn restic_env(&self) -> &BTreeMap<String, Secret> {
+        &self.restic_env
+    }
+
+    pub(crate) fn credentials(&self, backend: BackendType) -> Option<&BTreeMap<String, Secret>> {
+        self.credentials.get(&backend)
+    }
+
+    pub(crate) fn set_dry_run(&mut self, dry_run: DryRun) {
+        self.dry_run = dry_run;
+    }
+
+    pub(crate) fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    pub(crate) fn set_restic_password(&mut self, password: String) {
+        audit::secret(&password);
+        self.restic_password = Some(Secret(password));
     }
 
     pub fn restic_host(&self) -> Result<String, SerializableError> {
         self._get_env("RESTIC_HOST")
             .or_else(|| self.restic_host.clone())
-            .ok_or(SerializableError::new("restic_host must be set"))
+            .ok_or(SerializableError::config("restic_host must be set"))
     }
 
     pub fn restic_container_name(&self) -> String {
@@ -85,7 +440,7 @@ impl Config {
     pub fn intermediate_path(&self) -> Result<String, SerializableError> {
         self._get_env("INTERMEDIATE")
             .or_else(|| self.intermediate_path.clone())
-            .ok_or(SerializableError::new("intermediate_path must be set"))
+            .ok_or(SerializableError::config("intermediate_path must be set"))
     }
 
     pub fn intermediate_mount_override(&self) -> Option<String> {
@@ -93,18 +448,253 @@ impl Config {
             .or_else(|| self.intermediate_mount_override.clone())
     }
 
+    pub fn min_free_space(&self) -> Option<u64> {
+        self._get_env("MIN_FREE_SPACE")
+            .map(|v| v.parse().expect("HOARDER_MIN_FREE_SPACE must be a size in bytes"))
+            .or(self.min_free_space)
+    }
+
+    pub fn low_water_mark(&self) -> Option<u64> {
+        self._get_env("LOW_WATER_MARK")
+            .map(|v| v.parse().expect("HOARDER_LOW_WATER_MARK must be a size in bytes"))
+            .or(self.low_water_mark)
+    }
+
+    pub fn io_limit(&self) -> Option<u64> {
+        self._get_env("IO_LIMIT")
+            .map(|v| v.parse().expect("HOARDER_IO_LIMIT must be a rate in bytes per second"))
+            .or(self.io_limit)
+    }
+
+    pub fn state_dir(&self) -> Result<PathBuf, SerializableError> {
+        match self._get_env("STATE_DIR").or_else(|| self.state_dir.clone()) {
+            Some(dir) => Ok(PathBuf::from(dir)),
+            None => Ok(PathBuf::from(self.intermediate_path()?).join(".hoarder")),
+        }
+    }
+
+    pub fn io_buffer_size(&self) -> usize {
+        self._get_env("IO_BUFFER_SIZE")
+            .map(|v| v.parse().expect("HOARDER_IO_BUFFER_SIZE must be a size in bytes"))
+            .or(self.io_buffer_size)
+            .unwrap_or(IO_BUFFER_SIZE)
+    }
+
+    pub fn history_size(&self) -> usize {
+        self._get_env("HISTORY_SIZE")
+            .map(|v| v.parse().expect("HOARDER_HISTORY_SIZE must be a number of runs"))
+            .or(self.history_size)
+            .unwrap_or(HISTORY_SIZE)
+    }
+
+    pub fn metrics_dir(&self) -> Option<PathBuf> {
+        self._get_env("METRICS_DIR")
+            .or_else(|| self.metrics_dir.clone())
+            .map(PathBuf::from)
+    }
+
+    pub fn audit_log(&self) -> Result<PathBuf, SerializableError> {
+        match self._get_env("AUDIT_LOG").or_else(|| self.audit_log.clone()) {
+            Some(file) => Ok(PathBuf::from(file)),
+            None => Ok(self.state_dir()?.join(AUDIT_LOG)),
+        }
+    }
+
+    pub(crate) fn verify(&self) -> Option<&VerifyConfig> {
+        self.verify.as_ref()
+    }
+
+    pub fn lock(&self) -> Option<&LockConfig> {
+        self.lock.as_ref()
+    }
+
+    pub fn unmanaged(&self) -> Option<&UnmanagedConfig> {
+        self.unmanaged.as_ref()
+    }
+
+    pub fn docker_labels(&self) -> bool {
+        self.docker_labels
+    }
+
+    pub fn backend(&self) -> BackendType {
+        self.backend.unwrap_or_default()
+    }
+
+    pub fn fallback(&self) -> Option<BackendType> {
+        self.fallback
+    }
+
+    pub fn retention(&self) -> Option<&Retention> {
+        self.retention.as_ref()
+    }
+
+    pub(crate) fn check_schedule(&self) -> &[RepositoryCheck] {
+        &self.check_schedule
+    }
+
+    pub fn borg(&self) -> Option<&BorgConfig> {
+        self.borg.as_ref()
+    }
+
+    pub fn kopia(&self) -> Option<&KopiaConfig> {
+        self.kopia.as_ref()
+    }
+
+    pub fn rclone(&self) -> Option<&RcloneConfig> {
+        self.rclone.as_ref()
+    }
+
+    pub fn tarball(&self) -> Option<&TarballConfig> {
+        self.tarball.as_ref()
+    }
+
+    pub fn mirror(&self) -> Option<&MirrorConfig> {
+        self.mirror.as_ref()
+    }
+
+    pub fn rsync(&self) -> Option<&RsyncConfig> {
+        self.rsync.as_ref()
+    }
+
     pub fn docker_command_with_context(&self, subcommand: DockerSubcommand) -> DockerCommand {
         DockerCommand::new(
             subcommand,
             self.docker_context.clone(),
-        )
+        ).with_program(self.docker_binary())
     }
 
+    pub fn docker_binary(&self) -> String {
+        self._get_env("DOCKER_BINARY")
+            .or_else(|| self.docker_binary.clone())
+            .unwrap_or("docker".to_owned())
+    }
+
+    pub fn cleanup_intermediate(&self) -> bool {
+        self._get_env("CLEANUP_INTERMEDIATE")
+            .map(|v| v.parse().expect("HOARDER_CLEANUP_INTERMEDIATE must be true or false"))
+            .unwrap_or(self.cleanup_intermediate)
+    }
+
+    pub fn backup_config(&self) -> bool {
+        self._get_env("BACKUP_CONFIG")
+            .map(|v| v.parse().expect("HOARDER_BACKUP_CONFIG must be true or false"))
+            .unwrap_or(self.backup_config)
+    }
+
+    pub fn skip_readability_scan(&self) -> bool {
+        self._get_env("SKIP_READABILITY_SCAN")
+            .map(|v| v.parse().expect("HOARDER_SKIP_READABILITY_SCAN must be true or false"))
+            .unwrap_or(self.skip_readability_scan)
+    }
+
+    pub(crate) fn color(&self) -> ColorMode {
+        self._get_env("COLOR")
+            .map(|v| ColorMode::parse(&v).expect("HOARDER_COLOR must be auto, always or never"))
+            .unwrap_or(self.color)
+    }
+
+    pub(crate) fn theme(&self) -> Theme {
+        self._get_env("THEME")
+            .map(|v| Theme::parse(&v).expect("HOARDER_THEME must be default, colorblind or mono"))
+            .unwrap_or(self.theme)
+    }
+
+    /// Whether this isn't a real backup, at any dry run level: nothing is recorded in the state.
     pub fn dry_run(&self) -> bool {
+        self.dry_run_level() != DryRun::Off
+    }
+
+    /// Whether an operator is there to confirm the destructive steps of a run.
+    pub(crate) fn interactive(&self) -> bool {
+        self.interactive
+    }
+
+    pub fn dry_run_level(&self) -> DryRun {
         self._get_env("DRY_RUN")
-            .or_else(|| Some(self.dry_run.to_string()))
-            .unwrap_or("false".to_string())
-            .parse()
-            .unwrap()
+            .map(|v| DryRun::parse(&v).expect("HOARDER_DRY_RUN must be true, false, plan, stage or restic-dry-run"))
+            .unwrap_or(self.dry_run)
+    }
+}
+
+/// How much of a backup a dry run goes through.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DryRun {
+    /// a real backup
+    #[default]
+    Off,
+    /// print what would be done, only querying docker
+    Plan,
+    /// run the dumps and the service tasks, but nothing of the backends
+    Stage,
+    /// go through the whole run with the backends in their own dry run mode, like `restic backup
+    /// --dry-run`, without writing the dumps
+    #[serde(rename = "restic-dry-run")]
+    #[value(name = "restic-dry-run")]
+    Restic,
+}
+
+impl DryRun {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "true" => Some(Self::Restic),
+            "false" => Some(Self::Off),
+            _ => <Self as clap::ValueEnum>::from_str(value, false).ok(),
+        }
+    }
+
+    /// Whether the dumps and the service tasks are run for real.
+    pub(crate) fn stages(self) -> bool {
+        matches!(self, Self::Off | Self::Stage)
     }
+
+    /// Reads a level, or a boolean as the configurations before the levels have it.
+    fn deserialize_compat<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Compat {
+            Bool(bool),
+            Level(DryRun),
+        }
+
+        Ok(match Compat::deserialize(deserializer)? {
+            Compat::Bool(true) => Self::Restic,
+            Compat::Bool(false) => Self::Off,
+            Compat::Level(level) => level,
+        })
+    }
+}
+
+#[test]
+fn test_config_builder() {
+    use crate::{service::ServiceBuilder, ShellTask};
+
+    let service = ServiceBuilder::new("app")
+        .named_volume("data", "data", vec![])
+        .exec_stdout("db", "postgres", ShellTask::new("pg_dumpall"), "sql")
+        .build()
+        .unwrap();
+    let full_config = ConfigBuilder::new("/tmp/hoarder", "host").service(service).build().unwrap();
+    assert_eq!(full_config.config.intermediate_path().unwrap(), "/tmp/hoarder");
+    assert_eq!(full_config.services[0].archives.len(), 2);
+
+    assert!(ServiceBuilder::new("app").build().is_err());
+    assert!(ServiceBuilder::new("app")
+        .named_volume("data", "data", vec![])
+        .named_volume("data", "other", vec![])
+        .build()
+        .is_err());
+    let service = ServiceBuilder::new("../app").named_volume("data", "data", vec![]).build().unwrap();
+    assert!(ConfigBuilder::new("/tmp/hoarder", "host").service(service).build().is_err());
+}
+
+#[test]
+fn test_dry_run_levels() {
+    let level = |yaml: &str| serde_yaml::from_str::<Config>(yaml).unwrap().dry_run;
+    assert_eq!(level("dry_run: true"), DryRun::Restic);
+    assert_eq!(level("dry_run: false"), DryRun::Off);
+    assert_eq!(level("dry_run: stage"), DryRun::Stage);
+    assert_eq!(level("restic_host: test"), DryRun::Off);
+    assert_eq!(DryRun::parse("plan"), Some(DryRun::Plan));
+    assert!(serde_yaml::from_str::<Config>("dry_run: maybe").is_err());
 }
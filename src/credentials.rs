@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+
+use log::debug;
+
+use crate::{backend::BackendType, config::Config, secret::Secret};
+
+/// The credentials of a backend's containers, passed to `docker run` by name only: the values are
+/// set on the docker client, keeping them off the command lines.
+pub(crate) struct Credentials {
+    vars: BTreeMap<String, String>,
+}
+
+impl Credentials {
+    /// The credentials of `backend`: its section under `credentials` when it has one, or else the
+    /// variables of hoarder's environment starting with one of `prefixes`. Scoping each backend's
+    /// credentials keeps one repository's keys away from the others' containers.
+    pub(crate) fn new(config: &Config, backend: BackendType, prefixes: &[&str]) -> Self {
+        let vars = match config.credentials(backend) {
+            Some(scoped) => {
+                debug!("{}: using its scoped credentials", backend.name());
+                scoped.iter().map(|(k, Secret(v))| (k.clone(), v.clone())).collect()
+            }
+            None => std::env::vars()
+                .filter(|(key, _)| prefixes.iter().any(|p| key.starts_with(p)))
+                .inspect(|(key, _)| debug!("{}: forwarding env var {}", backend.name(), key))
+                .collect(),
+        };
+        Self { vars }
+    }
+
+    /// Leaves `name` out, for variables the backend sets itself.
+    pub(crate) fn without(mut self, names: &[&str]) -> Self {
+        self.vars.retain(|k, _| !names.contains(&k.as_str()));
+        self
+    }
+
+    /// Adds `vars`, over the ones already there.
+    pub(crate) fn with(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.vars.extend(vars);
+        self
+    }
+
+    /// `docker run` options passing every variable by name.
+    pub(crate) fn options(&self) -> Vec<String> {
+        self.vars.keys().flat_map(|k| ["--env".to_owned(), k.clone()]).collect()
+    }
+
+    /// The variables to set on the docker client.
+    pub(crate) fn vars(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.vars.iter()
+    }
+
+    pub(crate) fn into_vars(self) -> Vec<(String, String)> {
+        self.vars.into_iter().collect()
+    }
+}
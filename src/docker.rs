@@ -1,21 +1,66 @@
-use std::path::{Path, PathBuf};
+use std::{path::{Path, PathBuf}, process::ExitStatus};
 
 use serde::{Deserialize, Serialize};
+use tokio::process::Command;
 
-use crate::{either::Either, ShellTask};
+use crate::{audit::Audited, either::Either, SerializableError, ShellTask};
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "lowercase")]
-pub(crate) struct PathExclude(pub(crate) Vec<PathBuf>);
+/// What a mounted archive leaves out, or the only paths it backs up, as glob patterns relative
+/// to the archive.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub(crate) struct PathExclude {
+    /// patterns of the paths left out
+    #[serde(default)]
+    pub(crate) exclude: Vec<PathBuf>,
+    /// patterns matched regardless of case, as restic's `--iexclude`; backends that can't ignore
+    /// the case match them like the others
+    #[serde(default)]
+    pub(crate) iexclude: Vec<PathBuf>,
+    /// files on the host with more patterns, one per line, as restic's `--exclude-file`
+    #[serde(default)]
+    pub(crate) exclude_file: Vec<PathBuf>,
+    /// paths backed up instead of the whole archive, listed for restic's `--files-from`
+    #[serde(default)]
+    pub(crate) files_from: Vec<PathBuf>,
+}
 
 impl PathExclude {
-    pub(crate) fn join(self, x: impl AsRef<Path>) -> Self {
-        Self(self.0.into_iter()
-            .map(|p| p.join(x.as_ref()))
-            .collect())
+    pub(crate) fn new(exclude: Vec<PathBuf>) -> Self {
+        Self { exclude, ..Default::default() }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.exclude.is_empty() && self.iexclude.is_empty() && self.exclude_file.is_empty() && self.files_from.is_empty()
+    }
+
+    /// The patterns under `root`, where the archive is mounted, with those of the exclude files
+    /// read into `exclude`.
+    pub(crate) fn resolve(self, root: &Path) -> Result<Self, SerializableError> {
+        let mut exclude = self.exclude;
+        for file in &self.exclude_file {
+            let text = std::fs::read_to_string(file)
+                .map_err(|e| SerializableError::config(format!("failed to read the exclude file {}: {}", file.display(), e)))?;
+            exclude.extend(exclude_file_patterns(&text));
+        }
+        // a leading slash anchors the pattern to the archive, not to the backup container
+        let anchor = |p: PathBuf| root.join(p.strip_prefix("/").unwrap_or(&p));
+        Ok(Self {
+            exclude: exclude.into_iter().map(anchor).collect(),
+            iexclude: self.iexclude.into_iter().map(anchor).collect(),
+            exclude_file: vec![],
+            files_from: self.files_from.into_iter().map(anchor).collect(),
+        })
     }
 }
 
+/// The patterns of an exclude file, skipping blank lines and `#` comments like restic does.
+fn exclude_file_patterns(text: &str) -> impl Iterator<Item = PathBuf> + '_ {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(PathBuf::from)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "docker_type")]
 pub(crate) enum DockerInputType {
@@ -30,16 +75,39 @@ pub(crate) enum DockerInputType {
         #[serde(flatten)]
         filter: Option<PathExclude>,
     },
+    /// a volume by its full name, outside of any compose project
+    Volume {
+        name: String,
+        #[serde(flatten)]
+        filter: Option<PathExclude>,
+    },
     ExecStdout {
         service: String,
         task: ShellTask,
         ext: String,
+        /// task reading a dump from stdin, used to restore it
+        #[serde(default)]
+        restore_task: Option<ShellTask>,
+        /// user the task and the restore_task run as, the container's if unset
+        #[serde(default)]
+        user: Option<String>,
+    }
+}
+
+impl DockerInputType {
+    /// What the archive leaves out, or only backs up, when it is mounted.
+    pub(crate) fn filter(&self) -> Option<&PathExclude> {
+        match self {
+            Self::ComposeNamedVolume { filter, .. } | Self::ComposeBoundVolume { filter, .. } | Self::Volume { filter, .. } => filter.as_ref(),
+            Self::ExecStdout { .. } => None,
+        }
     }
 }
 
 pub(crate) enum DockerSubcommand {
     Compose {
-        project: Either<String, PathBuf>,
+        /// the project to act on, none for commands spanning every project
+        project: Option<Either<String, PathBuf>>,
         subcommand: DockerComposeSubcommand,
         options: Vec<String>,
         options_inner: Vec<String>,
@@ -47,10 +115,16 @@ pub(crate) enum DockerSubcommand {
     Volume {
         subcommand: DockerVolumeSubcommand
     },
+    Image {
+        subcommand: DockerImageSubcommand
+    },
     Container {
         subcommand: DockerContainerSubcommand,
         options: Vec<String>,
     },
+    System {
+        subcommand: DockerSystemSubcommand,
+    },
     Run {
         image: String,
         volumes: Vec<DockerBinding>,
@@ -70,7 +144,7 @@ pub(crate) enum DockerSubcommand {
 
 impl DockerSubcommand {
     pub(crate) fn compose(
-        project: Either<String, PathBuf>,
+        project: Option<Either<String, PathBuf>>,
         subcommand: DockerComposeSubcommand,
         options: Vec<impl ToString>,
         options_inner: Vec<impl ToString>,
@@ -87,6 +161,10 @@ impl DockerSubcommand {
         Self::Volume { subcommand }
     }
 
+    pub(crate) fn image(subcommand: DockerImageSubcommand) -> Self {
+        Self::Image { subcommand }
+    }
+
     pub(crate) fn container(subcommand: DockerContainerSubcommand, options: Vec<impl ToString>) -> Self {
         Self::Container {
             subcommand,
@@ -141,18 +219,56 @@ pub(crate) enum DockerComposeSubcommand {
         task: ShellTask,
     },
     Ps(Vec<String>),
+    Ls,
+    Up,
+    /// creates the containers of the project without starting them
+    Create,
 }
 
 pub(crate) enum DockerVolumeSubcommand {
     Inspect {
         volume: String,
-    }
+    },
+    Ls {
+        format: String,
+    },
+    Create {
+        volume: String,
+        labels: Vec<(String, String)>,
+    },
 }
 
 impl DockerVolumeSubcommand {
     pub(crate) fn inspect(volume: impl ToString) -> Self {
         Self::Inspect { volume: volume.to_string() }
     }
+
+    pub(crate) fn ls(format: impl ToString) -> Self {
+        Self::Ls { format: format.to_string() }
+    }
+
+    pub(crate) fn create(volume: impl ToString, labels: Vec<(String, String)>) -> Self {
+        Self::Create { volume: volume.to_string(), labels }
+    }
+}
+
+pub(crate) enum DockerImageSubcommand {
+    Inspect {
+        image: String,
+    },
+    Pull {
+        image: String,
+    },
+}
+
+impl DockerImageSubcommand {
+    pub(crate) fn inspect(image: impl ToString) -> Self {
+        Self::Inspect { image: image.to_string() }
+    }
+
+    pub(crate) fn pull(image: impl ToString) -> Self {
+        Self::Pull { image: image.to_string() }
+    }
 }
 
 pub(crate) enum DockerContainerSubcommand {
@@ -161,18 +277,32 @@ pub(crate) enum DockerContainerSubcommand {
     },
 }
 
+pub(crate) enum DockerSystemSubcommand {
+    /// disk usage of every image, container and volume, with `-v`
+    Df {
+        format: String,
+    },
+}
+
 pub(crate) struct DockerCommand {
     pub(crate) subcommand: DockerSubcommand,
     pub(crate) context: Option<String>,
+    /// docker cli the command is run with
+    pub(crate) program: String,
 }
 
 impl DockerCommand {
     pub(crate) fn new(subcommand: DockerSubcommand, context: Option<String>) -> Self {
-        Self { subcommand, context }
+        Self { subcommand, context, program: "docker".to_owned() }
+    }
+
+    pub(crate) fn with_program(mut self, program: impl ToString) -> Self {
+        self.program = program.to_string();
+        self
     }
 
-    pub(crate) fn into_command(self) -> std::process::Command {
-        let mut command = std::process::Command::new("docker");
+    pub(crate) fn into_command(self) -> Command {
+        let mut command = Command::new(self.program);
         if let Some(context) = self.context {
             command.arg("-c").arg(context);
         }
@@ -186,8 +316,9 @@ impl DockerCommand {
             } => {
                 command.arg("compose");
                 match project {
-                    Either::Left(project) => command.arg("-p").arg(project),
-                    Either::Right(path) => command.arg("-f").arg(path),
+                    Some(Either::Left(project)) => command.arg("-p").arg(project),
+                    Some(Either::Right(path)) => command.arg("-f").arg(path),
+                    None => &mut command,
                 };
                 command.args(options);
                 match subcommand {
@@ -210,6 +341,21 @@ impl DockerCommand {
                             .args(services)
                             .args(options_inner);
                     }
+                    DockerComposeSubcommand::Ls => {
+                        command
+                            .arg("ls")
+                            .args(options_inner);
+                    }
+                    DockerComposeSubcommand::Up => {
+                        command
+                            .arg("up")
+                            .args(options_inner);
+                    }
+                    DockerComposeSubcommand::Create => {
+                        command
+                            .arg("create")
+                            .args(options_inner);
+                    }
                 };
             }
             DockerSubcommand::Volume { subcommand } => {
@@ -218,6 +364,35 @@ impl DockerCommand {
                     DockerVolumeSubcommand::Inspect { volume } => {
                         command.arg("inspect").arg(volume);
                     }
+                    DockerVolumeSubcommand::Ls { format } => {
+                        command.arg("ls").arg("--format").arg(format);
+                    }
+                    DockerVolumeSubcommand::Create { volume, labels } => {
+                        command.arg("create");
+                        for (key, value) in labels {
+                            command.arg("--label").arg(format!("{}={}", key, value));
+                        }
+                        command.arg(volume);
+                    }
+                };
+            }
+            DockerSubcommand::Image { subcommand } => {
+                command.arg("image");
+                match subcommand {
+                    DockerImageSubcommand::Inspect { image } => {
+                        command.arg("inspect").arg(image);
+                    }
+                    DockerImageSubcommand::Pull { image } => {
+                        command.arg("pull").arg(image);
+                    }
+                };
+            }
+            DockerSubcommand::System { subcommand } => {
+                command.arg("system");
+                match subcommand {
+                    DockerSystemSubcommand::Df { format } => {
+                        command.arg("df").arg("-v").arg("--format").arg(format);
+                    }
                 };
             }
             DockerSubcommand::Container { subcommand, options } => {
@@ -259,24 +434,12 @@ impl DockerCommand {
         command
     }
 
-    pub(crate) fn spawn(self) -> std::io::Result<std::process::Child> {
-        self.into_command().spawn()
-    }
-
-    pub(crate) fn spawn_and_wait(self) -> std::io::Result<std::process::ExitStatus> {
-        self.into_command().spawn()?.wait()
-    }
-
-    pub(crate) fn spawn_and_expect(self) {
-        match self.spawn_and_wait() {
-            Ok(status) if status.success() => {},
-            Ok(status) => panic!("Docker command failed with status: {}", status),
-            Err(e) => panic!("Failed to spawn Docker command: {}", e),
-        }
+    pub(crate) async fn spawn_and_wait(self) -> std::io::Result<ExitStatus> {
+        self.into_command().audited_spawn()?.wait().await
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct DockerBinding {
     pub(crate) volume: String,
     pub(crate) path: PathBuf,
@@ -296,3 +459,26 @@ impl DockerBinding {
         format!("{}:{}{}", self.volume, self.path.display(), self.flags.map_or("".to_owned(), |f| format!(":{}", f)))
     }
 }
+
+#[test]
+fn test_path_exclude() {
+    let file = std::env::temp_dir().join(format!("hoarder-excludes-{}", std::process::id()));
+    std::fs::write(&file, "# caches\n*.tmp\n\n  /sessions  \n").unwrap();
+    let input: DockerInputType = serde_yaml::from_str(&format!(
+        "docker_type: ComposeNamedVolume\nname: data\nexclude: [cache]\niexclude: ['*.LOG']\nexclude_file: [{}]\nfiles_from: [/uploads]\n",
+        file.display(),
+    )).unwrap();
+    let DockerInputType::ComposeNamedVolume { filter: Some(filter), .. } = input else {
+        panic!("the filter is read: {:?}", input);
+    };
+    let resolved = filter.resolve(Path::new("/restic/app/data")).unwrap();
+    std::fs::remove_file(&file).unwrap();
+    assert_eq!(resolved.exclude, ["/restic/app/data/cache", "/restic/app/data/*.tmp", "/restic/app/data/sessions"].map(PathBuf::from));
+    assert_eq!(resolved.iexclude, [PathBuf::from("/restic/app/data/*.LOG")]);
+    assert_eq!(resolved.files_from, [PathBuf::from("/restic/app/data/uploads")]);
+    assert!(PathExclude::new(vec![PathBuf::from("missing")]).resolve(Path::new("/restic")).is_ok());
+    assert!(PathExclude { exclude_file: vec![PathBuf::from("/nonexistent")], ..Default::default() }.resolve(Path::new("/restic")).is_err());
+
+    let input: DockerInputType = serde_yaml::from_str("docker_type: Volume\nname: data\n").unwrap();
+    assert!(matches!(input, DockerInputType::Volume { filter: Some(f), .. } if f.is_empty()));
+}
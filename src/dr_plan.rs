@@ -0,0 +1,178 @@
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use log::warn;
+use serde::Serialize;
+
+use crate::{
+    archive::ArchiveInput,
+    config::Config,
+    manifest::{self, Manifest},
+    service::Service,
+    state::State,
+    DockerInputType,
+    SerializableError,
+};
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub(crate) enum DrPlanFormat {
+    Markdown,
+    Json,
+}
+
+/// An ordered recovery document, telling how to bring every service back from the repository.
+#[derive(Serialize, Debug)]
+struct DrPlan {
+    generated_at: DateTime<Utc>,
+    /// host whose backups are restored
+    host: String,
+    /// when the manifest the plan is based on was written
+    manifest_created_at: DateTime<Utc>,
+    services: Vec<ServicePlan>,
+}
+
+#[derive(Serialize, Debug)]
+struct ServicePlan {
+    name: String,
+    compose_project: String,
+    snapshot_id: Option<String>,
+    steps: Vec<Step>,
+}
+
+#[derive(Serialize, Debug)]
+struct Step {
+    archive: String,
+    /// what the archive was backed up from
+    source: Option<String>,
+    description: String,
+    commands: Vec<String>,
+}
+
+/// Prints the recovery plan, built from the configuration and the latest manifest in the repository,
+/// or the local history if the repository can't be read.
+pub(crate) async fn show(services: &[Service], config: &Config, format: DrPlanFormat) -> Result<(), SerializableError> {
+    let manifest = match manifest::fetch(config).await {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            warn!("failed to read the manifest from the repository, using the local history: {}", e);
+            let state = State::load(config.state_dir()?)?;
+            let run = state.last_completed_run()
+                .ok_or_else(|| SerializableError::state("no completed run found in the local history"))?;
+            let mut manifest = Manifest::new(run, &state, &config.restic_host()?);
+            manifest.created_at = run.started_at;
+            manifest
+        }
+    };
+
+    let plan = DrPlan {
+        generated_at: Utc::now(),
+        host: manifest.host.clone(),
+        manifest_created_at: manifest.created_at,
+        services: services.iter().map(|s| service_plan(s, config, &manifest)).collect(),
+    };
+    match format {
+        DrPlanFormat::Json => println!("{}", serde_json::to_string_pretty(&plan)?),
+        DrPlanFormat::Markdown => print_markdown(&plan),
+    }
+    Ok(())
+}
+
+fn service_plan(service: &Service, config: &Config, manifest: &Manifest) -> ServicePlan {
+    let project = service.compose_project.clone().unwrap_or(service.name.clone());
+    let recorded = manifest.services.get(&service.name);
+    let snapshot_id = recorded.and_then(|s| s.snapshot_id.clone());
+    let snapshot = snapshot_id.clone().unwrap_or("<snapshot>".to_owned());
+    let service_path = Path::new(&config.restic_root()).join(&service.name);
+
+    let steps = service.archives.iter().map(|archive| {
+        let source = recorded.and_then(|s| s.archives.get(&archive.name)).and_then(|a| a.source.clone());
+        let restore = format!("hoarder restore {} {} --snapshot {}", service.name, archive.name, snapshot);
+        let input = match &archive.input {
+            ArchiveInput::Docker(input) => input,
+            ArchiveInput::Plugin(plugin) => return Step {
+                archive: archive.name.clone(),
+                source,
+                description: format!(
+                    "restore the data staged by plugin {} into a directory, then put it back by hand",
+                    plugin.plugin.display(),
+                ),
+                commands: vec![format!("{} --target <dir>", restore)],
+            },
+        };
+        let (description, commands) = match input {
+            DockerInputType::ComposeNamedVolume { name, .. } => (
+                format!("create volume {project}_{name} and restore its data into it"),
+                vec![
+                    format!("docker volume create --label com.docker.compose.project={project} --label com.docker.compose.volume={name} {project}_{name}"),
+                    restore,
+                ],
+            ),
+            DockerInputType::Volume { name, .. } => (
+                format!("create volume {name} and restore its data into it"),
+                vec![format!("docker volume create {name}"), restore],
+            ),
+            DockerInputType::ComposeBoundVolume { service: compose_service, path, .. } => (
+                format!(
+                    "create {}, bound at {} in service {}, then restore its data into it",
+                    source.as_deref().and_then(|s| s.strip_prefix("bind ")).unwrap_or("the host directory"),
+                    path.display(),
+                    compose_service,
+                ),
+                vec![format!("docker compose -p {project} create {compose_service}"), restore],
+            ),
+            DockerInputType::ExecStdout { service: compose_service, ext, restore_task, .. } => {
+                let file = match archive.compression {
+                    Some(_) => format!("{}.{}.zst", archive.name, ext),
+                    None => format!("{}.{}", archive.name, ext),
+                };
+                let start = format!("docker compose -p {project} up -d {compose_service}");
+                match restore_task {
+                    Some(_) => (
+                        format!("start service {compose_service} and stream {file} into its restore_task"),
+                        vec![start, restore],
+                    ),
+                    None => (
+                        format!("start service {compose_service} and load {file} by hand, no restore_task is configured"),
+                        vec![start, format!("restic dump {} {}", snapshot, service_path.join(&file).display())],
+                    ),
+                }
+            }
+        };
+        Step { archive: archive.name.clone(), source, description, commands }
+    }).collect();
+
+    ServicePlan { name: service.name.clone(), compose_project: project, snapshot_id, steps }
+}
+
+fn print_markdown(plan: &DrPlan) {
+    println!("# Disaster recovery plan for {}", plan.host);
+    println!();
+    println!("Generated at {}, from the manifest written at {}.", plan.generated_at.to_rfc3339(), plan.manifest_created_at.to_rfc3339());
+    println!("Restore the services in order, each step assumes the previous ones are done.");
+    for (i, service) in plan.services.iter().enumerate() {
+        println!();
+        println!("## {}. {}", i + 1, service.name);
+        println!();
+        println!("- compose project: `{}`", service.compose_project);
+        match &service.snapshot_id {
+            Some(id) => println!("- snapshot: `{}`", id),
+            None => println!("- snapshot: none recorded, pick one with `restic snapshots`"),
+        }
+        for step in &service.steps {
+            println!();
+            println!("### {}", step.archive);
+            println!();
+            if let Some(source) = &step.source {
+                println!("Backed up from {}.", source);
+            }
+            println!("To restore it, {}:", step.description);
+            println!();
+            println!("```sh");
+            for command in &step.commands {
+                println!("{}", command);
+            }
+            println!("```");
+        }
+    }
+}
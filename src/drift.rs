@@ -0,0 +1,106 @@
+use std::fmt;
+
+use crate::state::{RunRecord, ServiceRecord};
+
+/// A difference between the plans of two runs.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct DriftChange {
+    pub(crate) service: String,
+    /// the archive that changed, none for a change of the whole service
+    pub(crate) archive: Option<String>,
+    pub(crate) change: String,
+}
+
+impl DriftChange {
+    fn service(service: &str, change: impl ToString) -> Self {
+        Self { service: service.to_owned(), archive: None, change: change.to_string() }
+    }
+
+    fn archive(service: &str, archive: &str, change: impl ToString) -> Self {
+        Self { service: service.to_owned(), archive: Some(archive.to_owned()), change: change.to_string() }
+    }
+}
+
+impl fmt::Display for DriftChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.archive {
+            Some(archive) => write!(f, "{}:{}: {}", self.service, archive, self.change),
+            None => write!(f, "{}: {}", self.service, self.change),
+        }
+    }
+}
+
+/// Compares the plan of the current run with a previous one, reporting added and removed services
+/// and archives, archives whose source changed and services whose excludes changed. Anything the
+/// previous run didn't record is not compared.
+pub(crate) fn detect(previous: &RunRecord, current: &RunRecord) -> Vec<DriftChange> {
+    let mut drift = vec![];
+    for name in previous.services.keys().filter(|s| !current.services.contains_key(*s)) {
+        drift.push(DriftChange::service(name, "service was removed"));
+    }
+    for (name, service) in &current.services {
+        let Some(previous_service) = previous.services.get(name) else {
+            drift.push(DriftChange::service(name, "service was added"));
+            continue;
+        };
+        if let (Some(before), Some(after)) = (excludes(previous_service), excludes(service))
+            && before != after
+        {
+            drift.push(DriftChange::service(name, format!(
+                "excludes changed from [{}] to [{}]",
+                before.join(", "),
+                after.join(", "),
+            )));
+        }
+        for archive in previous_service.archives.keys().filter(|a| !service.archives.contains_key(*a)) {
+            drift.push(DriftChange::archive(name, archive, "archive was removed"));
+        }
+        for (archive, record) in &service.archives {
+            let Some(previous_archive) = previous_service.archives.get(archive) else {
+                drift.push(DriftChange::archive(name, archive, "archive was added"));
+                continue;
+            };
+            if let (Some(before), Some(after)) = (&previous_archive.source, &record.source)
+                && before != after
+            {
+                drift.push(DriftChange::archive(name, archive, format!("source changed from {} to {}", before, after)));
+            }
+        }
+    }
+    drift
+}
+
+/// The excludes of `service`, the case-insensitive ones marked, if the run recorded them.
+fn excludes(service: &ServiceRecord) -> Option<Vec<String>> {
+    let mut excludes = service.excludes.clone()?;
+    excludes.extend(service.iexcludes.iter().flatten().map(|e| format!("{} (ignoring the case)", e)));
+    Some(excludes)
+}
+
+#[test]
+fn test_detect() {
+    let mut previous = RunRecord::start("");
+    previous.service_mut("gone");
+    previous.service_mut("app").excludes = Some(vec!["cache".to_owned()]);
+    previous.archive_mut("app", "old");
+    previous.archive_mut("app", "data").source = Some("volume app_data".to_owned());
+    previous.archive_mut("app", "db").source = Some("exec db".to_owned());
+
+    let mut current = RunRecord::start("");
+    current.service_mut("new");
+    current.service_mut("app").excludes = Some(vec!["cache".to_owned()]);
+    current.service_mut("app").iexcludes = Some(vec!["*.tmp".to_owned()]);
+    current.archive_mut("app", "data").source = Some("bind /srv/data".to_owned());
+    current.archive_mut("app", "db").source = Some("exec db".to_owned());
+    current.archive_mut("app", "uploads");
+
+    assert_eq!(detect(&previous, &current).iter().map(ToString::to_string).collect::<Vec<_>>(), vec![
+        "gone: service was removed",
+        "app: excludes changed from [cache] to [cache, *.tmp (ignoring the case)]",
+        "app:old: archive was removed",
+        "app:data: source changed from volume app_data to bind /srv/data",
+        "app:uploads: archive was added",
+        "new: service was added",
+    ]);
+    assert!(detect(&current, &current).is_empty());
+}
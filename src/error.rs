@@ -1,43 +1,263 @@
-use std::fmt::Display;
+use serde::{Serialize, Serializer};
+use thiserror::Error;
 
-use serde::Serialize;
+/// Errors that can end a run or fail an archive, serializable so that hooks can tell them apart.
+#[derive(Error, Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum SerializableError {
+    /// the configuration is invalid or incomplete
+    #[error("config error: {detail}")]
+    Config { detail: String },
+    /// a docker command couldn't be run or exited unsuccessfully
+    #[error("docker command `{command}` failed ({}): {}", describe_exit(.exit), .stderr.as_deref().unwrap_or("no stderr output"))]
+    Docker {
+        command: String,
+        exit: Option<i32>,
+        stderr: Option<String>,
+    },
+    /// an archive couldn't be staged in the intermediate directory
+    #[error("dump error: {detail}")]
+    Dump { detail: String },
+    /// restic couldn't back up the staged data
+    #[error("restic error: {detail}")]
+    Restic { detail: String },
+    /// a backend, or the container it runs in, failed
+    #[error("backend error: {detail}")]
+    Backend { detail: String },
+    /// a hook couldn't be delivered
+    #[error("hook error: {detail}")]
+    Hook { detail: String },
+    /// the state directory couldn't be read or written
+    #[error("state error: {detail}")]
+    State { detail: String },
+    #[error("io error: {detail}")]
+    Io { detail: String },
+    #[error("parse error: {detail}")]
+    Parse { detail: String },
+    /// the run was stopped by a signal
+    #[error("interrupted by {signal}")]
+    Interrupted { signal: String },
+    /// the run was cancelled through its cancellation token
+    #[error("cancelled")]
+    Cancelled,
+    /// a service wasn't successfully backed up for longer than its max_age
+    #[error("last successful backup {}, max age is {max_age}s", .last_success.as_deref().map_or("never happened".to_owned(), |l| format!("was at {l}")))]
+    Stale { last_success: Option<String>, max_age: u64 },
+    /// the repository holds fewer or older snapshots of a service than it is expected to
+    #[error("snapshot threshold: {detail}")]
+    SnapshotThreshold { detail: String },
+    /// the intermediate filesystem went under its low water mark while dumping
+    #[error("only {} left in {path}, under the low water mark of {}", indicatif::HumanBytes(*.available), indicatif::HumanBytes(*.low_water_mark))]
+    LowSpace { path: String, available: u64, low_water_mark: u64 },
+    /// a mounted volume holds paths the backup can't read, which would be missing from its snapshot
+    #[error("{} path(s) can't be read by the backup: {}", .paths.len(), .paths.join(", "))]
+    Unreadable { paths: Vec<String> },
+    /// the source of a required archive doesn't exist anymore
+    #[error("missing: {detail}")]
+    Missing { detail: String },
+    /// another run holds the lock, or the lock couldn't be taken
+    #[error("lock error: {detail}")]
+    Lock { detail: String },
+}
 
-#[derive(Serialize, Debug)]
-pub(crate) struct SerializableError {
-    message: String,
+fn describe_exit(exit: &Option<i32>) -> String {
+    match exit {
+        Some(code) => format!("exit code {}", code),
+        None => "no exit code".to_owned(),
+    }
 }
 
 impl SerializableError {
-    pub fn new(message: impl ToString) -> Self {
-        SerializableError { message: message.to_string() }
+    pub(crate) fn config(message: impl ToString) -> Self {
+        Self::Config { detail: message.to_string() }
     }
 
-    #[allow(dead_code)]
-    pub fn message(&self) -> &str {
-        &self.message
+    pub(crate) fn docker(command: &tokio::process::Command, exit: Option<i32>, stderr: Option<String>) -> Self {
+        let command = command.as_std();
+        let command = std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|a| a.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Self::Docker { command, exit, stderr }
     }
-}
 
-impl Display for SerializableError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Error {{ {} }}", self.message)
+    pub(crate) fn dump(message: impl ToString) -> Self {
+        Self::Dump { detail: message.to_string() }
+    }
+
+    pub(crate) fn restic(message: impl ToString) -> Self {
+        Self::Restic { detail: message.to_string() }
+    }
+
+    pub(crate) fn backend(message: impl ToString) -> Self {
+        Self::Backend { detail: message.to_string() }
+    }
+
+    pub(crate) fn hook(message: impl ToString) -> Self {
+        Self::Hook { detail: message.to_string() }
+    }
+
+    pub(crate) fn state(message: impl ToString) -> Self {
+        Self::State { detail: message.to_string() }
+    }
+
+    pub(crate) fn lock(message: impl ToString) -> Self {
+        Self::Lock { detail: message.to_string() }
+    }
+
+    pub(crate) fn snapshot_threshold(message: impl ToString) -> Self {
+        Self::SnapshotThreshold { detail: message.to_string() }
+    }
+
+    pub(crate) fn missing(message: impl ToString) -> Self {
+        Self::Missing { detail: message.to_string() }
+    }
+
+    /// Whether the error is a repository that couldn't be reached over the network, rather than
+    /// one that refused the credentials or is broken.
+    pub(crate) fn unreachable(&self) -> bool {
+        let output = match self {
+            SerializableError::Restic { detail } | SerializableError::Backend { detail } => detail,
+            SerializableError::Docker { stderr: Some(stderr), .. } => stderr,
+            _ => return false,
+        }
+        .to_lowercase();
+        UNREACHABLE.iter().any(|m| output.contains(m))
     }
 }
 
-impl std::error::Error for SerializableError {}
+/// What the backends print when they can't connect to their repository.
+static UNREACHABLE: &[&str] = &[
+    "connection refused",
+    "connection reset",
+    "connection timed out",
+    "i/o timeout",
+    "network is unreachable",
+    "no route to host",
+    "no such host",
+    "temporary failure in name resolution",
+    "could not resolve hostname",
+    "tls handshake timeout",
+];
 
 impl From<std::io::Error> for SerializableError {
     fn from(e: std::io::Error) -> Self {
-        SerializableError {
-            message: e.to_string(),
+        SerializableError::Io {
+            detail: e.to_string(),
         }
     }
 }
 
 impl From<serde_json::Error> for SerializableError {
     fn from(e: serde_json::Error) -> Self {
-        SerializableError {
-            message: e.to_string(),
+        SerializableError::Parse {
+            detail: e.to_string(),
+        }
+    }
+}
+
+/// Exit codes of the process, so that cron/systemd wrappers and monitoring can tell what went wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ExitCode {
+    /// every archive was backed up
+    Success = 0,
+    /// an error not covered by a more specific code
+    Failure = 1,
+    /// the run completed, but some archives failed
+    Partial = 2,
+    /// the configuration is invalid or incomplete
+    Config = 3,
+    /// docker is unavailable or a docker command failed
+    Docker = 4,
+    /// restic, or another backend, failed to back up the staged data
+    Restic = 5,
+    /// staging an archive failed in a way that stopped the run
+    Dump = 6,
+    /// the state directory couldn't be read or written
+    State = 7,
+    /// another run holds the lock
+    Locked = 8,
+    /// the run was interrupted by SIGINT
+    Interrupted = 130,
+    /// the run was terminated by SIGTERM
+    Terminated = 143,
+}
+
+impl From<&SerializableError> for ExitCode {
+    fn from(e: &SerializableError) -> Self {
+        match e {
+            SerializableError::Config { .. } => ExitCode::Config,
+            SerializableError::Docker { .. } => ExitCode::Docker,
+            SerializableError::Dump { .. }
+            | SerializableError::LowSpace { .. } => ExitCode::Dump,
+            SerializableError::Restic { .. }
+            | SerializableError::Backend { .. } => ExitCode::Restic,
+            SerializableError::State { .. } => ExitCode::State,
+            SerializableError::Lock { .. } => ExitCode::Locked,
+            SerializableError::Interrupted { signal } if signal == "SIGTERM" => ExitCode::Terminated,
+            SerializableError::Interrupted { .. }
+            | SerializableError::Cancelled => ExitCode::Interrupted,
+            SerializableError::Stale { .. }
+            | SerializableError::SnapshotThreshold { .. }
+            | SerializableError::Unreadable { .. }
+            | SerializableError::Missing { .. } => ExitCode::Partial,
+            SerializableError::Hook { .. }
+            | SerializableError::Io { .. }
+            | SerializableError::Parse { .. } => ExitCode::Failure,
         }
     }
 }
+
+impl ExitCode {
+    pub(crate) fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
+
+/// An error serialized as its fields plus its human readable `message`.
+#[derive(Serialize)]
+pub(crate) struct ErrorReport<'a> {
+    message: String,
+    #[serde(flatten)]
+    error: &'a SerializableError,
+}
+
+impl<'a> ErrorReport<'a> {
+    pub(crate) fn new(error: &'a SerializableError) -> Self {
+        Self { message: error.to_string(), error }
+    }
+}
+
+fn serialize_report<S: Serializer>(error: &SerializableError, serializer: S) -> Result<S::Ok, S::Error> {
+    ErrorReport::new(error).serialize(serializer)
+}
+
+/// An archive that couldn't be backed up, while the rest of the run went on.
+#[derive(Serialize, Debug)]
+pub(crate) struct ArchiveFailure {
+    pub(crate) service: String,
+    pub(crate) archive: String,
+    #[serde(serialize_with = "serialize_report")]
+    pub(crate) error: SerializableError,
+}
+
+impl ArchiveFailure {
+    pub(crate) fn new(service: impl ToString, archive: impl ToString, error: SerializableError) -> Self {
+        Self { service: service.to_string(), archive: archive.to_string(), error }
+    }
+}
+
+impl std::fmt::Display for ArchiveFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.service, self.archive, self.error)
+    }
+}
+
+#[test]
+fn test_unreachable() {
+    assert!(SerializableError::restic("exit status: 1: Fatal: unable to open config file: Stat: dial tcp 10.0.0.2:443: connect: connection refused").unreachable());
+    assert!(!SerializableError::restic("exit status: 12: Fatal: wrong password or no key found").unreachable());
+    assert!(SerializableError::backend("borg task exit status: 2: Remote: ssh: connect to host backup port 22: No route to host").unreachable());
+    assert!(!SerializableError::config("no password was given on stdin").unreachable());
+}
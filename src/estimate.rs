@@ -0,0 +1,103 @@
+//! How much data a run goes through, estimated before it starts.
+
+use std::{collections::{BTreeMap, HashMap}, fmt::Display};
+
+use indicatif::HumanBytes;
+use log::warn;
+use serde::Serialize;
+
+use crate::{archive::ArchiveInput, config::Config, inspect, service::Service, state::State, DockerInputType};
+
+/// The expected size of every archive of a run.
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct Estimate {
+    /// total bytes of the archives whose size is known
+    pub(crate) bytes: u64,
+    /// size in bytes of each archive, keyed by `service/archive`, none when it isn't known
+    pub(crate) archives: BTreeMap<String, Option<u64>>,
+}
+
+impl Estimate {
+    /// The expected bytes of the archives of `service` whose size is known.
+    pub(crate) fn service(&self, service: &str) -> u64 {
+        let prefix = format!("{}/", service);
+        self.archives.iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .filter_map(|(_, size)| *size)
+            .sum()
+    }
+
+    /// How many archives have no known size.
+    pub(crate) fn unknown(&self) -> usize {
+        self.archives.values().filter(|s| s.is_none()).count()
+    }
+}
+
+impl Display for Estimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "about {}", HumanBytes(self.bytes))?;
+        match self.unknown() {
+            0 => Ok(()),
+            1 => write!(f, ", and an archive of unknown size"),
+            unknown => write!(f, ", and {} archives of unknown size", unknown),
+        }
+    }
+}
+
+/// Estimates the size of the archives of `services`: volumes as docker reports them, dumps as
+/// big as the previous one. Bound paths and plugins have no known size.
+pub(crate) async fn estimate(config: &Config, services: &[Service], state: &State) -> Estimate {
+    let volumes = match services.iter().flat_map(|s| &s.archives).any(|a| matches!(
+        a.input,
+        ArchiveInput::Docker(DockerInputType::ComposeNamedVolume { .. } | DockerInputType::Volume { .. }),
+    )) {
+        true => inspect::volume_sizes(config).await.unwrap_or_else(|e| {
+            warn!("the size of the volumes is unknown, it is left out of the estimate: {}", e);
+            HashMap::new()
+        }),
+        false => HashMap::new(),
+    };
+    sizes(services, state, &volumes)
+}
+
+fn sizes(services: &[Service], state: &State, volumes: &HashMap<String, u64>) -> Estimate {
+    let mut estimate = Estimate::default();
+    for service in services {
+        let project = service.compose_project.as_deref().unwrap_or(&service.name);
+        for archive in &service.archives {
+            let size = match &archive.input {
+                ArchiveInput::Docker(DockerInputType::ComposeNamedVolume { name, .. }) => volumes.get(&format!("{}_{}", project, name)).copied(),
+                ArchiveInput::Docker(DockerInputType::Volume { name, .. }) => volumes.get(name).copied(),
+                ArchiveInput::Docker(DockerInputType::ExecStdout { .. }) => state.archive(&service.name, &archive.name).and_then(|a| a.size),
+                ArchiveInput::Docker(DockerInputType::ComposeBoundVolume { .. }) | ArchiveInput::Plugin(_) => None,
+            };
+            estimate.bytes += size.unwrap_or_default();
+            estimate.archives.insert(format!("{}/{}", service.name, archive.name), size);
+        }
+    }
+    estimate
+}
+
+#[test]
+fn test_sizes() {
+    use crate::{service::ServiceBuilder, ShellTask};
+
+    let services = vec![
+        ServiceBuilder::new("app")
+            .exec_stdout("db", "db", ShellTask::new("pg_dumpall"), "sql")
+            .named_volume("data", "data", vec![])
+            .build()
+            .unwrap(),
+        ServiceBuilder::new("other")
+            .exec_stdout("db", "db", ShellTask::new("pg_dumpall"), "sql")
+            .build()
+            .unwrap(),
+    ];
+    let mut state = State::default();
+    state.archive_mut("app", "db").size = Some(2_000);
+    let estimate = sizes(&services, &state, &HashMap::from([("app_data".to_owned(), 5_000_000)]));
+    assert_eq!(estimate.bytes, 5_002_000);
+    assert_eq!(estimate.service("app"), 5_002_000);
+    assert_eq!(estimate.service("other"), 0);
+    assert_eq!(estimate.to_string(), "about 4.77 MiB, and an archive of unknown size");
+}
@@ -0,0 +1,227 @@
+use std::{future::Future, io::Write, path::PathBuf, time::Duration};
+
+use chrono::{DateTime, Utc};
+use indicatif::{HumanBytes, HumanDuration, ProgressBar};
+use log::{debug, error, info};
+use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use crate::{backend::BackendType, error::ArchiveFailure, metrics, state::RunRecord};
+
+/// How often the progress of the dumps and uploads is reported.
+pub(crate) static PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the progress of a backup is shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// logs and progress bars
+    #[default]
+    Text,
+    /// one json event per line on stdout, the logs staying on stderr
+    Ndjson,
+}
+
+/// The steps of a run, in order. Dumping and uploading overlap, they are a single phase.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Phase {
+    /// checking that every archive can be backed up
+    Preflight,
+    /// starting the backends and probing their repositories
+    Prepare,
+    /// dumping the archives and uploading the services
+    Backup,
+    /// backing up the run manifest
+    Manifest,
+    /// forgetting the snapshots the retention policy doesn't keep
+    Retention,
+    /// checking the repositories
+    Check,
+    /// stopping the backends
+    Teardown,
+}
+
+/// Something that happened during a run.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    /// the run is about to stage its services
+    RunStarted { run: &'a RunRecord },
+    /// the run moved on to `phase`
+    PhaseStarted { phase: Phase },
+    /// an archive is ready to be backed up, either mounted or dumped
+    ArchiveStaged { service: &'a str, archive: &'a str },
+    /// bytes of an archive dumped so far into the intermediate directory
+    DumpProgress { service: &'a str, archive: &'a str, bytes: u64 },
+    /// bytes uploaded so far by a backend backing up a service, and how far along it is when the
+    /// backend knows the total
+    BackupProgress { service: &'a str, backend: BackendType, bytes: u64, percent: Option<f64> },
+    /// an archive was backed up, in `snapshot_id` if the backend has snapshots
+    ArchiveUploaded { service: &'a str, archive: &'a str, snapshot_id: Option<&'a str> },
+    /// an archive couldn't be backed up
+    ArchiveFailed { failure: &'a ArchiveFailure },
+    /// the run is over, its record filled in
+    RunFinished { run: &'a RunRecord },
+}
+
+type Subscriber = Box<dyn Fn(&Event) + Send + Sync>;
+
+/// The subscribers every event of a run is handed to, in the order they subscribed.
+#[derive(Default)]
+pub(crate) struct Events {
+    subscribers: Vec<Subscriber>,
+}
+
+impl Events {
+    pub(crate) fn subscribe(&mut self, subscriber: impl Fn(&Event) + Send + Sync + 'static) {
+        self.subscribers.push(Box::new(subscriber));
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        for subscriber in &self.subscribers {
+            subscriber(&event);
+        }
+    }
+
+    /// Progress of the dumps and uploads, as sent to the returned receiver.
+    #[cfg_attr(not(test), expect(dead_code, reason = "for embedders, the binary draws its own progress bars"))]
+    pub(crate) fn progress(&mut self) -> UnboundedReceiver<Progress> {
+        let (tx, rx) = unbounded_channel();
+        self.subscribe(move |event| {
+            let progress = match event {
+                Event::DumpProgress { service, archive, bytes } => Progress::Dump {
+                    service: service.to_string(),
+                    archive: archive.to_string(),
+                    bytes: *bytes,
+                },
+                Event::BackupProgress { service, backend, bytes, percent } => Progress::Backup {
+                    service: service.to_string(),
+                    backend: *backend,
+                    bytes: *bytes,
+                    percent: *percent,
+                },
+                _ => return,
+            };
+            // nobody listening anymore is fine
+            let _ = tx.send(progress);
+        });
+        rx
+    }
+
+    /// Reports `failure` and adds it to `failed`.
+    pub(crate) fn fail(&self, failed: &mut Vec<ArchiveFailure>, failure: ArchiveFailure) {
+        self.emit(Event::ArchiveFailed { failure: &failure });
+        failed.push(failure);
+    }
+}
+
+/// The progress events, owned so that they can be handed to another task.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Progress {
+    Dump { service: String, archive: String, bytes: u64 },
+    Backup { service: String, backend: BackendType, bytes: u64, percent: Option<f64> },
+}
+
+/// Awaits `work`, handing `report` the position of `bar` every [`PROGRESS_INTERVAL`] meanwhile.
+pub(crate) async fn tracked<T>(work: impl Future<Output = T>, bar: &ProgressBar, mut report: impl FnMut(&ProgressBar)) -> T {
+    tokio::pin!(work);
+    loop {
+        tokio::select! {
+            result = &mut work => return result,
+            _ = tokio::time::sleep(PROGRESS_INTERVAL) => report(bar),
+        }
+    }
+}
+
+/// How far along `bar` is, if its length is known.
+pub(crate) fn percent(bar: &ProgressBar) -> Option<f64> {
+    bar.length()
+        .filter(|length| *length > 0)
+        .map(|length| bar.position() as f64 * 100.0 / length as f64)
+}
+
+/// Logs the progress of the run.
+pub(crate) fn log(event: &Event) {
+    match event {
+        Event::RunStarted { run } => info!("run {} started", run.id()),
+        Event::PhaseStarted { phase } => debug!("phase: {:?}", phase),
+        Event::ArchiveStaged { service, archive } => info!("{}: {}: staged", service, archive),
+        Event::DumpProgress { service, archive, bytes } => debug!("{}: {}: dumped {}", service, archive, HumanBytes(*bytes)),
+        Event::BackupProgress { service, backend, bytes, .. } => debug!("{}: {}: uploaded {}", service, backend.name(), HumanBytes(*bytes)),
+        Event::ArchiveUploaded { service, archive, .. } => debug!("{}: {}: uploaded", service, archive),
+        Event::ArchiveFailed { failure } => debug!("failed: {}", failure),
+        Event::RunFinished { run } => info!(
+            "run {} finished in {}: {}",
+            run.id(),
+            HumanDuration(Duration::from_secs_f64(run.duration_secs)),
+            format!("{:?}", run.status).to_lowercase(),
+        ),
+    }
+}
+
+/// Writes the metrics of the finished run to `dir`.
+pub(crate) fn metrics(dir: PathBuf) -> impl Fn(&Event) + Send + Sync {
+    move |event| {
+        if let Event::RunFinished { run } = event
+            && let Err(e) = metrics::write(&dir, run)
+        {
+            error!("{}", e);
+        }
+    }
+}
+
+/// An event as a line of the ndjson output.
+#[derive(Serialize)]
+struct Line<'a, 'b> {
+    time: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'b Event<'a>,
+}
+
+fn ndjson_line(event: &Event) -> serde_json::Result<String> {
+    serde_json::to_string(&Line { time: Utc::now(), event })
+}
+
+/// Prints every event on stdout as a json line.
+pub(crate) fn ndjson(event: &Event) {
+    let line = match ndjson_line(event) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("failed to serialize {:?}: {}", event, e);
+            return;
+        }
+    };
+    let mut stdout = std::io::stdout().lock();
+    // a closed stdout only loses the events, the run goes on
+    let _ = writeln!(stdout, "{}", line).and_then(|_| stdout.flush());
+}
+
+#[test]
+fn test_ndjson_line() {
+    let line = ndjson_line(&Event::DumpProgress { service: "app", archive: "db", bytes: 42 }).unwrap();
+    let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(value["event"], "dump_progress");
+    assert_eq!(value["service"], "app");
+    assert_eq!(value["bytes"], 42);
+    assert!(value["time"].is_string());
+    let line = ndjson_line(&Event::PhaseStarted { phase: Phase::Retention }).unwrap();
+    assert!(line.contains(r#""event":"phase_started","phase":"retention""#));
+}
+
+#[test]
+fn test_progress() {
+    let mut events = Events::default();
+    let mut progress = events.progress();
+    events.emit(Event::PhaseStarted { phase: Phase::Backup });
+    events.emit(Event::DumpProgress { service: "app", archive: "db", bytes: 42 });
+    events.emit(Event::BackupProgress { service: "app", backend: BackendType::Restic, bytes: 1024, percent: Some(50.0) });
+    let Ok(Progress::Dump { service, archive, bytes }) = progress.try_recv() else { panic!("expected the dump progress") };
+    assert_eq!((service.as_str(), archive.as_str(), bytes), ("app", "db", 42));
+    let Ok(Progress::Backup { service, backend, bytes, percent }) = progress.try_recv() else { panic!("expected the backup progress") };
+    assert_eq!((service.as_str(), backend, bytes, percent), ("app", BackendType::Restic, 1024, Some(50.0)));
+    assert!(progress.try_recv().is_err());
+    // the run goes on when nobody listens anymore
+    drop(progress);
+    events.emit(Event::DumpProgress { service: "app", archive: "db", bytes: 43 });
+}
@@ -0,0 +1,171 @@
+//! `hoarder export`: a copy of a single archive in a local file, made without any backend.
+
+use std::{path::{Path, PathBuf}, process::Stdio};
+
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
+use tokio::{fs::File, io::AsyncReadExt};
+
+use crate::{
+    audit::Audited,
+    config::Config,
+    dump_command,
+    input::{InputProvider, StageContext, StagedArchive},
+    inspect,
+    service::Service,
+    stream::{PartialFile, SpinnerWriter},
+    theme,
+    DockerBinding,
+    DockerCommand,
+    DockerSubcommand,
+    SerializableError,
+};
+
+static EXPORT_IMAGE: &str = "alpine";
+/// Where the exported volume is mounted inside the tar container.
+static EXPORT_MOUNT: &str = "/export";
+
+/// Parses `service:archive`.
+pub(crate) fn parse_archive(value: &str) -> Result<(String, String), String> {
+    match value.split_once(':') {
+        Some((service, archive)) if !service.is_empty() && !archive.is_empty() => Ok((service.to_owned(), archive.to_owned())),
+        _ => Err(format!("expected service:archive, got {:?}", value)),
+    }
+}
+
+/// Dumps the archive `archive` of `service` to `to`, or to `<service>-<archive>.<ext>` in the
+/// current directory: the dump itself for ExecStdout archives, a tar of the volume for the mounted
+/// ones. Returns the path of the written file.
+pub(crate) async fn export(config: &Config, services: &[Service], service: &str, archive: &str, to: Option<PathBuf>) -> Result<PathBuf, SerializableError> {
+    let s = services.iter()
+        .find(|s| s.name == service)
+        .ok_or_else(|| SerializableError::config(format!("service {} is not configured", service)))?;
+    let a = s.archives.iter()
+        .find(|a| a.name == archive)
+        .ok_or_else(|| SerializableError::config(format!("service {} has no archive {}", service, archive)))?;
+    let compose_project = s.compose_project.clone().unwrap_or(s.name.clone());
+
+    let mut mounts = vec![];
+    let mut ctx = StageContext {
+        config,
+        service,
+        compose_project: &compose_project,
+        archive,
+        mounts: &mut mounts,
+        source: None,
+        planning: false,
+        events: None,
+    };
+    let staged = a.input.stage(&mut ctx).await?;
+    let (command, ext) = match staged {
+        StagedArchive::Dump { service: compose_service, task, ext, user } => {
+            let stopped = a.run_if_stopped && !inspect::running_services(config, &compose_project).await?.contains(&compose_service);
+            if stopped {
+                info!("{}: {}: service {} isn't running, dumping from a new container", service, archive, compose_service);
+            }
+            (dump_command(config, &compose_project, compose_service, task, user.as_deref(), stopped), ext)
+        }
+        StagedArchive::Mounted { exclude } => {
+            if exclude.is_some() {
+                warn!("{}: {}: the excludes of the archive are left to the backends, the export has every file", service, archive);
+            }
+            // a plugin that wrote the archive's files mounts nothing, they are in the intermediate directory
+            let source = match mounts.pop() {
+                Some(binding) => binding.volume,
+                None => PathBuf::from(config.intermediate_path()?).join(service).join(archive).display().to_string(),
+            };
+            (tar_command(config, source), "tar".to_owned())
+        }
+        StagedArchive::Missing { reason } => return Err(SerializableError::missing(reason)),
+    };
+    let to = to.unwrap_or_else(|| PathBuf::from(format!("{}-{}.{}", service, archive, ext)));
+    let written = write(config, command, &to, service, archive).await.and_then(|(written, head)| {
+        match ext.as_str() {
+            "tar" => Ok(written),
+            _ => a.dump_check.check(&ext, written, &head).map(|_| written).map_err(SerializableError::dump),
+        }
+    })?;
+    info!("{}: {}: exported {} to {}", service, archive, HumanBytes(written), to.display());
+    Ok(to)
+}
+
+/// The docker command writing a tar of `source`, a volume or a host path, to its stdout.
+fn tar_command(config: &Config, source: String) -> DockerCommand {
+    config.docker_command_with_context(DockerSubcommand::run(
+        EXPORT_IMAGE,
+        vec![DockerBinding::new_ro(source, PathBuf::from(EXPORT_MOUNT))],
+        vec!["--rm"],
+        vec!["tar", "-C", EXPORT_MOUNT, "-cf", "-", "."],
+    ))
+}
+
+/// Copies the stdout of `command` to `to`, which is only replaced once the copy is complete.
+/// Returns the amount of data written and its first bytes.
+async fn write(config: &Config, command: DockerCommand, to: &Path, service: &str, archive: &str) -> Result<(u64, Vec<u8>), SerializableError> {
+    let mut command = command.into_command();
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    debug!("{}: {}: export: executing command: {:?}", service, archive, command.as_std().get_args().collect::<Vec<_>>());
+    let mut handle = command.audited_spawn()
+        .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+    let stdout = handle.stdout.take().ok_or(SerializableError::dump("no stdout found in command output"))?;
+    let stderr_reader = handle.stderr.take().map(|mut stderr| tokio::spawn(async move {
+        let mut buf = String::new();
+        stderr.read_to_string(&mut buf).await.map(|_| buf)
+    }));
+
+    let partial = PartialFile::new(to);
+    let bar = ProgressBar::new_spinner()
+        .with_style(ProgressStyle::with_template(&theme::spinner_template()).expect("valid template"))
+        .with_prefix(format!("{}: {}", service, archive))
+        .with_message(format!("exporting to {}", to.display()));
+    let mut proxy = SpinnerWriter::new(stdout, Box::new(File::create(partial.path()).await?), config.io_buffer_size(), bar.clone());
+    let written = proxy.write_all().await
+        .map_err(|e| SerializableError::dump(format!("failed to write {}: {}", to.display(), e)))?;
+    bar.finish_and_clear();
+    let status = handle.wait().await
+        .map_err(|e| SerializableError::dump(format!("failed to wait for command: {}", e)))?;
+    if !status.success() {
+        let stderr = match stderr_reader {
+            Some(reader) => reader.await.ok().and_then(Result::ok).map(|s| s.trim().to_owned()),
+            None => None,
+        };
+        return Err(SerializableError::docker(&command, status.code(), stderr.filter(|s| !s.is_empty())));
+    }
+    partial.commit(to)?;
+    Ok((written, proxy.head().to_vec()))
+}
+
+#[tokio::test]
+async fn test_export() {
+    use crate::{mock::MockDocker, service::ServiceBuilder, ShellTask};
+
+    let docker = MockDocker::new("export")
+        .on("exec -i db pg_dumpall", "-- PostgreSQL database cluster dump\n")
+        .on("tar -C /export", "tar data")
+        .project("app", &["db"], &["data"]);
+    let config = docker.config();
+    let services = vec![
+        ServiceBuilder::new("app")
+            .exec_stdout("db", "db", ShellTask::new("pg_dumpall"), "sql")
+            .named_volume("data", "data", vec![])
+            .build()
+            .unwrap(),
+    ];
+    let to = docker.intermediate().join("db.sql");
+    assert_eq!(export(&config, &services, "app", "db", Some(to.clone())).await.unwrap(), to);
+    assert_eq!(std::fs::read_to_string(&to).unwrap(), "-- PostgreSQL database cluster dump\n");
+
+    let to = docker.intermediate().join("data.tar");
+    export(&config, &services, "app", "data", Some(to.clone())).await.unwrap();
+    assert_eq!(std::fs::read_to_string(&to).unwrap(), "tar data");
+    assert!(docker.ran(&["run -v app_data:/export:ro --rm alpine tar -C /export -cf - ."]));
+    assert!(!docker.ran(&["restic"]));
+
+    assert!(export(&config, &services, "app", "cache", None).await.is_err());
+    assert!(parse_archive("app").is_err());
+    assert_eq!(parse_archive("app:db").unwrap(), ("app".to_owned(), "db".to_owned()));
+}
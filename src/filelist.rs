@@ -0,0 +1,72 @@
+//! The paths a service backs up when its directory isn't backed up whole: some archives only back
+//! up their `files_from`, or `one_file_system` has to start from every archive on its own, as the
+//! archives are mounts of their own.
+
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::{config::Config, docker::PathExclude, SerializableError};
+
+/// Name of the list in the service's intermediate directory, next to its dumps.
+pub(crate) static FILE_LIST: &str = ".hoarder-files-from";
+
+/// The paths of a service's archives, as seen by the backup container.
+#[derive(Debug, Default)]
+pub(crate) struct FileList {
+    pub(crate) paths: Vec<PathBuf>,
+    /// whether an archive only backs up some of its files
+    selective: bool,
+}
+
+impl FileList {
+    /// Adds an archive found at `root`, only the `files_from` of its filter if it has any.
+    pub(crate) fn archive(&mut self, root: PathBuf, filter: Option<&PathExclude>) {
+        match filter.filter(|f| !f.files_from.is_empty()) {
+            Some(filter) => {
+                self.selective = true;
+                self.paths.extend(filter.files_from.iter().cloned());
+            }
+            None => self.paths.push(root),
+        }
+    }
+
+    /// Whether the service has to be backed up from the list rather than from its directory.
+    pub(crate) fn needed(&self, one_file_system: bool) -> bool {
+        self.selective || one_file_system
+    }
+
+    /// The list in the format of restic's `--files-from`, one path per line.
+    fn contents(&self) -> String {
+        self.paths.iter().map(|p| format!("{}\n", p.display())).collect()
+    }
+
+    /// Writes the list into the intermediate directory of `service`, where the backup container
+    /// reads it at [`list_path`].
+    pub(crate) fn write(&self, config: &Config, service: &str) -> Result<(), SerializableError> {
+        let dir = Path::new(&config.intermediate_path()?).join(service);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(FILE_LIST), self.contents())?;
+        debug!("{}: backing up the {} listed paths", service, self.paths.len());
+        Ok(())
+    }
+}
+
+/// Where the backup container reads the list of `service`.
+pub(crate) fn list_path(config: &Config, service: &str) -> PathBuf {
+    Path::new(&config.restic_root()).join(service).join(FILE_LIST)
+}
+
+#[test]
+fn test_file_list() {
+    let mut list = FileList::default();
+    list.archive(PathBuf::from("/restic/app/config"), None);
+    list.archive(PathBuf::from("/restic/app/db.sql"), None);
+    assert!(!list.needed(false));
+    assert!(list.needed(true));
+
+    let filter = PathExclude { files_from: vec![PathBuf::from("/restic/app/data/uploads")], ..Default::default() };
+    list.archive(PathBuf::from("/restic/app/data"), Some(&filter));
+    assert!(list.needed(false));
+    assert_eq!(list.contents(), "/restic/app/config\n/restic/app/db.sql\n/restic/app/data/uploads\n");
+}
@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use chrono::{Local, NaiveDate};
+use indicatif::{HumanBytes, HumanDuration};
+
+use crate::{config::Config, state::{ArchiveStatus, RunStatus, State}, theme::{self, Tone}, SerializableError};
+
+/// Which runs to show.
+#[derive(Debug, Default)]
+pub(crate) struct HistoryFilter {
+    /// only show this service
+    pub(crate) service: Option<String>,
+    /// only show runs started on or after this day
+    pub(crate) since: Option<NaiveDate>,
+    /// only show runs started on or before this day
+    pub(crate) until: Option<NaiveDate>,
+}
+
+/// Prints the runs recorded in the state directory, oldest first.
+pub(crate) fn show(config: &Config, filter: &HistoryFilter) -> Result<(), SerializableError> {
+    let state = State::load(config.state_dir()?)?;
+    let mut shown = 0;
+    for run in state.runs() {
+        let started = run.started_at.with_timezone(&Local);
+        if filter.since.is_some_and(|since| started.date_naive() < since)
+            || filter.until.is_some_and(|until| started.date_naive() > until)
+        {
+            continue;
+        }
+        let services = run.services
+            .iter()
+            .filter(|(name, _)| filter.service.as_ref().is_none_or(|s| s == *name))
+            .collect::<Vec<_>>();
+        if filter.service.is_some() && services.is_empty() {
+            continue;
+        }
+
+        let data_added = services.iter().filter_map(|(_, s)| s.data_added).sum::<u64>();
+        let tone = match run.status {
+            RunStatus::Success => Tone::Good,
+            RunStatus::Partial | RunStatus::Degraded | RunStatus::Interrupted => Tone::Warn,
+            RunStatus::Failed => Tone::Bad,
+            RunStatus::Running => Tone::Accent,
+        };
+        println!(
+            "{}  {}  {:<11}  {:>12}  added {}",
+            run.id(),
+            started.format("%Y-%m-%d %H:%M:%S"),
+            theme::paint(tone, format!("{:?}", run.status).to_lowercase()),
+            HumanDuration(Duration::from_secs_f64(run.duration_secs)).to_string(),
+            HumanBytes(data_added),
+        );
+        if let Some(error) = &run.error {
+            println!("    error: {}", error);
+        }
+        if let Some(degraded) = &run.degraded {
+            println!("    degraded: {}", degraded);
+        }
+        for warning in &run.warnings {
+            println!("    warning: {}", warning);
+        }
+        for (name, service) in services {
+            let failed = service.archives
+                .iter()
+                .filter(|(_, a)| a.status == ArchiveStatus::Failed)
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>();
+            println!(
+                "    {}: snapshot {}, added {}{}",
+                name,
+                service.snapshot_id.as_deref().map_or("(none)", |id| &id[..id.len().min(8)]),
+                HumanBytes(service.data_added.unwrap_or(0)),
+                if failed.is_empty() { String::new() } else { format!(", failed: {}", failed.join(", ")) },
+            );
+            if service.backends.len() > 1 {
+                for (backend, record) in &service.backends {
+                    match &record.error {
+                        Some(error) => println!("        {}: {}: {}", backend, theme::paint(Tone::Bad, "failed"), error),
+                        None => println!(
+                            "        {}: snapshot {}, added {}",
+                            backend,
+                            record.snapshot_id.as_deref().map_or("(none)", |id| &id[..id.len().min(8)]),
+                            HumanBytes(record.data_added.unwrap_or(0)),
+                        ),
+                    }
+                }
+            }
+        }
+        shown += 1;
+    }
+    if shown == 0 {
+        println!("no matching runs recorded");
+    }
+    Ok(())
+}
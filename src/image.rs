@@ -0,0 +1,104 @@
+use std::{process::Stdio, sync::OnceLock};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::{audit::Audited, config::Config, DockerImageSubcommand, DockerSubcommand, SerializableError};
+
+/// How the restic image is checked before anything runs in it, as it reads every volume.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub(crate) struct ImageVerification {
+    /// digest the image is pinned to, as `sha256:<hex>`: it is run by digest, so that docker
+    /// refuses any other content
+    #[serde(default)]
+    pub(crate) digest: Option<String>,
+    /// signature the image must carry, checked with cosign
+    #[serde(default)]
+    pub(crate) cosign: Option<CosignVerification>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct CosignVerification {
+    /// public key the image is signed with
+    #[serde(default)]
+    pub(crate) key: Option<String>,
+    /// identity of a keyless signature, along with its issuer
+    #[serde(default)]
+    pub(crate) identity: Option<String>,
+    /// OIDC issuer of a keyless signature
+    #[serde(default)]
+    pub(crate) issuer: Option<String>,
+}
+
+impl ImageVerification {
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if let Some(digest) = &self.digest {
+            let valid = digest.strip_prefix("sha256:").is_some_and(|hex| hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()));
+            if !valid {
+                problems.push(format!("restic_image_verify.digest {:?} isn't a sha256:<hex> digest", digest));
+            }
+        }
+        if let Some(cosign) = &self.cosign
+            && cosign.key.is_none()
+            && (cosign.identity.is_none() || cosign.issuer.is_none())
+        {
+            problems.push("restic_image_verify.cosign needs a key, or an identity and an issuer".to_owned());
+        }
+        problems
+    }
+}
+
+/// The result of the verification, done once per run.
+static VERIFIED: OnceLock<Result<(), String>> = OnceLock::new();
+
+/// Checks the restic image against its cosign signature, if one is expected, pulling it first.
+/// Only the first call does anything, the later ones return its result.
+pub(crate) async fn verify(config: &Config) -> Result<(), SerializableError> {
+    if let Some(verified) = VERIFIED.get() {
+        return verified.clone().map_err(SerializableError::config);
+    }
+    let Some(cosign) = config.restic_image_verify().and_then(|v| v.cosign.as_ref()) else {
+        return Ok(());
+    };
+    let image = config.restic_image();
+    let present = config.docker_command_with_context(DockerSubcommand::image(DockerImageSubcommand::inspect(&image)))
+        .into_command()
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .audited_status()
+        .await
+        .is_ok_and(|s| s.success());
+    if !present {
+        info!("pulling {}", image);
+        let pulled = config.docker_command_with_context(DockerSubcommand::image(DockerImageSubcommand::pull(&image)))
+            .spawn_and_wait()
+            .await?;
+        if !pulled.success() {
+            return Err(SerializableError::config(format!("failed to pull the restic image {}", image)));
+        }
+    }
+    let mut command = Command::new("cosign");
+    command.arg("verify");
+    match (&cosign.key, &cosign.identity, &cosign.issuer) {
+        (Some(key), _, _) => command.args(["--key", key]),
+        (None, Some(identity), Some(issuer)) => command.args(["--certificate-identity", identity, "--certificate-oidc-issuer", issuer]),
+        _ => return Err(SerializableError::config("restic_image_verify.cosign needs a key, or an identity and an issuer")),
+    };
+    command.arg(&image).stdin(Stdio::null());
+    debug!("verifying the restic image: {:?}", command.as_std().get_args().collect::<Vec<_>>());
+    let verified = match command.audited_output().await {
+        Ok(output) if output.status.success() => {
+            info!("the signature of {} is valid", image);
+            Ok(())
+        }
+        Ok(output) => Err(format!(
+            "the restic image {} failed the signature check, refusing to run it: {}",
+            image,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )),
+        Err(e) => Err(format!("failed to run cosign to check the restic image {}: {}", image, e)),
+    };
+    VERIFIED.get_or_init(|| verified).clone().map_err(SerializableError::config)
+}
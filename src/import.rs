@@ -0,0 +1,194 @@
+use std::{collections::BTreeMap, path::Path};
+
+use log::info;
+use serde_yaml::{value::{Tag, TaggedValue}, Mapping, Value};
+
+use crate::{config::Config, inspect::{self, Container}, SerializableError};
+
+/// A database recognized by its image, dumped rather than copied raw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KnownDatabase {
+    Postgres,
+    Mysql,
+    Mongodb,
+    Influxdb,
+    Redis,
+}
+
+impl KnownDatabase {
+    /// Recognizes the database an image runs, from the last component of its name.
+    pub(crate) fn detect(image: &str) -> Option<Self> {
+        let name = image.rsplit('/').next().unwrap_or(image);
+        let name = name.split(['@', ':']).next().unwrap_or(name);
+        Some(match name {
+            "postgres" | "postgresql" | "postgis" | "timescaledb" | "timescaledb-ha" => KnownDatabase::Postgres,
+            "mysql" | "mariadb" | "percona" | "percona-server" => KnownDatabase::Mysql,
+            "mongo" | "mongodb" | "mongodb-community-server" => KnownDatabase::Mongodb,
+            "influxdb" => KnownDatabase::Influxdb,
+            "redis" | "redis-stack-server" | "valkey" | "keydb" => KnownDatabase::Redis,
+            _ => return None,
+        })
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            KnownDatabase::Postgres => "postgres",
+            KnownDatabase::Mysql => "mysql",
+            KnownDatabase::Mongodb => "mongodb",
+            KnownDatabase::Influxdb => "influxdb",
+            KnownDatabase::Redis => "redis",
+        }
+    }
+
+    /// The archive entry dumping the database run by compose `service` of `image`, a preset when
+    /// there is one.
+    pub(crate) fn archive(self, service: &str, image: &str) -> Value {
+        if self == KnownDatabase::Redis {
+            // a point in time copy of the dataset, as replicas get it
+            let task = ["sh", "-c", r#"exec redis-cli ${REDIS_PASSWORD:+-a "$REDIS_PASSWORD" --no-auth-warning} --rdb -"#];
+            return docker_archive(&archive_name(service), "ExecStdout", vec![
+                ("service", service.into()),
+                ("task", Value::Sequence(task.into_iter().map(Value::from).collect())),
+                ("ext", "rdb".into()),
+            ]);
+        }
+        let mut archive = Mapping::new();
+        archive.insert("preset".into(), self.name().into());
+        archive.insert("service".into(), service.into());
+        match self {
+            KnownDatabase::Influxdb => {
+                let tag = image.rsplit_once(':').map(|(_, tag)| tag).unwrap_or_default();
+                let version = if tag.starts_with('1') { 1 } else { 2 };
+                archive.insert("version".into(), version.into());
+            }
+            _ => {
+                archive.insert("name".into(), service.into());
+            }
+        }
+        Value::Mapping(archive)
+    }
+}
+
+/// Host paths bound into containers that are never worth backing up.
+static SYSTEM_BINDS: &[&str] = &["/var/run", "/run", "/etc/localtime", "/etc/timezone", "/dev", "/proc", "/sys"];
+
+fn docker_archive(name: &str, docker_type: &str, fields: Vec<(&str, Value)>) -> Value {
+    let mut input = Mapping::new();
+    input.insert("docker_type".into(), docker_type.into());
+    for (key, value) in fields {
+        input.insert(key.into(), value);
+    }
+    let mut archive = Mapping::new();
+    archive.insert("name".into(), name.into());
+    archive.insert("input".into(), Value::Tagged(Box::new(TaggedValue { tag: Tag::new("Docker"), value: Value::Mapping(input) })));
+    Value::Mapping(archive)
+}
+
+/// Turns archive names into ones the configuration accepts.
+fn archive_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') { c } else { '-' })
+        .collect::<String>();
+    name.trim_start_matches(['.', '-']).to_owned()
+}
+
+/// The service definition of compose `project`, and notes about what was left out of it.
+fn service(project: &str, containers: &[Container]) -> (Value, Vec<String>) {
+    let mut archives = BTreeMap::new();
+    let mut notes = vec![];
+    for container in containers {
+        let Some(service) = container.service() else {
+            continue;
+        };
+        let database = KnownDatabase::detect(&container.config.image);
+        if let Some(database) = database {
+            archives.insert(archive_name(service), database.archive(service, &container.config.image));
+        }
+        for mount in &container.mounts {
+            let destination = mount.destination.display();
+            match (mount.kind.as_str(), &mount.name) {
+                (_, _) if database.is_some() => notes.push(format!(
+                    "{}: {} isn't copied, the {} database is dumped instead",
+                    service,
+                    destination,
+                    database.map(KnownDatabase::name).unwrap_or_default(),
+                )),
+                ("volume", Some(name)) if name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    notes.push(format!("{}: anonymous volume at {} left out, name it in the compose file to back it up", service, destination));
+                }
+                ("volume", Some(name)) => match name.strip_prefix(&format!("{}_", project)) {
+                    Some(short) => {
+                        archives.insert(archive_name(short), docker_archive(&archive_name(short), "ComposeNamedVolume", vec![("name", short.into())]));
+                    }
+                    None => {
+                        archives.insert(archive_name(name), docker_archive(&archive_name(name), "Volume", vec![("name", name.as_str().into())]));
+                    }
+                },
+                ("bind", _) if SYSTEM_BINDS.iter().any(|s| Path::new(&mount.source).starts_with(s)) => {
+                    notes.push(format!("{}: {} bound at {} left out", service, mount.source, destination));
+                }
+                ("bind", _) => {
+                    let last = mount.destination.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                    let name = archive_name(&format!("{}-{}", service, last));
+                    archives.insert(name.clone(), docker_archive(&name, "ComposeBoundVolume", vec![
+                        ("service", service.into()),
+                        ("path", mount.destination.display().to_string().into()),
+                    ]));
+                }
+                // nothing lasting in there
+                ("tmpfs", _) => (),
+                (kind, _) => notes.push(format!("{}: {} mount at {} left out", service, kind, destination)),
+            }
+        }
+    }
+    let mut definition = Mapping::new();
+    definition.insert("name".into(), project.into());
+    definition.insert("archives".into(), Value::Sequence(archives.into_values().collect()));
+    (Value::Mapping(definition), notes)
+}
+
+/// Prints a service definition for compose `project`, to be pasted under `services`.
+pub(crate) async fn import(config: &Config, project: &str) -> Result<(), SerializableError> {
+    let containers = inspect::project_containers(config, project).await?;
+    if containers.is_empty() {
+        return Err(SerializableError::config(format!("compose project {} has no containers", project)));
+    }
+    info!("{}: found {} containers", project, containers.len());
+    let (definition, notes) = service(project, &containers);
+    let yaml = serde_yaml::to_string(&Value::Sequence(vec![definition]))
+        .map_err(|e| SerializableError::config(format!("failed to write the service definition: {}", e)))?;
+    println!("# generated by `hoarder import {}`, review it before adding it to the configuration", project);
+    for note in notes {
+        println!("# {}", note);
+    }
+    print!("{}", yaml);
+    Ok(())
+}
+
+#[test]
+fn test_import_service() {
+    let containers: Vec<Container> = serde_json::from_str(r#"[
+        {"Config": {"Image": "postgres:16", "Labels": {"com.docker.compose.service": "db"}},
+         "Mounts": [{"Type": "volume", "Name": "app_pgdata", "Source": "/var/lib/docker/volumes/app_pgdata/_data", "Destination": "/var/lib/postgresql/data"}]},
+        {"Config": {"Image": "ghcr.io/example/app:latest", "Labels": {"com.docker.compose.service": "app"}},
+         "Mounts": [
+            {"Type": "volume", "Name": "app_uploads", "Source": "/x", "Destination": "/uploads"},
+            {"Type": "volume", "Name": "shared", "Source": "/y", "Destination": "/shared"},
+            {"Type": "bind", "Source": "/srv/app/config", "Destination": "/etc/app"},
+            {"Type": "bind", "Source": "/var/run/docker.sock", "Destination": "/var/run/docker.sock"}
+         ]}
+    ]"#).unwrap();
+    let (definition, notes) = service("app", &containers);
+    let archives = definition["archives"].as_sequence().unwrap();
+    let names = archives.iter().map(|a| a["name"].as_str().unwrap()).collect::<Vec<_>>();
+    assert_eq!(names, ["app-app", "db", "shared", "uploads"]);
+    assert_eq!(archives[1]["preset"], "postgres");
+    assert_eq!(notes.len(), 2);
+
+    // the definition loads, presets included
+    let mut config = Value::Mapping(Mapping::new());
+    config["services"] = Value::Sequence(vec![definition]);
+    crate::preset::expand(&mut config).unwrap();
+    serde_yaml::from_value::<crate::service::Service>(config["services"][0].clone()).unwrap();
+}
@@ -0,0 +1,208 @@
+use std::{path::PathBuf, process::Stdio};
+
+use async_trait::async_trait;
+use log::{debug, error, info};
+
+use crate::{
+    audit::Audited,
+    archive::ArchiveInput,
+    config::Config,
+    docker::PathExclude,
+    events::Events,
+    inspect,
+    DockerBinding,
+    DockerInputType,
+    DockerSubcommand,
+    DockerVolumeSubcommand,
+    SerializableError,
+    ShellTask,
+};
+
+/// What an input is staged with: the archive it belongs to, and the mounts of the backup container
+/// being put together.
+pub(crate) struct StageContext<'a> {
+    pub(crate) config: &'a Config,
+    /// the hoarder service the archive belongs to
+    pub(crate) service: &'a str,
+    /// the compose project of the service
+    pub(crate) compose_project: &'a str,
+    pub(crate) archive: &'a str,
+    /// mounts of the backup container, shared by every archive
+    pub(crate) mounts: &'a mut Vec<DockerBinding>,
+    /// where the data comes from, recorded in the run
+    pub(crate) source: Option<String>,
+    /// only resolving a plan, nothing may be changed
+    pub(crate) planning: bool,
+    /// where the progress of the staging is reported, if anywhere
+    pub(crate) events: Option<&'a Events>,
+}
+
+impl StageContext<'_> {
+    /// Where the archive is found inside the backup container.
+    pub(crate) fn output(&self) -> PathBuf {
+        PathBuf::from(self.config.restic_root()).join(self.service).join(self.archive)
+    }
+
+    /// Adds `binding` to the backup container mounts. A source already mounted for another archive
+    /// is mounted again, so that every archive is found under its own service, with its own
+    /// filter. Two sources mounted at the same path are an error, as docker would refuse to start
+    /// the container.
+    pub(crate) fn mount(&mut self, binding: DockerBinding) -> Result<(), SerializableError> {
+        if let Some(existing) = self.mounts.iter().find(|m| m.path == binding.path) {
+            return Err(SerializableError::config(format!(
+                "mount path {} is already used by {}",
+                binding.path.display(),
+                existing.volume,
+            )));
+        }
+        if let Some(existing) = self.mounts.iter().find(|m| m.volume == binding.volume) {
+            debug!("{} is already mounted at {}, mounting it at {} as well", binding.volume, existing.path.display(), binding.path.display());
+        }
+        self.mounts.push(binding);
+        Ok(())
+    }
+
+    /// Mounts `source` at the archive's output, staging it as [`StagedArchive::Mounted`].
+    pub(crate) fn mount_archive(&mut self, source: String, filter: Option<PathExclude>) -> Result<StagedArchive, SerializableError> {
+        let output = self.output();
+        self.mount(DockerBinding::new_ro(source, output.clone()))?;
+        Ok(StagedArchive::Mounted {
+            exclude: filter.filter(|f| !f.is_empty()).map(|f| f.resolve(&output)).transpose()?,
+        })
+    }
+}
+
+/// How a staged archive ends up in the backup.
+#[derive(Debug)]
+pub(crate) enum StagedArchive {
+    /// mounted into the backup container, with the paths to leave out of it
+    Mounted { exclude: Option<PathExclude> },
+    /// to be dumped into the intermediate directory by running `task` inside the compose `service`,
+    /// as `user` if set
+    Dump { service: String, task: ShellTask, ext: String, user: Option<String> },
+    /// nothing to back up, like a volume that disappeared
+    Missing { reason: String },
+}
+
+/// Where the data of an archive comes from. New kinds of inputs implement it in their own module and
+/// get a variant in [`ArchiveInput`].
+#[async_trait]
+pub(crate) trait InputProvider {
+    /// Gets the archive ready to be backed up, mounting it into the backup container or telling how
+    /// to dump it.
+    async fn stage(&self, ctx: &mut StageContext<'_>) -> Result<StagedArchive, SerializableError>;
+}
+
+#[async_trait]
+impl InputProvider for ArchiveInput {
+    async fn stage(&self, ctx: &mut StageContext<'_>) -> Result<StagedArchive, SerializableError> {
+        match self {
+            ArchiveInput::Docker(input) => input.stage(ctx).await,
+            ArchiveInput::Plugin(input) => input.stage(ctx).await,
+        }
+    }
+}
+
+#[async_trait]
+impl InputProvider for DockerInputType {
+    async fn stage(&self, ctx: &mut StageContext<'_>) -> Result<StagedArchive, SerializableError> {
+        let (service_name, archive_name) = (ctx.service, ctx.archive);
+        match self {
+            DockerInputType::ExecStdout { service, task, ext, user, .. } => {
+                ctx.source = Some(format!(
+                    "exec {}: {}",
+                    service,
+                    task.get_args().into_iter().collect::<Vec<_>>().join(" "),
+                ));
+                Ok(StagedArchive::Dump { service: service.clone(), task: task.clone(), ext: ext.clone(), user: user.clone() })
+            }
+            DockerInputType::ComposeNamedVolume { name, filter } => {
+                info!("{}: {}: using mode: ComposeNamedVolume", service_name, archive_name);
+                let global_volume_name = format!("{}_{}", ctx.compose_project, name);
+                debug!("{}: {}: ComposeNamedVolume: using canonical volume name: {}", service_name, archive_name, global_volume_name);
+                stage_volume(ctx, "ComposeNamedVolume", global_volume_name, filter.clone()).await
+            }
+            DockerInputType::Volume { name, filter } => {
+                info!("{}: {}: using mode: Volume", service_name, archive_name);
+                stage_volume(ctx, "Volume", name.clone(), filter.clone()).await
+            }
+            DockerInputType::ComposeBoundVolume { service, path, filter } => {
+                info!("{}: {}: using mode: ComposeBoundVolume", service_name, archive_name);
+                // find the bound volume inside the service
+                let host_path = match inspect::bound_volume(ctx.config, ctx.compose_project, service, path).await {
+                    Ok(Some(host_path)) => inspect::host_path_exists(ctx.config, &host_path).await
+                        .map(|exists| Some((host_path, exists))),
+                    Ok(None) => Ok(None),
+                    Err(e) => Err(e),
+                };
+                match host_path {
+                    // mounting it would back up the empty directory docker creates in its place
+                    Ok(Some((host_path, false))) => Ok(StagedArchive::Missing {
+                        reason: format!("source {} of the bound volume {} does not exist", host_path, path.display()),
+                    }),
+                    Ok(Some((host_path, true))) => {
+                        ctx.source = Some(format!("bind {}", host_path));
+                        ctx.mount_archive(host_path, filter.clone()).inspect_err(|e| {
+                            error!("{}: {}: ComposeBoundVolume: {}", service_name, archive_name, e);
+                        })
+                    }
+                    Ok(None) => Ok(StagedArchive::Missing {
+                        reason: format!("{} is not a bound volume of service {}", path.display(), service),
+                    }),
+                    Err(e) => {
+                        error!("{}: {}: ComposeBoundVolume: failed to find bound volume: {}", service_name, archive_name, e);
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Mounts the volume named `volume` if it exists.
+async fn stage_volume(ctx: &mut StageContext<'_>, mode: &str, volume: String, filter: Option<PathExclude>) -> Result<StagedArchive, SerializableError> {
+    let (service_name, archive_name) = (ctx.service, ctx.archive);
+    ctx.source = Some(format!("volume {}", volume));
+    // ensure the volume exists
+    let mut command = ctx.config
+        .docker_command_with_context(DockerSubcommand::volume(DockerVolumeSubcommand::inspect(&volume)))
+        .into_command();
+    command
+        .stderr(Stdio::null())
+        .stdout(Stdio::null());
+    debug!("{}: {}: {}: inspecting volume: docker {:?}", service_name, archive_name, mode, command.as_std().get_args().collect::<Vec<_>>());
+    let status = command.audited_status().await.map_err(|e| {
+        let e = SerializableError::docker(&command, None, Some(e.to_string()));
+        error!("{}: {}: {}: failed to inspect volume: {}", service_name, archive_name, mode, e);
+        e
+    })?;
+    if !status.success() {
+        return Ok(StagedArchive::Missing { reason: format!("volume {} does not exist", volume) });
+    }
+    ctx.mount_archive(volume, filter).inspect_err(|e| {
+        error!("{}: {}: {}: {}", service_name, archive_name, mode, e);
+    })
+}
+
+#[test]
+fn test_stage_context_mount() {
+    let config: Config = serde_yaml::from_str("intermediate_path: /tmp").unwrap();
+    let mut mounts = vec![];
+    let mut ctx = StageContext {
+        config: &config,
+        service: "service",
+        compose_project: "project",
+        archive: "data",
+        mounts: &mut mounts,
+        source: None,
+        planning: false,
+        events: None,
+    };
+    let output = ctx.output();
+    ctx.mount(DockerBinding::new_ro("volume".to_owned(), output.clone())).unwrap();
+    // the same source is mounted again for another archive
+    ctx.mount(DockerBinding::new_ro("volume".to_owned(), output.with_file_name("other"))).unwrap();
+    // two sources can't share a path
+    assert!(ctx.mount(DockerBinding::new_ro("other".to_owned(), output)).is_err());
+    assert_eq!(mounts.len(), 2);
+}
@@ -0,0 +1,286 @@
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, process::Stdio};
+
+use log::debug;
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::{audit::Audited, config::Config, either::Either::Left, image, DockerComposeSubcommand, DockerContainerSubcommand, DockerSubcommand, DockerSystemSubcommand, DockerVolumeSubcommand, SerializableError};
+
+/// Runs a docker query, returning its stdout lines.
+async fn query_lines(mut command: Command) -> Result<Vec<String>, SerializableError> {
+    command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    debug!("querying docker: {:?}", command.as_std().get_args().collect::<Vec<_>>());
+    let output = command.audited_output().await
+        .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+    if !output.status.success() {
+        return Err(SerializableError::docker(
+            &command,
+            output.status.code(),
+            Some(String::from_utf8_lossy(&output.stderr).trim().to_owned()),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|l| l.trim().to_owned())
+        .filter(|l| !l.is_empty())
+        .collect())
+}
+
+/// Names of every compose project known to the docker daemon, running or not.
+pub(crate) async fn compose_projects(config: &Config) -> Result<HashSet<String>, SerializableError> {
+    query_lines(config.docker_command_with_context(DockerSubcommand::compose(
+        None,
+        DockerComposeSubcommand::Ls,
+        Vec::<String>::new(),
+        vec!["-a", "--format", "json"],
+    )).into_command())
+        .await?
+        .into_iter()
+        .map(|line| {
+            #[derive(Deserialize)]
+            struct ComposeProject {
+                #[serde(rename = "Name")]
+                name: String,
+            }
+
+            Ok(serde_json::from_str::<Vec<ComposeProject>>(&line)?
+                .into_iter()
+                .map(|p| p.name))
+        })
+        .collect::<Result<Vec<_>, SerializableError>>()
+        .map(|projects| projects.into_iter().flatten().collect())
+}
+
+/// Services of a compose project that have a container, running or not.
+pub(crate) async fn compose_services(config: &Config, project: &str) -> Result<HashSet<String>, SerializableError> {
+    Ok(query_lines(config.docker_command_with_context(DockerSubcommand::compose(
+        Some(Left(project.to_owned())),
+        DockerComposeSubcommand::Ps(vec![]),
+        Vec::<String>::new(),
+        vec!["-a", "--format", "{{.Service}}"],
+    )).into_command())
+        .await?
+        .into_iter()
+        .collect())
+}
+
+/// Services of a compose project with a running container.
+pub(crate) async fn running_services(config: &Config, project: &str) -> Result<HashSet<String>, SerializableError> {
+    Ok(query_lines(config.docker_command_with_context(DockerSubcommand::compose(
+        Some(Left(project.to_owned())),
+        DockerComposeSubcommand::Ps(vec![]),
+        Vec::<String>::new(),
+        vec!["--status", "running", "--format", "{{.Service}}"],
+    )).into_command())
+        .await?
+        .into_iter()
+        .collect())
+}
+
+/// Names of every docker volume.
+pub(crate) async fn volumes(config: &Config) -> Result<HashSet<String>, SerializableError> {
+    Ok(query_lines(config.docker_command_with_context(DockerSubcommand::volume(
+        DockerVolumeSubcommand::ls("{{.Name}}"),
+    )).into_command())
+        .await?
+        .into_iter()
+        .collect())
+}
+
+/// Size in bytes of every docker volume, as `docker system df` reports it.
+pub(crate) async fn volume_sizes(config: &Config) -> Result<HashMap<String, u64>, SerializableError> {
+    #[derive(Deserialize)]
+    struct DiskUsage {
+        #[serde(rename = "Volumes", default)]
+        volumes: Vec<VolumeUsage>,
+    }
+
+    #[derive(Deserialize)]
+    struct VolumeUsage {
+        #[serde(rename = "Name")]
+        name: String,
+        #[serde(rename = "Size")]
+        size: String,
+    }
+
+    let lines = query_lines(config.docker_command_with_context(DockerSubcommand::System {
+        subcommand: DockerSystemSubcommand::Df { format: "json".to_owned() },
+    }).into_command()).await?;
+    let mut sizes = HashMap::new();
+    for line in lines {
+        let usage: DiskUsage = serde_json::from_str(&line)?;
+        sizes.extend(usage.volumes.into_iter().filter_map(|v| parse_size(&v.size).map(|size| (v.name, size))));
+    }
+    Ok(sizes)
+}
+
+/// Parses a size as docker prints it, like `1.5GB`, in decimal units.
+fn parse_size(size: &str) -> Option<u64> {
+    let unit_at = size.find(|c: char| c.is_ascii_alphabetic())?;
+    let (value, unit) = size.split_at(unit_at);
+    let multiplier = match unit {
+        "B" => 1e0,
+        "kB" | "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        "PB" => 1e15,
+        _ => return None,
+    };
+    value.trim().parse::<f64>().ok().map(|v| (v * multiplier) as u64)
+}
+
+/// A container of a compose project, as `docker inspect` describes it.
+#[derive(Deserialize, Debug)]
+pub(crate) struct Container {
+    #[serde(rename = "Config")]
+    pub(crate) config: ContainerConfig,
+    #[serde(rename = "Mounts", default)]
+    pub(crate) mounts: Vec<ContainerMount>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ContainerConfig {
+    #[serde(rename = "Image")]
+    pub(crate) image: String,
+    #[serde(rename = "Labels", default)]
+    pub(crate) labels: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ContainerMount {
+    /// `volume` or `bind`, among others
+    #[serde(rename = "Type")]
+    pub(crate) kind: String,
+    /// full name of the volume, for volumes
+    #[serde(rename = "Name", default)]
+    pub(crate) name: Option<String>,
+    #[serde(rename = "Source")]
+    pub(crate) source: String,
+    #[serde(rename = "Destination")]
+    pub(crate) destination: PathBuf,
+}
+
+impl Container {
+    /// The compose service the container belongs to.
+    pub(crate) fn service(&self) -> Option<&str> {
+        self.config.labels.get("com.docker.compose.service").map(String::as_str)
+    }
+}
+
+/// Every container of a compose project, running or not.
+pub(crate) async fn project_containers(config: &Config, project: &str) -> Result<Vec<Container>, SerializableError> {
+    let ids = query_lines(config.docker_command_with_context(DockerSubcommand::compose(
+        Some(Left(project.to_owned())),
+        DockerComposeSubcommand::Ps(vec![]),
+        Vec::<String>::new(),
+        vec!["-a", "--format", "{{.ID}}", "--no-trunc"],
+    )).into_command()).await?;
+    let mut containers = vec![];
+    for id in ids {
+        let command = config.docker_command_with_context(DockerSubcommand::container(
+            DockerContainerSubcommand::Inspect { container: id },
+            vec!["--format", "json"],
+        )).into_command();
+        let inspect = query_lines(command).await?.join("\n");
+        containers.extend(serde_json::from_str::<Vec<Container>>(&inspect)?);
+    }
+    Ok(containers)
+}
+
+/// Finds the host path bound at `path` inside the container of a compose service, None if the
+/// service has no container or nothing is bound at `path`.
+pub(crate) async fn bound_volume(
+    config: &Config,
+    project: &str,
+    service: &str,
+    path: &Path,
+) -> Result<Option<String>, SerializableError> {
+    #[derive(Deserialize, Debug)]
+    struct DockerContainerInspectOutput {
+        #[serde(rename = "Mounts")]
+        mounts: Vec<DockerContainerMount>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct DockerContainerMount {
+        #[serde(rename = "Source")]
+        source: String,
+        #[serde(rename = "Destination")]
+        destination: String,
+    }
+
+    let container_id = match query_lines(config.docker_command_with_context(DockerSubcommand::compose(
+        Some(Left(project.to_owned())),
+        DockerComposeSubcommand::Ps(vec![service.to_owned()]),
+        Vec::<String>::new(),
+        vec!["-a", "--format", "{{.ID}}", "--no-trunc"],
+    )).into_command()).await?.into_iter().next() {
+        Some(id) => id,
+        None => {
+            debug!("{}: {}: no container found", project, service);
+            return Ok(None);
+        }
+    };
+
+    let command = config.docker_command_with_context(DockerSubcommand::container(
+        DockerContainerSubcommand::Inspect { container: container_id },
+        vec!["--format", "json"],
+    )).into_command();
+    let inspect = query_lines(command).await?.join("\n");
+    let mounts = serde_json::from_str::<Vec<DockerContainerInspectOutput>>(&inspect)?
+        .into_iter()
+        .next()
+        .map(|i| i.mounts)
+        .unwrap_or_default();
+    Ok(mounts
+        .into_iter()
+        .find(|m| Path::new(&m.destination) == path)
+        .map(|m| m.source))
+}
+
+/// Where [`host_path_exists`] mounts the path it looks for.
+static PROBE_MOUNT: &str = "/probe";
+
+/// Whether `path` exists on the docker host, as the daemon sees it rather than hoarder, which
+/// usually runs in a container of its own. The path is bound with `--mount`, which fails on a
+/// missing source instead of creating it like `-v` does.
+pub(crate) async fn host_path_exists(config: &Config, path: &str) -> Result<bool, SerializableError> {
+    image::verify(config).await?;
+    let mut command = config.docker_command_with_context(DockerSubcommand::run(
+        config.restic_image(),
+        vec![],
+        vec![
+            "--rm".to_owned(),
+            "--network".to_owned(),
+            "none".to_owned(),
+            "--mount".to_owned(),
+            format!("type=bind,source={},target={},readonly", path, PROBE_MOUNT),
+            "--entrypoint".to_owned(),
+            "test".to_owned(),
+        ],
+        vec!["-e", PROBE_MOUNT],
+    )).into_command();
+    command
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    debug!("looking for {} on the docker host: {:?}", path, command.as_std().get_args().collect::<Vec<_>>());
+    let output = command.audited_output().await
+        .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    match output.status.success() {
+        true => Ok(true),
+        false if stderr.contains("bind source path does not exist") => Ok(false),
+        false => Err(SerializableError::docker(&command, output.status.code(), Some(stderr))),
+    }
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("0B"), Some(0));
+    assert_eq!(parse_size("512kB"), Some(512_000));
+    assert_eq!(parse_size("1.5GB"), Some(1_500_000_000));
+    assert_eq!(parse_size("N/A"), None);
+}
@@ -0,0 +1,437 @@
+use std::{path::{Path, PathBuf}, process::Stdio, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use indicatif::ProgressBar;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWriteExt}, process::Command};
+
+use crate::{
+    audit::Audited,
+    backend::{self, BackendType, BackupBackend, BackupRequest, BackupSummary, RepositoryStats, Retention, Snapshot, HOARDER_TAG},
+    config::Config,
+    credentials::Credentials,
+    DockerBinding,
+    DockerSubcommand,
+    SerializableError,
+    ShellTask,
+};
+
+static KOPIA_IMAGE: &str = "kopia/kopia";
+static KOPIA_CONTAINER_NAME: &str = "hoarder-kopia";
+/// Where the kopia configuration and cache directory is mounted inside kopia containers.
+static CONFIG_MOUNT: &str = "/kopia";
+static CONFIG_FILE: &str = "repository.config";
+/// Where the password file is mounted inside kopia containers.
+static PASSWORD_MOUNT: &str = "/kopia_password";
+/// Where the restore target is mounted inside kopia containers.
+static RESTORE_MOUNT: &str = "/restore";
+/// User the snapshots are taken as, kopia identifies sources by user, host and path.
+static KOPIA_USER: &str = "hoarder";
+
+/// Settings of the kopia backend.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct KopiaConfig {
+    /// arguments of `kopia repository connect`, like `[server, --url=https://kopia:51515]` or
+    /// `[s3, --bucket=backups]`, the rest of the connection is read from the KOPIA_* and AWS_*
+    /// environment variables
+    pub(crate) connect: Vec<String>,
+    /// file holding the repository's password
+    pub(crate) password_file: Option<String>,
+    /// directory keeping the connection and the cache between runs, defaults to `kopia` inside
+    /// the state directory
+    pub(crate) config_dir: Option<String>,
+    /// image providing kopia, the backups run in a long running container of it
+    pub(crate) image: Option<String>,
+    /// name of the long running kopia container
+    pub(crate) container_name: Option<String>,
+}
+
+impl KopiaConfig {
+    fn image(&self) -> String {
+        self.image.clone().unwrap_or(KOPIA_IMAGE.to_owned())
+    }
+
+    fn container_name(&self) -> String {
+        self.container_name.clone().unwrap_or(KOPIA_CONTAINER_NAME.to_owned())
+    }
+}
+
+/// A snapshot manifest, as printed by `kopia snapshot create --json` and `kopia snapshot list --json`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct KopiaSnapshot {
+    id: String,
+    source: KopiaSource,
+    start_time: DateTime<Utc>,
+    #[serde(default)]
+    tags: std::collections::BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct KopiaSource {
+    host: String,
+    path: String,
+}
+
+/// Backs up to a kopia repository, from a long running kopia container holding every mount. The
+/// repository connection is kept in `config_dir`, so that it is only made once.
+pub(crate) struct Kopia;
+
+impl Kopia {
+    fn settings(config: &Config) -> Result<&KopiaConfig, SerializableError> {
+        config.kopia().ok_or(SerializableError::config("the kopia backend needs a kopia section"))
+    }
+
+    fn config_dir(config: &Config) -> Result<PathBuf, SerializableError> {
+        Ok(match &Self::settings(config)?.config_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => config.state_dir()?.join("kopia"),
+        })
+    }
+
+    /// Environment of the kopia containers, as `docker run` options along with the credentials they
+    /// pass by name, adding the connection and the password file to `mounts`.
+    fn environment(config: &Config, mounts: &mut Vec<DockerBinding>) -> Result<(Vec<String>, Credentials), SerializableError> {
+        let settings = Self::settings(config)?;
+        let config_dir = Self::config_dir(config)?;
+        std::fs::create_dir_all(&config_dir)?;
+        mounts.push(DockerBinding::new_rw(config_dir.display().to_string(), PathBuf::from(CONFIG_MOUNT)));
+        let env = [
+            ("KOPIA_CONFIG_PATH", format!("{}/{}", CONFIG_MOUNT, CONFIG_FILE)),
+            ("KOPIA_CACHE_DIRECTORY", format!("{}/cache", CONFIG_MOUNT)),
+            ("KOPIA_CHECK_FOR_UPDATES", "false".to_owned()),
+        ];
+        if let Some(file) = &settings.password_file {
+            mounts.push(DockerBinding::new_ro(file.clone(), PathBuf::from(PASSWORD_MOUNT)));
+        }
+        let credentials = Credentials::new(config, BackendType::Kopia, &["KOPIA_", "AWS_"])
+            .without(&env.iter().map(|(k, _)| *k).collect::<Vec<_>>());
+        let options = env.into_iter()
+            .flat_map(|(k, v)| ["--env".to_owned(), format!("{}={}", k, v)])
+            .chain(credentials.options())
+            .collect();
+        Ok((options, credentials))
+    }
+
+    /// Runs the kopia `args` in a throwaway container, through a shell when the password has to be
+    /// read from its file.
+    fn command(config: &Config, mut mounts: Vec<DockerBinding>, task: ShellTask) -> Result<Command, SerializableError> {
+        let (env, credentials) = Self::environment(config, &mut mounts)?;
+        let settings = Self::settings(config)?;
+        let mut command = config.docker_command_with_context(DockerSubcommand::run(
+            settings.image(),
+            mounts,
+            ["--rm", "--entrypoint", "sh"].into_iter().map(str::to_owned).chain(env).collect(),
+            vec!["-c".to_owned(), Self::script(settings, task)],
+        )).into_command();
+        command.envs(credentials.vars());
+        Ok(command)
+    }
+
+    /// A shell script running `task`, with the password exported from its file if one is set.
+    fn script(settings: &KopiaConfig, task: ShellTask) -> String {
+        let command = task.to_shell();
+        match settings.password_file {
+            Some(_) => format!("KOPIA_PASSWORD=\"$(cat {})\" exec {}", PASSWORD_MOUNT, command),
+            None => format!("exec {}", command),
+        }
+    }
+
+    async fn query(config: &Config, mounts: Vec<DockerBinding>, task: ShellTask) -> Result<String, SerializableError> {
+        let mut command = Self::command(config, mounts, task)?;
+        command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        debug!("querying kopia: {:?}", command.as_std().get_args().collect::<Vec<_>>());
+        let output = command.audited_output().await
+            .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+        if !output.status.success() {
+            return Err(SerializableError::backend(format!(
+                "kopia {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim(),
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Connects to the repository, unless a previous run already did.
+    async fn connect(config: &Config) -> Result<(), SerializableError> {
+        if Self::config_dir(config)?.join(CONFIG_FILE).exists() {
+            return Ok(());
+        }
+        info!("connecting to the kopia repository");
+        let mut task = ShellTask::new("kopia");
+        task.args(["repository", "connect"])
+            .args(Self::settings(config)?.connect.iter())
+            .arg(format!("--override-hostname={}", config.restic_host()?))
+            .arg(format!("--override-username={}", KOPIA_USER));
+        Self::query(config, vec![], task).await.map(|_| ())
+    }
+
+    /// Builds the command running `task` inside the kopia container.
+    fn exec_command(config: &Config, task: ShellTask, stdin: bool) -> Result<Command, SerializableError> {
+        let settings = Self::settings(config)?;
+        let mut script = ShellTask::new("sh");
+        script.arg("-c").arg(Self::script(settings, task));
+        Ok(config.docker_command_with_context(DockerSubcommand::exec(
+            settings.container_name(),
+            script,
+            if stdin { vec!["-i"] } else { vec![] },
+        )).into_command())
+    }
+
+    /// The tasks backing up `request`: setting its excludes as the source's policy, unless it is a
+    /// dry run, then snapshotting it.
+    fn backup_tasks(config: &Config, request: &BackupRequest) -> Vec<ShellTask> {
+        let mut tasks = vec![];
+        // kopia reads excludes from the source's policy, relative to the source
+        if !config.dry_run() {
+            let mut policy = ShellTask::new("kopia");
+            policy.args(["policy", "set", "--clear-ignore"]);
+            for exclude in &request.excludes {
+                let relative = Path::new(exclude).strip_prefix(&request.path).unwrap_or(Path::new(exclude));
+                policy.arg("--add-ignore").arg(format!("/{}", relative.display()));
+            }
+            policy.arg(request.path.display());
+            tasks.push(policy);
+        }
+        let mut task = ShellTask::new("kopia");
+        if config.dry_run() {
+            task.args(["snapshot", "estimate"]);
+        } else {
+            task.args(["snapshot", "create", "--json", "--no-progress"]);
+            Self::tag_args(&mut task, &request.tags);
+        }
+        task.arg(request.path.display());
+        tasks.push(task);
+        tasks
+    }
+
+    /// The tasks setting `retention` as the global policy and expiring the snapshots it doesn't
+    /// keep, none for a dry run.
+    fn forget_tasks(config: &Config, retention: &Retention) -> Result<Vec<ShellTask>, SerializableError> {
+        let rules = retention.rules();
+        if rules.is_empty() {
+            return Err(SerializableError::config("retention must keep something, set at least one keep_* rule"));
+        }
+        if config.dry_run() {
+            return Ok(vec![]);
+        }
+        let mut policy = ShellTask::new("kopia");
+        policy.args(["policy", "set", "--global"]);
+        for (rule, keep) in rules {
+            let rule = match rule {
+                "last" => "latest",
+                "yearly" => "annual",
+                rule => rule,
+            };
+            policy.arg(format!("--keep-{}", rule)).arg(keep);
+        }
+        let mut expire = ShellTask::new("kopia");
+        expire.args(["snapshot", "expire", "--all", "--delete"]);
+        Ok(vec![policy, expire])
+    }
+
+    /// Runs `task` inside the kopia container, feeding it `input`, returning its stdout.
+    async fn exec(config: &Config, task: ShellTask, input: Option<Box<dyn AsyncRead + Send + Unpin>>) -> Result<String, SerializableError> {
+        let mut command = Self::exec_command(config, task, input.is_some())?;
+        command
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        info!("running kopia task: {:?}", command.as_std().get_args().collect::<Vec<_>>());
+        let mut handle = command.audited_spawn()?;
+        let stdin = handle.stdin.take();
+        let feed = async {
+            if let (Some(mut input), Some(mut stdin)) = (input, stdin) {
+                tokio::io::copy(&mut input, &mut stdin).await?;
+                stdin.shutdown().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        let (mut stdout, mut stderr) = (handle.stdout.take(), handle.stderr.take());
+        let read = async {
+            let (mut out, mut err) = (String::new(), String::new());
+            if let Some(stdout) = stdout.as_mut() {
+                stdout.read_to_string(&mut out).await?;
+            }
+            if let Some(stderr) = stderr.as_mut() {
+                stderr.read_to_string(&mut err).await?;
+            }
+            Ok::<_, std::io::Error>((out, err))
+        };
+        let (fed, output) = tokio::join!(feed, read);
+        let exit = handle.wait().await?;
+        let (stdout, stderr) = output?;
+        if !exit.success() {
+            return Err(SerializableError::backend(format!("kopia {}: {}", exit, stderr.trim())));
+        }
+        fed?;
+        Ok(stdout)
+    }
+
+    fn tag_args(task: &mut ShellTask, tags: &[String]) {
+        for tag in std::iter::once(HOARDER_TAG).chain(tags.iter().map(String::as_str)) {
+            task.arg("--tags").arg(format!("{}:true", tag));
+        }
+    }
+
+    /// Reads the summary of a `kopia snapshot create --json`.
+    fn summary(config: &Config, output: &str) -> Result<BackupSummary, SerializableError> {
+        if config.dry_run() {
+            return Ok(BackupSummary::default());
+        }
+        let snapshot: KopiaSnapshot = serde_json::from_str(output.trim())?;
+        // kopia doesn't report how much a snapshot added to the repository
+        Ok(BackupSummary { snapshot_id: Some(snapshot.id), data_added: None })
+    }
+
+    /// Splits a path under the backup root into the snapshot's source directory and the path
+    /// inside of it.
+    fn split(config: &Config, path: &Path) -> (PathBuf, PathBuf) {
+        let root = PathBuf::from(config.restic_root());
+        let relative = path.strip_prefix(&root).unwrap_or(path);
+        let mut components = relative.components();
+        let source = root.join(components.next().map(|c| c.as_os_str()).unwrap_or_default());
+        (source, components.as_path().to_path_buf())
+    }
+
+    fn object(snapshot: &str, inner: &Path) -> String {
+        match inner.as_os_str().is_empty() {
+            true => snapshot.to_owned(),
+            false => format!("{}/{}", snapshot, inner.display()),
+        }
+    }
+}
+
+#[async_trait]
+impl BackupBackend for Kopia {
+    async fn prepare(&self, config: &Config, mut mounts: Vec<DockerBinding>) -> Result<(), SerializableError> {
+        Self::connect(config).await?;
+        let settings = Self::settings(config)?;
+        let (env, credentials) = Self::environment(config, &mut mounts)?;
+        let options = ["--init", "--entrypoint", "sleep"].into_iter().map(str::to_owned).chain(env).collect();
+        backend::start_container_with_env(config, &settings.container_name(), &settings.image(), mounts, options, vec!["infinity"], credentials.into_vars()).await
+    }
+
+    async fn teardown(&self, config: &Config) -> Result<bool, SerializableError> {
+        backend::stop_container(config, &Self::settings(config)?.container_name()).await
+    }
+
+    async fn backup(
+        &self,
+        config: &Config,
+        request: BackupRequest,
+        bar: &ProgressBar,
+        _previous: Option<Duration>,
+    ) -> Result<BackupSummary, SerializableError> {
+        if config.dry_run() {
+            warn!("running in dry run mode, not actually uploading");
+        }
+        bar.set_message("uploading");
+        // the snapshot's summary is the output of the last task
+        let mut output = String::new();
+        for task in Self::backup_tasks(config, &request) {
+            output = Self::exec(config, task, None).await?;
+        }
+        Self::summary(config, &output)
+    }
+
+    fn plan_commands(&self, config: &Config, request: &BackupRequest) -> Result<Vec<Command>, SerializableError> {
+        Self::backup_tasks(config, request)
+            .into_iter()
+            .map(|task| Self::exec_command(config, task, false))
+            .collect()
+    }
+
+    async fn forget(&self, config: &Config, retention: &Retention) -> Result<(), SerializableError> {
+        let tasks = Self::forget_tasks(config, retention)?;
+        Self::connect(config).await?;
+        if config.dry_run() {
+            info!("dry run, not changing the retention policy");
+            return Ok(());
+        }
+        for task in tasks {
+            Self::query(config, vec![], task).await?;
+        }
+        Ok(())
+    }
+
+    fn plan_forget(&self, config: &Config, retention: &Retention) -> Result<Vec<Command>, SerializableError> {
+        Self::forget_tasks(config, retention)?
+            .into_iter()
+            .map(|task| Self::command(config, vec![], task))
+            .collect()
+    }
+
+    async fn snapshots(&self, config: &Config) -> Result<Vec<Snapshot>, SerializableError> {
+        Self::connect(config).await?;
+        let mut task = ShellTask::new("kopia");
+        task.args(["snapshot", "list", "--all", "--json"]);
+        let snapshots: Vec<KopiaSnapshot> = serde_json::from_str(&Self::query(config, vec![], task).await?)?;
+        let host = config.restic_host()?;
+        Ok(snapshots
+            .into_iter()
+            .filter(|s| s.source.host == host)
+            .filter(|s| s.tags.contains_key(&format!("tag:{}", HOARDER_TAG)))
+            .map(|s| Snapshot {
+                id: s.id,
+                time: s.start_time,
+                paths: vec![s.source.path],
+                tags: s.tags.keys().filter_map(|t| t.strip_prefix("tag:")).map(str::to_owned).collect(),
+            })
+            .collect())
+    }
+
+    async fn restore(
+        &self,
+        config: &Config,
+        snapshot: &str,
+        path: &Path,
+        target: Option<&str>,
+        dry_run: bool,
+    ) -> Result<String, SerializableError> {
+        Self::connect(config).await?;
+        let (_, inner) = Self::split(config, path);
+        let mut task = ShellTask::new("kopia");
+        let mounts = match dry_run {
+            // kopia has no dry run restore, list what would be restored instead
+            true => {
+                task.args(["ls", "--recursive"]).arg(Self::object(snapshot, &inner));
+                vec![]
+            }
+            false => {
+                task.args(["snapshot", "restore"]).arg(Self::object(snapshot, &inner)).arg(RESTORE_MOUNT);
+                target.iter().map(|t| DockerBinding::new_rw(t.to_string(), PathBuf::from(RESTORE_MOUNT))).collect()
+            }
+        };
+        Self::query(config, mounts, task).await
+    }
+
+    fn dump(&self, config: &Config, snapshot: &str, path: &Path) -> Result<Command, SerializableError> {
+        let (_, inner) = Self::split(config, path);
+        let mut task = ShellTask::new("kopia");
+        task.arg("show").arg(Self::object(snapshot, &inner));
+        Self::command(config, vec![], task)
+    }
+
+    async fn stats(&self, config: &Config) -> Result<RepositoryStats, SerializableError> {
+        Self::connect(config).await?;
+        let mut task = ShellTask::new("kopia");
+        task.args(["blob", "stats", "--raw"]);
+        let output = Self::query(config, vec![], task).await?;
+        let stored_bytes = output
+            .lines()
+            .find_map(|l| l.trim().strip_prefix("Total:"))
+            .and_then(|t| t.trim().parse().ok())
+            .ok_or_else(|| SerializableError::backend(format!("unexpected kopia blob stats output: {}", output.trim())))?;
+        Ok(RepositoryStats {
+            snapshots: self.snapshots(config).await?.len() as u64,
+            stored_bytes,
+        })
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::{BTreeMap, HashMap}, path::PathBuf};
+
+use log::{info, warn};
+
+use crate::{
+    archive::{ArchiveInput, ArchiveOptions},
+    config::{check_name, Config},
+    inspect,
+    service::Service,
+    DockerInputType,
+    SerializableError,
+    ShellTask,
+};
+
+/// Prefix of the labels declaring archives, as `hoarder.archive.<name>.<field>`.
+static ARCHIVE_LABEL: &str = "hoarder.archive.";
+
+/// Reads the archives declared in the labels of the container of compose `service`. The `type` of
+/// an archive is one of:
+/// - `exec_stdout`: `task` is run with `sh -c` inside the service, with `ext`, `restore_task` and
+///   `user` as in the configuration
+/// - `named_volume`: the compose `volume` of the project
+/// - `bound_volume`: whatever is bound at `path` in the service
+/// - `volume`: the docker `volume`, by its full name
+fn archives(service: &str, labels: &HashMap<String, String>) -> Result<Vec<ArchiveOptions>, String> {
+    let mut declared: BTreeMap<&str, HashMap<&str, &str>> = BTreeMap::new();
+    for (key, value) in labels {
+        let Some(rest) = key.strip_prefix(ARCHIVE_LABEL) else {
+            continue;
+        };
+        let Some((name, field)) = rest.rsplit_once('.') else {
+            return Err(format!("label {} doesn't name an archive field", key));
+        };
+        declared.entry(name).or_default().insert(field, value);
+    }
+    let sh = |script: &str| {
+        let mut task = ShellTask::new("sh");
+        task.args(["-c", script]);
+        task
+    };
+    declared.into_iter().map(|(name, mut fields)| {
+        check_name("archive name", name)?;
+        let mut take = |field: &str| fields.remove(field)
+            .ok_or_else(|| format!("archive {} needs a hoarder.archive.{}.{} label", name, name, field));
+        let kind = take("type")?;
+        let input = match kind {
+            "exec_stdout" => DockerInputType::ExecStdout {
+                service: service.to_owned(),
+                task: sh(take("task")?),
+                ext: take("ext").unwrap_or("dump").to_owned(),
+                restore_task: take("restore_task").ok().map(sh),
+                user: take("user").ok().map(str::to_owned),
+            },
+            "named_volume" => DockerInputType::ComposeNamedVolume { name: take("volume")?.to_owned(), filter: None },
+            "bound_volume" => DockerInputType::ComposeBoundVolume { service: service.to_owned(), path: PathBuf::from(take("path")?), filter: None },
+            "volume" => DockerInputType::Volume { name: take("volume")?.to_owned(), filter: None },
+            kind => return Err(format!("archive {} has an unknown type {}", name, kind)),
+        };
+        if let Some(field) = fields.keys().next() {
+            return Err(format!("archive {} has an unknown field {}", name, field));
+        }
+        Ok(ArchiveOptions::new(name, ArchiveInput::Docker(input)))
+    }).collect()
+}
+
+/// Adds the archives declared in the labels of every compose project's containers, when enabled.
+/// They go to the service backing the project up, or to a new one named after the project, the
+/// archives of the configuration winning over labelled ones of the same name.
+pub(crate) async fn merge(config: &Config, services: &mut Vec<Service>) -> Result<(), SerializableError> {
+    if !config.docker_labels() {
+        return Ok(());
+    }
+    let mut projects = inspect::compose_projects(config).await?.into_iter().collect::<Vec<_>>();
+    projects.sort();
+    let mut problems = vec![];
+    for project in projects {
+        let mut labelled = vec![];
+        for container in inspect::project_containers(config, &project).await? {
+            let Some(service) = container.service() else {
+                continue;
+            };
+            match archives(service, &container.config.labels) {
+                Ok(archives) => labelled.extend(archives),
+                Err(e) => problems.push(format!("{}: {}: {}", project, service, e)),
+            }
+        }
+        if labelled.is_empty() {
+            continue;
+        }
+        let existing = services.iter().position(|s| s.compose_project.as_ref().unwrap_or(&s.name) == &project);
+        let service = match existing {
+            Some(i) => &mut services[i],
+            None => {
+                if services.iter().any(|s| s.name == project) {
+                    problems.push(format!("{}: a service of another project already has its name", project));
+                    continue;
+                }
+                services.push(Service {
+                    name: project.clone(),
+                    archives: vec![],
+                    compose_project: None,
+                    compose_file: None,
+                    max_age: None,
+                    min_snapshots_7d: None,
+                    max_snapshot_age: None,
+                    backends: None,
+                    before: vec![],
+                    after: vec![],
+                    enabled: Service::default_enabled(),
+                    skip_if: None,
+                    depends_on: vec![],
+                    one_file_system: false,
+                });
+                services.last_mut().expect("just pushed")
+            }
+        };
+        for archive in labelled {
+            if service.archives.iter().any(|a| a.name == archive.name) {
+                warn!("{}: {}: defined both in the configuration and in labels, keeping the configuration", service.name, archive.name);
+                continue;
+            }
+            info!("{}: {}: declared in labels", service.name, archive.name);
+            service.archives.push(archive);
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(SerializableError::config(format!("invalid labels: {}", problems.join("; "))))
+    }
+}
+
+#[test]
+fn test_label_archives() {
+    let labels = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>();
+    let declared = archives("db", &labels(&[
+        ("com.docker.compose.service", "db"),
+        ("hoarder.archive.dump.type", "exec_stdout"),
+        ("hoarder.archive.dump.task", "pg_dumpall -U postgres"),
+        ("hoarder.archive.dump.ext", "sql"),
+        ("hoarder.archive.v1.2.type", "named_volume"),
+        ("hoarder.archive.v1.2.volume", "data"),
+    ])).unwrap();
+    assert_eq!(declared.len(), 2);
+    assert_eq!(declared[0].name, "dump");
+    assert!(matches!(&declared[0].input, ArchiveInput::Docker(DockerInputType::ExecStdout { service, ext, .. }) if service == "db" && ext == "sql"));
+    assert_eq!(declared[1].name, "v1.2");
+
+    let missing = archives("db", &labels(&[("hoarder.archive.dump.type", "exec_stdout")]));
+    assert!(missing.unwrap_err().contains("task"));
+    let unknown = archives("db", &labels(&[
+        ("hoarder.archive.dump.type", "named_volume"),
+        ("hoarder.archive.dump.volume", "x"),
+        ("hoarder.archive.dump.tsk", "x"),
+    ]));
+    assert!(unknown.unwrap_err().contains("tsk"));
+}
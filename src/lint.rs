@@ -0,0 +1,301 @@
+//! `hoarder lint`: configurations that load, but likely don't do what was meant.
+
+use std::{collections::HashMap, fmt::Display};
+
+use indicatif::HumanBytes;
+use serde_yaml::Value;
+
+use crate::{
+    archive::ArchiveInput,
+    config::FullConfig,
+    docker::PathExclude,
+    inspect,
+    migrate::{self, CONFIG_VERSION},
+    preset,
+    DockerInputType,
+    SerializableError,
+    ShellTask,
+};
+
+/// Volumes above this size should leave their caches and temporary files out with excludes.
+static LARGE_VOLUME: u64 = 10_000_000_000;
+
+/// Arguments a shell would interpret, passed as they are since no shell runs the tasks.
+static SHELL_OPERATORS: &[&str] = &["|", "||", "&&", ";", "&", ">", ">>", "<", "2>", "2>&1"];
+
+/// Shells, whose `-c` script is interpreted.
+static SHELLS: &[&str] = &["sh", "bash", "ash", "dash", "zsh"];
+
+/// What is wrong with a configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LintKind {
+    /// written for an older version of hoarder
+    Deprecated,
+    /// not a setting hoarder reads, likely misspelled
+    UnknownKey,
+    /// shell syntax in a task that isn't run by a shell
+    ShellSyntax,
+    /// the same data backed up by two archives
+    DuplicateMount,
+    /// a large volume backed up whole
+    LargeUnfiltered,
+}
+
+impl LintKind {
+    fn name(self) -> &'static str {
+        match self {
+            LintKind::Deprecated => "deprecated",
+            LintKind::UnknownKey => "unknown-key",
+            LintKind::ShellSyntax => "shell-syntax",
+            LintKind::DuplicateMount => "duplicate-mount",
+            LintKind::LargeUnfiltered => "large-unfiltered",
+        }
+    }
+}
+
+/// A suspicious part of a configuration, with what to do about it.
+#[derive(Debug)]
+pub(crate) struct Lint {
+    pub(crate) kind: LintKind,
+    /// where in the configuration, like `app: db`
+    pub(crate) location: String,
+    pub(crate) message: String,
+}
+
+impl Lint {
+    fn new(kind: LintKind, location: impl ToString, message: impl ToString) -> Self {
+        Self { kind, location: location.to_string(), message: message.to_string() }
+    }
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} [{}]", self.location, self.message, self.kind.name())
+    }
+}
+
+/// Lints the configuration `text`, loaded as `full_config`. Unless `offline`, the size of the
+/// volumes is asked to docker.
+pub(crate) async fn lint(text: &str, full_config: &FullConfig, offline: bool) -> Result<Vec<Lint>, SerializableError> {
+    let mut lints = deprecated(text)?;
+    lints.extend(unknown_keys(text, full_config)?);
+    lints.extend(shell_syntax(full_config));
+    lints.extend(duplicate_mounts(full_config));
+    if !offline {
+        let sizes = inspect::volume_sizes(&full_config.config).await?;
+        lints.extend(large_unfiltered(full_config, &sizes));
+    }
+    Ok(lints)
+}
+
+/// The settings older versions of hoarder wrote.
+fn deprecated(text: &str) -> Result<Vec<Lint>, SerializableError> {
+    let value: Value = serde_yaml::from_str(text)
+        .map_err(|e| SerializableError::config(format!("failed to parse config file: {}", e)))?;
+    let mut lints = vec![];
+    let version = value.get("version").and_then(Value::as_u64).unwrap_or(1);
+    if version < CONFIG_VERSION as u64 {
+        lints.push(Lint::new(LintKind::Deprecated, "version", format!(
+            "the configuration is version {}, run `hoarder migrate-config` to bring it to version {}",
+            version,
+            CONFIG_VERSION,
+        )));
+    }
+    if let Some(dry_run) = value.get("dry_run").and_then(Value::as_bool) {
+        lints.push(Lint::new(LintKind::Deprecated, "dry_run", format!(
+            "dry runs have levels now, write `dry_run: {}`",
+            if dry_run { "restic-dry-run" } else { "off" },
+        )));
+    }
+    Ok(lints)
+}
+
+/// The keys of the configuration, its services and its archives that hoarder ignores.
+fn unknown_keys(text: &str, full_config: &FullConfig) -> Result<Vec<Lint>, SerializableError> {
+    let value: Value = serde_yaml::from_str(text)
+        .map_err(|e| SerializableError::config(format!("failed to parse config file: {}", e)))?;
+    let (mut written, _) = migrate::migrate(value)?;
+    preset::expand(&mut written)?;
+    let known = serde_yaml::to_value(full_config)
+        .map_err(|e| SerializableError::config(format!("failed to serialize the configuration: {}", e)))?;
+
+    let mut lints = vec![];
+    compare_keys(&written, &known, "configuration", &mut lints);
+    let services = |v: &Value| v.get("services").and_then(Value::as_sequence).cloned().unwrap_or_default();
+    for (written, known) in services(&written).iter().zip(&services(&known)) {
+        let name = known.get("name").and_then(Value::as_str).unwrap_or_default().to_owned();
+        compare_keys(written, known, &name, &mut lints);
+        let archives = |v: &Value| v.get("archives").and_then(Value::as_sequence).cloned().unwrap_or_default();
+        for (written, known) in archives(written).iter().zip(&archives(known)) {
+            let archive = known.get("name").and_then(Value::as_str).unwrap_or_default();
+            compare_keys(written, known, &format!("{}: {}", name, archive), &mut lints);
+        }
+    }
+    Ok(lints)
+}
+
+fn compare_keys(written: &Value, known: &Value, location: &str, lints: &mut Vec<Lint>) {
+    let (Some(written), Some(known)) = (written.as_mapping(), known.as_mapping()) else {
+        return;
+    };
+    for key in written.keys().filter_map(Value::as_str) {
+        if !known.contains_key(key) {
+            lints.push(Lint::new(LintKind::UnknownKey, location, format!("`{}` isn't a setting and is ignored, check its spelling", key)));
+        }
+    }
+}
+
+/// The shell syntax of `task` that wouldn't be interpreted, if any.
+fn shell_operator(task: &ShellTask) -> Option<String> {
+    let args = task.get_args().into_iter().collect::<Vec<_>>();
+    if args.first().is_some_and(|program| SHELLS.contains(&program.rsplit('/').next().unwrap_or(program))) && args.contains(&"-c") {
+        return None;
+    }
+    args.into_iter().skip(1).find_map(|arg| {
+        if SHELL_OPERATORS.contains(&arg) {
+            Some(arg.to_owned())
+        } else if arg.contains("$(") || arg.contains('`') {
+            Some("command substitution".to_owned())
+        } else if arg.starts_with('$') && arg[1..].starts_with(|c: char| c == '{' || c == '_' || c.is_ascii_alphabetic()) {
+            Some(format!("variable {}", arg))
+        } else {
+            None
+        }
+    })
+}
+
+/// Tasks that look like shell command lines.
+fn shell_syntax(full_config: &FullConfig) -> Vec<Lint> {
+    let mut tasks = vec![];
+    for service in &full_config.services {
+        for task in service.before.iter().chain(&service.after) {
+            tasks.push((service.name.clone(), &task.task));
+        }
+        for archive in &service.archives {
+            let location = format!("{}: {}", service.name, archive.name);
+            if let ArchiveInput::Docker(DockerInputType::ExecStdout { task, restore_task, .. }) = &archive.input {
+                tasks.push((location.clone(), task));
+                tasks.extend(restore_task.iter().map(|t| (location.clone(), t)));
+            }
+            tasks.extend(archive.post_restore.iter().map(|t| (location.clone(), &t.task)));
+        }
+    }
+    tasks
+        .into_iter()
+        .filter_map(|(location, task)| shell_operator(task).map(|operator| Lint::new(LintKind::ShellSyntax, location, format!(
+            "`{}` has {}, which no shell interprets: wrap it as [sh, -c, \"...\"]",
+            task.get_args().into_iter().collect::<Vec<_>>().join(" "),
+            operator,
+        ))))
+        .collect()
+}
+
+/// Archives mounting data another archive already mounts, which is only backed up once.
+fn duplicate_mounts(full_config: &FullConfig) -> Vec<Lint> {
+    let mut sources: HashMap<String, String> = HashMap::new();
+    let mut lints = vec![];
+    for service in &full_config.services {
+        let project = service.compose_project.as_deref().unwrap_or(&service.name);
+        for archive in &service.archives {
+            let source = match &archive.input {
+                ArchiveInput::Docker(DockerInputType::ComposeNamedVolume { name, .. }) => format!("volume {}_{}", project, name),
+                ArchiveInput::Docker(DockerInputType::Volume { name, .. }) => format!("volume {}", name),
+                ArchiveInput::Docker(DockerInputType::ComposeBoundVolume { service, path, .. }) => {
+                    format!("{} of service {} in project {}", path.display(), service, project)
+                }
+                _ => continue,
+            };
+            let location = format!("{}: {}", service.name, archive.name);
+            match sources.get(&source) {
+                Some(first) => lints.push(Lint::new(LintKind::DuplicateMount, &location, format!(
+                    "{} is already backed up by {}, this archive adds nothing: remove one of them",
+                    source,
+                    first,
+                ))),
+                None => {
+                    sources.insert(source, location);
+                }
+            }
+        }
+    }
+    lints
+}
+
+/// Large volumes backed up without any exclude.
+fn large_unfiltered(full_config: &FullConfig, sizes: &HashMap<String, u64>) -> Vec<Lint> {
+    let mut lints = vec![];
+    for service in &full_config.services {
+        let project = service.compose_project.as_deref().unwrap_or(&service.name);
+        for archive in &service.archives {
+            let volume = match &archive.input {
+                ArchiveInput::Docker(DockerInputType::ComposeNamedVolume { name, filter }) if filter.as_ref().is_none_or(PathExclude::is_empty) => {
+                    format!("{}_{}", project, name)
+                }
+                ArchiveInput::Docker(DockerInputType::Volume { name, filter }) if filter.as_ref().is_none_or(PathExclude::is_empty) => name.clone(),
+                _ => continue,
+            };
+            if let Some(size) = sizes.get(&volume).filter(|s| **s > LARGE_VOLUME) {
+                lints.push(Lint::new(LintKind::LargeUnfiltered, format!("{}: {}", service.name, archive.name), format!(
+                    "volume {} holds {} and excludes nothing, leave its caches and temporary files out with `exclude`",
+                    volume,
+                    HumanBytes(*size),
+                )));
+            }
+        }
+    }
+    lints
+}
+
+#[test]
+fn test_lint() {
+    let text = r#"
+dry_run: true
+restic_hots: box
+services:
+  - name: app
+    archives:
+      - name: db
+        input: !Docker
+          docker_type: ExecStdout
+          service: db
+          task: [pg_dumpall, "|", gzip]
+          ext: sql
+        retires: 3
+      - name: data
+        input: !Docker
+          docker_type: ComposeNamedVolume
+          name: data
+      - name: shell
+        input: !Docker
+          docker_type: ExecStdout
+          service: db
+          task: [sh, -c, "pg_dumpall | gzip"]
+          ext: sql.gz
+  - name: other
+    compose_project: app
+    archives:
+      - name: data
+        input: !Docker
+          docker_type: Volume
+          name: app_data
+"#;
+    let (full_config, _) = migrate::load(text).unwrap();
+    let lints = deprecated(text).unwrap()
+        .into_iter()
+        .chain(unknown_keys(text, &full_config).unwrap())
+        .chain(shell_syntax(&full_config))
+        .chain(duplicate_mounts(&full_config))
+        .chain(large_unfiltered(&full_config, &HashMap::from([("app_data".to_owned(), 20_000_000_000)])))
+        .map(|l| (l.kind, l.location))
+        .collect::<Vec<_>>();
+    assert_eq!(lints, [
+        (LintKind::Deprecated, "version".to_owned()),
+        (LintKind::Deprecated, "dry_run".to_owned()),
+        (LintKind::UnknownKey, "configuration".to_owned()),
+        (LintKind::UnknownKey, "app: db".to_owned()),
+        (LintKind::ShellSyntax, "app: db".to_owned()),
+        (LintKind::DuplicateMount, "other: data".to_owned()),
+        (LintKind::LargeUnfiltered, "app: data".to_owned()),
+        (LintKind::LargeUnfiltered, "other: data".to_owned()),
+    ]);
+}
@@ -0,0 +1,209 @@
+use std::{io::Write, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use log::{debug, error, info, warn};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, SerializableError};
+
+static RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// An external lock shared by every hoarder instance backing up to the same repository.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct LockConfig {
+    /// lock file to create, on a filesystem every instance can reach
+    pub(crate) file: Option<PathBuf>,
+    /// endpoint locked with a POST and unlocked with a DELETE, answering 409 or 423 while locked
+    pub(crate) url: Option<String>,
+    /// how long in seconds to wait for the lock, also passed to restic as `--retry-lock`
+    #[serde(default)]
+    pub(crate) wait: u64,
+    /// age in seconds after which a lock file is taken over, as its run must have died without
+    /// releasing it
+    #[serde(default)]
+    pub(crate) stale_after: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+struct LockOwner {
+    host: String,
+    pid: u32,
+    /// the boot and pid namespace the process runs in, the pid only means something to the
+    /// instances sharing them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+}
+
+/// Identifies the processes whose pids this one can look up, on Linux: the ones of the same boot of
+/// the same machine, in the same pid namespace. Containers have their own.
+fn instance() -> Option<String> {
+    let boot = std::fs::read_to_string("/proc/sys/kernel/random/boot_id").ok()?;
+    let namespace = std::fs::read_link("/proc/self/ns/pid").ok()?;
+    Some(format!("{}/{}", boot.trim(), namespace.display()))
+}
+
+/// The lock held for the duration of a run, released with [`RunLock::release`].
+#[derive(Debug)]
+pub(crate) struct RunLock {
+    file: Option<PathBuf>,
+    url: Option<String>,
+    owner: LockOwner,
+}
+
+impl RunLock {
+    /// Acquires every configured lock, waiting up to `wait` seconds for them.
+    pub(crate) async fn acquire(config: &Config) -> Result<Self, SerializableError> {
+        let mut lock = Self {
+            file: None,
+            url: None,
+            owner: LockOwner { host: config.restic_host().unwrap_or_default(), pid: std::process::id(), instance: instance() },
+        };
+        let Some(lock_config) = config.lock() else {
+            return Ok(lock);
+        };
+        let deadline = Instant::now() + Duration::from_secs(lock_config.wait);
+
+        if let Some(file) = &lock_config.file {
+            while !lock.try_lock_file(file, lock_config.stale_after)? {
+                wait_until(deadline, &file.display().to_string()).await?;
+            }
+            lock.file = Some(file.clone());
+        }
+        if let Some(url) = &lock_config.url {
+            loop {
+                match lock.try_lock_url(url).await {
+                    Ok(true) => break,
+                    Ok(false) => {},
+                    Err(e) => return Err(lock.abandon(e).await),
+                }
+                if let Err(e) = wait_until(deadline, url).await {
+                    return Err(lock.abandon(e).await);
+                }
+            }
+            lock.url = Some(url.clone());
+        }
+        Ok(lock)
+    }
+
+    fn try_lock_file(&self, file: &Path, stale_after: Option<u64>) -> Result<bool, SerializableError> {
+        // create_new is atomic on NFS too, unlike advisory locks
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(file) {
+            Ok(mut f) => {
+                let instance = self.owner.instance.as_deref().unwrap_or("-");
+                writeln!(f, "{} {} {} {}", self.owner.host, self.owner.pid, chrono::Utc::now().to_rfc3339(), instance)?;
+                info!("acquired lock file {}", file.display());
+                Ok(true)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                let holder = std::fs::read_to_string(file).unwrap_or_default();
+                let Some(reason) = self.stale(&holder, stale_after, chrono::Utc::now(), |pid| Path::new("/proc").join(pid.to_string()).exists()) else {
+                    debug!("lock file {} is held by {}", file.display(), holder.trim());
+                    return Ok(false);
+                };
+                // only the lock that was found stale is broken, not one another run just took
+                if std::fs::read_to_string(file).unwrap_or_default() == holder {
+                    warn!("lock file {} is held by {}, which {}, breaking it", file.display(), holder.trim(), reason);
+                    std::fs::remove_file(file)
+                        .map_err(|e| SerializableError::lock(format!("failed to remove stale lock file {}: {}", file.display(), e)))?;
+                }
+                self.try_lock_file(file, None)
+            }
+            Err(e) => Err(SerializableError::lock(format!("failed to create lock file {}: {}", file.display(), e))),
+        }
+    }
+
+    /// Why the run holding a lock file with the contents `holder` is gone, if it is: a process of
+    /// this instance that isn't `running` anymore, or a lock older than `stale_after`. The pids of
+    /// other instances, like another container reporting the same host, can't be looked up.
+    fn stale(&self, holder: &str, stale_after: Option<u64>, now: chrono::DateTime<chrono::Utc>, running: impl Fn(u32) -> bool) -> Option<String> {
+        let mut fields = holder.split_whitespace();
+        let (host, pid, taken) = (fields.next()?, fields.next()?.parse::<u32>().ok()?, fields.next()?);
+        let same_instance = self.owner.instance.is_some() && fields.next() == self.owner.instance.as_deref();
+        if host == self.owner.host && same_instance && !running(pid) {
+            return Some(format!("isn't running anymore as process {}", pid));
+        }
+        let taken = chrono::DateTime::parse_from_rfc3339(taken).ok()?;
+        let age = (now - taken.to_utc()).num_seconds();
+        match stale_after {
+            Some(stale_after) if age > stale_after as i64 => Some(format!("took it {}s ago, over stale_after", age)),
+            _ => None,
+        }
+    }
+
+    async fn try_lock_url(&self, url: &str) -> Result<bool, SerializableError> {
+        let res = Client::new()
+            .post(url)
+            .json(&self.owner)
+            .send()
+            .await
+            .map_err(|e| SerializableError::lock(format!("failed to send lock request: {}", e)))?;
+        match res.status() {
+            status if status.is_success() => {
+                info!("acquired lock {}", url);
+                Ok(true)
+            }
+            StatusCode::CONFLICT | StatusCode::LOCKED => {
+                debug!("lock {} is held", url);
+                Ok(false)
+            }
+            status => Err(SerializableError::lock(format!("lock request failed with status: {}", status))),
+        }
+    }
+
+    /// Releases every lock held, even when releasing one of them fails, returning the first error.
+    pub(crate) async fn release(self) -> Result<(), SerializableError> {
+        let mut released = Ok(());
+        if let Some(url) = &self.url {
+            match Client::new().delete(url).json(&self.owner).send().await {
+                Ok(res) if !res.status().is_success() => warn!("unlock request failed with status: {}", res.status()),
+                Ok(_) => {},
+                Err(e) => released = Err(SerializableError::lock(format!("failed to send unlock request: {}", e))),
+            }
+        }
+        if let Some(file) = &self.file {
+            match std::fs::remove_file(file) {
+                Ok(()) => debug!("released lock file {}", file.display()),
+                Err(e) => {
+                    let e = SerializableError::lock(format!("failed to remove lock file {}: {}", file.display(), e));
+                    released = released.and(Err(e));
+                }
+            }
+        }
+        released
+    }
+
+    /// Releases the locks taken so far when acquiring failed with `e`, which is given back.
+    async fn abandon(self, e: SerializableError) -> SerializableError {
+        if let Err(release) = self.release().await {
+            error!("failed to release the lock: {}", release);
+        }
+        e
+    }
+}
+
+async fn wait_until(deadline: Instant, lock: &str) -> Result<(), SerializableError> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(SerializableError::lock(format!("{} is held by another run", lock)));
+    }
+    info!("{} is held by another run, waiting", lock);
+    tokio::time::sleep(RETRY_INTERVAL.min(deadline - now)).await;
+    Ok(())
+}
+
+#[test]
+fn test_stale() {
+    let lock = RunLock { file: None, url: None, owner: LockOwner { host: "box".to_owned(), pid: 1, instance: Some("boot/pid:[1]".to_owned()) } };
+    let now = chrono::Utc::now();
+    let holder = |host: &str, minutes: i64, instance: &str| {
+        format!("{} 42 {} {}\n", host, (now - chrono::Duration::minutes(minutes)).to_rfc3339(), instance)
+    };
+    assert!(lock.stale(&holder("box", 1, "boot/pid:[1]"), None, now, |_| true).is_none());
+    assert!(lock.stale(&holder("box", 1, "boot/pid:[1]"), None, now, |_| false).is_some());
+    // the processes of other hosts, or of other containers of the same host, can't be checked
+    assert!(lock.stale(&holder("other", 1, "boot/pid:[1]"), None, now, |_| false).is_none());
+    assert!(lock.stale(&holder("box", 1, "boot/pid:[2]"), None, now, |_| false).is_none());
+    assert!(lock.stale(&holder("box", 1, "-"), None, now, |_| false).is_none());
+    assert!(lock.stale(&holder("box", 120, "boot/pid:[2]"), Some(3600), now, |_| true).is_some());
+    assert!(lock.stale("garbage", Some(0), now, |_| false).is_none());
+}
@@ -1,91 +1,695 @@
-use archive::{ArchiveInput, ArchiveOptions};
-use config::{Config, FullConfig};
-use error::SerializableError;
-use indicatif::HumanBytes;
+use archive::{ArchiveOptions, DumpCheck};
+use audit::Audited;
+use backend::{BackendType, BackupBackend, BackupRequest};
+use cli::Cli;
+use config::{Config, DryRun, FullConfig};
+use events::{Event, Events, OutputFormat, Phase};
+use filelist::FileList;
+use error::{ArchiveFailure, ExitCode, SerializableError};
 use log::{debug, error, info, warn};
-use restic::ResticBackup;
-use service::Service;
-use std::{fs::File, io::{BufReader, BufWriter, Read, Write}, path::PathBuf, process::Stdio};
-use serde::Deserialize;
+use lock::RunLock;
+use manifest::Manifest;
+use notify::Notification;
+use plan::Runner;
+use sha2::{Digest, Sha256};
+use input::{InputProvider, StageContext, StagedArchive};
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+use service::{Service, ServiceTask};
+use std::{path::{Path, PathBuf}, process::Stdio, sync::Mutex, time::{Duration, Instant}};
+use state::{ArchiveProgress, ArchiveStatus, RunRecord, State};
+use stream::{CompressionOptions, PartialFile, SpinnerWriter, ThrottledWriter};
+use theme::{ColorMode, Theme};
+use tokio_util::sync::CancellationToken;
+use tokio::{fs::File, signal::unix::{signal, SignalKind}};
 
+mod cli;
 mod config;
 mod service;
 mod archive;
 mod task;
 mod docker;
+mod audit;
 mod either;
+mod backend;
+mod credentials;
 mod restic;
+mod borg;
+mod kopia;
+mod mirror;
+mod rclone;
+mod rsync;
+mod tarball;
 mod error;
-mod hooks;
+mod notify;
+mod cleanup;
+mod inspect;
+mod preflight;
+mod stream;
+mod state;
+mod history;
+mod status;
+mod manifest;
+mod drift;
+mod lock;
+mod metrics;
+mod events;
+mod restore;
+mod picker;
+mod dr_plan;
+mod mount;
+mod input;
+mod plan;
+mod migrate;
+mod plugin;
+mod preset;
+mod unmanaged;
+mod labels;
+mod suggest;
+mod password;
+mod sops;
+mod secret;
+mod image;
+mod import;
+mod verify;
+mod theme;
+mod export;
+mod lint;
+mod estimate;
+mod skip;
+mod order;
+mod filelist;
+#[cfg(test)]
+mod mock;
 
 use task::ShellTask;
-use docker::{DockerBinding, DockerCommand, DockerComposeSubcommand, DockerContainerSubcommand, DockerInputType, DockerSubcommand, DockerVolumeSubcommand};
+use docker::{DockerBinding, DockerCommand, DockerComposeSubcommand, DockerContainerSubcommand, DockerImageSubcommand, DockerInputType, DockerSubcommand, DockerSystemSubcommand, DockerVolumeSubcommand};
 #[allow(unused_imports)]
 use either::Either::{Left, Right};
 
-struct SpinnerWriter<R: Read> {
-    output: BufWriter<Box<dyn Write>>,
-    input: BufReader<R>,
-    bytes_written: usize,
-    bar: indicatif::ProgressBar,
-}
+#[tokio::main]
+async fn main() {
+    theme::init_logger();
+    let cli = Cli::parse_or_exit();
+    if cli.no_color {
+        theme::apply(ColorMode::Never, Theme::default());
+    }
 
-impl<R: Read> SpinnerWriter<R> {
-    fn write_all(&mut self) -> std::io::Result<()> {
-        let mut buffer = [0; 10 << 10];
-        loop {
-            let bytes_read = self.input.read(&mut buffer)?;
-            if bytes_read == 0 {
-                break;
-            }
-            self.output.write_all(&buffer[..bytes_read])?;
-            self.bytes_written += bytes_read;
-            self.bar.set_position(self.bytes_written as u64);
-            self.bar.set_message(format!("{}", HumanBytes(self.bytes_written as u64)));
-            self.output.flush()?;
+    if let Some(cli::Command::MigrateConfig { print }) = cli.command {
+        if let Err(e) = migrate::rewrite(&cli.config, print) {
+            error!("{}", e);
+            ExitCode::from(&e).exit();
         }
-        self.output.flush()?;
-        Ok(())
+        ExitCode::Success.exit();
     }
-}
 
-fn main() {
-    pretty_env_logger::init();
+    if let Some(cli::Command::Import { project, context }) = &cli.command {
+        let mut config = Config::default();
+        config.docker_context = context.clone();
+        if let Err(e) = import::import(&config, project).await {
+            error!("{}", e);
+            ExitCode::from(&e).exit();
+        }
+        ExitCode::Success.exit();
+    }
 
-    let config = match std::fs::read_to_string("config.yaml") {
+    let config_file = match std::fs::read_to_string(&cli.config) {
+        Ok(c) if sops::is_encrypted(&c) => match sops::decrypt(&cli.config) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("{}", e);
+                ExitCode::Config.exit();
+            }
+        },
         Ok(c) => c,
         Err(e) => {
             error!("failed to read config file: {}", e);
-            std::process::exit(1);
+            ExitCode::Config.exit();
+        }
+    };
+    // the references are kept in the configuration backup
+    let resolved = match secret::resolve(&config_file).await {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::Config.exit();
+        }
+    };
+    let full_config = match migrate::load(resolved.as_deref().unwrap_or(&config_file)) {
+        Ok((c, version)) => {
+            if version < migrate::CONFIG_VERSION {
+                warn!(
+                    "the configuration is version {}, migrated to version {} on the fly: run `hoarder migrate-config` to rewrite it",
+                    version,
+                    migrate::CONFIG_VERSION,
+                );
+            }
+            c
+        }
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::Config.exit();
+        }
+    };
+    if let Err(e) = full_config.validate() {
+        error!("{}", e);
+        ExitCode::Config.exit();
+    }
+    if let Some(cli::Command::Lint { offline }) = cli.command {
+        match lint::lint(resolved.as_deref().unwrap_or(&config_file), &full_config, offline).await {
+            Ok(lints) if lints.is_empty() => {
+                info!("nothing suspicious in the configuration");
+                ExitCode::Success.exit();
+            }
+            Ok(lints) => {
+                for lint in &lints {
+                    println!("{}", lint);
+                }
+                ExitCode::Partial.exit();
+            }
+            Err(e) => {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
         }
+    }
+    let FullConfig { mut services, mut config, hooks, .. } = full_config;
+    let color = match cli.no_color || theme::no_color_env() {
+        true => ColorMode::Never,
+        false => config.color(),
     };
-    let FullConfig { services, config, hooks } = serde_yaml::from_str(&config).expect("Failed to parse config file");
+    theme::apply(color, config.theme());
+    let password = if cli.password_from_stdin {
+        Some(restic::read_password(std::io::stdin().lock()))
+    } else if cli.ask_password || cli.remember_password {
+        let (ask, remember) = (cli.ask_password, cli.remember_password);
+        Some(tokio::task::spawn_blocking(move || password::interactive(ask, remember)).await.expect("the password prompt panicked"))
+    } else {
+        None
+    };
+    match password {
+        Some(Ok(password)) => config.set_restic_password(password),
+        Some(Err(e)) => {
+            error!("{}", e);
+            ExitCode::from(&e).exit();
+        }
+        None => (),
+    }
+    if let Err(e) = config.audit_log().and_then(audit::open) {
+        warn!("the executed commands won't be kept in the audit log: {}", e);
+    }
+    let mut notifier = hooks.dispatcher();
 
-    match inner(services, config) {
+    let (resume, output) = match cli.command.unwrap_or(cli::Command::Backup { resume: false, dry_run: None, interactive: false, output: OutputFormat::Text, notify_desktop: false }) {
+        cli::Command::Backup { resume, dry_run, interactive, output, notify_desktop } => {
+            if let Some(dry_run) = dry_run {
+                config.set_dry_run(dry_run);
+            }
+            if interactive && let Err(e) = picker::needs_terminal() {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+            config.set_interactive(interactive);
+            if notify_desktop {
+                match notify::desktop_session() {
+                    true => notifier = notifier.with_desktop(),
+                    false => warn!("no desktop session to notify, the outcome is only logged"),
+                }
+            }
+            (resume, output)
+        }
+        cli::Command::MigrateConfig { .. } | cli::Command::Import { .. } => unreachable!("handled before the configuration is loaded"),
+        cli::Command::Lint { .. } => unreachable!("handled once the configuration is loaded"),
+        cli::Command::Plan { json } => {
+            if let Err(e) = labels::merge(&config, &mut services).await {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+            if let Err(e) = unmanaged::add(&config, &mut services).await {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+            skip::apply(&mut services).await;
+            let plan = Runner::new(&config, services).plan().await;
+            if let Err(e) = plan.and_then(|plan| plan::show(&plan, json)) {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+            ExitCode::Success.exit();
+        }
+        cli::Command::Suggest { all } => {
+            if let Err(e) = labels::merge(&config, &mut services).await {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+            if let Err(e) = unmanaged::add(&config, &mut services).await {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+            match suggest::suggest(&config, &services, all).await {
+                Ok(0) => ExitCode::Success.exit(),
+                Ok(_) => ExitCode::Partial.exit(),
+                Err(e) => {
+                    error!("{}", e);
+                    ExitCode::from(&e).exit();
+                }
+            }
+        }
+        cli::Command::History { service, since, until } => {
+            if let Err(e) = history::show(&config, &history::HistoryFilter { service, since, until }) {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+            ExitCode::Success.exit();
+        }
+        cli::Command::Status { hook, offline } => {
+            let stale = match status::show(&services, &config, offline).await {
+                Ok(stale) => stale,
+                Err(e) => {
+                    error!("{}", e);
+                    ExitCode::from(&e).exit();
+                }
+            };
+            if stale.is_empty() {
+                ExitCode::Success.exit();
+            }
+            if hook {
+                info!("sending the partial notifications with {} stale services", stale.len());
+                if let Err(e) = notifier.notify(Notification::Partial { failed: &stale, warnings: &[] }).await {
+                    error!("{}", e);
+                }
+            }
+            ExitCode::Partial.exit();
+        }
+        cli::Command::Restore { service, archives, snapshot, target, owner, dry_run, interactive, all, up } => {
+            let mut options = restore::RestoreOptions {
+                service: service.unwrap_or_default(),
+                archives,
+                snapshot: snapshot.selector(),
+                owner,
+                dry_run,
+                target: target.target(),
+                progress: interactive,
+                up,
+            };
+            if interactive {
+                match picker::pick(&services, &config, &mut options).await {
+                    Ok(true) => {},
+                    Ok(false) => {
+                        info!("restore cancelled");
+                        ExitCode::Success.exit();
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        ExitCode::from(&e).exit();
+                    }
+                }
+            }
+            let result = match all {
+                true => restore::restore_all(&services, &config, options).await,
+                false => restore::restore(&services, &config, options).await,
+            };
+            match result {
+                Ok(failed) if failed.is_empty() => ExitCode::Success.exit(),
+                Ok(failed) => {
+                    warn!("{} archives failed to restore:", failed.len());
+                    for failure in &failed {
+                        warn!("- {}", failure);
+                    }
+                    ExitCode::Partial.exit();
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    ExitCode::from(&e).exit();
+                }
+            }
+        }
+        cli::Command::Verify { service, archive, files, hook } => {
+            let files = files
+                .or(config.verify().map(|v| v.files))
+                .unwrap_or_else(verify::VerifyConfig::default_files);
+            let record = match verify::targets(&config, &services, service.as_deref(), archive.as_deref()) {
+                Ok(targets) => verify::verify(&config, targets, files).await,
+                Err(e) => Err(e),
+            };
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    error!("{}", e);
+                    ExitCode::from(&e).exit();
+                }
+            };
+            if let Err(e) = verify::save(&config, &record) {
+                warn!("failed to keep the restore test: {}", e);
+            }
+            if hook && let Err(e) = notifier.notify(Notification::Verify { record: &record }).await {
+                error!("{}", e);
+            }
+            match record.ok() {
+                true => ExitCode::Success.exit(),
+                false => ExitCode::Partial.exit(),
+            }
+        }
+        cli::Command::Export { archive: (service, archive), to } => {
+            if let Err(e) = labels::merge(&config, &mut services).await {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+            if let Err(e) = unmanaged::add(&config, &mut services).await {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+            if let Err(e) = export::export(&config, &services, &service, &archive, to).await {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+            ExitCode::Success.exit();
+        }
+        cli::Command::DrPlan { format } => {
+            if let Err(e) = dr_plan::show(&services, &config, format).await {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+            ExitCode::Success.exit();
+        }
+        cli::Command::Restic { native, args } => {
+            let mounts = match native {
+                true => Ok(vec![]),
+                false => Runner::new(&config, services).plan().await.map(|plan| plan.mounts),
+            };
+            let status = match mounts {
+                Ok(mounts) => restic::passthrough(&config, args, mounts, native).await,
+                Err(e) => Err(e),
+            };
+            match status {
+                Ok(status) => std::process::exit(status.code().unwrap_or(ExitCode::Restic as i32)),
+                Err(e) => {
+                    error!("{}", e);
+                    ExitCode::from(&e).exit();
+                }
+            }
+        }
+        cli::Command::Mount { mountpoint, native } => {
+            if let Err(e) = mount::mount(&config, &mountpoint, native).await {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+            ExitCode::Success.exit();
+        }
+    };
+
+    if !config.dry_run()
+        && let Err(e) = manifest::stage_config(&config, &config_file)
+    {
+        warn!("failed to stage the configuration backup: {}", e);
+    }
+
+    if let Err(e) = labels::merge(&config, &mut services).await {
+        error!("failed to discover the labelled archives: {}", e);
+        if let Err(e) = notifier.notify(Notification::Failure { error: &e }).await {
+            error!("{}", e);
+        }
+        ExitCode::from(&e).exit();
+    }
+    if let Err(e) = unmanaged::add(&config, &mut services).await {
+        error!("failed to list the unmanaged volumes: {}", e);
+        if let Err(e) = notifier.notify(Notification::Failure { error: &e }).await {
+            error!("{}", e);
+        }
+        ExitCode::from(&e).exit();
+    }
+    skip::apply(&mut services).await;
+    if config.dry_run_level() == DryRun::Plan {
+        info!("dry run: only planning the backup");
+        let plan = Runner::new(&config, services).plan().await;
+        if let Err(e) = plan.and_then(|plan| plan::show(&plan, false)) {
+            error!("{}", e);
+            ExitCode::from(&e).exit();
+        }
+        ExitCode::Success.exit();
+    }
+    if config.interactive() {
+        let runner = Runner::new(&config, services);
+        let confirmed = runner.plan().await
+            .and_then(|plan| plan::show(&plan, false))
+            .and_then(|_| picker::confirm("run this backup"));
+        match confirmed {
+            Ok(true) => services = runner.into_services(),
+            Ok(false) => {
+                info!("backup cancelled");
+                ExitCode::Success.exit();
+            }
+            Err(e) => {
+                error!("{}", e);
+                ExitCode::from(&e).exit();
+            }
+        }
+    }
+    let degraded = match fall_back(&mut services, &config).await {
+        Ok(degraded) => degraded,
+        Err(e) => {
+            error!("the {} repository can't be used: {}", config.backend().name(), e);
+            if let Err(e) = notifier.notify(Notification::Failure { error: &e }).await {
+                error!("{}", e);
+            }
+            ExitCode::from(&e).exit();
+        }
+    };
+    let verify_targets = match config.verify() {
+        Some(_) => verify::targets(&config, &services, None, None).unwrap_or_default(),
+        None => vec![],
+    };
+    let backends = backend::used(&config, &services)
+        .into_iter()
+        .map(|b| (b, backend::open_type(b)))
+        .collect::<Vec<_>>();
+    let mut run = RunRecord::start(hex::encode(Sha256::digest(&config_file)));
+    run.degraded = degraded.clone();
+    let mut events = Events::default();
+    events.subscribe(events::log);
+    if output == OutputFormat::Ndjson {
+        events.subscribe(events::ndjson);
+    }
+    if !config.dry_run()
+        && let Some(dir) = config.metrics_dir()
+    {
+        events.subscribe(events::metrics(dir));
+    }
+    events.emit(Event::RunStarted { run: &run });
+    let run = Mutex::new(run);
+    // the lock is held outside of the run, so that it is released even when the run is interrupted
+    let result = match RunLock::acquire(&config).await {
+        Err(e) => Err(e),
+        Ok(lock) => {
+            let cancel = CancellationToken::new();
+            let execution = Runner::new(&config, services)
+                .with_cancel(cancel.clone())
+                .execute(&backends, resume, &run, &events);
+            tokio::pin!(execution);
+            let result = tokio::select! {
+                result = &mut execution => result,
+                signal = shutdown_signal() => {
+                    warn!("received {}, stopping the run (send it again to stop right away)", signal);
+                    cancel.cancel();
+                    tokio::select! {
+                        result = &mut execution => match result {
+                            Err(SerializableError::Cancelled) => Err(SerializableError::Interrupted { signal: signal.to_owned() }),
+                            result => result,
+                        },
+                        signal = shutdown_signal() => {
+                            // dropping the run kills the in-flight docker execs and removes partially written dumps
+                            warn!("received {} again, cleaning up", signal);
+                            for (kind, backend) in &backends {
+                                match backend.teardown(&config).await {
+                                    Ok(true) => info!("stopped the {} backup environment", kind.name()),
+                                    Ok(false) => debug!("no {} backup environment to stop", kind.name()),
+                                    Err(e) => error!("failed to stop the {} backup environment: {}", kind.name(), e),
+                                }
+                            }
+                            Err(SerializableError::Interrupted { signal: signal.to_owned() })
+                        }
+                    }
+                }
+            };
+            if let Err(e) = lock.release().await {
+                error!("failed to release the lock: {}", e);
+            }
+            result
+        }
+    };
+
+    let mut run = run.into_inner().unwrap();
+    // taken before the failures outside of the plan are added, the notifications carry them already
+    let warnings = run.warnings.clone();
+    run.finish(&result);
+    run.commands = audit::records();
+    events.emit(Event::RunFinished { run: &run });
+    if !config.dry_run()
+        && let Err(e) = record_run(&config, run)
+    {
+        error!("failed to record the run in the history: {}", e);
+    }
+    if result.is_ok() && !config.dry_run() {
+        verify::scheduled(&config, verify_targets, &notifier).await;
+    }
+
+    match result {
         Err(e) => {
             error!("an error occurred: {}", e);
-            // execute fail hook
-            info!("running fail hook");
-            hooks.failure(e);
-            std::process::exit(1);
+            let code = ExitCode::from(&e);
+            info!("sending the failure notifications");
+            if let Err(e) = notifier.notify(Notification::Failure { error: &e }).await {
+                error!("{}", e);
+            }
+            code.exit();
         }
         Ok(failed) => {
             info!("backup completed successfully");
-            // execute success hook
-            if failed.is_empty() {
-                info!("running success hook");
-                hooks.success();
+            if !warnings.is_empty() {
+                warn!("{} warning(s):", warnings.len());
+                for warning in &warnings {
+                    warn!("- {}", warning);
+                }
+            }
+            let (notification, code) = if let Some(reason) = &degraded {
+                info!("sending the degraded notifications: {}", reason);
+                (Notification::Degraded { reason, failed: &failed, warnings: &warnings }, ExitCode::Partial)
+            } else if failed.is_empty() {
+                info!("sending the success notifications");
+                (Notification::Success { warnings: &warnings }, ExitCode::Success)
             } else {
-                info!("running partial hook with {} failed backups", failed.len());
-                hooks.partial(failed);
+                info!("sending the partial notifications with {} failed backups", failed.len());
+                for failure in &failed {
+                    warn!("- {}", failure);
+                }
+                (Notification::Partial { failed: &failed, warnings: &warnings }, ExitCode::Partial)
+            };
+            let hook = notifier.notify(notification).await;
+            if let Err(e) = hook {
+                error!("{}", e);
             }
+            code.exit();
         }
     }
 }
 
-fn inner(services: Vec<Service>, config: Config) -> Result<Vec<String>, SerializableError> {
+/// Moves the services from the configured backend to the fallback one when its repository can't
+/// be reached, returning why. Any other error of the repository, like a wrong password, is
+/// returned, so that it fails the run rather than going unnoticed.
+async fn fall_back(services: &mut [Service], config: &Config) -> Result<Option<String>, SerializableError> {
+    let primary = config.backend();
+    let Some(fallback) = config.fallback().filter(|f| *f != primary) else {
+        return Ok(None);
+    };
+    if !services.iter().any(|s| s.backends(config).contains(&primary)) {
+        return Ok(None);
+    }
+    let Err(e) = backend::open_type(primary).check(config).await else {
+        return Ok(None);
+    };
+    if !e.unreachable() {
+        return Err(e);
+    }
+    warn!("the {} repository can't be reached, backing up to {} instead: {}", primary.name(), fallback.name(), e);
+    for service in services {
+        let mut backends = vec![];
+        for backend in service.backends(config) {
+            let backend = if backend == primary { fallback } else { backend };
+            if !backends.contains(&backend) {
+                backends.push(backend);
+            }
+        }
+        service.backends = Some(backends);
+    }
+    Ok(Some(format!("the {} repository can't be reached, fell back to {}: {}", primary.name(), fallback.name(), e)))
+}
+
+/// Appends a finished run to the history kept in the state directory.
+fn record_run(config: &Config, run: RunRecord) -> Result<(), SerializableError> {
+    let mut state = State::load(config.state_dir()?)?;
+    state.push_run(run, config.history_size());
+    state.save()
+}
 
-    info!("Backup summary:");
+/// Resolves once SIGINT or SIGTERM is received, returning the signal's name.
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+/// an ExecStdout archive waiting to be dumped into the intermediate directory
+#[derive(Debug, Clone)]
+struct PendingDump {
+    archive_name: String,
+    service: String,
+    task: ShellTask,
+    ext: String,
+    user: Option<String>,
+    timeout: Option<u64>,
+    compression: Option<CompressionOptions>,
+    skip_unchanged: bool,
+    io_limit: Option<u64>,
+    retries: u32,
+    retry_delay: u64,
+    dump_check: DumpCheck,
+    run_if_stopped: bool,
+}
+
+/// a service whose mounts have been resolved, with the dumps still to be staged
+#[derive(Debug)]
+struct ServicePlan {
+    name: String,
+    compose_project: String,
+    dumps: Vec<PendingDump>,
+    /// volume archives that were mounted into the restic container
+    volumes: Vec<String>,
+    /// the mounted archives, listed along with the dumps when the backup needs a list
+    files: FileList,
+    backup: BackupRequest,
+    /// backends the service is backed up to
+    backends: Vec<BackendType>,
+    before: Vec<ServiceTask>,
+    after: Vec<ServiceTask>,
+}
+
+/// a service whose dumps are staged, waiting for its restic backup
+#[derive(Debug)]
+struct StagedService {
+    name: String,
+    compose_project: String,
+    /// tasks to run once the service is backed up
+    after: Vec<ServiceTask>,
+    backup: BackupRequest,
+    backends: Vec<BackendType>,
+    staged: Vec<PathBuf>,
+    /// archives that are part of the backup
+    archives: Vec<String>,
+    /// whether every archive of the service made it this far
+    complete: bool,
+}
+
+async fn inner(
+    services: Vec<Service>,
+    config: &Config,
+    backends: &[(BackendType, Box<dyn BackupBackend>)],
+    resume: bool,
+    run: &Mutex<RunRecord>,
+    events: &Events,
+    cancel: &CancellationToken,
+) -> Result<Vec<ArchiveFailure>, SerializableError> {
+    // a staging dry run leaves the backends alone, from their containers to the retention
+    let staging_only = config.dry_run_level() == DryRun::Stage;
+    let backends = if staging_only { &[] } else { backends };
+
+    let mut state = State::load(config.state_dir()?)?;
+    if !resume && state.has_progress() {
+        warn!("the previous run was interrupted, starting over: use --resume to pick up where it stopped");
+        state.clear_progress();
+    }
+    let estimate = estimate::estimate(config, &services, &state).await;
+    info!("Backup summary: {}", estimate);
     for service in &services {
         info!("- {}:", service.name);
         for archive in &service.archives {
@@ -94,347 +698,846 @@ fn inner(services: Vec<Service>, config: Config) -> Result<Vec<String>, Serializ
     }
     info!("");
 
-    let mut backups: Vec<ResticBackup> = vec![];
-    let mut mounts: Vec<DockerBinding> = vec![
-        DockerBinding::new_ro(
-            config.restic_root(),
-            PathBuf::from(config.intermediate_mount_override().unwrap_or(config.intermediate_path()?)),
-        ),
-        DockerBinding::new_ro(
-            config.restic_password_file()?,
-            PathBuf::from("/restic_password"),
-        )
-    ];
+    events.emit(Event::PhaseStarted { phase: Phase::Preflight });
+    preflight::validate(&services, config).await?;
+
+    let mut plans: Vec<ServicePlan> = vec![];
+    let mut mounts: Vec<DockerBinding> = vec![root_mount(config)?];
 
-    let mut failed: Vec<String> = vec![];
+    let mut failed: Vec<ArchiveFailure> = vec![];
     let intermediate_path = config.intermediate_path()?;
     let restic_host = config.restic_host()?;
+    let state = Mutex::new(state);
+    let run_started = Instant::now();
+    let previous_run = state.lock().unwrap().last_run_secs.map(Duration::from_secs_f64);
+    if let Some(previous_run) = previous_run {
+        info!("the previous run took {}", HumanDuration(previous_run));
+    }
 
+    // resolve every volume first: the restic container needs all of its mounts when it is started
     for service in services {
+        if cancel.is_cancelled() {
+            return Err(SerializableError::Cancelled);
+        }
         debug!("{}: service: {:?}", service.name, service);
-        let Service { archives, compose_project, name: service_name } = service;
+        if resume && service.archives.iter().all(|a| state.lock().unwrap().progress(&service.name, &a.name) == Some(&ArchiveProgress::Uploaded)) {
+            info!("{}: already backed up by the interrupted run, skipping", service.name);
+            continue;
+        }
+        let service_backends = service.backends(config);
+        let Service { archives, compose_project, name: service_name, before, after, one_file_system, .. } = service;
         let compose_project = compose_project.unwrap_or(service_name.clone());
         let mut excludes = vec![];
+        let mut files = FileList::default();
+        let mut dumps = vec![];
+        let mut volumes = vec![];
         for archive in archives {
             debug!("{}: {}: archive: {:?}", service_name, compose_project, archive);
-            let ArchiveOptions { input, name: archive_name } = archive;
-            match input {
-                ArchiveInput::Docker(docker_input) => match docker_input {
-                    DockerInputType::ExecStdout { service, task, ext } => {
-                        info!("{}: {}: using mode: ExecStdout", service_name, archive_name);
-
-                        let dcommand = config.docker_command_with_context(
-                            DockerSubcommand::Compose {
-                                project: Left(compose_project.clone()),
-                                subcommand: DockerComposeSubcommand::Exec {
-                                    service: service.clone(),
-                                    task: task.clone(),
-                                },
-                                options: vec![],
-                                options_inner: vec!["-i".to_owned()],
-                            },
-                        );
-                        let mut command = dcommand.into_command();
-                        let output_path = PathBuf::from(&intermediate_path).join(&service_name);
-                        std::fs::create_dir_all(&output_path)?;
-                        let output_name = format!("{}.{}", archive_name, ext);
-                        let output_file = output_path.join(output_name);
-                        debug!("{}: {}: ExecStdout: output file: {:?}", service_name, archive_name, output_file);
-
-                        command
-                            .stderr(std::process::Stdio::piped())
-                            .stdout(Stdio::piped());
-                        debug!("{}: {}: ExecStdout: executing command: {:?}", service_name, archive_name, command.get_args().collect::<Vec<_>>());
-                        let mut handle = match command.spawn() {
-                            Ok(h) => h,
-                            Err(e) => {
-                                error!("{}: {}: ExecStdout: failed to execute command: {}", service_name, archive_name, e);
-                                failed.push(format!("{}:{}: {}", service_name, archive_name, e));
-                                continue;
-                            }
-                        };
-                        let stdout = match handle.stdout.take() {
-                            Some(s) => s,
-                            None => {
-                                error!("{}: {}: ExecStdout: no stdout found in command output", service_name, archive_name);
-                                failed.push(format!("{}:{}: no stdout found in command output", service_name, archive_name));
-                                continue;
-                            }
-                        };
-                        let mut proxy = if config.dry_run() {
-                            warn!("{}: {}: dry run mode, not writing to file {}", service_name, archive_name, output_file.display());
-                            SpinnerWriter {
-                                output: BufWriter::new(Box::new(std::io::sink())),
-                                input: BufReader::new(stdout),
-                                bytes_written: 0,
-                                bar: indicatif::ProgressBar::new_spinner(),
-                            }
-                        } else {
-                            let output = File::create(&output_file)?;
-                            SpinnerWriter {
-                                output: BufWriter::new(Box::new(output)),
-                                input: BufReader::new(stdout),
-                                bytes_written: 0,
-                                bar: indicatif::ProgressBar::new_spinner(),
-                            }
-                        };
-                        if let Err(e) = proxy.write_all() {
-                            error!("{}: {}: ExecStdout: failed to write output to file: {}", service_name, archive_name, e);
-                            failed.push(format!("{}:{}: {}", service_name, archive_name, e));
-                            continue;
-                        }
+            run.lock().unwrap().archive_mut(&service_name, &archive.name);
+            let ArchiveOptions { input, name: archive_name, timeout, compression, io_limit, retries, retry_delay, skip_unchanged, dump_check, run_if_stopped, required, .. } = archive;
+            let mut ctx = StageContext {
+                config,
+                service: &service_name,
+                compose_project: &compose_project,
+                archive: &archive_name,
+                mounts: &mut mounts,
+                source: None,
+                planning: false,
+                events: Some(events),
+            };
+            let staged = input.stage(&mut ctx).await;
+            if let Some(source) = ctx.source {
+                run.lock().unwrap().archive_mut(&service_name, &archive_name).source = Some(source);
+            }
+            match staged {
+                Ok(StagedArchive::Mounted { exclude }) => {
+                    files.archive(PathBuf::from(config.restic_root()).join(&service_name).join(&archive_name), exclude.as_ref());
+                    excludes.extend(exclude);
+                    events.emit(Event::ArchiveStaged { service: &service_name, archive: &archive_name });
+                    volumes.push(archive_name);
+                }
+                Ok(StagedArchive::Dump { service, task, ext, user }) => {
+                    dumps.push(PendingDump { archive_name, service, task, ext, user, timeout, compression, skip_unchanged, io_limit, retries, retry_delay, dump_check, run_if_stopped });
+                }
+                Ok(StagedArchive::Missing { reason }) if required => {
+                    error!("{}: {}: {}", service_name, archive_name, reason);
+                    events.fail(&mut failed, ArchiveFailure::new(&service_name, &archive_name, SerializableError::missing(reason)));
+                }
+                Ok(StagedArchive::Missing { reason }) => warn!("{}: {}: {}, skipping it as it isn't required", service_name, archive_name, reason),
+                Err(e) => events.fail(&mut failed, ArchiveFailure::new(&service_name, &archive_name, e)),
+            }
+        }
 
-                        let status = match handle.wait() {
-                            Ok(s) => s,
-                            Err(e) => {
-                                error!("{}: {}: ExecStdout: failed to wait for command: {}", service_name, archive_name, e);
-                                failed.push(format!("{}:{}: {}", service_name, archive_name, e));
-                                continue;
-                            }
-                        };
-                        if !status.success() {
-                            error!("{}: {}: docker exec stdout failure: {}", service_name, archive_name, status);
-                            if let Some(mut stderr) = handle.stderr {
-                                let mut buf = String::new();
-                                if let Err(e) = stderr.read_to_string(&mut buf) {
-                                    error!("{}: {}: ExecStdout: failed to read stderr: {}", service_name, archive_name, e);
-                                    failed.push(format!("{}:{}: {}", service_name, archive_name, e));
-                                    continue;
-                                }
-                                if !buf.is_empty() && buf != "\n" {
-                                    error!("stderr output:");
-                                    for line in buf.lines() {
-                                        error!("=> {}", line);
-                                    }
-                                    failed.push(format!("{}:{}: {}", service_name, archive_name, buf));
-                                    continue;
-                                }
-                            }
-                            error!("no stderr output");
-                        }
+        let backup = BackupRequest::with_excludes(
+            PathBuf::from(config.restic_root()).join(&service_name),
+            excludes,
+        ).with_files_from(files.needed(one_file_system).then(|| filelist::list_path(config, &service_name)), one_file_system);
+        {
+            let mut run = run.lock().unwrap();
+            let record = run.service_mut(&service_name);
+            record.excludes = Some(backup.excludes.clone());
+            record.iexcludes = Some(backup.iexcludes.clone()).filter(|e| !e.is_empty());
+        }
+        plans.push(ServicePlan {
+            backup,
+            backends: service_backends,
+            name: service_name,
+            compose_project,
+            dumps,
+            volumes,
+            files,
+            before,
+            after,
+        });
+    }
+
+    // a resumed run skips the services that were already backed up, they aren't drifting. Drift is
+    // only a warning: the services are still backed up as configured now
+    if !resume
+        && let Some(previous) = state.lock().unwrap().last_completed_run()
+    {
+        let drift = drift::detect(previous, &run.lock().unwrap());
+        if !drift.is_empty() {
+            warn!("the backup plan changed since the previous run:");
+            for change in &drift {
+                warn!("- {}", change);
+            }
+        }
+        run.lock().unwrap().warnings.extend(drift.iter().map(ToString::to_string));
+    }
+
+    mounts.push(intermediate_mount(config)?);
+    debug!("mountlist: {:#?}", mounts);
+    events.emit(Event::PhaseStarted { phase: Phase::Prepare });
+
+    // every backend only gets the mounts of the services routed to it
+    let service_names = plans.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+    info!("repository routing:");
+    for (kind, backend) in backends {
+        let routed = plans.iter()
+            .filter(|p| p.backends.contains(kind))
+            .map(|p| p.name.as_str())
+            .collect::<Vec<_>>();
+        info!("- {}: {}", kind.name(), if routed.is_empty() { "(no services)".to_owned() } else { routed.join(", ") });
+        backend.prepare(config, routed_mounts(config, &mounts, &service_names, &routed)).await?;
+        if let Err(e) = backend.probe(config).await {
+            error!("{}: the repository can't be reached with the configured credentials, giving up before dumping anything: {}", kind.name(), e);
+            for (kind, backend) in backends {
+                if let Err(e) = backend.teardown(config).await {
+                    error!("failed to stop the {} backup environment: {}", kind.name(), e);
+                }
+            }
+            return Err(e);
+        }
+        if !config.skip_readability_scan() {
+            let mounted = plans.iter()
+                .filter(|p| p.backends.contains(kind))
+                .flat_map(|p| p.volumes.iter().map(|v| (p.name.as_str(), v.as_str())))
+                .collect::<Vec<_>>();
+            let roots = mounted.iter()
+                .map(|(service, archive)| PathBuf::from(config.restic_root()).join(service).join(archive))
+                .collect::<Vec<_>>();
+            match backend.unreadable(config, &roots).await {
+                Ok(paths) => for ((service, archive), root) in mounted.iter().zip(&roots) {
+                    let paths = paths.iter().filter(|p| Path::new(p).starts_with(root)).cloned().collect::<Vec<_>>();
+                    if paths.is_empty() {
+                        continue;
                     }
-                    DockerInputType::ComposeNamedVolume { name, filter } => {
-                        info!("{}: {}: using mode: ComposeNamedVolume", service_name, archive_name);
-                        let global_volume_name = format!("{compose_project}_{name}");
-                        debug!("{}: {}: ComposeNamedVolume: using canonical volume name: {}", service_name, archive_name, global_volume_name);
-                        let output = PathBuf::from(config.restic_root()).join(&service_name).join(&archive_name);
-                        // ensure global volume exists
-                        let mut command = config
-                            .docker_command_with_context(DockerSubcommand::volume(DockerVolumeSubcommand::inspect(&global_volume_name)))
-                            .into_command();
-                        command
-                            .stderr(Stdio::null())
-                            .stdout(Stdio::null());
-                        debug!("{}: {}: ComposeNamedVolume: inspecting volume: docker {:?}", service_name, archive_name, command.get_args().collect::<Vec<_>>());
-                        let status = match command.status() {
-                            Ok(s) => s,
-                            Err(e) => {
-                                error!("{}: {}: ComposeNamedVolume: failed to inspect volume: {}", service_name, archive_name, e);
-                                failed.push(format!("{}:{}: {}", service_name, archive_name, e));
-                                continue;
-                            }
-                        };
-                        if !status.success() {
-                            error!("{}: {}: ComposeNamedVolume: volume {} does not exist", service_name, archive_name, global_volume_name);
-                        } else {
-                            mounts.push(DockerBinding::new_ro(global_volume_name, output));
-                            if let Some(filter) = filter {
-                                excludes.push(filter.join(&archive_name));
-                            }
-                        }
+                    warn!("{}: {}: {} can't read:", service, archive, kind.name());
+                    for path in &paths {
+                        warn!("- {}", path);
                     }
-                    DockerInputType::ComposeBoundVolume { service, path, filter } => {
-                        info!("{}: {}: using mode: ComposeBoundVolume", service_name, archive_name);
-                        let output = PathBuf::from(config.restic_root()).join(&service_name).join(&archive_name);
-                        // find the bound volume inside the service
-                        let mut command = config.docker_command_with_context(DockerSubcommand::compose(
-                            Left(compose_project.clone()),
-                            DockerComposeSubcommand::Ps(vec![service]),
-                            Vec::<String>::new(),
-                            vec!["-a", "--format", "{{.ID}}", "--no-trunc"],
-                        )).into_command();
-                        command
-                            .stderr(Stdio::null())
-                            .stdout(Stdio::piped());
-                        debug!("{}: {}: ComposeBoundVolume: getting container ID: docker {:?}", service_name, archive_name, command.get_args().collect::<Vec<_>>());
-                        match command.output() {
-                            Ok(out) => {
-                                if !out.status.success() {
-                                    error!("{}: {}: ComposeBoundVolume: failed to get container ID", service_name, archive_name);
-                                } else {
-                                    let container_id = String::from_utf8_lossy(&out.stdout).trim().to_string();
-                                    if container_id.is_empty() {
-                                        error!("{}: {}: ComposeBoundVolume: container ID is empty", service_name, archive_name);
-                                    } else {
-                                        #[derive(Deserialize, Debug)]
-                                        struct DockerContainerInspectOutput {
-                                            #[serde(rename = "Mounts")]
-                                            mounts: Vec<DockerContainerMount>,
-                                        }
-
-                                        #[derive(Deserialize, Debug)]
-                                        struct DockerContainerMount {
-                                            #[serde(rename = "Source")]
-                                            source: String,
-                                            #[serde(rename = "Destination")]
-                                            destination: String,
-                                        }
-
-                                        let mut command = config.docker_command_with_context(DockerSubcommand::container(
-                                            DockerContainerSubcommand::Inspect { container: container_id },
-                                            vec!["--format", "json"],
-                                        )).into_command();
-                                        command
-                                            .stdout(Stdio::piped());
-                                        debug!("{}: {}: ComposeBoundVolume: inspecting container: docker {:?}", service_name, archive_name, command.get_args().collect::<Vec<_>>());
-                                        let inspect_raw = match command.output() {
-                                            Ok(i) => i,
-                                            Err(e) => {
-                                                error!("{}: {}: ComposeBoundVolume: failed to inspect container: {}", service_name, archive_name, e);
-                                                failed.push(format!("{}:{}: {}", service_name, archive_name, e));
-                                                continue;
-                                            }
-                                        };
-                                        let inspect = match serde_json::from_slice::<Vec<DockerContainerInspectOutput>>(&inspect_raw.stdout)?.into_iter().next() {
-                                            Some(i) => i,
-                                            None => {
-                                                error!("{}: {}: ComposeBoundVolume: no mounts found in container inspect output", service_name, archive_name);
-                                                failed.push(format!("{}:{}: no mounts found in container inspect output", service_name, archive_name));
-                                                continue;
-                                            }
-                                        };
-                                        match inspect.mounts.into_iter().find(|m| m.destination == path.to_string_lossy()) {
-                                            Some(mount) => {
-                                                let host_path = mount.source;
-                                                mounts.push(DockerBinding::new_ro(host_path, output));
-                                                if let Some(filter) = filter {
-                                                    excludes.push(filter.join(&archive_name));
-                                                }
-                                            }
-                                            None => error!("{}: {}: ComposeBoundVolume: specified mount path is not a bound volume", service_name, archive_name),
-                                        }
-                                    }
+                    events.fail(&mut failed, ArchiveFailure::new(service, archive, SerializableError::Unreadable { paths }));
+                }
+                Err(e) => warn!("{}: failed to scan the mounted volumes for unreadable paths: {}", kind.name(), e),
+            }
+        }
+    }
+
+    // stage services one at a time, handing each one to the uploader as soon as its dumps are done,
+    // so that restic uploads a service while the next one is still dumping
+    let (staged_tx, mut staged_rx) = tokio::sync::mpsc::unbounded_channel::<StagedService>();
+    let intermediate_path = &intermediate_path;
+    let state = &state;
+    let progress = MultiProgress::new();
+    let progress = &progress;
+    let service_count = plans.len();
+    events.emit(Event::PhaseStarted { phase: Phase::Backup });
+    let stager = async move {
+        // set once the intermediate filesystem went under its low water mark, the dumps left aren't
+        // attempted while the ones already staged are still backed up
+        let mut low_space = false;
+        'services: for plan in plans {
+            let ServicePlan { name: service_name, compose_project, dumps, volumes, mut files, backup, backends, before, after } = plan;
+            if let Err(e) = service_tasks(config, &compose_project, &service_name, "before", &before).await {
+                error!("{}: {}", service_name, e);
+                events.fail(&mut failed, ArchiveFailure::new(&service_name, "before", e));
+                // undo what the tasks that went through did, the service isn't backed up
+                if let Err(e) = service_tasks(config, &compose_project, &service_name, "after", &after).await {
+                    error!("{}: {}", service_name, e);
+                    events.fail(&mut failed, ArchiveFailure::new(&service_name, "after", e));
+                }
+                continue;
+            }
+            let mut staged = vec![];
+            let mut archives = volumes;
+            for dump in dumps {
+                if cancel.is_cancelled() {
+                    if let Err(e) = service_tasks(config, &compose_project, &service_name, "after", &after).await {
+                        error!("{}: {}", service_name, e);
+                    }
+                    break 'services;
+                }
+                let archive_name = dump.archive_name.clone();
+                if low_space {
+                    warn!("{}: {}: skipped, the intermediate filesystem is under its low water mark", service_name, archive_name);
+                    events.fail(&mut failed, ArchiveFailure::new(&service_name, &archive_name, SerializableError::dump(
+                        "skipped, the intermediate filesystem went under its low water mark during the run",
+                    )));
+                    continue;
+                }
+                if resume {
+                    let done = match state.lock().unwrap().progress(&service_name, &archive_name) {
+                        Some(ArchiveProgress::Dumped { file }) => Some(file.clone()),
+                        _ => None,
+                    };
+                    if let Some(file) = done.filter(|f| f.exists()) {
+                        info!("{}: {}: already dumped by the interrupted run, reusing {}", service_name, archive_name, file.display());
+                        run.lock().unwrap().archive_mut(&service_name, &archive_name).bytes = std::fs::metadata(&file).ok().map(|m| m.len());
+                        events.emit(Event::ArchiveStaged { service: &service_name, archive: &archive_name });
+                        staged.push(file);
+                        archives.push(archive_name);
+                        continue;
+                    }
+                }
+                info!("{}: {}: using mode: ExecStdout", service_name, archive_name);
+                let started = Instant::now();
+                let mut attempt = 0;
+                loop {
+                    let bar = progress.add(ProgressBar::new_spinner());
+                    let dumped = dump_exec_stdout(config, state, bar.clone(), cancel, &compose_project, &service_name, intermediate_path, dump.clone());
+                    let dumped = events::tracked(dumped, &bar, |bar| events.emit(Event::DumpProgress {
+                        service: &service_name,
+                        archive: &archive_name,
+                        bytes: bar.position(),
+                    }));
+                    match dumped.await {
+                        Ok(file) => {
+                            let mut record = run.lock().unwrap();
+                            let record = record.archive_mut(&service_name, &archive_name);
+                            record.dump_secs = Some(started.elapsed().as_secs_f64());
+                            record.bytes = file.as_ref().and_then(|f| std::fs::metadata(f).ok()).map(|m| m.len());
+                            if let Some(file) = file.as_ref().filter(|_| !config.dry_run()) {
+                                let mut state = state.lock().unwrap();
+                                state.set_progress(&service_name, &archive_name, ArchiveProgress::Dumped { file: file.clone() });
+                                if let Err(e) = state.save() {
+                                    warn!("{}: {}: failed to record progress: {}", service_name, archive_name, e);
                                 }
                             }
-                            Err(err) => {
-                                error!("{}: {}: ComposeBoundVolume: failed to get container ID: {}", service_name, archive_name, err);
+                            events.emit(Event::ArchiveStaged { service: &service_name, archive: &archive_name });
+                            staged.extend(file);
+                            archives.push(archive_name.clone());
+                        }
+                        Err(SerializableError::Cancelled) => {
+                            if let Err(e) = service_tasks(config, &compose_project, &service_name, "after", &after).await {
+                                error!("{}: {}", service_name, e);
                             }
+                            break 'services;
+                        }
+                        Err(e @ SerializableError::LowSpace { .. }) => {
+                            error!("{}: {}: ExecStdout: {}, skipping the remaining dumps", service_name, archive_name, e);
+                            low_space = true;
+                            events.fail(&mut failed, ArchiveFailure::new(&service_name, &archive_name, e));
+                        }
+                        Err(e) if attempt < dump.retries => {
+                            let delay = Duration::from_secs(dump.retry_delay.saturating_mul(1 << attempt.min(16)));
+                            attempt += 1;
+                            warn!("{}: {}: ExecStdout: {}", service_name, archive_name, e);
+                            warn!("{}: {}: ExecStdout: retrying in {} (attempt {}/{})", service_name, archive_name, HumanDuration(delay), attempt, dump.retries);
+                            cancel.run_until_cancelled(tokio::time::sleep(delay)).await;
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("{}: {}: ExecStdout: {}", service_name, archive_name, e);
+                            events.fail(&mut failed, ArchiveFailure::new(&service_name, &archive_name, e));
                         }
                     }
+                    break;
                 }
             }
+            // only the dumps that made it are listed, a missing path would fail the whole backup
+            if backup.files_from.is_some() {
+                for file in &staged {
+                    files.archive(Path::new(&config.restic_root()).join(&service_name).join(file.file_name().expect("dumps are files")), None);
+                }
+                if let Err(e) = files.write(config, &service_name) {
+                    error!("{}: failed to write the list of paths to back up: {}", service_name, e);
+                    events.fail(&mut failed, ArchiveFailure::new(&service_name, "files_from", e));
+                }
+            }
+            debug!("{}: staged, queueing restic backup", service_name);
+            let complete = !failed.iter().any(|f: &ArchiveFailure| f.service == service_name);
+            if let Err(tokio::sync::mpsc::error::SendError(service)) = staged_tx.send(StagedService { name: service_name, compose_project, after, backup, backends, staged, archives, complete }) {
+                if let Err(e) = service_tasks(config, &service.compose_project, &service.name, "after", &service.after).await {
+                    error!("{}: {}", service.name, e);
+                }
+                // the uploader gave up, its error is reported below
+                break;
+            }
         }
+        failed
+    };
+    let uploader = async move {
+        let mut uploaded = 0;
+        let mut failed_backends = vec![];
+        while let Some(StagedService { name: service_name, compose_project, after, backup, backends: service_backends, staged, archives, mut complete }) = staged_rx.recv().await {
+            if cancel.is_cancelled() {
+                if let Err(e) = service_tasks(config, &compose_project, &service_name, "after", &after).await {
+                    error!("{}: {}", service_name, e);
+                }
+                return Err(SerializableError::Cancelled);
+            }
+            if staging_only {
+                info!("{}: dry run: staged, not backing it up", service_name);
+                if let Err(e) = service_tasks(config, &compose_project, &service_name, "after", &after).await {
+                    error!("{}: {}", service_name, e);
+                    events.fail(&mut failed_backends, ArchiveFailure::new(&service_name, "after", e));
+                }
+                continue;
+            }
+            let started = Instant::now();
+            let previous = state.lock().unwrap()
+                .service(&service_name)
+                .and_then(|s| s.upload_secs)
+                .map(Duration::from_secs_f64);
+            // each backend is tracked on its own, the run only stops when none of them took the service
+            let mut summaries = vec![];
+            let mut errors = vec![];
+            for kind in &service_backends {
+                let Some((_, backend)) = backends.iter().find(|(b, _)| b == kind) else {
+                    continue;
+                };
+                let backend_started = Instant::now();
+                // sized by the estimate until the backend knows better
+                let bar = progress.add(ProgressBar::new(estimate.service(&service_name))
+                    .with_style(ProgressStyle::with_template(&theme::bar_template()).expect("valid template"))
+                    .with_prefix(format!("{} ({})", service_name, kind.name())));
+                let request = match backend.ignores_case() {
+                    true => backup.clone(),
+                    false => backup.clone().case_sensitive(*kind),
+                };
+                let summary = backend.backup(config, request.with_retry_lock(config.lock().map(|l| l.wait)), &bar, previous);
+                let summary = events::tracked(summary, &bar, |bar| events.emit(Event::BackupProgress {
+                    service: &service_name,
+                    backend: *kind,
+                    bytes: bar.position(),
+                    percent: events::percent(bar),
+                }));
+                // dropping the backup kills it
+                let Some(summary) = cancel.run_until_cancelled(summary).await else {
+                    bar.finish_and_clear();
+                    if let Err(e) = service_tasks(config, &compose_project, &service_name, "after", &after).await {
+                        error!("{}: {}", service_name, e);
+                    }
+                    return Err(SerializableError::Cancelled);
+                };
+                bar.finish_and_clear();
+                let mut run = run.lock().unwrap();
+                let record = run.service_mut(&service_name).backends.entry(kind.name().to_owned()).or_default();
+                record.upload_secs = Some(backend_started.elapsed().as_secs_f64());
+                match summary {
+                    Ok(summary) => {
+                        info!(
+                            "{}: {}: snapshot {} added {} in {}",
+                            service_name,
+                            kind.name(),
+                            summary.snapshot_id.as_deref().unwrap_or("(none)"),
+                            HumanBytes(summary.data_added.unwrap_or_default()),
+                            HumanDuration(backend_started.elapsed()),
+                        );
+                        record.snapshot_id = summary.snapshot_id.clone();
+                        record.data_added = summary.data_added;
+                        summaries.push(summary);
+                    }
+                    Err(e) => {
+                        error!("{}: {}: backup failed: {}", service_name, kind.name(), e);
+                        record.error = Some(e.to_string());
+                        errors.push((kind, e));
+                    }
+                }
+            }
+            if let Err(e) = service_tasks(config, &compose_project, &service_name, "after", &after).await {
+                error!("{}: {}", service_name, e);
+                complete = false;
+                events.fail(&mut failed_backends, ArchiveFailure::new(&service_name, "after", e));
+            }
+            if summaries.is_empty() {
+                return Err(match errors.len() {
+                    1 => errors.remove(0).1,
+                    _ => SerializableError::backend(format!("{}: every backend failed", service_name)),
+                });
+            }
+            complete &= errors.is_empty();
+            for (kind, e) in errors {
+                events.fail(&mut failed_backends, ArchiveFailure::new(&service_name, kind.name(), e));
+            }
+            // the first backend that took the service stands for it
+            let summary = summaries.remove(0);
 
-        backups.push(ResticBackup::with_excludes(
-            PathBuf::from(config.restic_root()).join(&service_name),
-            excludes,
-        ));
+            uploaded += 1;
+            {
+                let mut run = run.lock().unwrap();
+                let service_record = run.service_mut(&service_name);
+                service_record.snapshot_id = summary.snapshot_id;
+                service_record.data_added = summary.data_added;
+                service_record.upload_secs = Some(started.elapsed().as_secs_f64());
+                let snapshot_id = service_record.snapshot_id.clone();
+                for archive in &archives {
+                    let archive_record = run.archive_mut(&service_name, archive);
+                    archive_record.status = ArchiveStatus::Uploaded;
+                    archive_record.snapshot_id = snapshot_id.clone();
+                    events.emit(Event::ArchiveUploaded { service: &service_name, archive, snapshot_id: snapshot_id.as_deref() });
+                }
+            }
+            if config.cleanup_intermediate() && !staged.is_empty() {
+                cleanup::remove_staged(Path::new(intermediate_path), &staged)?;
+            }
+            if !config.dry_run() {
+                let mut state = state.lock().unwrap();
+                let service_state = state.service_mut(&service_name);
+                service_state.upload_secs = Some(started.elapsed().as_secs_f64());
+                if complete {
+                    service_state.last_success = Some(chrono::Utc::now());
+                }
+                for archive in &archives {
+                    state.set_progress(&service_name, archive, ArchiveProgress::Uploaded);
+                }
+                state.save()?;
+            }
+            match previous_run {
+                Some(previous_run) => info!(
+                    "{}/{} services uploaded, about {} left judging by the previous run",
+                    uploaded,
+                    service_count,
+                    HumanDuration(previous_run.saturating_sub(run_started.elapsed())),
+                ),
+                None => info!("{}/{} services uploaded", uploaded, service_count),
+            }
+        }
+        Ok::<_, SerializableError>(failed_backends)
+    };
+    let (mut failed, uploaded) = tokio::join!(stager, uploader);
+    let uploaded = uploaded
+        .and_then(|failed_backends| match cancel.is_cancelled() {
+            true => Err(SerializableError::Cancelled),
+            false => Ok(failed_backends),
+        })
+        .map(|failed_backends| failed.extend(failed_backends));
+
+    if uploaded.is_ok() && !config.dry_run() {
+        events.emit(Event::PhaseStarted { phase: Phase::Manifest });
+        let manifest = Manifest::new(&run.lock().unwrap(), &state.lock().unwrap(), &restic_host);
+        for (kind, backend) in backends {
+            if let Err(e) = manifest::upload(config, backend.as_ref(), Path::new(intermediate_path), &manifest).await {
+                error!("{}: failed to back up the run manifest: {}", kind.name(), e);
+                failed.push(ArchiveFailure::new("hoarder", "manifest", e));
+            }
+        }
     }
 
-    mounts.push(DockerBinding::new_ro(
-        config.intermediate_mount_override().unwrap_or(intermediate_path),
+    // the snapshots a failed run didn't replace are still needed, nothing is forgotten then
+    if uploaded.is_ok() && config.retention().is_some() && !failed.is_empty() {
+        warn!("some archives failed, keeping every snapshot instead of applying the retention policy");
+    } else if uploaded.is_ok()
+        && let Some(retention) = config.retention()
+    {
+        events.emit(Event::PhaseStarted { phase: Phase::Retention });
+        for (kind, backend) in backends {
+            if config.interactive() {
+                let question = format!("{}: forget the snapshots the retention policy doesn't keep", kind.name());
+                match picker::confirm(&question) {
+                    Ok(true) => {},
+                    Ok(false) => {
+                        info!("{}: keeping every snapshot", kind.name());
+                        continue;
+                    }
+                    Err(e) => {
+                        failed.push(ArchiveFailure::new("hoarder", "retention", e));
+                        continue;
+                    }
+                }
+            }
+            info!("{}: forgetting the snapshots the retention policy doesn't keep", kind.name());
+            if let Err(e) = backend.forget(config, retention).await {
+                error!("{}: failed to apply the retention policy: {}", kind.name(), e);
+                failed.push(ArchiveFailure::new("hoarder", "retention", e));
+            }
+        }
+    }
+
+    if uploaded.is_ok() && !config.dry_run() {
+        events.emit(Event::PhaseStarted { phase: Phase::Check });
+        for (kind, backend) in backends {
+            let last_checks = state.lock().unwrap().last_checks.clone();
+            let due = backend::due_checks(config.check_schedule(), *kind, &last_checks, chrono::Utc::now());
+            if !due.is_empty() && !backend.deep_checks() {
+                warn!("{}: the backend can't check its repository, skipping the scheduled check", kind.name());
+                continue;
+            }
+            for (check, done) in due {
+                info!("{}: checking the repository, reading back {} of its data", kind.name(), check.read_data_subset.as_deref().unwrap_or("all"));
+                if let Err(e) = backend.deep_check(config, check.read_data_subset.as_deref()).await {
+                    error!("{}: the repository check failed: {}", kind.name(), e);
+                    failed.push(ArchiveFailure::new("hoarder", "check", e));
+                }
+                // a failed check isn't retried on every run, it was reported
+                let mut state = state.lock().unwrap();
+                for key in done {
+                    state.last_checks.insert(key, chrono::Utc::now());
+                }
+            }
+        }
+    }
+
+    events.emit(Event::PhaseStarted { phase: Phase::Teardown });
+    for (_, backend) in backends {
+        backend.teardown(config).await?;
+    }
+
+    uploaded?;
+    if !config.dry_run() {
+        let mut state = state.lock().unwrap();
+        state.last_run_secs = Some(run_started.elapsed().as_secs_f64());
+        // the run went through, there is nothing left to resume
+        state.clear_progress();
+        state.save()?;
+    }
+    Ok(failed)
+}
+
+/// The mounts a backend backing up `routed` needs: the ones shared by every service, and the
+/// volumes of the routed services.
+fn routed_mounts(config: &Config, mounts: &[DockerBinding], services: &[&str], routed: &[&str]) -> Vec<DockerBinding> {
+    let restic_root = PathBuf::from(config.restic_root());
+    mounts
+        .iter()
+        .filter(|m| {
+            let owner = m.path.strip_prefix(&restic_root).ok().and_then(|p| p.iter().next());
+            owner.is_none_or(|owner| routed.iter().any(|r| owner == *r) || !services.iter().any(|s| owner == *s))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Mounted first into the backup containers, before any archive.
+fn root_mount(config: &Config) -> Result<DockerBinding, SerializableError> {
+    Ok(DockerBinding::new_ro(
+        config.restic_root(),
+        PathBuf::from(config.intermediate_mount_override().unwrap_or(config.intermediate_path()?)),
+    ))
+}
+
+/// The intermediate directory holding the dumps, mounted last into the backup containers.
+fn intermediate_mount(config: &Config) -> Result<DockerBinding, SerializableError> {
+    Ok(DockerBinding::new_ro(
+        config.intermediate_mount_override().unwrap_or(config.intermediate_path()?),
         PathBuf::from(config.restic_root()),
-    ));
-    debug!("mountlist: {:#?}", mounts);
+    ))
+}
 
-    // get restic related env variables
-    let mut env = vec![
-        ("RESTIC_PASSWORD_FILE".to_owned(), "/restic_password".to_owned()),
-        ("RESTIC_HOST".to_owned(), restic_host),
-    ];
+/// The docker command dumping an ExecStdout archive to its stdout, in a new container of the
+/// service with `run` instead of its running one.
+fn dump_command(config: &Config, compose_project: &str, service: String, task: ShellTask, user: Option<&str>, run: bool) -> DockerCommand {
+    let (subcommand, options_inner) = match run {
+        false => (DockerComposeSubcommand::Exec { service, task }, exec_options("-i", user)),
+        true => {
+            let mut options = vec!["--rm".to_owned(), "--no-deps".to_owned()];
+            options.extend(exec_options("-T", user));
+            (DockerComposeSubcommand::Run { service, task }, options)
+        }
+    };
+    config.docker_command_with_context(
+        DockerSubcommand::Compose {
+            project: Some(Left(compose_project.to_owned())),
+            subcommand,
+            options: vec![],
+            options_inner,
+        },
+    )
+}
 
-    for (key, value) in std::env::vars() {
-        if key == "RESTIC_PASSWORD_FILE" {
+/// Options of `docker compose exec`, `io` being how it handles stdin, run as `user` if set.
+fn exec_options(io: &str, user: Option<&str>) -> Vec<String> {
+    let mut options = vec![io.to_owned()];
+    if let Some(user) = user {
+        options.extend(["-u".to_owned(), user.to_owned()]);
+    }
+    options
+}
+
+/// Runs the `when` tasks of a service in order, stopping at the first one that fails. Dry runs only
+/// log them.
+async fn service_tasks(config: &Config, compose_project: &str, service_name: &str, when: &str, tasks: &[ServiceTask]) -> Result<(), SerializableError> {
+    for task in tasks {
+        let description = format!("{} `{}` in service {}", when, task.task.get_args().into_iter().collect::<Vec<_>>().join(" "), task.service);
+        if !config.dry_run_level().stages() {
+            info!("{}: {} would be run", service_name, description);
             continue;
         }
-        if key.starts_with("RESTIC_") || key.starts_with("AWS_") {
-            debug!("setting env var: {}=***", key);
-            env.push((key, value));
+        info!("{}: running {}", service_name, description);
+        let options_inner = exec_options("-T", task.user.as_deref());
+        let mut command = config.docker_command_with_context(DockerSubcommand::Compose {
+            project: Some(Left(compose_project.to_owned())),
+            subcommand: DockerComposeSubcommand::Exec { service: task.service.clone(), task: task.task.clone() },
+            options: vec![],
+            options_inner,
+        }).into_command();
+        command.stdin(Stdio::null());
+        let output = command.audited_output().await
+            .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+        if !output.status.success() {
+            error!("{}: {} failed", service_name, description);
+            return Err(SerializableError::docker(&command, output.status.code(), Some(String::from_utf8_lossy(&output.stderr).trim().to_owned())));
         }
     }
-    let mut options = vec!["--rm".to_owned(), "--name".to_owned(), config.restic_container_name(), "-d".to_owned()];
-    // append env vars
-    for (k, v) in &env {
-        options.push("--env".to_owned());
-        options.push(format!("{}={}", k, v));
+    Ok(())
+}
+
+/// Where an ExecStdout archive is dumped in the intermediate directory.
+pub(crate) fn dump_file(intermediate_path: &str, service_name: &str, archive_name: &str, ext: &str, compressed: bool) -> PathBuf {
+    let output_name = match compressed {
+        true => format!("{}.{}.zst", archive_name, ext),
+        false => format!("{}.{}", archive_name, ext),
+    };
+    PathBuf::from(intermediate_path).join(service_name).join(output_name)
+}
+
+/// Where the stderr of an archive's dump is written, next to the dump.
+pub(crate) fn stderr_log(intermediate_path: &str, service_name: &str, archive_name: &str) -> PathBuf {
+    PathBuf::from(intermediate_path).join(service_name).join(format!("{}.stderr.log", archive_name))
+}
+
+/// Runs an ExecStdout archive's task inside its compose service and writes its stdout to the
+/// intermediate directory, and its stderr to [`stderr_log`].
+#[allow(clippy::too_many_arguments)]
+async fn dump_exec_stdout(
+    config: &Config,
+    state: &Mutex<State>,
+    bar: ProgressBar,
+    cancel: &CancellationToken,
+    compose_project: &str,
+    service_name: &str,
+    intermediate_path: &str,
+    dump: PendingDump,
+) -> Result<Option<PathBuf>, SerializableError> {
+    let PendingDump { archive_name, service, task, ext, user, timeout, compression, skip_unchanged, io_limit, dump_check, run_if_stopped, .. } = dump;
+
+    let stopped = run_if_stopped && !inspect::running_services(config, compose_project).await?.contains(&service);
+    if stopped {
+        info!("{}: {}: ExecStdout: service {} isn't running, dumping from a new container", service_name, archive_name, service);
     }
+    let mut command = dump_command(config, compose_project, service, task, user.as_deref(), stopped).into_command();
+    let output_file = dump_file(intermediate_path, service_name, &archive_name, &ext, compression.is_some());
+    let output_path = output_file.parent().expect("dumps are inside a service directory").to_owned();
+    std::fs::create_dir_all(&output_path)?;
+    debug!("{}: {}: ExecStdout: output file: {:?}", service_name, archive_name, output_file);
 
-    // stop any existing container
-    if config.docker_command_with_context(DockerSubcommand::stop(
-            config.restic_container_name(),
-            Vec::<String>::new(),
-        ))
-        .spawn_and_wait()?
-        .success()
-    {
-        warn!("another container with the name {} has been found and stopped", config.restic_container_name());
-        warn!("waiting 1 second for letting the daemon delete it...");
-        std::thread::sleep(std::time::Duration::from_secs(1));
-    }
-
-    if !config.docker_command_with_context(
-        DockerSubcommand::run(
-            config.restic_image(),
-            mounts,
-            options,
-            vec!["tini", "--", "sleep", "infinity"],
-        ))
-        .spawn_and_wait()?
-        .success()
-    {
-        error!("failed to start restic container");
-        return Err(SerializableError::new("failed to start restic container"));
+    let required = config.min_free_space().or_else(|| {
+        state.lock().unwrap().archive(service_name, &archive_name).and_then(|a| a.size)
+    });
+    if let Some(required) = required {
+        let available = fs4::available_space(&output_path)?;
+        debug!("{}: {}: ExecStdout: {} available, {} required", service_name, archive_name, HumanBytes(available), HumanBytes(required));
+        if available < required {
+            return Err(SerializableError::dump(format!(
+                "not enough space left in {}: {} available, {} required",
+                output_path.display(),
+                HumanBytes(available),
+                HumanBytes(required),
+            )));
+        }
     }
 
-    for backup in backups {
-        let task = backup.into_task();
+    command
+        .stderr(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+    debug!("{}: {}: ExecStdout: executing command: {:?}", service_name, archive_name, command.as_std().get_args().collect::<Vec<_>>());
+    let mut handle = command.audited_spawn()
+        .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+    let stdout = handle.stdout.take()
+        .ok_or(SerializableError::dump("no stdout found in command output"))?;
+    // drain stderr while stdout is being copied, so a chatty dump can't fill the pipe
+    let log = config.dry_run_level().stages().then(|| stderr_log(intermediate_path, service_name, &archive_name));
+    let stderr_reader = handle.stderr.take().map(|stderr| {
+        let log = log.clone();
+        tokio::spawn(async move { stream::capture_stderr(stderr, log.as_deref()).await })
+    });
+    // the dump is written next to the previous one, which is only replaced once the new one is complete
+    let partial = config.dry_run_level().stages().then(|| PartialFile::new(&output_file));
+    let (output, compressor): (Box<dyn tokio::io::AsyncWrite + Unpin + Send>, _) = match &partial {
+        None => {
+            warn!("{}: {}: dry run mode, not writing to file {}", service_name, archive_name, output_file.display());
+            (Box::new(tokio::io::sink()), None)
+        }
+        Some(partial) => match &compression {
+            Some(compression) => {
+                debug!("{}: {}: ExecStdout: compressing with {:?}", service_name, archive_name, compression);
+                let file = std::fs::File::create(partial.path())?;
+                let (writer, compressor) = stream::zstd_writer(file, compression, config.io_buffer_size())?;
+                (Box::new(writer), Some(compressor))
+            }
+            None => (Box::new(File::create(partial.path()).await?), None),
+        },
+    };
+    let output: Box<dyn tokio::io::AsyncWrite + Unpin + Send> = match io_limit.or(config.io_limit()) {
+        Some(limit) => {
+            debug!("{}: {}: ExecStdout: limiting writes to {}/s", service_name, archive_name, HumanBytes(limit));
+            Box::new(ThrottledWriter::new(output, limit))
+        }
+        None => output,
+    };
+    let proxy = SpinnerWriter::new(stdout, output, config.io_buffer_size(), bar);
+    let mut proxy = if skip_unchanged { proxy.hashed() } else { proxy };
 
-        let mut command = config.docker_command_with_context(DockerSubcommand::exec(
-            config.restic_container_name(),
-            task,
-            vec!["-it"],
-        )).into_command();
-        if config.dry_run() {
-            warn!("running in dry run mode, not actually uploading");
-            command.arg("--dry-run");
+    let dump = async {
+        let written = proxy.write_all().await
+            .map_err(|e| SerializableError::dump(format!("failed to write output to file: {}", e)))?;
+        if let Some(compressor) = compressor {
+            compressor.await
+                .map_err(|e| SerializableError::dump(format!("compressor panicked: {}", e)))?
+                .map_err(|e| SerializableError::dump(format!("failed to compress output: {}", e)))?;
+        }
+        handle.wait().await
+            .map(|status| (status, written))
+            .map_err(|e| SerializableError::dump(format!("failed to wait for command: {}", e)))
+    };
+    let dump = async {
+        match config.low_water_mark() {
+            Some(low_water_mark) => tokio::select! {
+                result = dump => result,
+                e = low_space(&output_path, low_water_mark) => Err(e),
+            },
+            None => dump.await,
         }
-        info!("running restic backup task: {:?}", command.get_args().collect::<Vec<_>>());
-        let exit = command
-            .spawn()?
-            .wait()?;
-        if !exit.success() {
-            error!("restic backup failed: {}", exit);
-            return Err(SerializableError::new(format!("restic backup failed: {}", exit)));
+    };
+    let dump = async {
+        cancel.run_until_cancelled(dump).await.unwrap_or(Err(SerializableError::Cancelled))
+    };
+    // on error the handle is dropped, which kills the docker exec
+    let (status, written) = match timeout {
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs), dump).await
+            .unwrap_or_else(|_| Err(SerializableError::dump(format!("timed out after {}s", secs))))?,
+        None => dump.await?,
+    };
+    let (lines, tail) = match stderr_reader {
+        Some(reader) => reader.await
+            .map_err(|e| SerializableError::dump(format!("stderr reader panicked: {}", e)))?
+            .map_err(|e| SerializableError::dump(format!("failed to read stderr: {}", e)))?,
+        None => (0, vec![]),
+    };
+    if let Some(log) = &log {
+        match lines {
+            0 => std::fs::remove_file(log)?,
+            lines => info!("{}: {}: ExecStdout: {} lines of stderr written to {}", service_name, archive_name, lines, log.display()),
+        }
+    }
+    if !status.success() {
+        error!("{}: {}: docker exec stdout failure: {}", service_name, archive_name, status);
+        if tail.iter().any(|l| !l.is_empty()) {
+            error!("stderr output{}:", if lines > tail.len() { format!(", last {} of {} lines", tail.len(), lines) } else { "".to_owned() });
+            for line in &tail {
+                error!("=> {}", line);
+            }
+            return Err(SerializableError::docker(&command, status.code(), Some(tail.join("\n"))));
         }
+        error!("no stderr output");
+        return Err(SerializableError::docker(&command, status.code(), None));
     }
+    dump_check.check(&ext, written, proxy.head()).map_err(SerializableError::dump)?;
 
-    config.docker_command_with_context(DockerSubcommand::stop(
-            config.restic_container_name(), Vec::<String>::with_capacity(0)
-        ))
-        .spawn_and_wait()?;
+    let Some(partial) = partial else {
+        return Ok(None);
+    };
+    let hash = proxy.hash();
+    let mut state = state.lock().unwrap();
+    let previous = state.archive(service_name, &archive_name).and_then(|a| a.hash.as_ref());
+    if hash.is_some() && hash.as_ref() == previous && output_file.exists() {
+        info!("{}: {}: ExecStdout: dump unchanged since last run, keeping the previous one", service_name, archive_name);
+    } else {
+        partial.commit(&output_file)?;
+        if config.dry_run() {
+            return Ok(Some(output_file));
+        }
+        let archive_state = state.archive_mut(service_name, &archive_name);
+        archive_state.hash = hash;
+        archive_state.size = Some(std::fs::metadata(&output_file)?.len());
+        state.save()?;
+    }
+    Ok(Some(output_file))
+}
 
-    Ok(failed)
+/// Resolves once the free space of the filesystem of `path` is under `low_water_mark`, checking it
+/// every second.
+async fn low_space(path: &Path, low_water_mark: u64) -> SerializableError {
+    loop {
+        match fs4::available_space(path) {
+            Ok(available) if available < low_water_mark => return SerializableError::LowSpace {
+                path: path.display().to_string(),
+                available,
+                low_water_mark,
+            },
+            Ok(_) => {}
+            Err(e) => debug!("failed to read the free space of {}: {}", path.display(), e),
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
 }
 
 #[test]
 fn test_config_dump() {
+    use archive::ArchiveInput;
     use docker::PathExclude;
 
-    let test = vec![
+    let _test = [
         Service {
             name: "test_service".to_owned(),
             compose_project: Some("different_compose".to_owned()),
+            compose_file: None,
+            max_age: None,
+            min_snapshots_7d: None,
+            max_snapshot_age: None,
+            backends: None,
+            before: vec![],
+            after: vec![],
+            enabled: true,
+            skip_if: None,
+            depends_on: vec![],
+            one_file_system: false,
             archives: vec![
                 ArchiveOptions {
                     input: ArchiveInput::Docker(DockerInputType::ComposeNamedVolume {
                         name: "test_volume".to_owned(),
-                        filter: Some(PathExclude(vec![PathBuf::from("ses")])),
+                        filter: Some(PathExclude::new(vec![PathBuf::from("ses")])),
                     }),
                     name: "data".to_owned(),
+                    timeout: None,
+                    retries: 0,
+                    retry_delay: 10,
+                    compression: None,
+                    io_limit: None,
+                    skip_unchanged: false,
+                    post_restore: vec![],
+                    dump_check: DumpCheck::default(),
+                    run_if_stopped: false,
+                    required: true,
+                    enabled: true,
+                    skip_if: None,
+                    depends_on: vec![],
                 },
             ],
         }
     ];
 
-    // println!("{}", serde_yaml::to_string(&test).unwrap());
+    // println!("{}", serde_yaml::to_string(&_test).unwrap());
 }
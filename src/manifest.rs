@@ -0,0 +1,166 @@
+use std::{collections::BTreeMap, path::Path, process::Stdio};
+
+use chrono::{DateTime, Utc};
+use indicatif::ProgressBar;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::Audited,
+    backend::{self, BackupBackend, BackupRequest},
+    config::Config,
+    state::{RunRecord, State},
+    SerializableError,
+};
+
+/// Directory inside the intermediate path holding the manifest, service names can't start with a
+/// dot so it can't clash with a service's dumps.
+static MANIFEST_DIR: &str = ".manifest";
+static MANIFEST_FILE: &str = "manifest.json";
+static CONFIG_FILE: &str = "config.yaml";
+static REDACTED: &str = "<redacted>";
+static MANIFEST_TAG: &str = "hoarder-manifest";
+
+/// What a run put into the repository, backed up as its own snapshot so that a recovery can find
+/// its way around the repository without the original host's state.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Manifest {
+    pub(crate) hoarder_version: String,
+    pub(crate) host: String,
+    pub(crate) created_at: DateTime<Utc>,
+    /// sha256 of the configuration file the run used
+    pub(crate) config_hash: Option<String>,
+    pub(crate) services: BTreeMap<String, ServiceManifest>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct ServiceManifest {
+    pub(crate) snapshot_id: Option<String>,
+    pub(crate) excludes: Option<Vec<String>>,
+    pub(crate) archives: BTreeMap<String, ArchiveManifest>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct ArchiveManifest {
+    pub(crate) snapshot_id: Option<String>,
+    /// where the archive's data comes from
+    pub(crate) source: Option<String>,
+    /// sha256 of the dump, only known for dumps of archives with skip_unchanged
+    pub(crate) hash: Option<String>,
+    /// size in bytes of the dump, volumes have none
+    pub(crate) bytes: Option<u64>,
+}
+
+impl Manifest {
+    pub(crate) fn new(run: &RunRecord, state: &State, host: &str) -> Self {
+        Self {
+            hoarder_version: env!("CARGO_PKG_VERSION").to_owned(),
+            host: host.to_owned(),
+            created_at: Utc::now(),
+            config_hash: run.config_hash.clone(),
+            services: run.services
+                .iter()
+                .map(|(service, record)| (service.clone(), ServiceManifest {
+                    snapshot_id: record.snapshot_id.clone(),
+                    excludes: record.excludes.clone(),
+                    archives: record.archives
+                        .iter()
+                        .map(|(archive, record)| (archive.clone(), ArchiveManifest {
+                            snapshot_id: record.snapshot_id.clone(),
+                            source: record.source.clone(),
+                            hash: state.archive(service, archive).and_then(|a| a.hash.clone()),
+                            bytes: record.bytes,
+                        }))
+                        .collect(),
+                }))
+                .collect(),
+        }
+    }
+}
+
+/// Stages the configuration file next to the manifest, with hook urls and anything that looks like
+/// a secret redacted, or removes a previously staged one if `backup_config` is off.
+pub(crate) fn stage_config(config: &Config, config_file: &str) -> Result<(), SerializableError> {
+    let path = Path::new(&config.intermediate_path()?).join(MANIFEST_DIR).join(CONFIG_FILE);
+    if !config.backup_config() {
+        return match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    let mut value: serde_yaml::Value = serde_yaml::from_str(config_file)
+        .map_err(|e| SerializableError::config(format!("failed to parse config file: {}", e)))?;
+    redact(&mut value, false);
+    let redacted = serde_yaml::to_string(&value)
+        .map_err(|e| SerializableError::config(format!("failed to serialize config file: {}", e)))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, redacted)?;
+    debug!("staged configuration backup at {}", path.display());
+    Ok(())
+}
+
+/// Replaces every string under `hooks` and every value whose key names a secret.
+fn redact(value: &mut serde_yaml::Value, secret: bool) {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => for (key, value) in mapping.iter_mut() {
+            let key = key.as_str().unwrap_or_default();
+            let is_secret = key == "hooks"
+                || (["password", "secret", "token"].iter().any(|s| key.contains(s)) && !key.ends_with("_file"));
+            redact(value, secret || is_secret);
+        },
+        serde_yaml::Value::Sequence(sequence) => for value in sequence {
+            redact(value, secret);
+        },
+        serde_yaml::Value::Null => {},
+        value if secret => *value = serde_yaml::Value::String(REDACTED.to_owned()),
+        _ => {},
+    }
+}
+
+/// Writes the manifest to the intermediate directory and backs it up, along with the staged
+/// configuration, with the prepared backend.
+pub(crate) async fn upload(
+    config: &Config,
+    backend: &dyn BackupBackend,
+    intermediate_path: &Path,
+    manifest: &Manifest,
+) -> Result<(), SerializableError> {
+    let dir = intermediate_path.join(MANIFEST_DIR);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join(MANIFEST_FILE), serde_json::to_vec_pretty(manifest)?)?;
+
+    let request = BackupRequest::new(Path::new(&config.restic_root()).join(MANIFEST_DIR))
+        .with_tag(MANIFEST_TAG)
+        .with_retry_lock(config.lock().map(|l| l.wait));
+    debug!("backing up the run manifest: {:?}", request);
+    backend.backup(config, request, &ProgressBar::hidden(), None).await
+        .map_err(|e| SerializableError::backend(format!("manifest backup failed: {}", e)))?;
+    info!("run manifest backed up");
+    Ok(())
+}
+
+/// Reads the manifest of the latest run of this host back from the repository.
+pub(crate) async fn fetch(config: &Config) -> Result<Manifest, SerializableError> {
+    let backend = backend::open(config);
+    let latest = backend.snapshots(config).await?
+        .into_iter()
+        .filter(|s| s.tags.iter().any(|t| t == MANIFEST_TAG))
+        .max_by_key(|s| s.time)
+        .ok_or_else(|| SerializableError::backend("no manifest found in the repository"))?;
+    let mut command = backend.dump(config, &latest.id, &Path::new(&config.restic_root()).join(MANIFEST_DIR).join(MANIFEST_FILE))?;
+    command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let output = command.audited_output().await
+        .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+    if !output.status.success() {
+        return Err(SerializableError::backend(format!(
+            "failed to read the manifest: {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )));
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
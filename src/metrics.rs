@@ -0,0 +1,102 @@
+use std::{fmt::Write, path::Path};
+
+use crate::{state::{ArchiveStatus, RunRecord, RunStatus}, verify::VerifyRecord, SerializableError};
+
+static METRICS_FILE: &str = "hoarder.prom";
+/// The restore tests don't run with every backup, so their metrics are kept in a file of their own.
+static VERIFY_METRICS_FILE: &str = "hoarder_verify.prom";
+
+/// Writes the metrics of a finished run in the node_exporter textfile collector format, replacing
+/// the previous file.
+pub(crate) fn write(dir: &Path, run: &RunRecord) -> Result<(), SerializableError> {
+    let mut out = String::new();
+    let archives = run.services.values().flat_map(|s| s.archives.values());
+    let failed = archives.clone().filter(|a| a.status == ArchiveStatus::Failed).count();
+
+    gauge(&mut out, "hoarder_last_run_timestamp_seconds", "when the last run started", &[
+        (vec![], run.started_at.timestamp() as f64),
+    ]);
+    gauge(&mut out, "hoarder_last_run_duration_seconds", "duration of the last run", &[
+        (vec![], run.duration_secs),
+    ]);
+    gauge(&mut out, "hoarder_last_run_status", "outcome of the last run", &[
+        RunStatus::Success,
+        RunStatus::Partial,
+        RunStatus::Degraded,
+        RunStatus::Failed,
+        RunStatus::Interrupted,
+    ].map(|status| (vec![("status", format!("{:?}", status).to_lowercase())], (run.status == status) as u8 as f64)));
+    gauge(&mut out, "hoarder_archives_total", "archives in the last run", &[
+        (vec![], archives.clone().count() as f64),
+    ]);
+    gauge(&mut out, "hoarder_archives_failed", "archives that failed in the last run", &[
+        (vec![], failed as f64),
+    ]);
+    gauge(&mut out, "hoarder_service_data_added_bytes", "bytes restic added to the repository for each service", &run.services
+        .iter()
+        .filter_map(|(name, s)| s.data_added.map(|d| (vec![("service", name.clone())], d as f64)))
+        .collect::<Vec<_>>());
+    gauge(&mut out, "hoarder_service_upload_duration_seconds", "duration of each service's restic upload", &run.services
+        .iter()
+        .filter_map(|(name, s)| s.upload_secs.map(|d| (vec![("service", name.clone())], d)))
+        .collect::<Vec<_>>());
+    gauge(&mut out, "hoarder_archive_bytes", "size of each dump", &run.services
+        .iter()
+        .flat_map(|(service, s)| s.archives.iter().filter_map(move |(archive, a)| {
+            a.bytes.map(|b| (vec![("service", service.clone()), ("archive", archive.clone())], b as f64))
+        }))
+        .collect::<Vec<_>>());
+
+    replace(dir, METRICS_FILE, &out)
+}
+
+/// Writes the metrics of the last restore test.
+pub(crate) fn write_verify(dir: &Path, record: &VerifyRecord) -> Result<(), SerializableError> {
+    let mut out = String::new();
+    let labels = || vec![("service", record.service.clone()), ("archive", record.archive.clone())];
+    gauge(&mut out, "hoarder_verify_timestamp_seconds", "when the last restore test ran", &[
+        (labels(), record.at.timestamp() as f64),
+    ]);
+    gauge(&mut out, "hoarder_verify_success", "whether the restored files of the last restore test matched the live ones", &[
+        (labels(), record.ok() as u8 as f64),
+    ]);
+    gauge(&mut out, "hoarder_verify_files_compared", "files compared by the last restore test", &[
+        (labels(), record.compared as f64),
+    ]);
+    gauge(&mut out, "hoarder_verify_files_mismatched", "files of the last restore test that didn't match", &[
+        (labels(), record.mismatched.len() as f64),
+    ]);
+    replace(dir, VERIFY_METRICS_FILE, &out)
+}
+
+/// Replaces the metrics file `name` atomically, so that a scrape never sees half of it.
+fn replace(dir: &Path, name: &str, out: &str) -> Result<(), SerializableError> {
+    let write = || -> std::io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(name);
+        // node_exporter only reads *.prom files, so the temporary file is never collected
+        let tmp = path.with_extension("prom.tmp");
+        std::fs::write(&tmp, out)?;
+        std::fs::rename(&tmp, &path)
+    };
+    write().map_err(|e| SerializableError::Io { detail: format!("failed to write metrics to {}: {}", dir.display(), e) })
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, samples: &[(Vec<(&str, String)>, f64)]) {
+    if samples.is_empty() {
+        return;
+    }
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (labels, value) in samples {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+use crate::{config::FullConfig, notify::{NotifierConfig, NotifierKind, Outcome}, preset, sops, SerializableError};
+
+/// Version of the configuration schema this build reads, the one `version` is set to.
+pub(crate) static CONFIG_VERSION: u32 = 2;
+
+/// The hooks of a version 1 configuration, one url per outcome.
+#[derive(Deserialize, Debug, Default)]
+struct HooksV1 {
+    success: Option<String>,
+    failure: Option<String>,
+    partial: Option<String>,
+    #[serde(default)]
+    degraded: Option<String>,
+}
+
+/// Parses a configuration of any known version, returning the version it was written for. Presets
+/// are expanded along the way.
+pub(crate) fn load(text: &str) -> Result<(FullConfig, u32), SerializableError> {
+    let value: Value = serde_yaml::from_str(text)
+        .map_err(|e| SerializableError::config(format!("failed to parse config file: {}", e)))?;
+    let (mut value, version) = migrate(value)?;
+    preset::expand(&mut value)?;
+    let full_config = serde_yaml::from_value(value)
+        .map_err(|e| SerializableError::config(format!("failed to parse config file: {}", e)))?;
+    Ok((full_config, version))
+}
+
+/// Brings a configuration up to [`CONFIG_VERSION`], one version at a time, returning the version
+/// it was written for. Configurations without a `version` are version 1.
+pub(crate) fn migrate(mut value: Value) -> Result<(Value, u32), SerializableError> {
+    let Value::Mapping(mapping) = &mut value else {
+        return Err(SerializableError::config("the configuration must be a mapping"));
+    };
+    let version = match mapping.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or(SerializableError::config(format!("invalid configuration version {:?}", version)))?,
+    };
+    if version > CONFIG_VERSION {
+        return Err(SerializableError::config(format!(
+            "the configuration is version {}, this hoarder only reads up to version {}",
+            version,
+            CONFIG_VERSION,
+        )));
+    }
+    if version < 2 {
+        v1_to_v2(mapping)?;
+    }
+    mapping.insert("version".into(), CONFIG_VERSION.into());
+    Ok((value, version))
+}
+
+/// Version 2 replaced the hook urls with notifiers.
+fn v1_to_v2(mapping: &mut Mapping) -> Result<(), SerializableError> {
+    let hooks: HooksV1 = match mapping.remove("hooks") {
+        Some(hooks) => serde_yaml::from_value(hooks)
+            .map_err(|e| SerializableError::config(format!("invalid version 1 hooks: {}", e)))?,
+        None => HooksV1::default(),
+    };
+    let notifiers = [
+        (hooks.success, Outcome::Success),
+        (hooks.failure, Outcome::Failure),
+        (hooks.partial, Outcome::Partial),
+        (hooks.degraded, Outcome::Degraded),
+    ]
+        .into_iter()
+        .filter_map(|(url, outcome)| url.map(|url| NotifierConfig {
+            kind: NotifierKind::Http { url },
+            on: vec![outcome],
+            retries: 0,
+            retry_delay: NotifierConfig::default_retry_delay(),
+        }))
+        .collect::<Vec<_>>();
+    let mut hooks = Mapping::new();
+    hooks.insert("notifiers".into(), serde_yaml::to_value(notifiers).expect("notifiers are serializable"));
+    mapping.insert("hooks".into(), Value::Mapping(hooks));
+    Ok(())
+}
+
+/// Rewrites the configuration file at `path` in the current version, keeping the original next to
+/// it, or only prints it.
+pub(crate) fn rewrite(path: &Path, print: bool) -> Result<(), SerializableError> {
+    let mut text = std::fs::read_to_string(path)?;
+    if sops::is_encrypted(&text) {
+        if !print {
+            return Err(SerializableError::config(
+                "the configuration is encrypted with sops: migrate it with --print and encrypt the result again",
+            ));
+        }
+        text = sops::decrypt(path)?;
+    }
+    let value: Value = serde_yaml::from_str(&text)
+        .map_err(|e| SerializableError::config(format!("failed to parse config file: {}", e)))?;
+    let (value, version) = migrate(value)?;
+    // only write configurations that load, presets are kept as they are written though
+    let mut expanded = value.clone();
+    preset::expand(&mut expanded)?;
+    serde_yaml::from_value::<FullConfig>(expanded)
+        .map_err(|e| SerializableError::config(format!("the migrated configuration doesn't load: {}", e)))?
+        .validate()?;
+    let migrated = serde_yaml::to_string(&value)
+        .map_err(|e| SerializableError::config(format!("failed to write the configuration: {}", e)))?;
+    if print {
+        print!("{}", migrated);
+        return Ok(());
+    }
+    if version == CONFIG_VERSION {
+        info!("the configuration is already version {}", CONFIG_VERSION);
+        return Ok(());
+    }
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", version));
+    let backup = PathBuf::from(backup);
+    std::fs::copy(path, &backup)?;
+    std::fs::write(path, migrated)?;
+    warn!("comments aren't kept by the migration, the original is at {}", backup.display());
+    info!("migrated the configuration from version {} to {}", version, CONFIG_VERSION);
+    Ok(())
+}
+
+#[test]
+fn test_migrate_v1() {
+    let v1 = "intermediate_path: /tmp\nrestic_host: host\nservices: []\nhooks:\n  success: http://example.com/ok\n  partial: http://example.com/partial\n";
+    let (full_config, version) = load(v1).unwrap();
+    assert_eq!(version, 1);
+    let notifiers = &full_config.hooks.notifiers;
+    assert_eq!(notifiers.len(), 2);
+    assert!(matches!(&notifiers[0].kind, NotifierKind::Http { url } if url == "http://example.com/ok"));
+    assert_eq!(notifiers[0].on, vec![Outcome::Success]);
+    assert_eq!(notifiers[1].on, vec![Outcome::Partial]);
+
+    let (value, version) = migrate(serde_yaml::to_value(&full_config).unwrap()).unwrap();
+    assert_eq!(version, CONFIG_VERSION);
+    assert_eq!(value["hooks"]["notifiers"].as_sequence().unwrap().len(), 2);
+    assert!(migrate(serde_yaml::from_str("version: 99").unwrap()).is_err());
+}
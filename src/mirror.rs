@@ -0,0 +1,333 @@
+use std::{collections::BTreeMap, path::{Path, PathBuf}, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use indicatif::ProgressBar;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncRead, process::Command};
+
+use crate::{
+    backend::{self, BackupBackend, BackupRequest, BackupSummary, RepositoryStats, Retention, Snapshot, HOARDER_TAG},
+    config::Config,
+    DockerBinding,
+    DockerSubcommand,
+    SerializableError,
+    ShellTask,
+};
+
+static MIRROR_IMAGE: &str = "alpine";
+static MIRROR_CONTAINER_NAME: &str = "hoarder-mirror";
+/// Installs rsync, unless the image already provides it.
+static SETUP: &str = "command -v rsync >/dev/null || apk add --no-cache rsync >/dev/null";
+/// Where the mirror is mounted inside the containers.
+static MIRROR_MOUNT: &str = "/mirror";
+/// Where the restore target is mounted inside the containers.
+static RESTORE_MOUNT: &str = "/restore";
+/// Suffix of copies that are still being written.
+static PARTIAL: &str = ".partial";
+static TIME_FORMAT: &str = "%Y-%m-%dT%H%M%SZ";
+
+/// Settings of the mirror backend.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct MirrorConfig {
+    /// host directory the copies are kept in, at `path/host/directory/time`
+    pub(crate) path: String,
+    /// image the copies are made in, it needs rsync, which is installed on alpine images missing it
+    pub(crate) image: Option<String>,
+    /// name of the long running container
+    pub(crate) container_name: Option<String>,
+}
+
+impl MirrorConfig {
+    fn image(&self) -> String {
+        self.image.clone().unwrap_or(MIRROR_IMAGE.to_owned())
+    }
+
+    fn container_name(&self) -> String {
+        self.container_name.clone().unwrap_or(MIRROR_CONTAINER_NAME.to_owned())
+    }
+}
+
+/// Copies each directory under the backup root to a dated directory on a local or NAS path, files
+/// unchanged since the previous copy being hard links to it, like `rsync --link-dest`. Every copy
+/// is complete and browsable, but only takes the space of what changed.
+pub(crate) struct Mirror;
+
+impl Mirror {
+    fn settings(config: &Config) -> Result<&MirrorConfig, SerializableError> {
+        config.mirror().ok_or(SerializableError::config("the mirror backend needs a mirror section"))
+    }
+
+    /// The host's directory in the mirror, as seen from the host.
+    fn host_dir(config: &Config) -> Result<PathBuf, SerializableError> {
+        Ok(Path::new(&Self::settings(config)?.path).join(config.restic_host()?))
+    }
+
+    /// The copy `id` of `dir`, as seen from the containers.
+    fn copy_path(config: &Config, dir: &str, id: &str) -> Result<PathBuf, SerializableError> {
+        Ok(Path::new(MIRROR_MOUNT).join(config.restic_host()?).join(dir).join(id))
+    }
+
+    /// Splits a path under the backup root into its directory there and the path inside of it.
+    fn split(config: &Config, path: &Path) -> (String, PathBuf) {
+        let relative = path.strip_prefix(config.restic_root()).unwrap_or(path);
+        let mut components = relative.components();
+        let dir = components.next().map(|c| c.as_os_str().to_string_lossy().into_owned()).unwrap_or_default();
+        (dir, components.as_path().to_path_buf())
+    }
+
+    fn mirror_mount(config: &Config, read_only: bool) -> Result<DockerBinding, SerializableError> {
+        let path = Self::settings(config)?.path.clone();
+        Ok(match read_only {
+            true => DockerBinding::new_ro(path, PathBuf::from(MIRROR_MOUNT)),
+            false => DockerBinding::new_rw(path, PathBuf::from(MIRROR_MOUNT)),
+        })
+    }
+
+    /// Builds a command running `script` in a throwaway container holding the mirror.
+    fn command(config: &Config, mut mounts: Vec<DockerBinding>, script: &str, read_only: bool) -> Result<Command, SerializableError> {
+        mounts.push(Self::mirror_mount(config, read_only)?);
+        Ok(config.docker_command_with_context(DockerSubcommand::run(
+            Self::settings(config)?.image(),
+            mounts,
+            vec!["--rm", "--entrypoint", "sh"],
+            vec!["-c".to_owned(), format!("{}; {}", SETUP, script)],
+        )).into_command())
+    }
+
+    /// Builds a command running `script` in the long running container.
+    fn exec(config: &Config, script: &str, stdin: bool) -> Result<Command, SerializableError> {
+        let mut task = ShellTask::new("sh");
+        task.arg("-c").arg(script);
+        Ok(config.docker_command_with_context(DockerSubcommand::exec(
+            Self::settings(config)?.container_name(),
+            task,
+            if stdin { vec!["-i"] } else { vec![] },
+        )).into_command())
+    }
+
+    /// The complete copies of every directory of this host, oldest first.
+    fn copies(config: &Config) -> Result<BTreeMap<String, Vec<Snapshot>>, SerializableError> {
+        let host_dir = Self::host_dir(config)?;
+        let mut copies = BTreeMap::new();
+        if !host_dir.exists() {
+            return Ok(copies);
+        }
+        for dir in std::fs::read_dir(&host_dir)? {
+            let dir = dir?;
+            let name = dir.file_name().to_string_lossy().into_owned();
+            let mut snapshots = std::fs::read_dir(dir.path())?
+                .filter_map(|c| {
+                    let id = c.ok()?.file_name().to_string_lossy().into_owned();
+                    Some(Snapshot {
+                        time: NaiveDateTime::parse_from_str(&id, TIME_FORMAT).ok()?.and_utc(),
+                        id: format!("{}/{}", name, id),
+                        paths: vec![Path::new(&config.restic_root()).join(&name).display().to_string()],
+                        tags: vec![HOARDER_TAG.to_owned()],
+                    })
+                })
+                .collect::<Vec<_>>();
+            snapshots.sort_by_key(|s| s.time);
+            copies.insert(name, snapshots);
+        }
+        Ok(copies)
+    }
+
+    /// A script copying `source` to a new copy of `dir`, hard linking what didn't change since
+    /// the latest one. The copy is written under a partial name, left over ones being removed.
+    /// The script copying `request` into the mirror, and the id of the copy.
+    fn backup_script(config: &Config, request: &BackupRequest) -> Result<(String, String), SerializableError> {
+        let (dir, _) = Self::split(config, &request.path);
+        // rsync anchors patterns starting with a slash to the source directory
+        let excludes = request.excludes
+            .iter()
+            .map(|e| format!("/{}", Path::new(e).strip_prefix(&request.path).unwrap_or(Path::new(e)).display()))
+            .collect::<Vec<_>>();
+        Self::copy_script(config, &dir, &request.path.display().to_string(), &excludes)
+    }
+
+    fn copy_script(config: &Config, dir: &str, source: &str, excludes: &[String]) -> Result<(String, String), SerializableError> {
+        let id = Utc::now().format(TIME_FORMAT).to_string();
+        let copy = Self::copy_path(config, dir, &id)?;
+        let partial = format!("{}{}", copy.display(), PARTIAL);
+        let mut rsync = ShellTask::new("rsync");
+        rsync.args(["-a", "--delete", "--stats"]);
+        if config.dry_run() {
+            rsync.arg("--dry-run");
+        }
+        let latest = Self::copies(config)?.remove(dir).and_then(|mut c| c.pop());
+        if let Some(latest) = latest {
+            rsync.arg(format!("--link-dest={}", Path::new(MIRROR_MOUNT).join(config.restic_host()?).join(&latest.id).display()));
+        }
+        for exclude in excludes {
+            rsync.arg(format!("--exclude={}", exclude));
+        }
+        rsync.arg(format!("{}/", source.trim_end_matches('/'))).arg(format!("{}/", partial));
+
+        let mut cleanup = ShellTask::new("rm");
+        cleanup.arg("-rf");
+        let parent = copy.parent().unwrap_or(Path::new(MIRROR_MOUNT)).display().to_string();
+        let mut mkdir = ShellTask::new("mkdir");
+        mkdir.arg("-p").arg(&partial);
+        let mut rename = ShellTask::new("mv");
+        rename.arg(&partial).arg(copy.display());
+        let script = match config.dry_run() {
+            true => rsync.to_shell(),
+            false => format!(
+                "{cleanup} '{parent}'/*{PARTIAL} && {mkdir} && {rsync} && {rename}",
+                cleanup = cleanup.to_shell(),
+                parent = parent.replace('\'', r"'\''"),
+                mkdir = mkdir.to_shell(),
+                rsync = rsync.to_shell(),
+                rename = rename.to_shell(),
+            ),
+        };
+        Ok((script, format!("{}/{}", dir, id)))
+    }
+
+    /// Runs a copy `script`, reporting the copy and how much it transferred.
+    async fn copy(config: &Config, script: String, id: String, input: Option<Box<dyn AsyncRead + Send + Unpin>>) -> Result<BackupSummary, SerializableError> {
+        let stdin = input.is_some();
+        let output = backend::run("mirror", Self::exec(config, &script, stdin)?, input).await?;
+        let transferred = output
+            .lines()
+            .find_map(|l| l.strip_prefix("Total transferred file size:"))
+            .and_then(|l| l.trim().trim_end_matches("bytes").trim().replace(',', "").parse().ok());
+        if config.dry_run() {
+            info!("dry run, {} bytes would have been copied", transferred.unwrap_or_default());
+            return Ok(BackupSummary::default());
+        }
+        Ok(BackupSummary { snapshot_id: Some(id), data_added: transferred })
+    }
+}
+
+#[async_trait]
+impl BackupBackend for Mirror {
+    async fn prepare(&self, config: &Config, mut mounts: Vec<DockerBinding>) -> Result<(), SerializableError> {
+        let settings = Self::settings(config)?;
+        std::fs::create_dir_all(Self::host_dir(config)?)?;
+        mounts.push(Self::mirror_mount(config, false)?);
+        backend::start_container(
+            config,
+            &settings.container_name(),
+            &settings.image(),
+            mounts,
+            vec!["--init".to_owned(), "--entrypoint".to_owned(), "sleep".to_owned()],
+            vec!["infinity"],
+        ).await?;
+        backend::run("mirror", Self::exec(config, SETUP, false)?, None).await?;
+        Ok(())
+    }
+
+    async fn teardown(&self, config: &Config) -> Result<bool, SerializableError> {
+        backend::stop_container(config, &Self::settings(config)?.container_name()).await
+    }
+
+    async fn backup(
+        &self,
+        config: &Config,
+        request: BackupRequest,
+        bar: &ProgressBar,
+        _previous: Option<Duration>,
+    ) -> Result<BackupSummary, SerializableError> {
+        if config.dry_run() {
+            warn!("running in dry run mode, not actually copying");
+        }
+        let (script, id) = Self::backup_script(config, &request)?;
+        bar.set_message("copying");
+        Self::copy(config, script, id, None).await
+    }
+
+    fn plan_commands(&self, config: &Config, request: &BackupRequest) -> Result<Vec<Command>, SerializableError> {
+        let (script, _) = Self::backup_script(config, request)?;
+        Ok(vec![Self::exec(config, &script, false)?])
+    }
+
+    async fn forget(&self, config: &Config, retention: &Retention) -> Result<(), SerializableError> {
+        if retention.rules().is_empty() {
+            return Err(SerializableError::config("retention must keep something, set at least one keep_* rule"));
+        }
+        for snapshots in Self::copies(config)?.values() {
+            let kept = retention.keep(&snapshots.iter().map(|s| s.time).collect::<Vec<_>>());
+            for (_, snapshot) in snapshots.iter().enumerate().filter(|(i, _)| !kept.contains(i)) {
+                info!("removing copy {}", snapshot.id);
+                if config.dry_run() {
+                    continue;
+                }
+                let mut task = ShellTask::new("rm");
+                task.arg("-rf").arg(Path::new(MIRROR_MOUNT).join(config.restic_host()?).join(&snapshot.id).display());
+                backend::run("mirror", Self::command(config, vec![], &task.to_shell(), false)?, None).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn snapshots(&self, config: &Config) -> Result<Vec<Snapshot>, SerializableError> {
+        Ok(Self::copies(config)?.into_values().flatten().collect())
+    }
+
+    async fn restore(
+        &self,
+        config: &Config,
+        snapshot: &str,
+        path: &Path,
+        target: Option<&str>,
+        dry_run: bool,
+    ) -> Result<String, SerializableError> {
+        let (dir, inner) = Self::split(config, path);
+        let (snapshot_dir, id) = snapshot.split_once('/')
+            .ok_or_else(|| SerializableError::backend(format!("{} isn't a copy of the mirror", snapshot)))?;
+        if snapshot_dir != dir {
+            return Err(SerializableError::backend(format!("copy {} isn't a copy of {}", snapshot, dir)));
+        }
+        let source = Self::copy_path(config, &dir, id)?.join(inner);
+        let mut rsync = ShellTask::new("rsync");
+        rsync.args(["-a", "--out-format=%n"]);
+        if dry_run {
+            rsync.arg("--dry-run");
+        }
+        // a directory's contents are restored into the target, a file into it
+        let script = format!(
+            "source='{}'; [ -d \"$source\" ] && source=\"$source/\"; {} \"$source\" {}/",
+            source.display().to_string().replace('\'', r"'\''"),
+            rsync.to_shell(),
+            RESTORE_MOUNT,
+        );
+        let mounts = target
+            .iter()
+            .map(|t| DockerBinding::new_rw(t.to_string(), PathBuf::from(RESTORE_MOUNT)))
+            .collect();
+        backend::run("mirror", Self::command(config, mounts, &script, true)?, None).await
+    }
+
+    fn dump(&self, config: &Config, snapshot: &str, path: &Path) -> Result<Command, SerializableError> {
+        let (dir, inner) = Self::split(config, path);
+        let id = snapshot.split_once('/').map_or(snapshot, |(_, id)| id);
+        let mut task = ShellTask::new("cat");
+        task.arg(Self::copy_path(config, &dir, id)?.join(inner).display());
+        // cat doesn't need rsync, skip installing it
+        Ok(config.docker_command_with_context(DockerSubcommand::run(
+            Self::settings(config)?.image(),
+            vec![Self::mirror_mount(config, true)?],
+            vec!["--rm"],
+            task.get_args().into_iter().collect(),
+        )).into_command())
+    }
+
+    async fn stats(&self, config: &Config) -> Result<RepositoryStats, SerializableError> {
+        // du counts hard linked files once
+        let mut task = ShellTask::new("du");
+        task.arg("-sk").arg(Path::new(MIRROR_MOUNT).join(config.restic_host()?).display());
+        let output = backend::run("mirror", Self::command(config, vec![], &task.to_shell(), true)?, None).await?;
+        let kilobytes: u64 = output
+            .split_whitespace()
+            .next()
+            .and_then(|k| k.parse().ok())
+            .ok_or_else(|| SerializableError::backend(format!("unexpected du output: {}", output.trim())))?;
+        Ok(RepositoryStats {
+            snapshots: self.snapshots(config).await?.len() as u64,
+            stored_bytes: kilobytes * 1024,
+        })
+    }
+}
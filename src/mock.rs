@@ -0,0 +1,285 @@
+//! A fake docker cli for the tests, so that whole runs can go through without a docker daemon.
+
+use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf, sync::Mutex};
+
+use crate::{backend::{self, BackendType}, config::Config, events::Events, plan::Runner, service::Service, state::RunRecord, ArchiveFailure, SerializableError};
+
+/// What the fake docker does when its arguments contain `pattern`.
+struct Rule {
+    pattern: String,
+    stdout: String,
+    stderr: String,
+    exit: i32,
+}
+
+/// A fake docker cli in its own temporary directory, answering with the first rule whose pattern
+/// its arguments contain, and exiting successfully without output when none does. Every command
+/// it receives is recorded.
+pub(crate) struct MockDocker {
+    dir: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl MockDocker {
+    /// A fake docker with no containers: stopping one fails, as there is nothing to stop.
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("hoarder-mock-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("intermediate")).expect("the temporary directory is writable");
+        fs::write(dir.join("password"), "password").expect("the temporary directory is writable");
+        Self { dir, rules: vec![] }.fail(" stop ", 1, "no such container")
+    }
+
+    /// Prints `stdout` when the arguments contain `pattern`.
+    pub(crate) fn on(mut self, pattern: &str, stdout: &str) -> Self {
+        self.rules.push(Rule { pattern: pattern.to_owned(), stdout: stdout.to_owned(), stderr: String::new(), exit: 0 });
+        self
+    }
+
+    /// Exits with `exit`, printing `stderr`, when the arguments contain `pattern`.
+    pub(crate) fn fail(mut self, pattern: &str, exit: i32, stderr: &str) -> Self {
+        self.rules.push(Rule { pattern: pattern.to_owned(), stdout: String::new(), stderr: stderr.to_owned(), exit });
+        self
+    }
+
+    /// Answers the queries about the compose project `project`: it has the containers of
+    /// `services`, all running, and the named volumes `volumes`.
+    pub(crate) fn project(self, project: &str, services: &[&str], volumes: &[&str]) -> Self {
+        let services = services.iter().map(|s| format!("{}\n", s)).collect::<String>();
+        let volumes = volumes.iter().map(|v| format!("{}_{}\n", project, v)).collect::<String>();
+        self.on("compose ls", &format!(r#"[{{"Name":"{}","Status":"running(1)"}}]"#, project))
+            .on(&format!("compose -p {} ps", project), &services)
+            .on("volume ls", &volumes)
+    }
+
+    /// Writes the fake docker script, rules first.
+    fn install(&self) -> PathBuf {
+        let quote = |s: &str| format!("'{}'", s.replace('\'', r"'\''"));
+        let mut script = format!("#!/bin/sh\nprintf '%s\\n' \"$*\" >> {}\ncase \"$*\" in\n", quote(&self.dir.join("commands").display().to_string()));
+        for (i, rule) in self.rules.iter().enumerate() {
+            let stdout = self.dir.join(format!("stdout-{}", i));
+            let stderr = self.dir.join(format!("stderr-{}", i));
+            fs::write(&stdout, &rule.stdout).expect("the temporary directory is writable");
+            fs::write(&stderr, &rule.stderr).expect("the temporary directory is writable");
+            script.push_str(&format!(
+                "  *{}*) cat {}; cat {} >&2; exit {};;\n",
+                quote(&rule.pattern),
+                quote(&stdout.display().to_string()),
+                quote(&stderr.display().to_string()),
+                rule.exit,
+            ));
+        }
+        script.push_str("esac\nexit 0\n");
+        let path = self.dir.join("docker");
+        fs::write(&path, script).expect("the temporary directory is writable");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).expect("the fake docker can be made executable");
+        path
+    }
+
+    /// A configuration running its docker commands with the fake docker, backing up to restic
+    /// from an intermediate directory in the temporary directory.
+    pub(crate) fn config(&self) -> Config {
+        let yaml = format!(
+            "intermediate_path: {}\nrestic_host: test\nrestic_password_file: {}\ndocker_binary: {}\n",
+            self.dir.join("intermediate").display(),
+            self.dir.join("password").display(),
+            self.install().display(),
+        );
+        serde_yaml::from_str(&yaml).expect("the mock configuration is valid")
+    }
+
+    /// Every command the fake docker received, as its space separated arguments.
+    pub(crate) fn commands(&self) -> Vec<String> {
+        fs::read_to_string(self.dir.join("commands"))
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Whether a command containing every one of `parts` was received.
+    pub(crate) fn ran(&self, parts: &[&str]) -> bool {
+        self.commands().iter().any(|c| parts.iter().all(|p| c.contains(p)))
+    }
+
+    /// Where the dumps are staged.
+    pub(crate) fn intermediate(&self) -> PathBuf {
+        self.dir.join("intermediate")
+    }
+}
+
+impl Drop for MockDocker {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Runs a backup of `services` like `hoarder backup` does, returning the archives that failed.
+pub(crate) async fn run(config: &Config, services: Vec<Service>) -> Result<Vec<ArchiveFailure>, SerializableError> {
+    let backends = backend::used(config, &services)
+        .into_iter()
+        .map(|b: BackendType| (b, backend::open_type(b)))
+        .collect::<Vec<_>>();
+    let run = Mutex::new(RunRecord::start(String::new()));
+    Runner::new(config, services).execute(&backends, false, &run, &Events::default()).await
+}
+
+#[tokio::test]
+async fn test_run_compose_named_volume() {
+    use crate::service::ServiceBuilder;
+
+    let docker = MockDocker::new("named-volume").project("app", &["db"], &["data"]);
+    let config = docker.config();
+    let service = ServiceBuilder::new("app").named_volume("data", "data", vec![]).build().unwrap();
+    assert!(run(&config, vec![service]).await.unwrap().is_empty());
+    assert!(docker.ran(&["run ", "-v app_data:/restic/app/data:ro", "--name hoarder-restic"]));
+    assert!(docker.ran(&["exec hoarder-restic restic cat config"]));
+    assert!(docker.ran(&["exec hoarder-restic restic backup /restic/app "]));
+    assert_eq!(docker.commands().last().map(String::as_str), Some("stop hoarder-restic"));
+}
+
+#[tokio::test]
+async fn test_run_volume() {
+    use crate::{service::ServiceBuilder, DockerInputType};
+
+    let docker = MockDocker::new("volume").project("app", &[], &[]).on("volume ls", "shared\n");
+    let config = docker.config();
+    let service = ServiceBuilder::new("app")
+        .archive("shared", DockerInputType::Volume { name: "shared".to_owned(), filter: None })
+        .build()
+        .unwrap();
+    assert!(run(&config, vec![service]).await.unwrap().is_empty());
+    assert!(docker.ran(&["run ", "-v shared:/restic/app/shared:ro"]));
+    assert!(docker.ran(&["restic backup /restic/app "]));
+}
+
+#[tokio::test]
+async fn test_run_compose_bound_volume() {
+    use crate::service::ServiceBuilder;
+
+    let docker = MockDocker::new("bound-volume")
+        .on("ps web -a --format {{.ID}}", "0123abcd\n")
+        .on("inspect 0123abcd", r#"[{"Mounts":[{"Type":"bind","Source":"/srv/www","Destination":"/var/www"}]}]"#)
+        .project("app", &["web"], &[]);
+    let config = docker.config();
+    let service = ServiceBuilder::new("app").bound_volume("www", "web", "/var/www", vec![]).build().unwrap();
+    assert!(run(&config, vec![service]).await.unwrap().is_empty());
+    assert!(docker.ran(&["run ", "-v /srv/www:/restic/app/www:ro"]));
+
+    // a source deleted from the host fails the archive rather than backing up an empty directory
+    let docker = MockDocker::new("bound-volume-gone")
+        .on("ps web -a --format {{.ID}}", "0123abcd\n")
+        .on("inspect 0123abcd", r#"[{"Mounts":[{"Type":"bind","Source":"/srv/www","Destination":"/var/www"}]}]"#)
+        .fail("type=bind,source=/srv/www", 125, "docker: Error response from daemon: invalid mount config for type \"bind\": bind source path does not exist: /srv/www")
+        .project("app", &["web"], &[]);
+    let config = docker.config();
+    let service = ServiceBuilder::new("app").bound_volume("www", "web", "/var/www", vec![]).build().unwrap();
+    let failed = run(&config, vec![service]).await.unwrap();
+    assert!(matches!(&failed[..], [ArchiveFailure { error: SerializableError::Missing { .. }, .. }]));
+    assert!(!docker.ran(&["-v /srv/www:/restic/app/www:ro"]));
+}
+
+#[tokio::test]
+async fn test_run_exec_stdout() {
+    use crate::{service::ServiceBuilder, ShellTask};
+
+    let docker = MockDocker::new("exec-stdout")
+        .on("exec -i db pg_dumpall", "-- PostgreSQL database cluster dump\n")
+        .project("app", &["db"], &[]);
+    let config = docker.config();
+    let service = ServiceBuilder::new("app").exec_stdout("db", "db", ShellTask::new("pg_dumpall"), "sql").build().unwrap();
+    assert!(run(&config, vec![service]).await.unwrap().is_empty());
+    let dump = fs::read_to_string(docker.intermediate().join("app").join("db.sql")).unwrap();
+    assert_eq!(dump, "-- PostgreSQL database cluster dump\n");
+    assert!(docker.ran(&["compose -p app exec -i db pg_dumpall"]));
+    assert!(docker.ran(&["restic backup /restic/app "]));
+}
+
+#[tokio::test]
+async fn test_run_failures() {
+    use crate::{service::ServiceBuilder, ShellTask};
+
+    // a failing dump and a volume gone since the run started fail their archives only
+    let docker = MockDocker::new("failures")
+        .fail("exec -i db pg_dumpall", 1, "authentication failed")
+        .fail("volume inspect app_data", 1, "no such volume")
+        .project("app", &["db"], &["data", "cache"]);
+    let config = docker.config();
+    let service = ServiceBuilder::new("app")
+        .exec_stdout("db", "db", ShellTask::new("pg_dumpall"), "sql")
+        .named_volume("data", "data", vec![])
+        .named_volume("cache", "cache", vec![])
+        .build()
+        .unwrap();
+    let failed = run(&config, vec![service]).await.unwrap();
+    assert!(matches!(&failed[..], [
+        ArchiveFailure { archive: data, error: SerializableError::Missing { .. }, .. },
+        ArchiveFailure { archive: db, error: SerializableError::Docker { stderr: Some(stderr), .. }, .. },
+    ] if data == "data" && db == "db" && stderr.contains("authentication failed")));
+    assert!(docker.ran(&["-v app_cache:/restic/app/cache:ro"]));
+
+    // an unreachable repository stops the run before anything is dumped
+    let docker = MockDocker::new("unreachable")
+        .fail("restic cat config", 1, "Fatal: unable to open config file")
+        .project("app", &["db"], &[]);
+    let config = docker.config();
+    let service = ServiceBuilder::new("app").exec_stdout("db", "db", ShellTask::new("pg_dumpall"), "sql").build().unwrap();
+    assert!(run(&config, vec![service]).await.is_err());
+    assert!(!docker.ran(&["pg_dumpall"]));
+    assert_eq!(docker.commands().last().map(String::as_str), Some("stop hoarder-restic"));
+}
+
+#[tokio::test]
+async fn test_run_dry_run_stage() {
+    use crate::{config::DryRun, service::ServiceBuilder, ShellTask};
+
+    let docker = MockDocker::new("dry-run-stage")
+        .on("exec -i db pg_dumpall", "-- PostgreSQL database cluster dump\n")
+        .project("app", &["db"], &["data"]);
+    let mut config = docker.config();
+    config.set_dry_run(DryRun::Stage);
+    let service = ServiceBuilder::new("app")
+        .exec_stdout("db", "db", ShellTask::new("pg_dumpall"), "sql")
+        .named_volume("data", "data", vec![])
+        .build()
+        .unwrap();
+    assert!(run(&config, vec![service]).await.unwrap().is_empty());
+    assert!(docker.intermediate().join("app").join("db.sql").exists());
+    assert!(!docker.ran(&["hoarder-restic"]));
+}
+
+#[tokio::test]
+async fn test_plan() {
+    use crate::{plan::PlannedAction, service::ServiceBuilder};
+
+    let docker = MockDocker::new("plan")
+        .on("volume inspect app_data", "[{}]\n")
+        .project("app", &["db"], &["data"]);
+    let config = docker.config();
+    let service = ServiceBuilder::new("app").named_volume("data", "data", vec![]).build().unwrap();
+    let plan = Runner::new(&config, vec![service]).plan().await.unwrap();
+    assert!(matches!(&plan.services[0].archives[0].action, PlannedAction::Mount { mount: Some(mount) } if mount == "app_data:/restic/app/data:ro"));
+    let [restic] = &plan.backends[..] else {
+        panic!("only restic is planned: {:?}", plan.backends);
+    };
+    assert!(matches!(&restic.commands[..], [command] if command.purpose == "backup app"
+        && command.argv.windows(2).any(|w| w == ["backup", "/restic/app"])));
+    // planning runs nothing
+    assert!(!docker.ran(&["hoarder-restic"]));
+
+    // an archive that can't be resolved fails on its own, the rest is still planned
+    let docker = MockDocker::new("plan-failed")
+        .fail("ps --status running", 1, "Cannot connect to the Docker daemon")
+        .on("volume inspect app_data", "[{}]\n")
+        .project("app", &["db"], &["data"]);
+    let config = docker.config();
+    let mut service = ServiceBuilder::new("app")
+        .exec_stdout("db", "db", crate::ShellTask::new("pg_dumpall"), "sql")
+        .named_volume("data", "data", vec![])
+        .build()
+        .unwrap();
+    service.archives[0].run_if_stopped = true;
+    let plan = Runner::new(&config, vec![service]).plan().await.unwrap();
+    assert!(matches!(&plan.services[0].archives[0].action, PlannedAction::Failed { error } if error.contains("Cannot connect")));
+    assert!(matches!(&plan.services[0].archives[1].action, PlannedAction::Mount { .. }));
+}
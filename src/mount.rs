@@ -0,0 +1,66 @@
+use std::path::{Path, PathBuf};
+
+use log::info;
+use tokio::process::Command;
+
+use crate::{audit::Audited, backend::BackendType, config::Config, image, restic, DockerBinding, SerializableError, ShellTask};
+
+/// Where the mountpoint is bound inside the restic container.
+static MOUNT_TARGET: &str = "/mnt/hoarder";
+
+/// Mounts hoarder's snapshots on `mountpoint` with `restic mount`, until interrupted. The mount
+/// is made inside the restic container and propagated to the host, unless `native` asks for the
+/// host's restic and FUSE.
+pub(crate) async fn mount(config: &Config, mountpoint: &Path, native: bool) -> Result<(), SerializableError> {
+    if config.backend() != BackendType::Restic {
+        return Err(SerializableError::config("mount is only supported by the restic backend"));
+    }
+    std::fs::create_dir_all(mountpoint)?;
+    let mountpoint = mountpoint.canonicalize()?;
+    let mut task = ShellTask::new("restic");
+    task.arg("mount")
+        .args(["--tag", "hoarder", "--host"])
+        .arg(config.restic_host()?);
+
+    let mut command = match native {
+        true => {
+            task.arg(mountpoint.display());
+            let mut args = task.get_args().into_iter();
+            let mut command = Command::new(args.next().expect("restic is the first argument"));
+            command.args(args).envs(restic::native_env(config)?);
+            command
+        }
+        false => {
+            image::verify(config).await?;
+            task.args(["--allow-other", MOUNT_TARGET]);
+            restic::command_with_options(
+                config,
+                vec![DockerBinding {
+                    volume: mountpoint.display().to_string(),
+                    path: PathBuf::from(MOUNT_TARGET),
+                    flags: Some("rshared".to_owned()),
+                }],
+                ["--cap-add", "SYS_ADMIN", "--device", "/dev/fuse", "--security-opt", "apparmor:unconfined"]
+                    .into_iter()
+                    .map(str::to_owned)
+                    .collect(),
+                task,
+            )?
+        }
+    };
+    info!("mounting the snapshots on {}, press ctrl-c to unmount", mountpoint.display());
+    let mut child = command.audited_spawn()
+        .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+    // ctrl-c reaches restic too, wait for it to unmount rather than dying first
+    let status = loop {
+        tokio::select! {
+            status = child.wait() => break status?,
+            _ = tokio::signal::ctrl_c() => info!("unmounting {}", mountpoint.display()),
+        }
+    };
+    // restic unmounts and exits cleanly on SIGINT
+    if !status.success() && status.code() != Some(130) {
+        return Err(SerializableError::restic(format!("restic mount exited with {}", status)));
+    }
+    Ok(())
+}
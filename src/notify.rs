@@ -0,0 +1,427 @@
+use std::{path::PathBuf, process::Stdio, time::Duration};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::{audit::Audited, error::{ArchiveFailure, ErrorReport}, plugin::PluginNotifier, secret::Secret, verify::VerifyRecord, SerializableError};
+
+/// Where the outcome of a run is sent, under `hooks` in the configuration.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct NotifyConfig {
+    /// notifiers, each told about the outcomes it is interested in
+    #[serde(default)]
+    pub(crate) notifiers: Vec<NotifierConfig>,
+}
+
+/// How a run ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Outcome {
+    Success,
+    Partial,
+    Degraded,
+    Failure,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct NotifierConfig {
+    #[serde(flatten)]
+    pub(crate) kind: NotifierKind,
+    /// outcomes the notifier is told about, every one if empty
+    #[serde(default)]
+    pub(crate) on: Vec<Outcome>,
+    /// how many more times a failed notification is attempted
+    #[serde(default)]
+    pub(crate) retries: u32,
+    /// seconds to wait before the first retry, doubled after every further failure
+    #[serde(default = "NotifierConfig::default_retry_delay")]
+    pub(crate) retry_delay: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum NotifierKind {
+    /// the payload is posted as json, a GET is sent when there is none
+    Http { url: String },
+    /// the command is run with the payload as json on stdin and the outcome in `HOARDER_OUTCOME`
+    Command { command: Vec<String> },
+    /// a message is published to the topic url of an ntfy server
+    Ntfy {
+        url: String,
+        /// file holding the access token of the topic
+        #[serde(default)]
+        token_file: Option<String>,
+        /// access token of the topic, usually a secret reference, over the file
+        #[serde(default)]
+        token: Option<Secret>,
+    },
+    /// a message is posted to a Slack incoming webhook
+    Slack { webhook: String },
+    /// an external executable is sent a `notify` request, see [`crate::plugin::PluginInput`]
+    Plugin {
+        plugin: PathBuf,
+        /// handed to the plugin as is
+        #[serde(default)]
+        options: serde_json::Value,
+    },
+}
+
+impl NotifierConfig {
+    pub(crate) fn default_retry_delay() -> u64 {
+        10
+    }
+}
+
+/// What the degraded notification carries.
+#[derive(Serialize)]
+pub(crate) struct DegradedReport<'a> {
+    pub(crate) reason: &'a str,
+    pub(crate) failed: &'a [ArchiveFailure],
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    pub(crate) warnings: &'a [String],
+}
+
+/// What the partial notification carries.
+#[derive(Serialize)]
+pub(crate) struct PartialReport<'a> {
+    pub(crate) failed: &'a [ArchiveFailure],
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    pub(crate) warnings: &'a [String],
+}
+
+/// The outcome of a run, as handed to every notifier. `warnings` are the problems of the run that
+/// didn't fail an archive, like the drift of its plan.
+pub(crate) enum Notification<'a> {
+    Success { warnings: &'a [String] },
+    Partial { failed: &'a [ArchiveFailure], warnings: &'a [String] },
+    Degraded { reason: &'a str, failed: &'a [ArchiveFailure], warnings: &'a [String] },
+    Failure { error: &'a SerializableError },
+    /// a restore test ran, a failure if the restored files didn't match
+    Verify { record: &'a VerifyRecord },
+}
+
+impl Notification<'_> {
+    pub(crate) fn outcome(&self) -> Outcome {
+        match self {
+            Notification::Success { .. } => Outcome::Success,
+            Notification::Partial { .. } => Outcome::Partial,
+            Notification::Degraded { .. } => Outcome::Degraded,
+            Notification::Failure { .. } => Outcome::Failure,
+            Notification::Verify { record } if record.ok() => Outcome::Success,
+            Notification::Verify { .. } => Outcome::Failure,
+        }
+    }
+
+    /// The json body of the notification, none for a success without warnings.
+    pub(crate) fn payload(&self) -> Result<Option<serde_json::Value>, SerializableError> {
+        Ok(match self {
+            Notification::Success { warnings: [] } => None,
+            Notification::Success { warnings } => Some(serde_json::json!({ "warnings": warnings })),
+            Notification::Partial { failed, warnings } => Some(serde_json::to_value(PartialReport { failed, warnings })?),
+            Notification::Degraded { reason, failed, warnings } => Some(serde_json::to_value(DegradedReport { reason, failed, warnings })?),
+            Notification::Failure { error } => Some(serde_json::to_value(ErrorReport::new(error))?),
+            Notification::Verify { record } => Some(serde_json::to_value(record)?),
+        })
+    }
+
+    /// A short message for humans.
+    pub(crate) fn summary(&self) -> String {
+        let summary = match self {
+            Notification::Success { .. } => "backup completed successfully".to_owned(),
+            Notification::Partial { failed, .. } => format!(
+                "backup completed, {} failed: {}",
+                failed.len(),
+                failed.iter().map(|f| f.to_string()).collect::<Vec<_>>().join("; "),
+            ),
+            Notification::Degraded { reason, failed: [], .. } => format!("backup completed degraded: {}", reason),
+            Notification::Degraded { reason, failed, .. } => format!("backup completed degraded: {}, {} failed", reason, failed.len()),
+            Notification::Failure { error } => format!("backup failed: {}", error),
+            Notification::Verify { record } => match (&record.error, record.mismatched.len()) {
+                (Some(e), _) => format!("restore test of {}/{} failed: {}", record.service, record.archive, e),
+                (None, 0) => format!("restore test of {}/{}: the {} restored files match", record.service, record.archive, record.compared),
+                (None, mismatched) => format!(
+                    "restore test of {}/{}: {} of the {} restored files don't match",
+                    record.service,
+                    record.archive,
+                    mismatched,
+                    record.compared,
+                ),
+            },
+        };
+        match self.warnings() {
+            [] => summary,
+            warnings => format!("{}, {} warning(s): {}", summary, warnings.len(), warnings.join("; ")),
+        }
+    }
+
+    fn warnings(&self) -> &[String] {
+        match self {
+            Notification::Success { warnings } | Notification::Partial { warnings, .. } | Notification::Degraded { warnings, .. } => warnings,
+            Notification::Failure { .. } | Notification::Verify { .. } => &[],
+        }
+    }
+}
+
+/// A sink the outcome of a run is sent to.
+#[async_trait]
+pub(crate) trait NotificationProvider: Send + Sync {
+    /// Names the provider in logs.
+    fn name(&self) -> String;
+
+    async fn notify(&self, notification: &Notification<'_>) -> Result<(), SerializableError>;
+}
+
+struct Http {
+    url: String,
+}
+
+#[async_trait]
+impl NotificationProvider for Http {
+    fn name(&self) -> String {
+        "http".to_owned()
+    }
+
+    async fn notify(&self, notification: &Notification<'_>) -> Result<(), SerializableError> {
+        let cli = Client::new();
+        let request = match notification.payload()? {
+            Some(payload) => cli.post(&self.url).json(&payload),
+            None => cli.get(&self.url),
+        };
+        let res = request
+            .send()
+            .await
+            .map_err(|e| SerializableError::hook(format!("failed to send request: {}", e)))?;
+        check_status(res).await
+    }
+}
+
+struct CommandNotifier {
+    command: Vec<String>,
+}
+
+#[async_trait]
+impl NotificationProvider for CommandNotifier {
+    fn name(&self) -> String {
+        format!("command {}", self.command.first().map(String::as_str).unwrap_or_default())
+    }
+
+    async fn notify(&self, notification: &Notification<'_>) -> Result<(), SerializableError> {
+        let Some((program, args)) = self.command.split_first() else {
+            return Err(SerializableError::config("notifier command can't be empty"));
+        };
+        let payload = serde_json::to_vec(&notification.payload()?)?;
+        let mut handle = Command::new(program)
+            .args(args)
+            .env("HOARDER_OUTCOME", serde_json::to_value(notification.outcome())?.as_str().unwrap_or_default())
+            .stdin(Stdio::piped())
+            .kill_on_drop(true)
+            .audited_spawn()?;
+        if let Some(mut stdin) = handle.stdin.take() {
+            stdin.write_all(&payload).await?;
+        }
+        let status = handle.wait().await?;
+        if !status.success() {
+            return Err(SerializableError::hook(format!("command exited with {}", status)));
+        }
+        Ok(())
+    }
+}
+
+struct Ntfy {
+    url: String,
+    token_file: Option<String>,
+    token: Option<Secret>,
+}
+
+#[async_trait]
+impl NotificationProvider for Ntfy {
+    fn name(&self) -> String {
+        "ntfy".to_owned()
+    }
+
+    async fn notify(&self, notification: &Notification<'_>) -> Result<(), SerializableError> {
+        let (priority, tag) = match notification.outcome() {
+            Outcome::Success => ("default", "white_check_mark"),
+            Outcome::Partial | Outcome::Degraded => ("high", "warning"),
+            Outcome::Failure => ("urgent", "rotating_light"),
+        };
+        let mut request = Client::new()
+            .post(&self.url)
+            .header("Title", "hoarder")
+            .header("Priority", priority)
+            .header("Tags", tag)
+            .body(notification.summary());
+        if let Some(Secret(token)) = &self.token {
+            request = request.bearer_auth(token);
+        } else if let Some(token_file) = &self.token_file {
+            let token = std::fs::read_to_string(token_file)?;
+            request = request.bearer_auth(token.trim());
+        }
+        let res = request
+            .send()
+            .await
+            .map_err(|e| SerializableError::hook(format!("failed to send request: {}", e)))?;
+        check_status(res).await
+    }
+}
+
+struct Slack {
+    webhook: String,
+}
+
+#[async_trait]
+impl NotificationProvider for Slack {
+    fn name(&self) -> String {
+        "slack".to_owned()
+    }
+
+    async fn notify(&self, notification: &Notification<'_>) -> Result<(), SerializableError> {
+        let res = Client::new()
+            .post(&self.webhook)
+            .json(&serde_json::json!({ "text": format!("hoarder: {}", notification.summary()) }))
+            .send()
+            .await
+            .map_err(|e| SerializableError::hook(format!("failed to send request: {}", e)))?;
+        check_status(res).await
+    }
+}
+
+/// A notification shown by the desktop session hoarder runs in.
+struct Desktop;
+
+#[async_trait]
+impl NotificationProvider for Desktop {
+    fn name(&self) -> String {
+        "desktop".to_owned()
+    }
+
+    async fn notify(&self, notification: &Notification<'_>) -> Result<(), SerializableError> {
+        let title = format!("hoarder: {}", serde_json::to_value(notification.outcome())?.as_str().unwrap_or_default());
+        let body = notification.summary();
+        // the notification server is talked to with blocking calls
+        tokio::task::spawn_blocking(move || notify_rust::Notification::new()
+            .appname("hoarder")
+            .summary(&title)
+            .body(&body)
+            .show()
+            .map(|_| ()))
+            .await
+            .map_err(|e| SerializableError::hook(format!("the desktop notification panicked: {}", e)))?
+            .map_err(|e| SerializableError::hook(format!("failed to show the notification: {}", e)))
+    }
+}
+
+/// Whether there is a desktop session to show notifications in: always on macOS and Windows, a
+/// graphical session elsewhere.
+pub(crate) fn desktop_session() -> bool {
+    cfg!(any(target_os = "macos", target_os = "windows"))
+        || ["DISPLAY", "WAYLAND_DISPLAY"].iter().any(|var| std::env::var_os(var).is_some_and(|v| !v.is_empty()))
+}
+
+async fn check_status(res: reqwest::Response) -> Result<(), SerializableError> {
+    if res.status().is_success() {
+        return Ok(());
+    }
+    Err(SerializableError::hook(format!("request failed with status: {}", res.status())))
+}
+
+/// A provider along with when and how hard to notify it.
+struct Subscription {
+    provider: Box<dyn NotificationProvider>,
+    on: Vec<Outcome>,
+    retries: u32,
+    retry_delay: u64,
+}
+
+/// Sends every notification to the providers interested in its outcome.
+pub(crate) struct Dispatcher {
+    subscriptions: Vec<Subscription>,
+}
+
+impl NotifyConfig {
+    pub(crate) fn dispatcher(&self) -> Dispatcher {
+        let mut subscriptions = vec![];
+        for notifier in &self.notifiers {
+            let provider: Box<dyn NotificationProvider> = match &notifier.kind {
+                NotifierKind::Http { url } => Box::new(Http { url: url.clone() }),
+                NotifierKind::Command { command } => Box::new(CommandNotifier { command: command.clone() }),
+                NotifierKind::Ntfy { url, token_file, token } => Box::new(Ntfy {
+                    url: url.clone(),
+                    token_file: token_file.clone(),
+                    token: token.clone(),
+                }),
+                NotifierKind::Slack { webhook } => Box::new(Slack { webhook: webhook.clone() }),
+                NotifierKind::Plugin { plugin, options } => Box::new(PluginNotifier { plugin: plugin.clone(), options: options.clone() }),
+            };
+            subscriptions.push(Subscription {
+                provider,
+                on: notifier.on.clone(),
+                retries: notifier.retries,
+                retry_delay: notifier.retry_delay,
+            });
+        }
+        Dispatcher { subscriptions }
+    }
+}
+
+impl Dispatcher {
+    /// Also shows the outcome of the run on the desktop, whatever it is.
+    pub(crate) fn with_desktop(mut self) -> Self {
+        self.subscriptions.push(Subscription {
+            provider: Box::new(Desktop),
+            on: vec![],
+            retries: 0,
+            retry_delay: NotifierConfig::default_retry_delay(),
+        });
+        self
+    }
+
+    /// Notifies every interested provider, even when some of them fail, returning the failures.
+    pub(crate) async fn notify(&self, notification: Notification<'_>) -> Result<(), SerializableError> {
+        let outcome = notification.outcome();
+        let mut problems = vec![];
+        for subscription in &self.subscriptions {
+            if !subscription.on.is_empty() && !subscription.on.contains(&outcome) {
+                continue;
+            }
+            let name = subscription.provider.name();
+            let mut attempt = 0;
+            loop {
+                match subscription.provider.notify(&notification).await {
+                    Ok(()) => info!("{}: {:?} notification sent", name, outcome),
+                    Err(e) if attempt < subscription.retries => {
+                        let delay = Duration::from_secs(subscription.retry_delay.saturating_mul(1 << attempt.min(16)));
+                        attempt += 1;
+                        warn!("{}: {}, retrying in {}s (attempt {}/{})", name, e, delay.as_secs(), attempt, subscription.retries);
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                    Err(e) => problems.push(format!("{}: {}", name, e)),
+                }
+                break;
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(SerializableError::hook(format!("failed to notify: {}", problems.join("; "))))
+        }
+    }
+}
+
+#[test]
+fn test_warnings() {
+    let warnings = vec!["app: service was removed".to_owned()];
+    assert!(Notification::Success { warnings: &[] }.payload().unwrap().is_none());
+    let success = Notification::Success { warnings: &warnings };
+    assert_eq!(success.payload().unwrap(), Some(serde_json::json!({ "warnings": ["app: service was removed"] })));
+    assert_eq!(success.summary(), "backup completed successfully, 1 warning(s): app: service was removed");
+
+    let failed = vec![ArchiveFailure::new("db", "dump", SerializableError::dump("exit code 1"))];
+    let partial = Notification::Partial { failed: &failed, warnings: &warnings };
+    assert_eq!(partial.payload().unwrap().unwrap()["warnings"], serde_json::json!(["app: service was removed"]));
+    assert_eq!(partial.payload().unwrap().unwrap()["failed"][0]["archive"], "dump");
+}
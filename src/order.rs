@@ -0,0 +1,102 @@
+//! The order services and archives are backed up in, following their `depends_on`.
+
+use std::collections::HashSet;
+
+use crate::service::Service;
+
+/// Indices of `items`, each one after the items it depends on, otherwise in their original order,
+/// then the ones that couldn't be placed as they are in a cycle or depend on one. Dependencies that
+/// aren't in `items`, like skipped ones, are ignored.
+fn resolve(items: &[(&str, &[String])]) -> (Vec<usize>, Vec<usize>) {
+    let names = items.iter().map(|(name, _)| *name).collect::<HashSet<_>>();
+    let mut placed = HashSet::new();
+    let mut order = vec![];
+    while let Some((i, (name, _))) = items.iter().enumerate().find(|(i, (_, depends_on))| {
+        !order.contains(i) && depends_on.iter().all(|d| !names.contains(d.as_str()) || placed.contains(d.as_str()))
+    }) {
+        placed.insert(*name);
+        order.push(i);
+    }
+    let unplaced = (0..items.len()).filter(|i| !order.contains(i)).collect();
+    (order, unplaced)
+}
+
+/// The order of `items`, with the ones in a cycle left at the end in their original order.
+fn order(items: &[(&str, &[String])]) -> Vec<usize> {
+    let (mut order, unplaced) = resolve(items);
+    order.extend(unplaced);
+    order
+}
+
+/// A cycle of `items`, as the names along it, if there is one.
+fn cycle(items: &[(&str, &[String])]) -> Option<Vec<String>> {
+    let (_, unplaced) = resolve(items);
+    let unplaced = unplaced.into_iter().map(|i| items[i]).collect::<Vec<_>>();
+    // every item left depends on another one left, following them ends in a cycle
+    let (mut current, _) = *unplaced.first()?;
+    let mut path: Vec<&str> = vec![];
+    loop {
+        if let Some(start) = path.iter().position(|n| *n == current) {
+            let mut cycle = path[start..].iter().map(|n| n.to_string()).collect::<Vec<_>>();
+            cycle.push(current.to_owned());
+            return Some(cycle);
+        }
+        path.push(current);
+        let (_, depends_on) = unplaced.iter().find(|(name, _)| *name == current)?;
+        current = depends_on.iter().find(|d| unplaced.iter().any(|(name, _)| name == d))?;
+    }
+}
+
+/// Sorts `services`, then the archives of each of them, after what they depend on.
+pub(crate) fn sort(services: &mut Vec<Service>) {
+    let sorted = order(&services.iter().map(|s| (s.name.as_str(), s.depends_on.as_slice())).collect::<Vec<_>>());
+    let mut taken = std::mem::take(services).into_iter().map(Some).collect::<Vec<_>>();
+    services.extend(sorted.into_iter().filter_map(|i| taken[i].take()));
+    for service in services {
+        let sorted = order(&service.archives.iter().map(|a| (a.name.as_str(), a.depends_on.as_slice())).collect::<Vec<_>>());
+        let mut taken = std::mem::take(&mut service.archives).into_iter().map(Some).collect::<Vec<_>>();
+        service.archives.extend(sorted.into_iter().filter_map(|i| taken[i].take()));
+    }
+}
+
+/// Dependencies on services or archives that don't exist, and cycles.
+pub(crate) fn problems(services: &[Service]) -> Vec<String> {
+    let mut problems = vec![];
+    let service_items = services.iter().map(|s| (s.name.as_str(), s.depends_on.as_slice())).collect::<Vec<_>>();
+    for service in services {
+        for dependency in &service.depends_on {
+            if !services.iter().any(|s| &s.name == dependency) {
+                problems.push(format!("{}: depends on service {}, which isn't configured", service.name, dependency));
+            }
+        }
+        let archive_items = service.archives.iter().map(|a| (a.name.as_str(), a.depends_on.as_slice())).collect::<Vec<_>>();
+        for archive in &service.archives {
+            for dependency in &archive.depends_on {
+                if !service.archives.iter().any(|a| &a.name == dependency) {
+                    problems.push(format!("{}: {}: depends on archive {}, which the service doesn't have", service.name, archive.name, dependency));
+                }
+            }
+        }
+        if let Some(cycle) = cycle(&archive_items) {
+            problems.push(format!("{}: the archives depend on each other: {}", service.name, cycle.join(" -> ")));
+        }
+    }
+    if let Some(cycle) = cycle(&service_items) {
+        problems.push(format!("the services depend on each other: {}", cycle.join(" -> ")));
+    }
+    problems
+}
+
+#[test]
+fn test_order() {
+    let deps = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    let (none, db, app, skipped) = (deps(&[]), deps(&["db"]), deps(&["app", "db"]), deps(&["gone"]));
+    let items = [("uploads", app.as_slice()), ("app", db.as_slice()), ("db", none.as_slice()), ("logs", skipped.as_slice())];
+    assert_eq!(order(&items), [2, 1, 0, 3]);
+    assert_eq!(cycle(&items), None);
+
+    let (a, b, c) = (deps(&["b"]), deps(&["c"]), deps(&["b"]));
+    let items = [("first", none.as_slice()), ("a", a.as_slice()), ("b", b.as_slice()), ("c", c.as_slice())];
+    assert_eq!(order(&items), [0, 1, 2, 3]);
+    assert_eq!(cycle(&items).unwrap(), ["b", "c", "b"]);
+}
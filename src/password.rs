@@ -0,0 +1,65 @@
+use log::{info, warn};
+
+use crate::SerializableError;
+
+/// Keyring service the restic passwords are stored under, one entry per repository.
+static KEYRING_SERVICE: &str = "hoarder";
+
+/// The repository restic is given, which the password is remembered for.
+fn repository() -> Result<String, SerializableError> {
+    if let Ok(repository) = std::env::var("RESTIC_REPOSITORY")
+        && !repository.is_empty()
+    {
+        return Ok(repository);
+    }
+    if let Ok(file) = std::env::var("RESTIC_REPOSITORY_FILE")
+        && !file.is_empty()
+    {
+        return Ok(std::fs::read_to_string(file)?.trim().to_owned());
+    }
+    Err(SerializableError::config("remembering the password needs RESTIC_REPOSITORY or RESTIC_REPOSITORY_FILE to be set"))
+}
+
+fn prompt(repository: Option<&str>) -> Result<String, SerializableError> {
+    let password = rpassword::prompt_password(format!("password of {}: ", repository.unwrap_or("the restic repository")))
+        .map_err(|e| SerializableError::config(format!("failed to ask for the password: {}", e)))?;
+    if password.is_empty() {
+        return Err(SerializableError::config("no password was given"));
+    }
+    Ok(password)
+}
+
+/// Asks for the restic password on the terminal. With `remember` the password is looked up in the
+/// OS keyring first, by repository, and stored there once asked for, `ask` asking for it anyway to
+/// replace the stored one. Blocks, keyring lookups included.
+pub(crate) fn interactive(ask: bool, remember: bool) -> Result<String, SerializableError> {
+    if !remember {
+        return prompt(None);
+    }
+    let repository = repository()?;
+    let entry = match keyring::Entry::new(KEYRING_SERVICE, &repository) {
+        Ok(entry) => Some(entry),
+        Err(e) => {
+            warn!("the keyring can't be used, the password won't be remembered: {}", e);
+            None
+        }
+    };
+    if !ask && let Some(entry) = &entry {
+        match entry.get_password() {
+            Ok(password) => {
+                info!("using the password of {} from the keyring", repository);
+                return Ok(password);
+            }
+            Err(keyring::Error::NoEntry) => (),
+            Err(e) => warn!("failed to read the password from the keyring: {}", e),
+        }
+    }
+    let password = prompt(Some(&repository))?;
+    if let Some(entry) = &entry {
+        match entry.set_password(&password) {
+            Ok(()) => info!("the password of {} is remembered in the keyring", repository),
+            Err(e) => warn!("failed to store the password in the keyring: {}", e),
+        }
+    }
+    Ok(password)
+}
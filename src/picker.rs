@@ -0,0 +1,161 @@
+use std::{io::IsTerminal, path::Path};
+
+use chrono::Local;
+use indicatif::HumanBytes;
+
+use crate::{
+    backend,
+    config::Config,
+    restore::{RestoreOptions, SnapshotSelector},
+    service::Service,
+    state::State,
+    SerializableError,
+};
+
+/// Walks the user through picking a service, its archives and a snapshot, filling in `options`.
+/// Returns false if the user gave up before confirming.
+pub(crate) async fn pick(services: &[Service], config: &Config, options: &mut RestoreOptions) -> Result<bool, SerializableError> {
+    needs_terminal()?;
+    if services.is_empty() {
+        return Err(SerializableError::config("no service is configured"));
+    }
+    let state = State::load(config.state_dir()?)?;
+    let last_run = state.last_completed_run();
+
+    let service = &services[select(
+        "service",
+        &services
+            .iter()
+            .map(|s| {
+                let last_success = state.service(&s.name)
+                    .and_then(|s| s.last_success)
+                    .map_or("never backed up".to_owned(), |t| format!("last backed up {}", t.with_timezone(&Local).format("%Y-%m-%d %H:%M")));
+                format!("{} ({} archives, {})", s.name, s.archives.len(), last_success)
+            })
+            .collect::<Vec<_>>(),
+    )?];
+
+    let archives = multi_select(
+        "archives",
+        &service.archives
+            .iter()
+            .map(|a| {
+                let bytes = last_run
+                    .and_then(|r| r.services.get(&service.name))
+                    .and_then(|s| s.archives.get(&a.name))
+                    .and_then(|a| a.bytes);
+                match bytes {
+                    Some(bytes) => format!("{} ({})", a.name, HumanBytes(bytes)),
+                    None => a.name.clone(),
+                }
+            })
+            .collect::<Vec<_>>(),
+    )?;
+
+    let path = Path::new(&config.restic_root()).join(&service.name);
+    let mut snapshots = backend::open_type(backend::primary(config, service))
+        .snapshots(config)
+        .await?
+        .into_iter()
+        .filter(|s| s.covers(&path))
+        .collect::<Vec<_>>();
+    if snapshots.is_empty() {
+        return Err(SerializableError::backend(format!("no snapshot of {} found", service.name)));
+    }
+    snapshots.sort_by_key(|s| std::cmp::Reverse(s.time));
+    let snapshot = &snapshots[select(
+        "snapshot",
+        &snapshots
+            .iter()
+            .map(|s| {
+                let run = state.runs()
+                    .iter()
+                    .find(|r| r.services.get(&service.name).and_then(|s| s.snapshot_id.as_deref()) == Some(s.id.as_str()));
+                let mut description = format!("{} taken at {}", &s.id[..s.id.len().min(8)], s.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"));
+                if let Some(run) = run {
+                    description.push_str(&format!(", run {}", run.id()));
+                    if let Some(added) = run.services.get(&service.name).and_then(|s| s.data_added) {
+                        description.push_str(&format!(", {} added", HumanBytes(added)));
+                    }
+                }
+                description
+            })
+            .collect::<Vec<_>>(),
+    )?];
+
+    options.service = service.name.clone();
+    options.archives = archives.into_iter().map(|i| service.archives[i].name.clone()).collect();
+    options.snapshot = SnapshotSelector::Id(snapshot.id.clone());
+    let summary = format!(
+        "restore {} of {} from snapshot {}{}",
+        options.archives.join(", "),
+        service.name,
+        &snapshot.id[..snapshot.id.len().min(8)],
+        if options.dry_run { " (dry run)" } else { "" },
+    );
+    confirm(&summary)
+}
+
+/// Asks the user to pick one of `items` by number.
+pub(crate) fn select(what: &str, items: &[String]) -> Result<usize, SerializableError> {
+    list(what, items);
+    loop {
+        match prompt(&format!("which {}? [1-{}] ", what, items.len()))?.parse::<usize>() {
+            Ok(i) if (1..=items.len()).contains(&i) => return Ok(i - 1),
+            _ => eprintln!("not a valid choice"),
+        }
+    }
+}
+
+/// Asks the user to pick some of `items` by number, every one of them if the answer is empty.
+fn multi_select(what: &str, items: &[String]) -> Result<Vec<usize>, SerializableError> {
+    list(what, items);
+    loop {
+        let answer = prompt(&format!("which {}? [1-{}, separated by spaces or commas, empty for all] ", what, items.len()))?;
+        if answer.is_empty() {
+            return Ok((0..items.len()).collect());
+        }
+        let picked = answer
+            .split([' ', ','])
+            .filter(|i| !i.is_empty())
+            .map(|i| i.parse::<usize>().ok().filter(|i| (1..=items.len()).contains(i)).map(|i| i - 1))
+            .collect::<Option<Vec<_>>>();
+        match picked {
+            Some(mut picked) => {
+                picked.sort();
+                picked.dedup();
+                return Ok(picked);
+            }
+            None => eprintln!("not a valid choice"),
+        }
+    }
+}
+
+/// Fails unless the answers can be asked for on a terminal.
+pub(crate) fn needs_terminal() -> Result<(), SerializableError> {
+    match std::io::stdin().is_terminal() {
+        true => Ok(()),
+        false => Err(SerializableError::config("--interactive needs a terminal")),
+    }
+}
+
+pub(crate) fn confirm(question: &str) -> Result<bool, SerializableError> {
+    Ok(matches!(prompt(&format!("{}? [y/N] ", question))?.to_lowercase().as_str(), "y" | "yes"))
+}
+
+fn list(what: &str, items: &[String]) {
+    eprintln!("{}:", what);
+    for (i, item) in items.iter().enumerate() {
+        eprintln!("  {}) {}", i + 1, item);
+    }
+}
+
+/// Reads a trimmed line from stdin, failing once it is closed.
+fn prompt(question: &str) -> Result<String, SerializableError> {
+    eprint!("{}", question);
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line)? == 0 {
+        return Err(SerializableError::config("no answer given"));
+    }
+    Ok(line.trim().to_owned())
+}
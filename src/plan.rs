@@ -0,0 +1,340 @@
+use std::{path::PathBuf, sync::Mutex};
+
+use chrono::Utc;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    audit,
+    backend::{self, BackendType, BackupBackend, BackupRequest},
+    config::Config,
+    docker::DockerBinding,
+    dump_command,
+    dump_file,
+    estimate::{self, Estimate},
+    filelist::{self, FileList},
+    events::Events,
+    inner,
+    inspect,
+    input::{InputProvider, StageContext, StagedArchive},
+    intermediate_mount,
+    order,
+    root_mount,
+    routed_mounts,
+    service::{Service, ServiceTask},
+    state::{RunRecord, State},
+    ArchiveFailure,
+    SerializableError,
+};
+
+/// Everything a backup run would do, resolved against docker but without touching anything.
+#[derive(Serialize, Debug)]
+pub(crate) struct BackupPlan {
+    pub(crate) services: Vec<PlannedService>,
+    pub(crate) backends: Vec<PlannedBackend>,
+    /// how much data the run would go through
+    pub(crate) estimate: Estimate,
+    /// every mount of the backup containers, the intermediate directory included
+    #[serde(skip)]
+    pub(crate) mounts: Vec<DockerBinding>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct PlannedService {
+    pub(crate) name: String,
+    pub(crate) compose_project: String,
+    pub(crate) archives: Vec<PlannedArchive>,
+    /// directory backed up as the service's snapshot, inside the backup containers
+    pub(crate) path: PathBuf,
+    /// excludes passed to the backends
+    pub(crate) excludes: Vec<String>,
+    /// excludes matched regardless of case, by the backends that can
+    pub(crate) iexcludes: Vec<String>,
+    /// paths backed up instead of the whole `path`, when they have to be listed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) files: Vec<PathBuf>,
+    /// whether the backup stays on the file system of each path it starts from
+    pub(crate) one_file_system: bool,
+    /// backends the service is backed up to
+    pub(crate) backends: Vec<BackendType>,
+    pub(crate) before: Vec<ServiceTask>,
+    pub(crate) after: Vec<ServiceTask>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct PlannedArchive {
+    pub(crate) name: String,
+    /// where the archive's data comes from
+    pub(crate) source: Option<String>,
+    /// expected size in bytes of the archive, if known
+    pub(crate) estimated_bytes: Option<u64>,
+    #[serde(flatten)]
+    pub(crate) action: PlannedAction,
+}
+
+/// How an archive would end up in the backup.
+#[derive(Serialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub(crate) enum PlannedAction {
+    /// mounted into the backup containers, as a docker `-v` argument, none if a plugin writes it
+    /// into the intermediate directory
+    Mount { mount: Option<String> },
+    /// dumped into the intermediate directory by a docker command
+    Dump { command: Vec<String>, output: PathBuf },
+    /// nothing to back up, like a volume that doesn't exist
+    Missing { reason: String },
+    /// the archive couldn't be resolved
+    Failed { error: String },
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct PlannedBackend {
+    pub(crate) backend: BackendType,
+    /// services backed up to it, in order
+    pub(crate) services: Vec<String>,
+    /// volumes mounted into its container, as docker `-v` arguments
+    pub(crate) mounts: Vec<String>,
+    /// what it would run, in order
+    pub(crate) commands: Vec<PlannedCommand>,
+}
+
+/// A command a backend would run, with the secrets redacted like in the audit log.
+#[derive(Serialize, Debug)]
+pub(crate) struct PlannedCommand {
+    /// what the command is for: backing up a service, forgetting snapshots or checking the
+    /// repository
+    pub(crate) purpose: String,
+    pub(crate) argv: Vec<String>,
+}
+
+/// Plans and runs the backup of a set of services.
+pub(crate) struct Runner<'a> {
+    config: &'a Config,
+    services: Vec<Service>,
+    cancel: CancellationToken,
+}
+
+impl<'a> Runner<'a> {
+    /// Runs `services` in the order of their dependencies.
+    pub(crate) fn new(config: &'a Config, mut services: Vec<Service>) -> Self {
+        order::sort(&mut services);
+        Self { config, services, cancel: CancellationToken::new() }
+    }
+
+    /// Stops the run at the next safe point once `cancel` is cancelled: between archives, or by
+    /// killing the dump or upload in progress. The run then fails with
+    /// [`SerializableError::Cancelled`], after tearing the backends down.
+    pub(crate) fn with_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Gives the services back, once a plan has been shown.
+    pub(crate) fn into_services(self) -> Vec<Service> {
+        self.services
+    }
+
+    /// Resolves what a run would do, only querying docker.
+    pub(crate) async fn plan(&self) -> Result<BackupPlan, SerializableError> {
+        let config = self.config;
+        let intermediate_path = config.intermediate_path()?;
+        let mut mounts = vec![root_mount(config)?];
+        let state = State::load(config.state_dir()?)?;
+        let estimate = estimate::estimate(config, &self.services, &state).await;
+        let mut services = vec![];
+        let mut requests = vec![];
+        for service in &self.services {
+            let compose_project = service.compose_project.clone().unwrap_or(service.name.clone());
+            let mut archives = vec![];
+            let mut excludes = vec![];
+            let mut files = FileList::default();
+            for archive in &service.archives {
+                let compressed = archive.compression.is_some();
+                let mounted = mounts.len();
+                let mut ctx = StageContext {
+                    config,
+                    service: &service.name,
+                    compose_project: &compose_project,
+                    archive: &archive.name,
+                    mounts: &mut mounts,
+                    source: None,
+                    planning: true,
+                    events: None,
+                };
+                let staged = archive.input.stage(&mut ctx).await;
+                let source = ctx.source;
+                let action = match staged {
+                    Ok(StagedArchive::Mounted { exclude }) => {
+                        files.archive(PathBuf::from(config.restic_root()).join(&service.name).join(&archive.name), exclude.as_ref());
+                        excludes.extend(exclude);
+                        PlannedAction::Mount { mount: mounts.get(mounted).cloned().map(|m| m.into_arg()) }
+                    }
+                    Ok(StagedArchive::Dump { service: compose_service, task, ext, user }) => {
+                        let stopped = match archive.run_if_stopped {
+                            true => inspect::running_services(config, &compose_project).await
+                                .map(|running| !running.contains(&compose_service)),
+                            false => Ok(false),
+                        };
+                        match stopped {
+                            Ok(stopped) => {
+                                files.archive(dump_file(&config.restic_root(), &service.name, &archive.name, &ext, compressed), None);
+                                PlannedAction::Dump {
+                                    command: dump_command(config, &compose_project, compose_service, task, user.as_deref(), stopped)
+                                        .into_command()
+                                        .as_std()
+                                        .get_args()
+                                        .map(|a| a.to_string_lossy().into_owned())
+                                        .collect(),
+                                    output: dump_file(&intermediate_path, &service.name, &archive.name, &ext, compressed),
+                                }
+                            }
+                            Err(e) => PlannedAction::Failed { error: e.to_string() },
+                        }
+                    }
+                    Ok(StagedArchive::Missing { reason }) => PlannedAction::Missing { reason },
+                    Err(e) => PlannedAction::Failed { error: e.to_string() },
+                };
+                let estimated_bytes = estimate.archives.get(&format!("{}/{}", service.name, archive.name)).copied().flatten();
+                archives.push(PlannedArchive { name: archive.name.clone(), source, estimated_bytes, action });
+            }
+            let listed = files.needed(service.one_file_system);
+            let backup = BackupRequest::with_excludes(PathBuf::from(config.restic_root()).join(&service.name), excludes)
+                .with_files_from(listed.then(|| filelist::list_path(config, &service.name)), service.one_file_system)
+                .with_retry_lock(config.lock().map(|l| l.wait));
+            requests.push(backup.clone());
+            services.push(PlannedService {
+                name: service.name.clone(),
+                compose_project,
+                archives,
+                path: backup.path,
+                excludes: backup.excludes,
+                iexcludes: backup.iexcludes,
+                files: match listed {
+                    true => files.paths,
+                    false => vec![],
+                },
+                one_file_system: service.one_file_system,
+                backends: service.backends(config),
+                before: service.before.clone(),
+                after: service.after.clone(),
+            });
+        }
+        mounts.push(intermediate_mount(config)?);
+
+        let service_names = services.iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+        let mut backends = vec![];
+        for backend in backend::used(config, &self.services) {
+            let routed = services.iter()
+                .zip(&requests)
+                .filter(|(s, _)| s.backends.contains(&backend))
+                .map(|(s, r)| (s.name.as_str(), r))
+                .collect::<Vec<_>>();
+            let names = routed.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+            backends.push(PlannedBackend {
+                backend,
+                mounts: routed_mounts(config, &mounts, &service_names, &names).into_iter().map(|m| m.into_arg()).collect(),
+                services: names.into_iter().map(str::to_owned).collect(),
+                commands: backend_commands(config, backend, &routed, &state)?,
+            });
+        }
+        Ok(BackupPlan { services, backends, estimate, mounts })
+    }
+
+    /// Backs the services up to `backends`.
+    pub(crate) async fn execute(
+        self,
+        backends: &[(BackendType, Box<dyn BackupBackend>)],
+        resume: bool,
+        run: &Mutex<RunRecord>,
+        events: &Events,
+    ) -> Result<Vec<ArchiveFailure>, SerializableError> {
+        inner(self.services, self.config, backends, resume, run, events, &self.cancel).await
+    }
+}
+
+/// The commands `kind` would run to back up the `routed` services, then to forget the snapshots
+/// and to run the checks that are due.
+fn backend_commands(
+    config: &Config,
+    kind: BackendType,
+    routed: &[(&str, &BackupRequest)],
+    state: &State,
+) -> Result<Vec<PlannedCommand>, SerializableError> {
+    let backend = backend::open_type(kind);
+    let mut commands = vec![];
+    let mut push = |purpose: &str, planned: Vec<Command>| {
+        commands.extend(planned.iter().map(|c| PlannedCommand { purpose: purpose.to_owned(), argv: audit::redacted(c.as_std()) }));
+    };
+    for (service, request) in routed {
+        let request = match backend.ignores_case() {
+            true => (*request).clone(),
+            false => (*request).clone().case_sensitive(kind),
+        };
+        push(&format!("backup {}", service), backend.plan_commands(config, &request)?);
+    }
+    if let Some(retention) = config.retention() {
+        push("forget", backend.plan_forget(config, retention)?);
+    }
+    if !config.dry_run() && backend.deep_checks() {
+        for (check, _) in backend::due_checks(config.check_schedule(), kind, &state.last_checks, Utc::now()) {
+            push("check", backend.plan_check(config, check.read_data_subset.as_deref())?);
+        }
+    }
+    Ok(commands)
+}
+
+/// Prints the plan, as json or for humans.
+pub(crate) fn show(plan: &BackupPlan, json: bool) -> Result<(), SerializableError> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(plan)?);
+        return Ok(());
+    }
+    println!("{} to back up", plan.estimate);
+    for service in &plan.services {
+        let backends = service.backends.iter().map(|b| b.name()).collect::<Vec<_>>();
+        println!("{} (project {}, to {}):", service.name, service.compose_project, backends.join(", "));
+        for task in &service.before {
+            println!("  before: `{}` in service {}", task.task.get_args().into_iter().collect::<Vec<_>>().join(" "), task.service);
+        }
+        for archive in &service.archives {
+            let source = archive.source.as_deref().unwrap_or("(unknown source)");
+            let size = archive.estimated_bytes.map_or("".to_owned(), |b| format!(" (about {})", HumanBytes(b)));
+            match &archive.action {
+                PlannedAction::Mount { mount: Some(mount) } => println!("  {}: mount {}{}: -v {}", archive.name, source, size, mount),
+                PlannedAction::Mount { mount: None } => println!("  {}: {}, written into the intermediate directory", archive.name, source),
+                PlannedAction::Dump { command, output } => println!("  {}: dump `docker {}`{} to {}", archive.name, command.join(" "), size, output.display()),
+                PlannedAction::Missing { reason } => println!("  {}: {}: missing, nothing to back up: {}", archive.name, source, reason),
+                PlannedAction::Failed { error } => println!("  {}: failed: {}", archive.name, error),
+            }
+        }
+        let crossing = match service.one_file_system {
+            true => ", without crossing into other file systems",
+            false => "",
+        };
+        match service.files.is_empty() {
+            true => println!("  backs up {}{}", service.path.display(), crossing),
+            false => println!("  backs up the listed paths{}: {}", crossing, service.files.iter().map(|f| f.display().to_string()).collect::<Vec<_>>().join(", ")),
+        }
+        for exclude in &service.excludes {
+            println!("  excluding {}", exclude);
+        }
+        for exclude in &service.iexcludes {
+            println!("  excluding {}, ignoring the case", exclude);
+        }
+        for task in &service.after {
+            println!("  after: `{}` in service {}", task.task.get_args().into_iter().collect::<Vec<_>>().join(" "), task.service);
+        }
+    }
+    for backend in &plan.backends {
+        println!("{}: {}", backend.backend.name(), if backend.services.is_empty() { "(no services)".to_owned() } else { backend.services.join(", ") });
+        for mount in &backend.mounts {
+            println!("  -v {}", mount);
+        }
+        for command in &backend.commands {
+            println!("  {}: `{}`", command.purpose, command.argv.join(" "));
+        }
+    }
+    Ok(())
+}
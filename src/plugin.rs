@@ -0,0 +1,298 @@
+use std::{path::{Path, PathBuf}, process::Stdio};
+
+use async_trait::async_trait;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, process::Command};
+
+use crate::{
+    audit::Audited,
+    docker::PathExclude,
+    events::Event,
+    input::{InputProvider, StageContext, StagedArchive},
+    notify::{Notification, NotificationProvider, Outcome},
+    SerializableError,
+    ShellTask,
+};
+
+/// An archive staged by an external executable, driven with json lines over its stdin and stdout:
+/// hoarder writes one request, the plugin answers with any number of `{"progress": {"bytes": n}}`
+/// lines followed by either `{"result": ...}` or `{"error": "..."}`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct PluginInput {
+    /// the plugin executable
+    pub(crate) plugin: PathBuf,
+    /// handed to the plugin as is
+    #[serde(default)]
+    pub(crate) options: Value,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Request<'a> {
+    /// what the plugin is and can do, answered with a [`Description`]
+    Describe,
+    /// gets an archive ready, answered with a [`StageResult`]
+    Stage {
+        service: &'a str,
+        compose_project: &'a str,
+        archive: &'a str,
+        /// directory the plugin can write the archive's files to
+        output: PathBuf,
+        /// nothing may be changed, the run is only planned or dry
+        dry_run: bool,
+        options: &'a Value,
+    },
+    /// tells about the outcome of a run, answered with any result
+    Notify {
+        outcome: Outcome,
+        summary: String,
+        payload: Option<Value>,
+        options: &'a Value,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Response {
+    Progress { bytes: u64 },
+    Result(Value),
+    Error(String),
+}
+
+/// What a plugin answers to `describe`.
+#[derive(Deserialize, Debug)]
+pub(crate) struct Description {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) version: Option<String>,
+    /// the requests it handles, among `stage` and `notify`
+    #[serde(default)]
+    pub(crate) capabilities: Vec<String>,
+}
+
+/// How the plugin staged an archive.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum StageResult {
+    /// a host path or a volume to mount into the backup containers
+    Mount {
+        source: String,
+        #[serde(default)]
+        exclude: Vec<PathBuf>,
+    },
+    /// a command dumping the archive to its stdout inside a compose service
+    Dump {
+        service: String,
+        command: Vec<String>,
+        ext: String,
+        #[serde(default)]
+        user: Option<String>,
+    },
+    /// the archive's files were written to the output directory
+    Written,
+    /// nothing to back up
+    Missing { reason: String },
+}
+
+/// Sends `request` to `plugin` and waits for its result, handing its progress to `progress`.
+async fn call(plugin: &Path, request: &Request<'_>, progress: impl Fn(u64) + Send) -> Result<Value, String> {
+    let mut handle = Command::new(plugin)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .audited_spawn()
+        .map_err(|e| format!("failed to start plugin {}: {}", plugin.display(), e))?;
+    let mut line = serde_json::to_vec(request).map_err(|e| e.to_string())?;
+    line.push(b'\n');
+    let mut stdin = handle.stdin.take().expect("stdin is piped");
+    stdin.write_all(&line).await.map_err(|e| format!("failed to write to plugin {}: {}", plugin.display(), e))?;
+    drop(stdin);
+
+    let mut lines = BufReader::new(handle.stdout.take().expect("stdout is piped")).lines();
+    let result = loop {
+        let Some(line) = lines.next_line().await.map_err(|e| e.to_string())? else {
+            break Err(format!("plugin {} exited without a result", plugin.display()));
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(Response::Progress { bytes }) => {
+                debug!("plugin {}: {} bytes", plugin.display(), bytes);
+                progress(bytes);
+            }
+            Ok(Response::Result(result)) => break Ok(result),
+            Ok(Response::Error(e)) => break Err(e),
+            Err(e) => break Err(format!("plugin {} answered {:?}: {}", plugin.display(), line, e)),
+        }
+    };
+    let status = handle.wait().await.map_err(|e| e.to_string())?;
+    if result.is_ok() && !status.success() {
+        return Err(format!("plugin {} exited with {}", plugin.display(), status));
+    }
+    result
+}
+
+/// Asks `plugin` what it is, failing if it can't handle `capability`.
+pub(crate) async fn describe(plugin: &Path, capability: &str) -> Result<Description, SerializableError> {
+    let description: Description = call(plugin, &Request::Describe, |_| {}).await
+        .and_then(|d| serde_json::from_value(d).map_err(|e| e.to_string()))
+        .map_err(|e| SerializableError::config(format!("plugin {}: {}", plugin.display(), e)))?;
+    if !description.capabilities.iter().any(|c| c == capability) {
+        return Err(SerializableError::config(format!("plugin {} ({}) can't {}", plugin.display(), description.name, capability)));
+    }
+    debug!(
+        "plugin {}: {} {}",
+        plugin.display(),
+        description.name,
+        description.version.as_deref().unwrap_or("(unknown version)"),
+    );
+    Ok(description)
+}
+
+#[async_trait]
+impl InputProvider for PluginInput {
+    async fn stage(&self, ctx: &mut StageContext<'_>) -> Result<StagedArchive, SerializableError> {
+        let (service_name, archive_name) = (ctx.service, ctx.archive);
+        info!("{}: {}: using plugin {}", service_name, archive_name, self.plugin.display());
+        let output = PathBuf::from(ctx.config.intermediate_path()?).join(service_name).join(archive_name);
+        let dry_run = ctx.planning || !ctx.config.dry_run_level().stages();
+        if !dry_run {
+            std::fs::create_dir_all(&output)?;
+        }
+        let request = Request::Stage {
+            service: service_name,
+            compose_project: ctx.compose_project,
+            archive: archive_name,
+            output,
+            dry_run,
+            options: &self.options,
+        };
+        let events = ctx.events;
+        let progress = |bytes| {
+            if let Some(events) = events {
+                events.emit(Event::DumpProgress { service: service_name, archive: archive_name, bytes });
+            }
+        };
+        let result: StageResult = call(&self.plugin, &request, progress).await
+            .and_then(|r| serde_json::from_value(r).map_err(|e| e.to_string()))
+            .map_err(|e| {
+                error!("{}: {}: plugin: {}", service_name, archive_name, e);
+                SerializableError::dump(format!("plugin {}: {}", self.plugin.display(), e))
+            })?;
+        ctx.source = Some(format!("plugin {}", self.plugin.display()));
+        match result {
+            StageResult::Mount { source, exclude } => {
+                let filter = (!exclude.is_empty()).then(|| PathExclude::new(exclude));
+                ctx.mount_archive(source, filter).inspect_err(|e| {
+                    error!("{}: {}: plugin: {}", service_name, archive_name, e);
+                })
+            }
+            StageResult::Dump { service, command, ext, user } => {
+                let Some((program, args)) = command.split_first() else {
+                    return Err(SerializableError::dump(format!("plugin {} returned an empty dump command", self.plugin.display())));
+                };
+                let mut task = ShellTask::new(program);
+                for arg in args {
+                    task.arg(arg);
+                }
+                Ok(StagedArchive::Dump { service, task, ext, user })
+            }
+            // the intermediate directory is mounted in the backup containers already
+            StageResult::Written => Ok(StagedArchive::Mounted { exclude: None }),
+            StageResult::Missing { reason } => Ok(StagedArchive::Missing { reason }),
+        }
+    }
+}
+
+/// A notifier driven like [`PluginInput`], sent a `notify` request.
+pub(crate) struct PluginNotifier {
+    pub(crate) plugin: PathBuf,
+    pub(crate) options: Value,
+}
+
+#[async_trait]
+impl NotificationProvider for PluginNotifier {
+    fn name(&self) -> String {
+        format!("plugin {}", self.plugin.display())
+    }
+
+    async fn notify(&self, notification: &Notification<'_>) -> Result<(), SerializableError> {
+        let request = Request::Notify {
+            outcome: notification.outcome(),
+            summary: notification.summary(),
+            payload: notification.payload()?,
+            options: &self.options,
+        };
+        call(&self.plugin, &request, |_| {}).await
+            .map(|_| ())
+            .map_err(SerializableError::hook)
+    }
+}
+
+#[tokio::test]
+async fn test_plugin() {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::{config::Config, events::{Events, Progress}};
+
+    let dir = std::env::temp_dir().join(format!("hoarder-plugin-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let plugin = dir.join("plugin");
+    let requests = dir.join("requests");
+    std::fs::write(&plugin, format!(r#"#!/bin/sh
+read -r request
+printf '%s\n' "$request" >> {}
+case "$request" in
+    *'"method":"describe"'*) echo '{{"result": {{"name": "test", "version": "1.0", "capabilities": ["stage", "notify"]}}}}' ;;
+    *'"archive":"broken"'*) echo '{{"progress": {{"bytes": 1}}}}'; echo '{{"error": "no database"}}' ;;
+    *'"method":"stage"'*) echo '{{"progress": {{"bytes": 512}}}}'; echo; echo '{{"progress": {{"bytes": 1024}}}}'; echo '{{"result": {{"kind": "written"}}}}' ;;
+    *'"method":"notify"'*) echo '{{"result": null}}' ;;
+esac
+"#, requests.display())).unwrap();
+    std::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let description = describe(&plugin, "stage").await.unwrap();
+    assert_eq!((description.name.as_str(), description.version.as_deref()), ("test", Some("1.0")));
+    assert!(describe(&plugin, "restore").await.is_err());
+
+    let config: Config = serde_yaml::from_str(&format!("intermediate_path: {}", dir.display())).unwrap();
+    let input = PluginInput { plugin: plugin.clone(), options: serde_json::json!({ "database": "app" }) };
+    let mut events = Events::default();
+    let mut progress = events.progress();
+    let mut mounts = vec![];
+    let mut ctx = StageContext {
+        config: &config,
+        service: "app",
+        compose_project: "app",
+        archive: "db",
+        mounts: &mut mounts,
+        source: None,
+        planning: false,
+        events: Some(&events),
+    };
+    assert!(matches!(input.stage(&mut ctx).await, Ok(StagedArchive::Mounted { exclude: None })));
+    assert_eq!(ctx.source, Some(format!("plugin {}", plugin.display())));
+    assert!(dir.join("app").join("db").is_dir());
+    let mut dumped = vec![];
+    while let Ok(Progress::Dump { archive, bytes, .. }) = progress.try_recv() {
+        dumped.push((archive, bytes));
+    }
+    assert_eq!(dumped, vec![("db".to_owned(), 512), ("db".to_owned(), 1024)]);
+
+    ctx.archive = "broken";
+    let e = input.stage(&mut ctx).await.unwrap_err();
+    assert!(e.to_string().contains("no database"), "{}", e);
+
+    let notifier = PluginNotifier { plugin: plugin.clone(), options: Value::Null };
+    notifier.notify(&Notification::Success { warnings: &["app:db: archive was added".to_owned()] }).await.unwrap();
+    let requests = std::fs::read_to_string(&requests).unwrap();
+    assert_eq!(requests.lines().count(), 5);
+    assert!(requests.contains(r#""options":{"database":"app"}"#));
+    assert!(requests.contains(r#""method":"notify","outcome":"success""#));
+    assert!(requests.contains(r#""payload":{"warnings":["app:db: archive was added"]}"#));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
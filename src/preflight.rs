@@ -0,0 +1,172 @@
+use std::{collections::HashMap, path::Path};
+
+use log::{error, info, warn};
+
+use crate::{archive::{ArchiveInput, ArchiveOptions}, backend::{self, BackendType}, config::Config, inspect, plugin, service::Service, DockerInputType, SerializableError};
+
+/// Checks everything the run depends on before anything is touched, reporting every problem at once.
+pub(crate) async fn validate(services: &[Service], config: &Config) -> Result<(), SerializableError> {
+    let mut problems = vec![];
+
+    for setting in [config.intermediate_path(), config.restic_host()] {
+        if let Err(e) = setting {
+            problems.push(e.to_string());
+        }
+    }
+    for service in services {
+        let backends = service.backends(config);
+        if backends.is_empty() {
+            problems.push(format!("{}: no backend to back up to", service.name));
+        }
+        for (i, backend) in backends.iter().enumerate() {
+            if backends[..i].contains(backend) {
+                problems.push(format!("{}: backend {} is listed more than once", service.name, backend.name()));
+            }
+        }
+    }
+    for backend in backend::used(config, services) {
+        let routed = services.iter()
+            .filter(|s| s.backends(config).contains(&backend))
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>();
+        let problem = match backend {
+            BackendType::Restic => config.restic_password().err().map(|e| e.to_string()),
+            BackendType::Borg => config.borg().is_none().then(|| "the borg backend needs a borg section".to_owned()),
+            BackendType::Kopia => config.kopia().is_none().then(|| "the kopia backend needs a kopia section".to_owned()),
+            BackendType::Rclone => config.rclone().is_none().then(|| "the rclone backend needs an rclone section".to_owned()),
+            BackendType::Tarball => config.tarball().is_none().then(|| "the tarball backend needs a tarball section".to_owned()),
+            BackendType::Mirror => config.mirror().is_none().then(|| "the mirror backend needs a mirror section".to_owned()),
+            BackendType::Rsync => config.rsync().is_none().then(|| "the rsync backend needs an rsync section".to_owned()),
+        };
+        match problem {
+            Some(problem) if !routed.is_empty() => problems.push(format!("{} (used by {})", problem, routed.join(", "))),
+            Some(problem) => problems.push(problem),
+            None => (),
+        }
+    }
+    if let Ok(intermediate_path) = config.intermediate_path()
+        && let Err(e) = check_writable(Path::new(&intermediate_path), config.dry_run())
+    {
+        problems.push(format!("intermediate path {} is not writable: {}", intermediate_path, e));
+    }
+
+    // failing to talk to docker at all is not a configuration problem, bail out right away
+    let projects = inspect::compose_projects(config).await?;
+    let volumes = inspect::volumes(config).await?;
+    let mut project_services = HashMap::new();
+    let mut running_services = HashMap::new();
+
+    for service in services {
+        let project = service.compose_project.clone().unwrap_or(service.name.clone());
+        // plugins and volumes outside of compose don't need the project
+        let uses_project = service.archives.iter().any(|a| {
+            matches!(&a.input, ArchiveInput::Docker(input) if !matches!(input, DockerInputType::Volume { .. }))
+        });
+        if uses_project {
+            if !projects.contains(&project) {
+                problems.push(format!("{}: compose project {} does not exist", service.name, project));
+                continue;
+            }
+            if !project_services.contains_key(&project) {
+                project_services.insert(project.clone(), inspect::compose_services(config, &project).await?);
+            }
+        }
+        let compose_services = project_services.get(&project).cloned().unwrap_or_default();
+
+        for archive in &service.archives {
+            let input = match &archive.input {
+                ArchiveInput::Docker(input) => input,
+                ArchiveInput::Plugin(input) => {
+                    if let Err(e) = plugin::describe(&input.plugin, "stage").await {
+                        problems.push(format!("{}: {}: {}", service.name, archive.name, e));
+                    }
+                    continue;
+                }
+            };
+            match input {
+                DockerInputType::ExecStdout { service: compose_service, .. } => {
+                    if !compose_services.contains(compose_service) {
+                        problems.push(format!("{}: {}: service {} is not defined in compose project {}", service.name, archive.name, compose_service, project));
+                        continue;
+                    }
+                    if !running_services.contains_key(&project) {
+                        running_services.insert(project.clone(), inspect::running_services(config, &project).await?);
+                    }
+                    if !running_services[&project].contains(compose_service) {
+                        match archive.run_if_stopped {
+                            true => info!("{}: {}: service {} isn't running, the dump will run in a new container", service.name, archive.name, compose_service),
+                            false => problems.push(format!(
+                                "{}: {}: service {} of compose project {} has no running container to dump from, start it or set run_if_stopped",
+                                service.name, archive.name, compose_service, project,
+                            )),
+                        }
+                    }
+                }
+                DockerInputType::ComposeNamedVolume { name, .. } => {
+                    let volume = format!("{project}_{name}");
+                    if !volumes.contains(&volume) {
+                        missing(service, archive, format!("volume {} does not exist", volume));
+                    }
+                }
+                DockerInputType::Volume { name, .. } => {
+                    if !volumes.contains(name) {
+                        missing(service, archive, format!("volume {} does not exist", name));
+                    }
+                }
+                DockerInputType::ComposeBoundVolume { service: compose_service, path, .. } => {
+                    if !compose_services.contains(compose_service) {
+                        problems.push(format!("{}: {}: service {} is not defined in compose project {}", service.name, archive.name, compose_service, project));
+                    } else {
+                        match inspect::bound_volume(config, &project, compose_service, path).await? {
+                            None => missing(service, archive, format!("{} is not a bound volume of service {}", path.display(), compose_service)),
+                            // the source is on the docker host, which hoarder doesn't necessarily see
+                            Some(source) if !inspect::host_path_exists(config, &source).await? => {
+                                missing(service, archive, format!("source {} of the bound volume does not exist", source));
+                            }
+                            Some(_) => (),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        info!("preflight checks passed");
+        return Ok(());
+    }
+    error!("preflight checks found {} problem(s):", problems.len());
+    for problem in &problems {
+        error!("- {}", problem);
+    }
+    Err(SerializableError::config(format!("preflight checks failed: {}", problems.join("; "))))
+}
+
+/// Warns about an archive whose source is gone: it doesn't stop the run, the archive fails on its
+/// own unless it isn't required.
+fn missing(service: &Service, archive: &ArchiveOptions, reason: String) {
+    match archive.required {
+        true => warn!("{}: {}: {}, the archive will fail", service.name, archive.name, reason),
+        false => info!("{}: {}: {}, the archive isn't required and will be skipped", service.name, archive.name, reason),
+    }
+}
+
+/// Checks that files can be written into `dir`, creating it. A dry run changes nothing and only
+/// looks at the permissions of `dir`, or of its closest existing parent.
+fn check_writable(dir: &Path, dry_run: bool) -> std::io::Result<()> {
+    if dry_run {
+        let existing = dir.ancestors().find(|d| d.exists()).unwrap_or(dir);
+        let metadata = std::fs::metadata(existing)?;
+        if !metadata.is_dir() {
+            return Err(std::io::Error::other(format!("{} is not a directory", existing.display())));
+        }
+        if metadata.permissions().readonly() {
+            return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        }
+        return Ok(());
+    }
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(".hoarder-preflight");
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
+}
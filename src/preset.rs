@@ -0,0 +1,421 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+use crate::{archive::{ArchiveInput, ArchiveOptions}, service::ServiceTask, DockerInputType, SerializableError, ShellTask};
+
+/// An archive entry standing for the archives and tasks an application needs to be backed up
+/// properly, written as `preset: <name>` in a service's archives. Compose services and volumes
+/// default to the names of the application's reference compose file.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "preset", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum Preset {
+    /// a postgres database, dumped with pg_dump as the user of the image's environment
+    Postgres {
+        #[serde(default)]
+        service: Option<String>,
+        /// name of the archive, `database` by default
+        #[serde(default)]
+        name: Option<String>,
+    },
+    /// a mysql or mariadb database, dumped in a single transaction
+    Mysql {
+        #[serde(default)]
+        service: Option<String>,
+        #[serde(default)]
+        name: Option<String>,
+    },
+    /// a mongodb server, every database dumped with mongodump as the root user of the image's
+    /// environment
+    Mongodb {
+        #[serde(default)]
+        service: Option<String>,
+        #[serde(default)]
+        name: Option<String>,
+    },
+    /// Nextcloud in maintenance mode while its database is dumped and its files are backed up
+    Nextcloud {
+        #[serde(default)]
+        app: Option<String>,
+        #[serde(default)]
+        db: Option<String>,
+        #[serde(default)]
+        database: Database,
+        /// named volume mounted at /var/www/html
+        #[serde(default)]
+        volume: Option<String>,
+    },
+    /// Gitea or Forgejo, backed up with its own dump command so that the database and the
+    /// repositories are consistent with each other
+    Gitea {
+        #[serde(default)]
+        app: Option<String>,
+        /// user gitea runs as, it refuses to dump as root
+        #[serde(default)]
+        user: Option<String>,
+    },
+    /// Vaultwarden, its data volume backed up along with its database when it isn't the sqlite one
+    /// living in the volume
+    Vaultwarden {
+        #[serde(default)]
+        db: Option<String>,
+        #[serde(default)]
+        database: Option<Database>,
+        /// named volume mounted at /data
+        #[serde(default)]
+        volume: Option<String>,
+    },
+    /// InfluxDB, backed up with its own tool as its data volume can't be copied while it runs
+    Influxdb {
+        #[serde(default)]
+        service: Option<String>,
+        /// major version, 1 or 2
+        #[serde(default)]
+        version: Option<u32>,
+    },
+    /// Elasticsearch or OpenSearch, snapshotted through the cluster's api into a repository on a
+    /// named volume, which is backed up once the snapshot is complete
+    Elasticsearch {
+        #[serde(default)]
+        service: Option<String>,
+        /// the cluster's api, from inside the container
+        #[serde(default)]
+        url: Option<String>,
+        /// named volume holding the snapshot repository
+        #[serde(default)]
+        volume: Option<String>,
+        /// where the volume is mounted in the container, listed in the node's `path.repo`
+        #[serde(default)]
+        location: Option<String>,
+    },
+    /// etcd, snapshotted with etcdctl, the container needing a shell
+    Etcd {
+        #[serde(default)]
+        service: Option<String>,
+        #[serde(default)]
+        endpoints: Option<String>,
+        /// paths in the container of the tls files of the client
+        #[serde(default)]
+        cacert: Option<String>,
+        #[serde(default)]
+        cert: Option<String>,
+        #[serde(default)]
+        key: Option<String>,
+        /// environment etcdctl is run with, like `ETCDCTL_USER`
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+    },
+    /// OpenLDAP, every database exported as ldif with slapcat, the configuration one included
+    Openldap {
+        #[serde(default)]
+        service: Option<String>,
+        /// numbers of the databases, 0 being the configuration
+        #[serde(default)]
+        databases: Option<Vec<u32>>,
+        /// user slapd runs as, so that slapcat doesn't leave files it can't read
+        #[serde(default)]
+        user: Option<String>,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Database {
+    #[default]
+    Postgres,
+    Mysql,
+}
+
+/// What a preset stands for.
+#[derive(Debug, Default)]
+pub(crate) struct Expansion {
+    pub(crate) archives: Vec<ArchiveOptions>,
+    pub(crate) before: Vec<ServiceTask>,
+    pub(crate) after: Vec<ServiceTask>,
+}
+
+impl Preset {
+    pub(crate) fn expand(self) -> Result<Expansion, String> {
+        Ok(match self {
+            Preset::Postgres { service, name } => Expansion {
+                archives: vec![database_dump(&name.unwrap_or("database".to_owned()), &service.unwrap_or("db".to_owned()), Database::Postgres)],
+                ..Default::default()
+            },
+            Preset::Mysql { service, name } => Expansion {
+                archives: vec![database_dump(&name.unwrap_or("database".to_owned()), &service.unwrap_or("db".to_owned()), Database::Mysql)],
+                ..Default::default()
+            },
+            Preset::Mongodb { service, name } => {
+                let auth = r#"${MONGO_INITDB_ROOT_USERNAME:+--username "$MONGO_INITDB_ROOT_USERNAME" --password "$MONGO_INITDB_ROOT_PASSWORD" --authenticationDatabase admin}"#;
+                Expansion {
+                    archives: vec![ArchiveOptions::new(name.unwrap_or("database".to_owned()), ArchiveInput::Docker(DockerInputType::ExecStdout {
+                        service: service.unwrap_or("mongo".to_owned()),
+                        task: sh(&format!("exec mongodump --archive {}", auth)),
+                        ext: "archive".to_owned(),
+                        restore_task: Some(sh(&format!("exec mongorestore --archive --drop {}", auth))),
+                        user: None,
+                    }))],
+                    ..Default::default()
+                }
+            }
+            Preset::Nextcloud { app, db, database, volume } => {
+                let app = app.unwrap_or("app".to_owned());
+                let occ = |mode: &str| ServiceTask {
+                    service: app.clone(),
+                    task: sh(&format!("php occ maintenance:mode --{}", mode)),
+                    user: Some("www-data".to_owned()),
+                };
+                Expansion {
+                    before: vec![occ("on")],
+                    after: vec![occ("off")],
+                    archives: vec![
+                        database_dump("database", &db.unwrap_or("db".to_owned()), database),
+                        named_volume("data", volume.unwrap_or("nextcloud".to_owned())),
+                    ],
+                }
+            }
+            // forgejo images ship a gitea binary too, but not the other way around; the rootless
+            // images tell where their configuration is, the others keep it where gitea looks
+            Preset::Gitea { app, user } => Expansion {
+                archives: vec![ArchiveOptions::new("dump", ArchiveInput::Docker(DockerInputType::ExecStdout {
+                    service: app.unwrap_or("server".to_owned()),
+                    task: backed_up_file(
+                        r#"cd "$dir" && "$(command -v forgejo || command -v gitea)" dump ${GITEA_APP_INI:+--config "$GITEA_APP_INI"} --tempdir "$dir" --type zip --file "$file""#,
+                    ),
+                    ext: "zip".to_owned(),
+                    // the dump is restored by hand, following the instructions of gitea's docs
+                    restore_task: None,
+                    user: Some(user.unwrap_or("git".to_owned())),
+                }))],
+                ..Default::default()
+            },
+            Preset::Vaultwarden { db, database, volume } => {
+                let mut archives = vec![];
+                if let Some(database) = database {
+                    archives.push(database_dump("database", &db.unwrap_or("db".to_owned()), database));
+                }
+                archives.push(named_volume("data", volume.unwrap_or("vaultwarden".to_owned())));
+                Expansion { archives, ..Default::default() }
+            }
+            Preset::Influxdb { service, version } => {
+                let (backup, restore) = match version.unwrap_or(2) {
+                    1 => (r#"influxd backup -portable "$dir""#, r#"influxd restore -portable "$dir""#),
+                    // the image's setup leaves an admin configuration for the cli, unless a token is given
+                    2 => (
+                        r#"influx backup ${INFLUX_TOKEN:+-t "$INFLUX_TOKEN"} "$dir""#,
+                        r#"influx restore --full ${INFLUX_TOKEN:+-t "$INFLUX_TOKEN"} "$dir""#,
+                    ),
+                    version => return Err(format!("influxdb version {} isn't supported, only 1 and 2 are", version)),
+                };
+                Expansion {
+                    archives: vec![ArchiveOptions::new("backup", ArchiveInput::Docker(DockerInputType::ExecStdout {
+                        service: service.unwrap_or("influxdb".to_owned()),
+                        task: backed_up_dir(backup),
+                        ext: "tar".to_owned(),
+                        restore_task: Some(restored_dir(restore)),
+                        user: None,
+                    }))],
+                    ..Default::default()
+                }
+            }
+            Preset::Elasticsearch { service, url, volume, location } => {
+                let url = url.unwrap_or("http://localhost:9200".to_owned());
+                let location = location.unwrap_or("/usr/share/elasticsearch/snapshots".to_owned());
+                // a single snapshot is kept, the repository only holds the segments it needs and
+                // the backends keep the history
+                let snapshot = format!(
+                    r#"set -e
+if [ -n "$ELASTIC_PASSWORD" ]; then auth="elastic:$ELASTIC_PASSWORD"; elif [ -n "$OPENSEARCH_INITIAL_ADMIN_PASSWORD" ]; then auth="admin:$OPENSEARCH_INITIAL_ADMIN_PASSWORD"; fi
+api() {{ curl -ksS ${{auth:+-u "$auth"}} -H 'Content-Type: application/json' "$@"; }}
+api -f -X PUT '{url}/_snapshot/hoarder' -d '{{"type": "fs", "settings": {{"location": "{location}"}}}}' >/dev/null
+api -X DELETE '{url}/_snapshot/hoarder/hoarder' >/dev/null
+result=$(api -f -X PUT '{url}/_snapshot/hoarder/hoarder?wait_for_completion=true')
+case "$result" in *'"state":"SUCCESS"'*) ;; *) echo "snapshot failed: $result" >&2; exit 1 ;; esac"#,
+                );
+                Expansion {
+                    before: vec![ServiceTask {
+                        service: service.unwrap_or("elasticsearch".to_owned()),
+                        task: sh(&snapshot),
+                        user: None,
+                    }],
+                    archives: vec![named_volume("snapshots", volume.unwrap_or("snapshots".to_owned()))],
+                    ..Default::default()
+                }
+            }
+            Preset::Etcd { service, endpoints, cacert, cert, key, env } => {
+                let mut etcdctl = ShellTask::new("etcdctl");
+                for (flag, value) in [("endpoints", endpoints), ("cacert", cacert), ("cert", cert), ("key", key)] {
+                    if let Some(value) = value {
+                        etcdctl.arg(format!("--{}={}", flag, value));
+                    }
+                }
+                etcdctl.args(["snapshot", "save"]);
+                let env = env.iter()
+                    .map(|(name, value)| format!("{}={}", name, ShellTask::new(value).to_shell()))
+                    .collect::<Vec<_>>();
+                let save = format!("{} ETCDCTL_API=3 {} \"$file\"", env.join(" "), etcdctl.to_shell());
+                Expansion {
+                    archives: vec![ArchiveOptions::new("snapshot", ArchiveInput::Docker(DockerInputType::ExecStdout {
+                        service: service.unwrap_or("etcd".to_owned()),
+                        task: backed_up_file(save.trim_start()),
+                        ext: "db".to_owned(),
+                        // restoring needs the cluster down and its members' settings
+                        restore_task: None,
+                        user: None,
+                    }))],
+                    ..Default::default()
+                }
+            }
+            Preset::Openldap { service, databases, user } => {
+                let service = service.unwrap_or("openldap".to_owned());
+                let user = user.unwrap_or("openldap".to_owned());
+                let archives = databases.unwrap_or(vec![0, 1]).into_iter().map(|n| {
+                    let name = match n {
+                        0 => "config".to_owned(),
+                        1 => "data".to_owned(),
+                        n => format!("data{}", n),
+                    };
+                    let mut task = ShellTask::new("slapcat");
+                    task.args(["-n", &n.to_string()]);
+                    ArchiveOptions::new(name, ArchiveInput::Docker(DockerInputType::ExecStdout {
+                        service: service.clone(),
+                        task,
+                        ext: "ldif".to_owned(),
+                        // slapadd needs slapd stopped
+                        restore_task: None,
+                        user: Some(user.clone()),
+                    }))
+                }).collect();
+                Expansion { archives, ..Default::default() }
+            }
+        })
+    }
+}
+
+fn sh(script: &str) -> ShellTask {
+    let mut task = ShellTask::new("sh");
+    task.args(["-c", script]);
+    task
+}
+
+/// Runs `script` with `$dir` set to an empty temporary directory, then writes the directory as a tar
+/// to stdout. Whatever the script prints goes to stderr, as stdout is the dump.
+fn backed_up_dir(script: &str) -> ShellTask {
+    sh(&format!(r#"dir=$(mktemp -d) || exit; trap 'rm -rf "$dir"' EXIT; {{ {}; }} >&2 && tar -C "$dir" -cf - ."#, script))
+}
+
+/// Runs `script` with `$file` set to a path in an empty temporary directory, then writes the file to
+/// stdout.
+fn backed_up_file(script: &str) -> ShellTask {
+    sh(&format!(r#"dir=$(mktemp -d) || exit; trap 'rm -rf "$dir"' EXIT; file="$dir/backup"; {{ {}; }} >&2 && cat "$file""#, script))
+}
+
+/// Extracts the tar of [`backed_up_dir`] read from stdin into `$dir`, then runs `script`.
+fn restored_dir(script: &str) -> ShellTask {
+    sh(&format!(r#"dir=$(mktemp -d) || exit; trap 'rm -rf "$dir"' EXIT; tar -C "$dir" -xf - && {}"#, script))
+}
+
+/// Dumps the database the image's environment describes, so that no credentials are needed here.
+fn database_dump(name: &str, service: &str, database: Database) -> ArchiveOptions {
+    let (task, restore_task) = match database {
+        Database::Postgres => (
+            r#"exec pg_dump -U "${POSTGRES_USER:-postgres}" "${POSTGRES_DB:-${POSTGRES_USER:-postgres}}""#,
+            r#"exec psql -U "${POSTGRES_USER:-postgres}" "${POSTGRES_DB:-${POSTGRES_USER:-postgres}}""#,
+        ),
+        // mariadb images set the MARIADB_ variables, and only maybe the MYSQL_ ones
+        Database::Mysql => (
+            r#"exec "$(command -v mariadb-dump || command -v mysqldump)" --single-transaction -u"${MARIADB_USER:-$MYSQL_USER}" -p"${MARIADB_PASSWORD:-$MYSQL_PASSWORD}" "${MARIADB_DATABASE:-$MYSQL_DATABASE}""#,
+            r#"exec "$(command -v mariadb || command -v mysql)" -u"${MARIADB_USER:-$MYSQL_USER}" -p"${MARIADB_PASSWORD:-$MYSQL_PASSWORD}" "${MARIADB_DATABASE:-$MYSQL_DATABASE}""#,
+        ),
+    };
+    ArchiveOptions::new(name, ArchiveInput::Docker(DockerInputType::ExecStdout {
+        service: service.to_owned(),
+        task: sh(task),
+        ext: "sql".to_owned(),
+        restore_task: Some(sh(restore_task)),
+        user: None,
+    }))
+}
+
+fn named_volume(name: &str, volume: String) -> ArchiveOptions {
+    ArchiveOptions::new(name, ArchiveInput::Docker(DockerInputType::ComposeNamedVolume { name: volume, filter: None }))
+}
+
+/// Replaces the archives of every service that name a preset with what the preset stands for, its
+/// tasks going after the ones of the service.
+pub(crate) fn expand(value: &mut Value) -> Result<(), SerializableError> {
+    let Some(Value::Sequence(services)) = value.get_mut("services") else {
+        return Ok(());
+    };
+    for service in services {
+        let Value::Mapping(service) = service else {
+            continue;
+        };
+        let name = service.get("name").and_then(Value::as_str).unwrap_or("(unnamed)").to_owned();
+        let Some(Value::Sequence(archives)) = service.get_mut("archives") else {
+            continue;
+        };
+        let mut before = vec![];
+        let mut after = vec![];
+        for archive in std::mem::take(archives) {
+            if archive.get("preset").is_none() {
+                archives.push(archive);
+                continue;
+            }
+            let preset: Preset = serde_yaml::from_value(archive)
+                .map_err(|e| SerializableError::config(format!("{}: invalid preset: {}", name, e)))?;
+            let expansion = preset.expand()
+                .map_err(|e| SerializableError::config(format!("{}: {}", name, e)))?;
+            archives.extend(expansion.archives.iter().map(|a| serde_yaml::to_value(a).expect("archives are serializable")));
+            before.extend(expansion.before);
+            after.extend(expansion.after);
+        }
+        append(service, "before", before);
+        append(service, "after", after);
+    }
+    Ok(())
+}
+
+fn append(service: &mut Mapping, key: &str, tasks: Vec<ServiceTask>) {
+    if tasks.is_empty() {
+        return;
+    }
+    let tasks = tasks.iter().map(|t| serde_yaml::to_value(t).expect("tasks are serializable"));
+    match service.get_mut(key) {
+        Some(Value::Sequence(existing)) => existing.extend(tasks),
+        // anything else is left for the configuration to reject
+        Some(_) => (),
+        None => {
+            service.insert(key.into(), Value::Sequence(tasks.collect()));
+        }
+    }
+}
+
+#[test]
+fn test_expand_presets() {
+    let mut value: Value = serde_yaml::from_str("
+services:
+  - name: cloud
+    after: [{service: app, task: ['true']}]
+    archives:
+      - preset: nextcloud
+        database: mysql
+      - name: extra
+        input: !Docker
+          docker_type: ComposeNamedVolume
+          name: extra
+").unwrap();
+    expand(&mut value).unwrap();
+    let service: crate::service::Service = serde_yaml::from_value(value["services"][0].clone()).unwrap();
+    let archives = service.archives.iter().map(|a| a.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(archives, ["database", "data", "extra"]);
+    assert_eq!(service.before.len(), 1);
+    assert_eq!(service.before[0].user.as_deref(), Some("www-data"));
+    // the service's own tasks come first
+    assert_eq!(service.after.len(), 2);
+    assert_eq!(service.after[1].service, "app");
+
+    let mut unknown: Value = serde_yaml::from_str("services: [{name: s, archives: [{preset: nextcloud, typo: 1}]}]").unwrap();
+    assert!(expand(&mut unknown).is_err());
+}
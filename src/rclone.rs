@@ -0,0 +1,417 @@
+use std::{path::{Path, PathBuf}, process::Stdio, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use indicatif::ProgressBar;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader}, process::Command};
+
+use crate::{
+    audit::Audited,
+    backend::{self, BackendType, BackupBackend, BackupRequest, BackupSummary, RepositoryStats, Retention, Snapshot, HOARDER_TAG},
+    config::Config,
+    credentials::Credentials,
+    DockerBinding,
+    DockerSubcommand,
+    SerializableError,
+    ShellTask,
+};
+
+static RCLONE_IMAGE: &str = "rclone/rclone";
+static RCLONE_CONTAINER_NAME: &str = "hoarder-rclone";
+/// Where the rclone configuration file is mounted inside rclone containers.
+static CONFIG_MOUNT: &str = "/config/rclone/rclone.conf";
+/// Where the restore target is mounted inside rclone containers.
+static RESTORE_MOUNT: &str = "/restore";
+/// Directory next to the copies, holding what each sync replaced or deleted.
+static VERSIONS_DIR: &str = ".versions";
+/// File touched in a copy once it is synced, its modification time being the one of the copy.
+static MARKER: &str = ".hoarder-synced";
+static TIME_FORMAT: &str = "%Y-%m-%dT%H%M%SZ";
+
+/// Settings of the rclone backend.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RcloneConfig {
+    /// rclone remote and path the copies are synced to, like `nas:backups`, every host getting a
+    /// directory in it
+    pub(crate) remote: String,
+    /// rclone configuration file defining the remote, remotes can also be defined with
+    /// RCLONE_CONFIG_* environment variables
+    pub(crate) config_file: Option<String>,
+    /// image providing rclone, the syncs run in a long running container of it
+    pub(crate) image: Option<String>,
+    /// name of the long running rclone container
+    pub(crate) container_name: Option<String>,
+}
+
+impl RcloneConfig {
+    fn image(&self) -> String {
+        self.image.clone().unwrap_or(RCLONE_IMAGE.to_owned())
+    }
+
+    fn container_name(&self) -> String {
+        self.container_name.clone().unwrap_or(RCLONE_CONTAINER_NAME.to_owned())
+    }
+}
+
+/// A line of `rclone --use-json-log` output on stderr.
+#[derive(Deserialize, Debug)]
+struct RcloneLogMessage {
+    #[serde(default)]
+    level: String,
+    #[serde(default)]
+    msg: String,
+    #[serde(default)]
+    stats: Option<RcloneStats>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RcloneStats {
+    bytes: u64,
+    #[serde(default)]
+    total_bytes: u64,
+}
+
+/// An entry of `rclone lsjson`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct RcloneEntry {
+    path: String,
+    #[serde(default)]
+    mod_time: Option<DateTime<Utc>>,
+}
+
+/// Syncs each directory under the backup root to a plain, browsable copy on an rclone remote, at
+/// `remote/host/directory`. What a sync replaces or deletes is moved to
+/// `remote/host/.versions/time/directory` with `--backup-dir`, so only the latest copy can be
+/// restored as a snapshot, older files being kept for browsing by hand.
+pub(crate) struct Rclone;
+
+impl Rclone {
+    fn settings(config: &Config) -> Result<&RcloneConfig, SerializableError> {
+        config.rclone().ok_or(SerializableError::config("the rclone backend needs an rclone section"))
+    }
+
+    /// Joins `parts` to the host's directory on the remote.
+    fn remote(config: &Config, parts: &[&str]) -> Result<String, SerializableError> {
+        let remote = &Self::settings(config)?.remote;
+        let mut path = match remote.ends_with(':') {
+            true => remote.clone(),
+            false => format!("{}/", remote.trim_end_matches('/')),
+        };
+        path.push_str(&config.restic_host()?);
+        for part in parts.iter().filter(|p| !p.is_empty()) {
+            path.push('/');
+            path.push_str(part.trim_matches('/'));
+        }
+        Ok(path)
+    }
+
+    /// Splits a path under the backup root into its directory there and the path inside of it.
+    fn split(config: &Config, path: &Path) -> (String, String) {
+        let relative = path.strip_prefix(config.restic_root()).unwrap_or(path);
+        let mut components = relative.components();
+        let dir = components.next().map(|c| c.as_os_str().to_string_lossy().into_owned()).unwrap_or_default();
+        (dir, components.as_path().display().to_string())
+    }
+
+    /// Credentials of the rclone containers, as `docker run` options along with the values they pass
+    /// by name, adding the configuration file to `mounts`.
+    fn environment(config: &Config, mounts: &mut Vec<DockerBinding>) -> Result<(Vec<String>, Credentials), SerializableError> {
+        if let Some(file) = &Self::settings(config)?.config_file {
+            mounts.push(DockerBinding::new_ro(file.clone(), PathBuf::from(CONFIG_MOUNT)));
+        }
+        let credentials = Credentials::new(config, BackendType::Rclone, &["RCLONE_"]);
+        Ok((credentials.options(), credentials))
+    }
+
+    /// Builds a command running `task` in a throwaway rclone container.
+    fn command(config: &Config, mut mounts: Vec<DockerBinding>, task: ShellTask) -> Result<Command, SerializableError> {
+        let (env, credentials) = Self::environment(config, &mut mounts)?;
+        let mut command = config.docker_command_with_context(DockerSubcommand::run(
+            Self::settings(config)?.image(),
+            mounts,
+            std::iter::once("--rm".to_owned()).chain(env).collect(),
+            task.get_args().into_iter().collect(),
+        )).into_command();
+        command.envs(credentials.vars());
+        Ok(command)
+    }
+
+    /// Runs `task` in a throwaway rclone container, returning its stdout.
+    async fn query(config: &Config, mounts: Vec<DockerBinding>, task: ShellTask) -> Result<String, SerializableError> {
+        let mut command = Self::command(config, mounts, task)?;
+        command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        debug!("querying rclone: {:?}", command.as_std().get_args().collect::<Vec<_>>());
+        let output = command.audited_output().await
+            .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+        if !output.status.success() {
+            return Err(SerializableError::backend(format!(
+                "rclone {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim(),
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Builds the command running `task` in the rclone container.
+    fn exec_command(config: &Config, task: ShellTask, stdin: bool) -> Result<Command, SerializableError> {
+        Ok(config.docker_command_with_context(DockerSubcommand::exec(
+            Self::settings(config)?.container_name(),
+            task,
+            if stdin { vec!["-i"] } else { vec![] },
+        )).into_command())
+    }
+
+    /// The task syncing `request` to its copy, moving what it replaces to the versions of `now`.
+    fn sync_task(config: &Config, request: &BackupRequest, now: DateTime<Utc>) -> Result<ShellTask, SerializableError> {
+        let (dir, _) = Self::split(config, &request.path);
+        let mut task = Self::task(config, "sync");
+        task.arg(request.path.display())
+            .arg(Self::remote(config, &[&dir])?)
+            .arg("--backup-dir")
+            .arg(Self::remote(config, &[VERSIONS_DIR, &now.format(TIME_FORMAT).to_string(), &dir])?)
+            // the marker would otherwise be deleted by every sync
+            .args(["--exclude", &format!("/{}", MARKER)]);
+        for exclude in &request.excludes {
+            let relative = Path::new(exclude).strip_prefix(&request.path).unwrap_or(Path::new(exclude));
+            task.arg("--exclude").arg(format!("/{}", relative.display()));
+            task.arg("--exclude").arg(format!("/{}/**", relative.display()));
+        }
+        Ok(task)
+    }
+
+    /// The task marking the copy of `dir` as synced, none for a dry run.
+    fn mark_task(config: &Config, dir: &str) -> Result<Option<ShellTask>, SerializableError> {
+        if config.dry_run() {
+            return Ok(None);
+        }
+        let mut task = ShellTask::new("rclone");
+        task.arg("touch").arg(Self::remote(config, &[dir, MARKER])?);
+        Ok(Some(task))
+    }
+
+    /// Runs `task` in the rclone container, feeding it `input` and following its json log on
+    /// stderr.
+    async fn exec(
+        config: &Config,
+        task: ShellTask,
+        input: Option<Box<dyn AsyncRead + Send + Unpin>>,
+        bar: &ProgressBar,
+    ) -> Result<(), SerializableError> {
+        let mut command = Self::exec_command(config, task, input.is_some())?;
+        command
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        info!("running rclone task: {:?}", command.as_std().get_args().collect::<Vec<_>>());
+
+        let mut handle = command.audited_spawn()?;
+        let stdin = handle.stdin.take();
+        let feed = async {
+            if let (Some(mut input), Some(mut stdin)) = (input, stdin) {
+                tokio::io::copy(&mut input, &mut stdin).await?;
+                stdin.shutdown().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        let stderr = handle.stderr.take()
+            .ok_or(SerializableError::backend("no stderr found in rclone output"))?;
+        let log = async {
+            let mut errors = vec![];
+            let mut lines = BufReader::new(stderr).lines();
+            while let Some(line) = lines.next_line().await? {
+                match serde_json::from_str::<RcloneLogMessage>(&line) {
+                    Ok(RcloneLogMessage { stats: Some(stats), .. }) => {
+                        bar.set_length(stats.total_bytes.max(stats.bytes));
+                        bar.set_position(stats.bytes);
+                    }
+                    Ok(RcloneLogMessage { level, msg, .. }) if level == "error" => errors.push(msg),
+                    Ok(RcloneLogMessage { msg, .. }) => debug!("rclone: {}", msg),
+                    Err(_) => debug!("rclone: {}", line),
+                }
+            }
+            Ok::<_, std::io::Error>(errors)
+        };
+        let (fed, errors) = tokio::join!(feed, log);
+        let exit = handle.wait().await?;
+        if !exit.success() {
+            return Err(SerializableError::backend(format!("rclone failed: {}: {}", exit, errors?.join("; "))));
+        }
+        fed?;
+        Ok(())
+    }
+
+    /// Starts an rclone task logging json, with its stats every second.
+    fn task(config: &Config, subcommand: &str) -> ShellTask {
+        let mut task = ShellTask::new("rclone");
+        task.arg(subcommand)
+            .args(["--use-json-log", "--stats", "1s", "--stats-log-level", "NOTICE"]);
+        if config.dry_run() {
+            task.arg("--dry-run");
+        }
+        task
+    }
+
+    /// Marks the copy of `dir` as synced now, returning the name of the snapshot it makes.
+    async fn mark(config: &Config, dir: &str, time: DateTime<Utc>) -> Result<String, SerializableError> {
+        if let Some(task) = Self::mark_task(config, dir)? {
+            Self::exec(config, task, None, &ProgressBar::hidden()).await?;
+        }
+        Ok(format!("{}@{}", dir, time.format(TIME_FORMAT)))
+    }
+
+    /// Lists the directories right under `path` on the remote, an empty list if it doesn't exist.
+    async fn list_dirs(config: &Config, path: &str) -> Result<Vec<RcloneEntry>, SerializableError> {
+        let mut task = ShellTask::new("rclone");
+        task.args(["lsjson", "--dirs-only"]).arg(path);
+        match Self::query(config, vec![], task).await {
+            Ok(output) => Ok(serde_json::from_str(&output)?),
+            Err(e) if e.to_string().contains("directory not found") => Ok(vec![]),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl BackupBackend for Rclone {
+    async fn prepare(&self, config: &Config, mut mounts: Vec<DockerBinding>) -> Result<(), SerializableError> {
+        let settings = Self::settings(config)?;
+        let (env, credentials) = Self::environment(config, &mut mounts)?;
+        let options = ["--init", "--entrypoint", "sleep"].into_iter().map(str::to_owned).chain(env).collect();
+        backend::start_container_with_env(config, &settings.container_name(), &settings.image(), mounts, options, vec!["infinity"], credentials.into_vars()).await
+    }
+
+    async fn teardown(&self, config: &Config) -> Result<bool, SerializableError> {
+        backend::stop_container(config, &Self::settings(config)?.container_name()).await
+    }
+
+    async fn backup(
+        &self,
+        config: &Config,
+        request: BackupRequest,
+        bar: &ProgressBar,
+        _previous: Option<Duration>,
+    ) -> Result<BackupSummary, SerializableError> {
+        if config.dry_run() {
+            warn!("running in dry run mode, not actually uploading");
+        }
+        let (dir, _) = Self::split(config, &request.path);
+        let now = Utc::now();
+        Self::exec(config, Self::sync_task(config, &request, now)?, None, bar).await?;
+        Ok(BackupSummary {
+            snapshot_id: Some(Self::mark(config, &dir, now).await?),
+            // rclone doesn't tell apart what it uploaded from what it moved to the versions
+            data_added: None,
+        })
+    }
+
+    fn plan_commands(&self, config: &Config, request: &BackupRequest) -> Result<Vec<Command>, SerializableError> {
+        let (dir, _) = Self::split(config, &request.path);
+        std::iter::once(Self::sync_task(config, request, Utc::now())?)
+            .chain(Self::mark_task(config, &dir)?)
+            .map(|task| Self::exec_command(config, task, false))
+            .collect()
+    }
+
+    async fn forget(&self, config: &Config, retention: &Retention) -> Result<(), SerializableError> {
+        if retention.rules().is_empty() {
+            return Err(SerializableError::config("retention must keep something, set at least one keep_* rule"));
+        }
+        // the copies are always kept, the rules apply to the versions the syncs moved aside
+        let versions = Self::list_dirs(config, &Self::remote(config, &[VERSIONS_DIR])?).await?
+            .into_iter()
+            .filter_map(|e| NaiveDateTime::parse_from_str(&e.path, TIME_FORMAT).ok().map(|t| (e.path, t.and_utc())))
+            .collect::<Vec<_>>();
+        let kept = retention.keep(&versions.iter().map(|(_, t)| *t).collect::<Vec<_>>());
+        for (name, _) in versions.iter().enumerate().filter(|(i, _)| !kept.contains(i)).map(|(_, v)| v) {
+            info!("purging versions of {}", name);
+            if config.dry_run() {
+                continue;
+            }
+            let mut task = ShellTask::new("rclone");
+            task.arg("purge").arg(Self::remote(config, &[VERSIONS_DIR, name])?);
+            Self::query(config, vec![], task).await?;
+        }
+        Ok(())
+    }
+
+    async fn snapshots(&self, config: &Config) -> Result<Vec<Snapshot>, SerializableError> {
+        let mut task = ShellTask::new("rclone");
+        task.args(["lsjson", "--files-only", "--max-depth", "2", "--exclude", &format!("/{}/**", VERSIONS_DIR), "--include"])
+            .arg(MARKER)
+            .arg(Self::remote(config, &[])?);
+        let entries: Vec<RcloneEntry> = match Self::query(config, vec![], task).await {
+            Ok(output) => serde_json::from_str(&output)?,
+            Err(e) if e.to_string().contains("directory not found") => vec![],
+            Err(e) => return Err(e),
+        };
+        Ok(entries
+            .into_iter()
+            .filter_map(|e| {
+                let (dir, _) = e.path.split_once('/')?;
+                let time = e.mod_time?;
+                Some(Snapshot {
+                    id: format!("{}@{}", dir, time.format(TIME_FORMAT)),
+                    time,
+                    paths: vec![Path::new(&config.restic_root()).join(dir).display().to_string()],
+                    tags: vec![HOARDER_TAG.to_owned()],
+                })
+            })
+            .collect())
+    }
+
+    async fn restore(
+        &self,
+        config: &Config,
+        snapshot: &str,
+        path: &Path,
+        target: Option<&str>,
+        dry_run: bool,
+    ) -> Result<String, SerializableError> {
+        let (dir, inner) = Self::split(config, path);
+        if !snapshot.starts_with(&format!("{}@", dir)) {
+            return Err(SerializableError::backend(format!("snapshot {} isn't a copy of {}", snapshot, dir)));
+        }
+        let source = Self::remote(config, &[&dir, &inner])?;
+        let mut task = ShellTask::new("rclone");
+        let mounts = match dry_run {
+            true => {
+                task.args(["lsf", "--recursive", "--exclude"]).arg(format!("/{}", MARKER)).arg(source);
+                vec![]
+            }
+            false => {
+                task.args(["copy", "--exclude"]).arg(format!("/{}", MARKER)).arg(source).arg(RESTORE_MOUNT);
+                target.iter().map(|t| DockerBinding::new_rw(t.to_string(), PathBuf::from(RESTORE_MOUNT))).collect()
+            }
+        };
+        Self::query(config, mounts, task).await
+    }
+
+    fn dump(&self, config: &Config, _snapshot: &str, path: &Path) -> Result<Command, SerializableError> {
+        let (dir, inner) = Self::split(config, path);
+        let mut task = ShellTask::new("rclone");
+        task.arg("cat").arg(Self::remote(config, &[&dir, &inner])?);
+        Self::command(config, vec![], task)
+    }
+
+    async fn stats(&self, config: &Config) -> Result<RepositoryStats, SerializableError> {
+        #[derive(Deserialize)]
+        struct Size {
+            bytes: u64,
+        }
+        let mut task = ShellTask::new("rclone");
+        task.args(["size", "--json"]).arg(Self::remote(config, &[])?);
+        let size: Size = serde_json::from_str(&Self::query(config, vec![], task).await?)?;
+        Ok(RepositoryStats {
+            snapshots: self.snapshots(config).await?.len() as u64,
+            stored_bytes: size.bytes,
+        })
+    }
+}
@@ -1,42 +1,606 @@
-use std::path::PathBuf;
+use std::{io::{BufRead, IsTerminal}, path::{Path, PathBuf}, process::{ExitStatus, Stdio}, time::{Duration, Instant}};
 
-use crate::{docker::PathExclude, ShellTask};
+use async_trait::async_trait;
+use indicatif::{HumanDuration, ProgressBar};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader}, process::Command};
 
-#[derive(Debug)]
-pub(crate) struct ResticBackup {
-    path: PathBuf,
-    /// exclude string globs
-    excludes: Vec<String>
+use crate::{
+    audit::Audited,
+    backend::{self, BackendType, BackupBackend, BackupRequest, BackupSummary, RepositoryStats, Retention, Snapshot, HOARDER_TAG},
+    config::Config,
+    credentials::Credentials,
+    image,
+    secret::Secret,
+    DockerBinding,
+    DockerSubcommand,
+    SerializableError,
+    ShellTask,
+};
+
+/// Where the password file is mounted inside restic containers.
+pub(crate) static PASSWORD_MOUNT: &str = "/restic_password";
+
+/// Where the restore target is mounted inside restic containers.
+pub(crate) static RESTORE_MOUNT: &str = "/restore";
+
+/// A line of `restic backup --json` output.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "message_type", rename_all = "snake_case")]
+enum ResticMessage {
+    Status {
+        #[serde(default)]
+        seconds_remaining: Option<u64>,
+        #[serde(default)]
+        total_bytes: u64,
+        #[serde(default)]
+        bytes_done: u64,
+    },
+    Summary {
+        #[serde(default)]
+        snapshot_id: Option<String>,
+        #[serde(default)]
+        data_added: u64,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// How the restic containers are locked down, as they only need to read the mounts and talk to the
+/// repository. Containers restoring into a writable mount are also given the capabilities needed
+/// to write files of any owner.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ResticHardening {
+    /// whether the root filesystem is read-only
+    #[serde(default = "ResticHardening::default_true")]
+    pub(crate) read_only: bool,
+    /// whether every capability is dropped but DAC_READ_SEARCH, which reads files of any owner
+    #[serde(default = "ResticHardening::default_true")]
+    pub(crate) drop_capabilities: bool,
+    /// whether gaining privileges, through setuid binaries for instance, is forbidden
+    #[serde(default = "ResticHardening::default_true")]
+    pub(crate) no_new_privileges: bool,
+    /// writable tmpfs mounts, restic's cache going in the first one
+    #[serde(default = "ResticHardening::default_tmpfs")]
+    pub(crate) tmpfs: Vec<PathBuf>,
+    /// more capabilities to keep
+    #[serde(default)]
+    pub(crate) capabilities: Vec<String>,
+}
+
+impl ResticHardening {
+    fn default_true() -> bool {
+        true
+    }
+
+    fn default_tmpfs() -> Vec<PathBuf> {
+        vec![PathBuf::from("/tmp")]
+    }
 }
 
-impl ResticBackup {
-    pub(crate) fn with_excludes(path: PathBuf, excludes: Vec<PathExclude>) -> Self {
+impl Default for ResticHardening {
+    fn default() -> Self {
         Self {
-            excludes: excludes.into_iter()
-                .flat_map(|pe| pe.0)
-                .map(|p| p.join(&path).to_string_lossy().to_string())
-                .collect(),
-            path,
+            read_only: true,
+            drop_capabilities: true,
+            no_new_privileges: true,
+            tmpfs: Self::default_tmpfs(),
+            capabilities: vec![],
         }
     }
+}
 
-    pub(crate) fn new(path: PathBuf) -> Self {
-        Self {
-            excludes: vec![],
-            path,
+/// Capabilities kept on top of DAC_READ_SEARCH when restoring, to write files as their owners.
+static RESTORE_CAPABILITIES: &[&str] = &["CHOWN", "FOWNER", "DAC_OVERRIDE"];
+
+/// Docker options locking a restic container down, `writable` when one of its mounts is written.
+/// restic's cache is moved to the first tmpfs unless `credentials` set its place.
+fn hardening_options(config: &Config, writable: bool, credentials: &Credentials) -> Vec<String> {
+    let hardening = config.restic_hardening();
+    let mut options = vec![];
+    if hardening.read_only {
+        options.push("--read-only".to_owned());
+    }
+    if hardening.drop_capabilities {
+        options.extend(["--cap-drop".to_owned(), "ALL".to_owned(), "--cap-add".to_owned(), "DAC_READ_SEARCH".to_owned()]);
+        let restoring = RESTORE_CAPABILITIES.iter().filter(|_| writable).map(|c| c.to_string());
+        for capability in restoring.chain(hardening.capabilities.iter().cloned()) {
+            options.extend(["--cap-add".to_owned(), capability]);
+        }
+    }
+    if hardening.no_new_privileges {
+        options.extend(["--security-opt".to_owned(), "no-new-privileges".to_owned()]);
+    }
+    for tmpfs in &hardening.tmpfs {
+        options.extend(["--tmpfs".to_owned(), tmpfs.display().to_string()]);
+    }
+    if hardening.read_only
+        && let Some(tmpfs) = hardening.tmpfs.first()
+        && !credentials.vars().any(|(k, _)| k == "RESTIC_CACHE_DIR")
+    {
+        options.extend(["--env".to_owned(), format!("RESTIC_CACHE_DIR={}", tmpfs.join("restic-cache").display())]);
+    }
+    options
+}
+
+/// Where restic gets the repository password from.
+pub(crate) enum ResticPassword {
+    /// a file on the host, mounted in the containers
+    File(String),
+    /// a command printing it, run by restic
+    Command(String),
+    /// the password itself
+    Value(String),
+}
+
+/// Variables restic reads the password from, only ever set by hoarder.
+static PASSWORD_VARS: &[&str] = &["RESTIC_PASSWORD", "RESTIC_PASSWORD_FILE", "RESTIC_PASSWORD_COMMAND"];
+
+/// Environment of the restic containers set by hoarder itself: the host and where the password
+/// comes from, unless it is given as is. The rest goes through [`credentials`].
+fn env(config: &Config) -> Result<Vec<(String, String)>, SerializableError> {
+    let mut env = vec![("RESTIC_HOST".to_owned(), config.restic_host()?)];
+    match config.restic_password()? {
+        ResticPassword::File(_) => env.push(("RESTIC_PASSWORD_FILE".to_owned(), PASSWORD_MOUNT.to_owned())),
+        ResticPassword::Command(command) => env.push(("RESTIC_PASSWORD_COMMAND".to_owned(), command)),
+        ResticPassword::Value(_) => (),
+    }
+    Ok(env)
+}
+
+/// Credentials of the restic containers: the scoped ones or the `RESTIC_*` and `AWS_*` variables
+/// hoarder was given, then `restic_env` and the password given as is.
+fn credentials(config: &Config) -> Result<Credentials, SerializableError> {
+    let mut credentials = Credentials::new(config, BackendType::Restic, &["RESTIC_", "AWS_"])
+        .without(PASSWORD_VARS)
+        .without(&["RESTIC_HOST"])
+        .with(config.restic_env().iter().map(|(k, Secret(v))| (k.clone(), v.clone())));
+    if let ResticPassword::Value(password) = config.restic_password()? {
+        credentials = credentials.with([("RESTIC_PASSWORD".to_owned(), password)]);
+    }
+    Ok(credentials)
+}
+
+/// Reads a password from the first line of `input`.
+pub(crate) fn read_password(mut input: impl BufRead) -> Result<String, SerializableError> {
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    let password = line.trim_end_matches(['\n', '\r']);
+    if password.is_empty() {
+        return Err(SerializableError::config("no password was given on stdin"));
+    }
+    Ok(password.to_owned())
+}
+
+/// The password file mounted in the containers, if the password comes from one.
+fn password_mounts(config: &Config) -> Result<Vec<DockerBinding>, SerializableError> {
+    Ok(match config.restic_password()? {
+        ResticPassword::File(file) => vec![DockerBinding::new_ro(file, PathBuf::from(PASSWORD_MOUNT))],
+        ResticPassword::Command(_) | ResticPassword::Value(_) => vec![],
+    })
+}
+
+/// Docker options of the restic containers: the hardening, for a container with `mounts`, and the
+/// environment.
+fn container_options(config: &Config, mounts: &[DockerBinding], credentials: &Credentials) -> Result<Vec<String>, SerializableError> {
+    let writable = mounts.iter().any(|m| m.flags.as_deref() != Some("ro"));
+    let mut options = hardening_options(config, writable, credentials);
+    for (k, v) in env(config)? {
+        options.push("--env".to_owned());
+        options.push(format!("{}={}", k, v));
+    }
+    options.extend(credentials.options());
+    Ok(options)
+}
+
+/// Environment of restic run on the host rather than in a container.
+pub(crate) fn native_env(config: &Config) -> Result<Vec<(String, String)>, SerializableError> {
+    let mut env = env(config)?;
+    env.extend(credentials(config)?.into_vars());
+    if let ResticPassword::File(file) = config.restic_password()? {
+        env.retain(|(k, _)| k != "RESTIC_PASSWORD_FILE");
+        env.push(("RESTIC_PASSWORD_FILE".to_owned(), file));
+    }
+    Ok(env)
+}
+
+/// Builds a command running `task` in a throwaway restic container with the repository's
+/// credentials and `mounts`.
+pub(crate) fn command(config: &Config, mounts: Vec<DockerBinding>, task: ShellTask) -> Result<Command, SerializableError> {
+    command_with_options(config, mounts, vec![], task)
+}
+
+/// Like [`command`], passing `options` to `docker run` too.
+pub(crate) fn command_with_options(
+    config: &Config,
+    mounts: Vec<DockerBinding>,
+    options: Vec<String>,
+    task: ShellTask,
+) -> Result<Command, SerializableError> {
+    let credentials = credentials(config)?;
+    let options = std::iter::once("--rm".to_owned())
+        .chain(options)
+        .chain(container_options(config, &mounts, &credentials)?)
+        .collect();
+    let mut command = config.docker_command_with_context(DockerSubcommand::run(
+        config.restic_image(),
+        password_mounts(config)?.into_iter().chain(mounts).collect(),
+        options,
+        task.get_args().into_iter().collect(),
+    )).into_command();
+    command.envs(credentials.vars());
+    Ok(command)
+}
+
+/// Runs a command in a throwaway restic container with the repository's credentials and `mounts`,
+/// returning its stdout.
+pub(crate) async fn query(config: &Config, mounts: Vec<DockerBinding>, task: ShellTask) -> Result<String, SerializableError> {
+    image::verify(config).await?;
+    let mut command = command(config, mounts, task)?;
+    command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    debug!("querying restic: {:?}", command.as_std().get_args().collect::<Vec<_>>());
+    let output = command.audited_output().await
+        .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+    if !output.status.success() {
+        return Err(SerializableError::restic(format!(
+            "{}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Runs restic with `args` and the repository's credentials, in a throwaway restic container or
+/// with the host's restic if `native`, attached to the terminal. The container gets `mounts`, the
+/// volumes and the intermediate directory under the restic root as in a backup, so that restic
+/// sees the paths of the snapshots. Returns how restic exited.
+pub(crate) async fn passthrough(
+    config: &Config,
+    args: Vec<String>,
+    mounts: Vec<DockerBinding>,
+    native: bool,
+) -> Result<ExitStatus, SerializableError> {
+    if config.backend() != BackendType::Restic {
+        return Err(SerializableError::config("the restic passthrough needs the restic backend"));
+    }
+    let mut task = ShellTask::new("restic");
+    task.args(args);
+    let mut command = match native {
+        true => {
+            let mut args = task.get_args().into_iter();
+            let mut command = Command::new(args.next().expect("restic is the first argument"));
+            command.args(args).envs(native_env(config)?);
+            command
+        }
+        false => {
+            image::verify(config).await?;
+            let mut options = vec!["-i".to_owned()];
+            if std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+                options.push("-t".to_owned());
+            }
+            command_with_options(config, mounts, options, task)?
+        }
+    };
+    debug!("running restic: {:?}", command.as_std().get_args().collect::<Vec<_>>());
+    let mut child = command.audited_spawn()
+        .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+    // ctrl-c reaches restic too, it decides when to stop
+    loop {
+        tokio::select! {
+            status = child.wait() => return Ok(status?),
+            _ = tokio::signal::ctrl_c() => debug!("interrupted, waiting for restic to exit"),
         }
     }
+}
+
+/// Mounts a restore target, read-only for a dry run.
+pub(crate) fn restore_mount(target: &str, dry_run: bool) -> DockerBinding {
+    match dry_run {
+        true => DockerBinding::new_ro(target.to_owned(), PathBuf::from(RESTORE_MOUNT)),
+        false => DockerBinding::new_rw(target.to_owned(), PathBuf::from(RESTORE_MOUNT)),
+    }
+}
 
-    pub(crate) fn into_task(self) -> ShellTask {
+/// Backs up to a restic repository, from a long running restic container holding every mount.
+pub(crate) struct Restic;
+
+impl Restic {
+    fn backup_task(request: BackupRequest) -> ShellTask {
         let mut task = ShellTask::new("restic");
-        task
-            .arg("backup")
-            .arg(self.path.to_string_lossy().to_string())
-            .args(["--tag", "hoarder"]);
-        for exclude in self.excludes {
+        task.arg("backup");
+        match request.files_from {
+            Some(list) => task.arg("--files-from").arg(list.to_string_lossy().to_string()),
+            None => task.arg(request.path.to_string_lossy().to_string()),
+        };
+        task.args(["--tag", HOARDER_TAG, "--json"]);
+        if request.one_file_system {
+            task.arg("--one-file-system");
+        }
+        Self::retry_lock(&mut task, request.retry_lock);
+        for tag in request.tags {
+            task.arg("--tag");
+            task.arg(tag);
+        }
+        for exclude in request.excludes {
             task.arg("--exclude");
             task.arg(exclude);
         }
+        for exclude in request.iexcludes {
+            task.arg("--iexclude");
+            task.arg(exclude);
+        }
         task
     }
+
+    fn retry_lock(task: &mut ShellTask, secs: Option<u64>) {
+        if let Some(secs) = secs {
+            task.arg("--retry-lock");
+            task.arg(format!("{}s", secs));
+        }
+    }
+
+    /// Builds the command running the backup `task` in the restic container.
+    fn backup_command(config: &Config, task: ShellTask, stdin: bool) -> Command {
+        let mut command = config.docker_command_with_context(DockerSubcommand::exec(
+            config.restic_container_name(),
+            task,
+            if stdin { vec!["-i"] } else { vec![] },
+        )).into_command();
+        if config.dry_run() {
+            command.arg("--dry-run");
+        }
+        command
+    }
+
+    /// The `restic forget` task removing the snapshots that `retention` doesn't keep.
+    fn forget_task(config: &Config, retention: &Retention) -> Result<ShellTask, SerializableError> {
+        let rules = retention.rules();
+        if rules.is_empty() {
+            return Err(SerializableError::config("retention must keep something, set at least one keep_* rule"));
+        }
+        let mut task = ShellTask::new("restic");
+        task.args(["forget", "--prune", "--tag", HOARDER_TAG, "--host"])
+            .arg(config.restic_host()?)
+            .args(["--group-by", "host,paths"]);
+        Self::retry_lock(&mut task, config.lock().map(|l| l.wait));
+        for (rule, keep) in rules {
+            task.arg(format!("--keep-{}", rule)).arg(keep);
+        }
+        if config.dry_run() {
+            task.arg("--dry-run");
+        }
+        Ok(task)
+    }
+
+    /// Builds the command checking the repository in the restic container.
+    fn check_command(config: &Config, read_data_subset: Option<&str>) -> Command {
+        let mut task = ShellTask::new("restic");
+        task.arg("check");
+        Self::retry_lock(&mut task, config.lock().map(|l| l.wait));
+        match read_data_subset {
+            Some(subset) => task.arg(format!("--read-data-subset={}", subset)),
+            None => task.arg("--read-data"),
+        };
+        config.docker_command_with_context(DockerSubcommand::exec(
+            config.restic_container_name(),
+            task,
+            Vec::<String>::new(),
+        )).into_command()
+    }
+
+    /// Runs `task` in the restic container, following its json output until its summary.
+    async fn exec_backup(
+        config: &Config,
+        task: ShellTask,
+        input: Option<Box<dyn AsyncRead + Send + Unpin>>,
+        bar: &ProgressBar,
+        previous: Option<Duration>,
+    ) -> Result<BackupSummary, SerializableError> {
+        if config.dry_run() {
+            warn!("running in dry run mode, not actually uploading");
+        }
+        let mut command = Self::backup_command(config, task, input.is_some());
+        command
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .kill_on_drop(true);
+        info!("running restic backup task: {:?}", command.as_std().get_args().collect::<Vec<_>>());
+        let started = Instant::now();
+
+        let mut handle = command.audited_spawn()?;
+        let stdin = handle.stdin.take();
+        let feed = async {
+            if let (Some(mut input), Some(mut stdin)) = (input, stdin) {
+                tokio::io::copy(&mut input, &mut stdin).await?;
+                stdin.shutdown().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        let stdout = handle.stdout.take()
+            .ok_or(SerializableError::restic("no stdout found in restic output"))?;
+        let read = async {
+            let mut summary = BackupSummary::default();
+            let mut lines = BufReader::new(stdout).lines();
+            while let Some(line) = lines.next_line().await? {
+                match serde_json::from_str::<ResticMessage>(&line) {
+                    Ok(ResticMessage::Status { seconds_remaining, total_bytes, bytes_done }) => {
+                        bar.set_length(total_bytes);
+                        bar.set_position(bytes_done);
+                        // restic only knows the remaining time once it is done scanning, until then
+                        // guess from how long the previous upload took
+                        let eta = seconds_remaining
+                            .map(Duration::from_secs)
+                            .or_else(|| previous.map(|p| p.saturating_sub(started.elapsed())));
+                        bar.set_message(eta.map_or("eta unknown".to_owned(), |eta| format!("eta {}", HumanDuration(eta))));
+                    }
+                    Ok(ResticMessage::Summary { snapshot_id, data_added, .. }) => {
+                        summary = BackupSummary { snapshot_id, data_added: Some(data_added) };
+                    }
+                    Ok(ResticMessage::Other) => {},
+                    Err(_) => debug!("restic: {}", line),
+                }
+            }
+            Ok::<_, std::io::Error>(summary)
+        };
+        let (fed, summary) = tokio::join!(feed, read);
+        let exit = handle.wait().await?;
+        if !exit.success() {
+            return Err(SerializableError::restic(format!("backup failed: {}", exit)));
+        }
+        fed?;
+        Ok(summary?)
+    }
+}
+
+#[async_trait]
+impl BackupBackend for Restic {
+    fn ignores_case(&self) -> bool {
+        true
+    }
+
+    fn deep_checks(&self) -> bool {
+        true
+    }
+
+    async fn prepare(&self, config: &Config, mut mounts: Vec<DockerBinding>) -> Result<(), SerializableError> {
+        image::verify(config).await?;
+        mounts.extend(password_mounts(config)?);
+        let credentials = credentials(config)?;
+        let options = container_options(config, &mounts, &credentials)?;
+        backend::start_container_with_env(
+            config,
+            &config.restic_container_name(),
+            &config.restic_image(),
+            mounts,
+            options,
+            vec!["tini", "--", "sleep", "infinity"],
+            credentials.into_vars(),
+        ).await
+    }
+
+    async fn probe(&self, config: &Config) -> Result<(), SerializableError> {
+        let mut task = ShellTask::new("restic");
+        task.args(["cat", "config"]);
+        let command = config.docker_command_with_context(DockerSubcommand::exec(
+            config.restic_container_name(),
+            task,
+            Vec::<String>::new(),
+        )).into_command();
+        backend::run("restic cat config", command, None).await.map(|_| ())
+    }
+
+    async fn unreadable(&self, config: &Config, roots: &[PathBuf]) -> Result<Vec<String>, SerializableError> {
+        if roots.is_empty() {
+            return Ok(vec![]);
+        }
+        // find doesn't descend into the directories it can't read, they are reported as a whole
+        let mut task = ShellTask::new("sh");
+        task.args([
+            "-c",
+            r#"find "$@" \( -type d -o -type f \) -exec sh -c 'for p; do [ -r "$p" ] && { [ ! -d "$p" ] || [ -x "$p" ]; } || echo "$p"; done' sh {} + 2>/dev/null; true"#,
+            "sh",
+        ]);
+        task.args(roots.iter().map(|r| r.display()));
+        let command = config.docker_command_with_context(DockerSubcommand::exec(
+            config.restic_container_name(),
+            task,
+            Vec::<String>::new(),
+        )).into_command();
+        let output = backend::run("readability scan", command, None).await?;
+        Ok(output.lines().filter(|l| !l.is_empty()).map(str::to_owned).collect())
+    }
+
+    async fn teardown(&self, config: &Config) -> Result<bool, SerializableError> {
+        backend::stop_container(config, &config.restic_container_name()).await
+    }
+
+    async fn backup(
+        &self,
+        config: &Config,
+        request: BackupRequest,
+        bar: &ProgressBar,
+        previous: Option<Duration>,
+    ) -> Result<BackupSummary, SerializableError> {
+        Self::exec_backup(config, Self::backup_task(request), None, bar, previous).await
+    }
+
+    fn plan_commands(&self, config: &Config, request: &BackupRequest) -> Result<Vec<Command>, SerializableError> {
+        Ok(vec![Self::backup_command(config, Self::backup_task(request.clone()), false)])
+    }
+
+    async fn forget(&self, config: &Config, retention: &Retention) -> Result<(), SerializableError> {
+        let output = query(config, vec![], Self::forget_task(config, retention)?).await?;
+        for line in output.lines().filter(|l| !l.trim().is_empty()) {
+            debug!("restic forget: {}", line);
+        }
+        Ok(())
+    }
+
+    fn plan_forget(&self, config: &Config, retention: &Retention) -> Result<Vec<Command>, SerializableError> {
+        Ok(vec![command(config, vec![], Self::forget_task(config, retention)?)?])
+    }
+
+    async fn snapshots(&self, config: &Config) -> Result<Vec<Snapshot>, SerializableError> {
+        let mut task = ShellTask::new("restic");
+        task.args(["snapshots", "--json", "--tag", HOARDER_TAG, "--host"])
+            .arg(config.restic_host()?);
+        Ok(serde_json::from_str(&query(config, vec![], task).await?)?)
+    }
+
+    async fn restore(
+        &self,
+        config: &Config,
+        snapshot: &str,
+        path: &Path,
+        target: Option<&str>,
+        dry_run: bool,
+    ) -> Result<String, SerializableError> {
+        let mounts = target
+            .iter()
+            .map(|t| restore_mount(t, dry_run))
+            .collect();
+        let mut task = ShellTask::new("restic");
+        task.arg("restore")
+            .arg(format!("{}:{}", snapshot, path.display()))
+            .args(["--target", RESTORE_MOUNT]);
+        if dry_run {
+            task.args(["--dry-run", "--verbose=2"]);
+        }
+        query(config, mounts, task).await
+    }
+
+    fn dump(&self, config: &Config, snapshot: &str, path: &Path) -> Result<Command, SerializableError> {
+        let mut task = ShellTask::new("restic");
+        task.arg("dump").arg(snapshot).arg(path.display());
+        command(config, vec![], task)
+    }
+
+    async fn stats(&self, config: &Config) -> Result<RepositoryStats, SerializableError> {
+        #[derive(Deserialize)]
+        struct Stats {
+            total_size: u64,
+            #[serde(default)]
+            snapshots_count: u64,
+        }
+        let mut task = ShellTask::new("restic");
+        task.args(["stats", "--json", "--mode", "raw-data"]);
+        let stats: Stats = serde_json::from_str(&query(config, vec![], task).await?)?;
+        Ok(RepositoryStats { snapshots: stats.snapshots_count, stored_bytes: stats.total_size })
+    }
+
+    async fn deep_check(&self, config: &Config, read_data_subset: Option<&str>) -> Result<(), SerializableError> {
+        let output = backend::run("restic check", Self::check_command(config, read_data_subset), None).await?;
+        for line in output.lines().filter(|l| !l.trim().is_empty()) {
+            debug!("restic check: {}", line);
+        }
+        Ok(())
+    }
+
+    fn plan_check(&self, config: &Config, read_data_subset: Option<&str>) -> Result<Vec<Command>, SerializableError> {
+        Ok(vec![Self::check_command(config, read_data_subset)])
+    }
 }
@@ -0,0 +1,648 @@
+use std::{io::IsTerminal, path::{Path, PathBuf}, process::Stdio, time::Duration};
+
+use chrono::{Local, NaiveDate, NaiveDateTime};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{error, info, warn};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::SyncIoBridge;
+
+use crate::{
+    audit::Audited,
+    archive::{ArchiveInput, ArchiveOptions, PostRestoreTask},
+    config::Config,
+    either::Either::{Left, Right},
+    inspect,
+    picker,
+    backend::{self, BackupBackend, Snapshot},
+    restic,
+    service::Service,
+    state::State,
+    stream,
+    theme,
+    ArchiveFailure,
+    DockerComposeSubcommand,
+    DockerInputType,
+    DockerSubcommand,
+    DockerVolumeSubcommand,
+    exec_options,
+    PartialFile,
+    SerializableError,
+    ShellTask,
+};
+
+/// What to restore, and from where.
+#[derive(Debug, Clone)]
+pub(crate) struct RestoreOptions {
+    pub(crate) service: String,
+    /// archives to restore, every archive of the service if empty
+    pub(crate) archives: Vec<String>,
+    pub(crate) snapshot: SnapshotSelector,
+    /// owner given to the restored files, as `uid:gid`
+    pub(crate) owner: Option<String>,
+    /// only print what would be restored
+    pub(crate) dry_run: bool,
+    pub(crate) target: AlternateTarget,
+    /// show a spinner while each archive is restored
+    pub(crate) progress: bool,
+    /// bring the compose project up once its volumes are restored
+    pub(crate) up: bool,
+}
+
+/// Where to restore to, instead of where the archives were backed up from.
+#[derive(Debug, Clone)]
+pub(crate) enum AlternateTarget {
+    /// back where the archives came from
+    Original,
+    /// a host directory, each archive in its own subdirectory and dumps written as files
+    Directory(PathBuf),
+    /// the same volumes and services, but of another compose project
+    Project(String),
+    /// another named volume, for a single volume archive
+    Volume(String),
+}
+
+/// Restores the archives of a service from the repository, returning the ones that failed.
+pub(crate) async fn restore(services: &[Service], config: &Config, options: RestoreOptions) -> Result<Vec<ArchiveFailure>, SerializableError> {
+    let service = services
+        .iter()
+        .find(|s| s.name == options.service)
+        .ok_or_else(|| SerializableError::config(format!("service {} is not configured", options.service)))?;
+    if let Some(unknown) = options.archives.iter().find(|a| !service.archives.iter().any(|b| &&b.name == a)) {
+        return Err(SerializableError::config(format!("{}: archive {} is not configured", service.name, unknown)));
+    }
+    let archives = service.archives
+        .iter()
+        .filter(|a| options.archives.is_empty() || options.archives.contains(&a.name))
+        .collect::<Vec<_>>();
+    if let AlternateTarget::Volume(volume) = &options.target
+        && archives.len() != 1
+    {
+        return Err(SerializableError::config(format!("only one archive can be restored into volume {}", volume)));
+    }
+    let compose_project = match &options.target {
+        AlternateTarget::Project(project) => project.clone(),
+        _ => service.compose_project.clone().unwrap_or(service.name.clone()),
+    };
+    let service_path = Path::new(&config.restic_root()).join(&service.name);
+
+    let backend = backend::open_type(backend::primary(config, service));
+    let backend = backend.as_ref();
+    let snapshot = select_snapshot(config, backend, &service.name, &options.snapshot).await?;
+    info!("{}: restoring from snapshot {}", service.name, snapshot);
+
+    let mut failed = vec![];
+    let mut targets = vec![];
+    for archive in archives {
+        match resolve_target(config, service, &compose_project, archive, &options).await {
+            Ok(Some(target)) => targets.push((archive, target)),
+            Ok(None) => warn!("{}: {}: no restore_task configured, skipping", service.name, archive.name),
+            Err(e) => {
+                error!("{}: {}: restore failed: {}", service.name, archive.name, e);
+                failed.push(ArchiveFailure::new(&service.name, &archive.name, e));
+            }
+        }
+    }
+    // dumps are restored through the running services, which need their volumes first
+    targets.sort_by_key(|(_, target)| matches!(target, RestoreTarget::Stdin { .. }));
+    let mut up = options.up;
+    let mut restored = vec![];
+    for (archive, target) in targets {
+        if up && matches!(target, RestoreTarget::Stdin { .. }) {
+            up = false;
+            if let Err(e) = compose_up(config, service, &compose_project, options.dry_run).await {
+                error!("{}: failed to bring the project up: {}", service.name, e);
+                failed.push(ArchiveFailure::new(&service.name, "*", e));
+            }
+        }
+        info!("{}: {}: restoring into {}", service.name, archive.name, target);
+        let bar = match options.progress && !options.dry_run {
+            true => ProgressBar::new_spinner()
+                .with_style(ProgressStyle::with_template(&theme::spinner_template()).expect("valid template"))
+                .with_prefix(format!("{}: {}", service.name, archive.name))
+                .with_message(format!("restoring into {}", target)),
+            false => ProgressBar::hidden(),
+        };
+        bar.enable_steady_tick(Duration::from_millis(100));
+        let result = restore_archive(config, backend, &snapshot, &service_path, &archive.name, target, &options).await;
+        bar.finish_with_message(if result.is_ok() { "restored" } else { "failed" });
+        match result {
+            Ok(()) if options.dry_run => restored.push(archive),
+            Ok(()) => {
+                info!("{}: {}: restored", service.name, archive.name);
+                restored.push(archive);
+            }
+            Err(e) => {
+                error!("{}: {}: restore failed: {}", service.name, archive.name, e);
+                failed.push(ArchiveFailure::new(&service.name, &archive.name, e));
+            }
+        }
+    }
+    if up && let Err(e) = compose_up(config, service, &compose_project, options.dry_run).await {
+        error!("{}: failed to bring the project up: {}", service.name, e);
+        failed.push(ArchiveFailure::new(&service.name, "*", e));
+    }
+    for archive in restored {
+        if archive.post_restore.is_empty() {
+            continue;
+        }
+        if let AlternateTarget::Directory(_) | AlternateTarget::Volume(_) = options.target {
+            warn!("{}: {}: restored outside of the compose project, skipping post_restore", service.name, archive.name);
+            continue;
+        }
+        for post in &archive.post_restore {
+            if let Err(e) = post_restore(config, &compose_project, &format!("{}: {}", service.name, archive.name), post, options.dry_run).await {
+                error!("{}: {}: post_restore failed: {}", service.name, archive.name, e);
+                failed.push(ArchiveFailure::new(&service.name, &archive.name, e));
+                break;
+            }
+        }
+    }
+    Ok(failed)
+}
+
+/// Runs a post_restore task, printing the last line it wrote as its result.
+async fn post_restore(config: &Config, compose_project: &str, prefix: &str, post: &PostRestoreTask, dry_run: bool) -> Result<(), SerializableError> {
+    let description = format!("{}: post_restore `{}` in service {}", prefix, post.task.get_args().into_iter().collect::<Vec<_>>().join(" "), post.service);
+    if dry_run {
+        println!("{} would be run", description);
+        return Ok(());
+    }
+    let mut command = config.docker_command_with_context(DockerSubcommand::compose(
+        Some(Left(compose_project.to_owned())),
+        DockerComposeSubcommand::Exec { service: post.service.clone(), task: post.task.clone() },
+        Vec::<String>::new(),
+        vec!["-T"],
+    )).into_command();
+    let output = command.audited_output().await?;
+    if !output.status.success() {
+        return Err(SerializableError::docker(&command, output.status.code(), Some(String::from_utf8_lossy(&output.stderr).trim().to_owned())));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.lines().rev().find(|l| !l.trim().is_empty()) {
+        Some(line) => println!("{}: ok: {}", description, line.trim()),
+        None => println!("{}: ok", description),
+    }
+    Ok(())
+}
+
+/// Restores every configured service, in the order of the configuration, returning the archives
+/// that failed.
+pub(crate) async fn restore_all(services: &[Service], config: &Config, options: RestoreOptions) -> Result<Vec<ArchiveFailure>, SerializableError> {
+    let mut failed = vec![];
+    for service in services {
+        let options = RestoreOptions {
+            service: service.name.clone(),
+            archives: vec![],
+            target: match &options.target {
+                AlternateTarget::Directory(dir) => AlternateTarget::Directory(dir.join(&service.name)),
+                target => target.clone(),
+            },
+            ..options.clone()
+        };
+        match restore(services, config, options).await {
+            Ok(service_failed) => failed.extend(service_failed),
+            Err(e) => {
+                error!("{}: restore failed: {}", service.name, e);
+                failed.push(ArchiveFailure::new(&service.name, "*", e));
+            }
+        }
+    }
+    Ok(failed)
+}
+
+/// Starts the compose project of the service, from its compose_file.
+async fn compose_up(config: &Config, service: &Service, compose_project: &str, dry_run: bool) -> Result<(), SerializableError> {
+    let Some(compose_file) = &service.compose_file else {
+        warn!("{}: no compose_file configured, not bringing project {} up", service.name, compose_project);
+        return Ok(());
+    };
+    if dry_run {
+        println!("{}: project {} would be brought up from {}", service.name, compose_project, compose_file.display());
+        return Ok(());
+    }
+    info!("{}: bringing project {} up", service.name, compose_project);
+    let mut command = config.docker_command_with_context(DockerSubcommand::compose(
+        Some(Right(compose_file.clone())),
+        DockerComposeSubcommand::Up,
+        vec!["-p", compose_project],
+        vec!["-d", "--wait"],
+    )).into_command();
+    let output = command.audited_output().await?;
+    if !output.status.success() {
+        return Err(SerializableError::docker(&command, output.status.code(), Some(String::from_utf8_lossy(&output.stderr).trim().to_owned())));
+    }
+    Ok(())
+}
+
+/// Creates the containers of the service's project from its compose_file without starting them, so
+/// that their bound volumes can be found. Returns whether they were created.
+async fn compose_create(config: &Config, service: &Service, compose_project: &str, dry_run: bool) -> Result<bool, SerializableError> {
+    let Some(compose_file) = &service.compose_file else {
+        return Ok(false);
+    };
+    if dry_run {
+        println!("{}: the containers of project {} would be created from {} to find its bound volumes", service.name, compose_project, compose_file.display());
+        return Ok(false);
+    }
+    info!("{}: creating the containers of project {} to find its bound volumes", service.name, compose_project);
+    let mut command = config.docker_command_with_context(DockerSubcommand::compose(
+        Some(Right(compose_file.clone())),
+        DockerComposeSubcommand::Create,
+        vec!["-p", compose_project],
+        Vec::<String>::new(),
+    )).into_command();
+    let output = command.audited_output().await?;
+    if !output.status.success() {
+        return Err(SerializableError::docker(&command, output.status.code(), Some(String::from_utf8_lossy(&output.stderr).trim().to_owned())));
+    }
+    Ok(true)
+}
+
+/// Where an archive is restored to.
+#[derive(Debug)]
+enum RestoreTarget {
+    /// a named volume, created if it doesn't exist, labelled as the volume `name` of compose
+    /// `project` if set
+    Volume { volume: String, compose: Option<(String, String)> },
+    /// a directory on the host
+    Directory(String),
+    /// the stdin of a task run inside a compose service, fed with the dump `file`
+    Stdin { file: String, project: String, service: String, task: ShellTask, user: Option<String>, compressed: bool },
+    /// a file on the host, where the dump `file` is copied as is
+    File { file: String, destination: PathBuf },
+}
+
+impl std::fmt::Display for RestoreTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestoreTarget::Volume { volume, .. } => write!(f, "volume {}", volume),
+            RestoreTarget::Directory(path) => write!(f, "directory {}", path),
+            RestoreTarget::Stdin { service, task, .. } => write!(
+                f,
+                "the stdin of `{}` in service {}",
+                task.get_args().into_iter().collect::<Vec<_>>().join(" "),
+                service,
+            ),
+            RestoreTarget::File { destination, .. } => write!(f, "file {}", destination.display()),
+        }
+    }
+}
+
+/// Finds where the archive is restored: where it was backed up from unless an alternate target is
+/// given. None for dumps without a restore_task.
+async fn resolve_target(
+    config: &Config,
+    owner: &Service,
+    compose_project: &str,
+    archive: &ArchiveOptions,
+    options: &RestoreOptions,
+) -> Result<Option<RestoreTarget>, SerializableError> {
+    let input = match (&archive.input, &options.target) {
+        (ArchiveInput::Docker(input), _) => input,
+        // only the plugin knows where its data goes back
+        (ArchiveInput::Plugin(_), AlternateTarget::Directory(dir)) => {
+            return Ok(Some(RestoreTarget::Directory(absolute(&dir.join(&archive.name))?.display().to_string())));
+        }
+        (ArchiveInput::Plugin(input), _) => {
+            return Err(SerializableError::config(format!(
+                "archives staged by plugin {} can only be restored into a directory target",
+                input.plugin.display(),
+            )));
+        }
+    };
+    let file = |ext: &str| match archive.compression {
+        Some(_) => format!("{}.{}.zst", archive.name, ext),
+        None => format!("{}.{}", archive.name, ext),
+    };
+    match (&options.target, input) {
+        (AlternateTarget::Directory(dir), DockerInputType::ExecStdout { ext, .. }) => {
+            return Ok(Some(RestoreTarget::File { file: file(ext), destination: absolute(dir)?.join(file(ext)) }));
+        }
+        (AlternateTarget::Directory(dir), _) => {
+            return Ok(Some(RestoreTarget::Directory(absolute(&dir.join(&archive.name))?.display().to_string())));
+        }
+        (AlternateTarget::Volume(_), DockerInputType::ExecStdout { .. }) => {
+            return Err(SerializableError::config("dumps can't be restored into a volume, use a directory target instead"));
+        }
+        (AlternateTarget::Volume(volume), _) => {
+            return Ok(Some(RestoreTarget::Volume { volume: volume.clone(), compose: None }));
+        }
+        (AlternateTarget::Original | AlternateTarget::Project(_), _) => {},
+    }
+    Ok(Some(match input {
+        DockerInputType::Volume { name, .. } => RestoreTarget::Volume { volume: name.clone(), compose: None },
+        DockerInputType::ComposeNamedVolume { name, .. } => RestoreTarget::Volume {
+            volume: format!("{compose_project}_{name}"),
+            compose: Some((compose_project.to_owned(), name.clone())),
+        },
+        DockerInputType::ComposeBoundVolume { service, path, .. } => {
+            let mut host_path = inspect::bound_volume(config, compose_project, service, path).await?;
+            // on a new host the containers telling where the volume is bound don't exist yet
+            if host_path.is_none() && compose_create(config, owner, compose_project, options.dry_run).await? {
+                host_path = inspect::bound_volume(config, compose_project, service, path).await?;
+            }
+            let host_path = host_path
+                .ok_or_else(|| SerializableError::config(format!("{} is not a bound volume of service {}", path.display(), service)))?;
+            RestoreTarget::Directory(host_path)
+        }
+        DockerInputType::ExecStdout { service, ext, restore_task, user, .. } => {
+            let Some(task) = restore_task else {
+                return Ok(None);
+            };
+            RestoreTarget::Stdin {
+                file: file(ext),
+                project: compose_project.to_owned(),
+                service: service.clone(),
+                task: task.clone(),
+                user: user.clone(),
+                compressed: archive.compression.is_some(),
+            }
+        }
+    }))
+}
+
+async fn restore_archive(
+    config: &Config,
+    backend: &dyn BackupBackend,
+    snapshot: &str,
+    service_path: &Path,
+    archive_name: &str,
+    target: RestoreTarget,
+    options: &RestoreOptions,
+) -> Result<(), SerializableError> {
+    match target {
+        RestoreTarget::Volume { volume, compose } => {
+            let exists = volume_exists(config, &volume).await?;
+            if !options.dry_run {
+                create_volume(config, &volume, compose).await?;
+            } else if !exists {
+                println!("{}: volume {} would be created", archive_name, volume);
+            }
+            let mount = (exists || !options.dry_run).then_some(volume);
+            restore_into(config, backend, snapshot, &service_path.join(archive_name), archive_name, mount, options).await
+        }
+        RestoreTarget::Directory(path) => {
+            if !Path::new(&path).exists() {
+                if options.dry_run {
+                    println!("{}: directory {} would be created and every file restored into it", archive_name, path);
+                    return Ok(());
+                }
+                std::fs::create_dir_all(&path)?;
+            }
+            restore_into(config, backend, snapshot, &service_path.join(archive_name), archive_name, Some(path), options).await
+        }
+        RestoreTarget::Stdin { file, project, service, task, user, compressed } => {
+            if options.dry_run {
+                println!(
+                    "{}: {} would be streamed into `{}` in service {}",
+                    archive_name,
+                    file,
+                    task.get_args().into_iter().collect::<Vec<_>>().join(" "),
+                    service,
+                );
+                return Ok(());
+            }
+            restore_exec_stdin(config, backend, snapshot, &service_path.join(file), &project, &service, task, user.as_deref(), compressed).await
+        }
+        RestoreTarget::File { file, destination } => {
+            if options.dry_run {
+                println!("{}: {} would be written to {}", archive_name, file, destination.display());
+                return Ok(());
+            }
+            if let Some(dir) = destination.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            restore_file(config, backend, snapshot, &service_path.join(file), &destination).await
+        }
+    }
+}
+
+/// How a snapshot is picked among the ones of a service.
+#[derive(Debug, Clone)]
+pub(crate) enum SnapshotSelector {
+    /// a restic snapshot id
+    Id(String),
+    /// the most recent snapshot
+    Latest,
+    /// the most recent snapshot taken at or before this local time
+    At(NaiveDateTime),
+    /// a snapshot taken on this local day
+    Day(NaiveDate),
+    /// the snapshot taken by the hoarder run with this id, or id prefix
+    Run(String),
+}
+
+/// Resolves `selector` to the id of one of the service's snapshots, asking which one is meant when
+/// several match and stdin is a terminal.
+pub(crate) async fn select_snapshot(config: &Config, backend: &dyn BackupBackend, service: &str, selector: &SnapshotSelector) -> Result<String, SerializableError> {
+    let path = Path::new(&config.restic_root()).join(service);
+    let not_found = || SerializableError::backend(format!("no snapshot of {} matches {:?}", service, selector));
+    let candidates = match selector {
+        SnapshotSelector::Id(id) => return Ok(id.clone()),
+        SnapshotSelector::Run(id) => {
+            let state = State::load(config.state_dir()?)?;
+            let runs = state.runs()
+                .iter()
+                .filter(|r| r.id().starts_with(id.as_str()))
+                .filter_map(|r| r.services.get(service).and_then(|s| s.snapshot_id.clone()).map(|s| (r.id(), s)))
+                .collect::<Vec<_>>();
+            return choose(runs.into_iter().map(|(run, snapshot)| (format!("run {}: snapshot {}", run, snapshot), snapshot)).collect())?
+                .ok_or_else(not_found);
+        }
+        _ => backend.snapshots(config).await?,
+    };
+    let local = |s: &Snapshot| s.time.with_timezone(&Local);
+    let matching = candidates.iter().filter(|s| s.covers(&path));
+    let selected = match selector {
+        SnapshotSelector::Latest => matching.max_by_key(|s| s.time).map(|s| s.id.clone()),
+        SnapshotSelector::At(time) => matching
+            .filter(|s| local(s).naive_local() <= *time)
+            .max_by_key(|s| s.time)
+            .map(|s| s.id.clone()),
+        SnapshotSelector::Day(day) => {
+            let mut on_day = matching.filter(|s| local(s).date_naive() == *day).collect::<Vec<_>>();
+            on_day.sort_by_key(|s| s.time);
+            choose(on_day
+                .into_iter()
+                .map(|s| (format!("snapshot {} taken at {}", &s.id[..s.id.len().min(8)], local(s).format("%H:%M:%S")), s.id.clone()))
+                .collect())?
+        }
+        SnapshotSelector::Id(_) | SnapshotSelector::Run(_) => unreachable!("resolved above"),
+    };
+    selected.ok_or_else(not_found)
+}
+
+/// Picks one of `options`: the only one, or the one the user chooses when stdin is a terminal.
+fn choose(mut options: Vec<(String, String)>) -> Result<Option<String>, SerializableError> {
+    if options.len() <= 1 {
+        return Ok(options.pop().map(|(_, value)| value));
+    }
+    let listing = options.iter().map(|(description, _)| description.clone()).collect::<Vec<_>>();
+    if !std::io::stdin().is_terminal() {
+        return Err(SerializableError::config(format!("several snapshots match, pick one with --snapshot: {}", listing.join("; "))));
+    }
+    let picked = picker::select("snapshot", &listing)?;
+    Ok(Some(options.swap_remove(picked).1))
+}
+
+/// The most recent snapshot holding `path`.
+pub(crate) fn latest_snapshot<'a>(snapshots: &'a [Snapshot], path: &Path) -> Option<&'a Snapshot> {
+    snapshots
+        .iter()
+        .filter(|s| s.covers(path))
+        .max_by_key(|s| s.time)
+}
+
+/// Streams a dump out of the repository into the stdin of `restore_task`, run inside the compose
+/// service, decompressing it on the way if needed.
+#[allow(clippy::too_many_arguments)]
+async fn restore_exec_stdin(
+    config: &Config,
+    backend: &dyn BackupBackend,
+    snapshot: &str,
+    file: &Path,
+    compose_project: &str,
+    compose_service: &str,
+    restore_task: ShellTask,
+    user: Option<&str>,
+    compressed: bool,
+) -> Result<(), SerializableError> {
+    let mut dump = backend.dump(config, snapshot, file)?;
+    dump.stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let mut restore = config.docker_command_with_context(DockerSubcommand::compose(
+        Some(Left(compose_project.to_owned())),
+        DockerComposeSubcommand::Exec { service: compose_service.to_owned(), task: restore_task },
+        Vec::<String>::new(),
+        exec_options("-T", user),
+    )).into_command();
+    restore.stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let mut dump_handle = dump.audited_spawn()
+        .map_err(|e| SerializableError::docker(&dump, None, Some(e.to_string())))?;
+    let mut restore_handle = restore.audited_spawn()
+        .map_err(|e| SerializableError::docker(&restore, None, Some(e.to_string())))?;
+    let mut stdout = dump_handle.stdout.take()
+        .ok_or(SerializableError::restic("no stdout found in restic dump output"))?;
+    let mut stdin = restore_handle.stdin.take()
+        .ok_or(SerializableError::dump("no stdin found for the restore task"))?;
+    let dump_stderr = stream::drain_stderr(&mut dump_handle);
+    let restore_stderr = stream::drain_stderr(&mut restore_handle);
+
+    let copied = if compressed {
+        let reader = SyncIoBridge::new(stdout);
+        let writer = SyncIoBridge::new(stdin);
+        tokio::task::spawn_blocking(move || {
+            let mut writer = writer;
+            zstd::stream::copy_decode(reader, &mut writer)?;
+            writer.shutdown()
+        }).await
+            .map_err(|e| SerializableError::dump(format!("decompressor panicked: {}", e)))?
+    } else {
+        async {
+            tokio::io::copy(&mut stdout, &mut stdin).await?;
+            stdin.shutdown().await
+        }.await
+    };
+
+    let dump_status = dump_handle.wait().await?;
+    if !dump_status.success() {
+        return Err(SerializableError::docker(&dump, dump_status.code(), Some(dump_stderr.await)));
+    }
+    copied.map_err(|e| SerializableError::dump(format!("failed to stream the dump: {}", e)))?;
+    let restore_status = restore_handle.wait().await?;
+    if !restore_status.success() {
+        return Err(SerializableError::docker(&restore, restore_status.code(), Some(restore_stderr.await)));
+    }
+    Ok(())
+}
+
+/// Copies a dump out of the repository into a file on the host, as it is stored.
+async fn restore_file(config: &Config, backend: &dyn BackupBackend, snapshot: &str, file: &Path, destination: &Path) -> Result<(), SerializableError> {
+    let mut command = backend.dump(config, snapshot, file)?;
+    command.stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let mut handle = command.audited_spawn()
+        .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+    let mut stdout = handle.stdout.take()
+        .ok_or(SerializableError::restic("no stdout found in restic dump output"))?;
+    let stderr = stream::drain_stderr(&mut handle);
+    let partial = PartialFile::new(destination);
+    let copied = async {
+        let mut output = tokio::fs::File::create(partial.path()).await?;
+        tokio::io::copy(&mut stdout, &mut output).await?;
+        output.shutdown().await
+    }.await;
+    let status = handle.wait().await?;
+    if !status.success() {
+        return Err(SerializableError::docker(&command, status.code(), Some(stderr.await)));
+    }
+    copied.map_err(|e| SerializableError::dump(format!("failed to write {}: {}", destination.display(), e)))?;
+    partial.commit(destination)?;
+    Ok(())
+}
+
+/// Resolves `dir` to an absolute path, as docker needs for bind mounts.
+fn absolute(dir: &Path) -> Result<PathBuf, SerializableError> {
+    Ok(std::path::absolute(dir)?)
+}
+
+async fn volume_exists(config: &Config, volume: &str) -> Result<bool, SerializableError> {
+    Ok(inspect::volumes(config).await?.contains(volume))
+}
+
+/// Creates the named volume, labelled so that docker compose adopts it as the volume `name` of
+/// `project`, or reuses it if it exists.
+async fn create_volume(config: &Config, volume: &str, compose: Option<(String, String)>) -> Result<(), SerializableError> {
+    let labels = compose.map_or(vec![], |(project, name)| vec![
+        ("com.docker.compose.project".to_owned(), project),
+        ("com.docker.compose.volume".to_owned(), name),
+    ]);
+    let mut command = config.docker_command_with_context(DockerSubcommand::volume(DockerVolumeSubcommand::create(
+        volume,
+        labels,
+    ))).into_command();
+    let status = command.audited_status().await
+        .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+    if !status.success() {
+        return Err(SerializableError::docker(&command, status.code(), None));
+    }
+    Ok(())
+}
+
+/// Restores `path` of `snapshot` into `target`, a volume or host directory mounted read-write in a
+/// throwaway restic container, then hands the files over to the configured owner. In dry run mode
+/// the target is mounted read-only, or not at all if it doesn't exist yet, and the files restic
+/// would write are printed instead.
+async fn restore_into(
+    config: &Config,
+    backend: &dyn BackupBackend,
+    snapshot: &str,
+    path: &Path,
+    archive_name: &str,
+    target: Option<String>,
+    options: &RestoreOptions,
+) -> Result<(), SerializableError> {
+    let output = backend.restore(config, snapshot, path, target.as_deref(), options.dry_run).await?;
+    if options.dry_run {
+        for line in output.lines().filter(|l| !l.trim().is_empty()) {
+            println!("{}: {}", archive_name, line);
+        }
+    }
+
+    if let Some(owner) = &options.owner {
+        if options.dry_run {
+            println!("{}: the restored files would be given to {}", archive_name, owner);
+            return Ok(());
+        }
+        let mut task = ShellTask::new("chown");
+        task.args(["-R", owner, restic::RESTORE_MOUNT]);
+        // the restic image doubles as a helper container with the target mounted
+        restic::query(config, target.iter().map(|t| restic::restore_mount(t, false)).collect(), task).await?;
+    }
+    Ok(())
+}
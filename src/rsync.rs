@@ -0,0 +1,355 @@
+use std::{path::{Path, PathBuf}, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use indicatif::ProgressBar;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncRead, process::Command};
+
+use crate::{
+    backend::{self, BackupBackend, BackupRequest, BackupSummary, RepositoryStats, Retention, Snapshot, HOARDER_TAG},
+    config::Config,
+    DockerBinding,
+    DockerSubcommand,
+    SerializableError,
+    ShellTask,
+};
+
+static RSYNC_IMAGE: &str = "alpine";
+static RSYNC_CONTAINER_NAME: &str = "hoarder-rsync";
+/// Installs rsync and ssh, unless the image already provides them.
+static SETUP: &str = "command -v rsync >/dev/null && command -v ssh >/dev/null || apk add --no-cache rsync openssh-client >/dev/null";
+/// Where the ssh key is mounted inside the containers.
+static SSH_KEY_MOUNT: &str = "/rsync_ssh_key";
+/// Where the rsync daemon password file is mounted inside the containers.
+static PASSWORD_MOUNT: &str = "/rsync_password";
+/// Where the restore target is mounted inside the containers.
+static RESTORE_MOUNT: &str = "/restore";
+/// Prefix of the file marking a copy as synced, followed by the time it was.
+static MARKER: &str = ".hoarder-synced-";
+static TIME_FORMAT: &str = "%Y-%m-%dT%H%M%SZ";
+
+/// Settings of the rsync backend.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RsyncConfig {
+    /// remote host, as `user@host`
+    pub(crate) host: String,
+    /// ssh port of the remote host
+    pub(crate) port: Option<u16>,
+    /// rsync daemon module to push to, through ssh, instead of a path of the remote filesystem
+    pub(crate) module: Option<String>,
+    /// directory the copies are pushed to, in the module if one is set, every host getting a
+    /// directory in it
+    pub(crate) path: Option<String>,
+    /// ssh private key used to reach the remote host
+    pub(crate) ssh_key: Option<String>,
+    /// file holding the password of the rsync daemon module
+    pub(crate) password_file: Option<String>,
+    /// bandwidth limit, as passed to `rsync --bwlimit`, like `10m`
+    pub(crate) bwlimit: Option<String>,
+    /// image the copies are pushed from, it needs rsync and ssh, which are installed on alpine
+    /// images missing them
+    pub(crate) image: Option<String>,
+    /// name of the long running container
+    pub(crate) container_name: Option<String>,
+}
+
+impl RsyncConfig {
+    fn image(&self) -> String {
+        self.image.clone().unwrap_or(RSYNC_IMAGE.to_owned())
+    }
+
+    fn container_name(&self) -> String {
+        self.container_name.clone().unwrap_or(RSYNC_CONTAINER_NAME.to_owned())
+    }
+}
+
+/// Pushes each directory under the backup root to a plain copy on a remote host with rsync over
+/// ssh, at `path/host/directory`, for targets that only speak rsync. Only the latest copy is kept,
+/// a marker file in it recording when it was synced.
+pub(crate) struct Rsync;
+
+impl Rsync {
+    fn settings(config: &Config) -> Result<&RsyncConfig, SerializableError> {
+        config.rsync().ok_or(SerializableError::config("the rsync backend needs an rsync section"))
+    }
+
+    /// Joins `parts` to the configured path on the remote, as an rsync destination.
+    fn root(config: &Config, parts: &[&str]) -> Result<String, SerializableError> {
+        let settings = Self::settings(config)?;
+        let mut path = match &settings.module {
+            Some(module) => format!("{}::{}", settings.host, module),
+            None => format!("{}:", settings.host),
+        };
+        for part in settings.path.iter().map(String::as_str).chain(parts.iter().copied()) {
+            if part.is_empty() {
+                continue;
+            }
+            if !path.ends_with(':') {
+                path.push('/');
+            }
+            path.push_str(part.trim_matches('/'));
+        }
+        Ok(path)
+    }
+
+    /// Joins `parts` to the host's directory on the remote, as an rsync destination.
+    fn remote(config: &Config, parts: &[&str]) -> Result<String, SerializableError> {
+        let host = config.restic_host()?;
+        Self::root(config, &std::iter::once(host.as_str()).chain(parts.iter().copied()).collect::<Vec<_>>())
+    }
+
+    /// Splits a path under the backup root into its directory there and the path inside of it.
+    fn split(config: &Config, path: &Path) -> (String, String) {
+        let relative = path.strip_prefix(config.restic_root()).unwrap_or(path);
+        let mut components = relative.components();
+        let dir = components.next().map(|c| c.as_os_str().to_string_lossy().into_owned()).unwrap_or_default();
+        (dir, components.as_path().display().to_string())
+    }
+
+    /// Starts an rsync task reaching the remote host, adding the credentials to `mounts`.
+    fn rsync(config: &Config, mounts: &mut Vec<DockerBinding>) -> Result<ShellTask, SerializableError> {
+        let settings = Self::settings(config)?;
+        let mut ssh = ShellTask::new("ssh");
+        ssh.args(["-o", "StrictHostKeyChecking=accept-new", "-o", "BatchMode=yes"]);
+        if let Some(port) = settings.port {
+            ssh.arg("-p").arg(port);
+        }
+        if let Some(key) = &settings.ssh_key {
+            ssh.arg("-i").arg(SSH_KEY_MOUNT);
+            mounts.push(DockerBinding::new_ro(key.clone(), PathBuf::from(SSH_KEY_MOUNT)));
+        }
+        let mut task = ShellTask::new("rsync");
+        task.arg("-e").arg(ssh.get_args().into_iter().collect::<Vec<_>>().join(" "));
+        if let Some(file) = &settings.password_file {
+            task.arg(format!("--password-file={}", PASSWORD_MOUNT));
+            mounts.push(DockerBinding::new_ro(file.clone(), PathBuf::from(PASSWORD_MOUNT)));
+        }
+        if let Some(bwlimit) = &settings.bwlimit {
+            task.arg(format!("--bwlimit={}", bwlimit));
+        }
+        Ok(task)
+    }
+
+    /// Builds a command running `script` in a throwaway container.
+    fn command(config: &Config, mounts: Vec<DockerBinding>, script: &str) -> Result<Command, SerializableError> {
+        Ok(config.docker_command_with_context(DockerSubcommand::run(
+            Self::settings(config)?.image(),
+            mounts,
+            vec!["--rm", "--entrypoint", "sh"],
+            vec!["-c".to_owned(), format!("{}; {}", SETUP, script)],
+        )).into_command())
+    }
+
+    /// Builds a command running `script` in the long running container.
+    fn exec(config: &Config, script: &str, stdin: bool) -> Result<Command, SerializableError> {
+        let mut task = ShellTask::new("sh");
+        task.arg("-c").arg(script);
+        Ok(config.docker_command_with_context(DockerSubcommand::exec(
+            Self::settings(config)?.container_name(),
+            task,
+            if stdin { vec!["-i"] } else { vec![] },
+        )).into_command())
+    }
+
+    /// A script pushing `source` to the copy of `dir`, then replacing its marker.
+    /// The script pushing `request` to the remote, and the id of the copy.
+    fn backup_script(config: &Config, request: &BackupRequest) -> Result<(String, String), SerializableError> {
+        let (dir, _) = Self::split(config, &request.path);
+        // rsync anchors patterns starting with a slash to the source directory
+        let excludes = request.excludes
+            .iter()
+            .map(|e| format!("/{}", Path::new(e).strip_prefix(&request.path).unwrap_or(Path::new(e)).display()))
+            .collect::<Vec<_>>();
+        Self::push_script(config, &dir, &request.path.display().to_string(), &excludes)
+    }
+
+    fn push_script(config: &Config, dir: &str, source: &str, excludes: &[String]) -> Result<(String, String), SerializableError> {
+        let time = Utc::now().format(TIME_FORMAT).to_string();
+        let destination = format!("{}/", Self::remote(config, &[dir])?);
+        // the credentials are already mounted in the long running container
+        let mut rsync = Self::rsync(config, &mut vec![])?;
+        rsync.arg("-a");
+        if config.dry_run() {
+            rsync.arg("--dry-run");
+        }
+        // old rsyncs can't create the parents of the destination, push them from an empty tree
+        let skeleton = Path::new("/tmp/hoarder-skeleton").join(dir);
+        let marker_dir = skeleton.join(config.restic_host()?).join(dir);
+        let mut setup = ShellTask::new("mkdir");
+        setup.arg("-p").arg(marker_dir.display());
+        let mut parents = rsync.clone();
+        parents.arg(format!("{}/", skeleton.display())).arg(format!("{}/", Self::root(config, &[])?));
+
+        let mut push = rsync.clone();
+        // the marker is kept away from --delete, it is replaced on its own
+        push.args(["--delete", "--stats"]).arg(format!("--exclude=/{}*", MARKER));
+        for exclude in excludes {
+            push.arg(format!("--exclude={}", exclude));
+        }
+        push.arg(format!("{}/", source.trim_end_matches('/'))).arg(&destination);
+
+        let mut touch = ShellTask::new("touch");
+        touch.arg(marker_dir.join(format!("{}{}", MARKER, time)).display());
+        let mut mark = rsync;
+        mark.arg("--delete")
+            .arg(format!("--include=/{}*", MARKER))
+            .arg("--exclude=*")
+            .arg(format!("{}/", marker_dir.display()))
+            .arg(&destination);
+        let mut cleanup = ShellTask::new("rm");
+        cleanup.arg("-rf").arg(skeleton.display());
+        let script = format!(
+            "{cleanup} && {setup} && {parents} && {push} && {touch} && {mark}; status=$?; {cleanup}; exit $status",
+            cleanup = cleanup.to_shell(),
+            setup = setup.to_shell(),
+            parents = parents.to_shell(),
+            push = push.to_shell(),
+            touch = touch.to_shell(),
+            mark = mark.to_shell(),
+        );
+        Ok((script, format!("{}@{}", dir, time)))
+    }
+
+    /// Runs a push `script`, reporting the copy and how much it transferred.
+    async fn push(config: &Config, script: String, id: String, input: Option<Box<dyn AsyncRead + Send + Unpin>>) -> Result<BackupSummary, SerializableError> {
+        let stdin = input.is_some();
+        let output = backend::run("rsync", Self::exec(config, &script, stdin)?, input).await?;
+        let transferred = Self::total(&output, "Total transferred file size:");
+        if config.dry_run() {
+            info!("dry run, {} bytes would have been pushed", transferred.unwrap_or_default());
+            return Ok(BackupSummary::default());
+        }
+        Ok(BackupSummary { snapshot_id: Some(id), data_added: transferred })
+    }
+
+    /// Reads the byte count following `label` in the output of `rsync --stats`.
+    fn total(output: &str, label: &str) -> Option<u64> {
+        output
+            .lines()
+            .find_map(|l| l.strip_prefix(label))
+            .and_then(|l| l.trim().trim_end_matches("bytes").trim().replace(',', "").parse().ok())
+    }
+}
+
+#[async_trait]
+impl BackupBackend for Rsync {
+    async fn prepare(&self, config: &Config, mut mounts: Vec<DockerBinding>) -> Result<(), SerializableError> {
+        let settings = Self::settings(config)?;
+        Self::rsync(config, &mut mounts)?;
+        backend::start_container(
+            config,
+            &settings.container_name(),
+            &settings.image(),
+            mounts,
+            vec!["--init".to_owned(), "--entrypoint".to_owned(), "sleep".to_owned()],
+            vec!["infinity"],
+        ).await?;
+        backend::run("rsync", Self::exec(config, SETUP, false)?, None).await?;
+        Ok(())
+    }
+
+    async fn teardown(&self, config: &Config) -> Result<bool, SerializableError> {
+        backend::stop_container(config, &Self::settings(config)?.container_name()).await
+    }
+
+    async fn backup(
+        &self,
+        config: &Config,
+        request: BackupRequest,
+        bar: &ProgressBar,
+        _previous: Option<Duration>,
+    ) -> Result<BackupSummary, SerializableError> {
+        if config.dry_run() {
+            warn!("running in dry run mode, not actually pushing");
+        }
+        let (script, id) = Self::backup_script(config, &request)?;
+        bar.set_message("pushing");
+        Self::push(config, script, id, None).await
+    }
+
+    fn plan_commands(&self, config: &Config, request: &BackupRequest) -> Result<Vec<Command>, SerializableError> {
+        let (script, _) = Self::backup_script(config, request)?;
+        Ok(vec![Self::exec(config, &script, false)?])
+    }
+
+    async fn forget(&self, _config: &Config, _retention: &Retention) -> Result<(), SerializableError> {
+        warn!("the rsync backend only keeps the latest copy, there is nothing to forget");
+        Ok(())
+    }
+
+    async fn snapshots(&self, config: &Config) -> Result<Vec<Snapshot>, SerializableError> {
+        let mut mounts = vec![];
+        let mut task = Self::rsync(config, &mut mounts)?;
+        task.args(["--list-only", "-r", "--include=/*/"])
+            .arg(format!("--include=/*/{}*", MARKER))
+            .arg("--exclude=*")
+            .arg(format!("{}/", Self::remote(config, &[])?));
+        let output = match backend::run("rsync", Self::command(config, mounts, &task.to_shell())?, None).await {
+            Ok(output) => output,
+            // nothing was pushed yet
+            Err(e) if e.to_string().contains("No such file or directory") => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        Ok(output
+            .lines()
+            .filter_map(|l| {
+                let (dir, marker) = l.split_whitespace().last()?.split_once('/')?;
+                let time = NaiveDateTime::parse_from_str(marker.strip_prefix(MARKER)?, TIME_FORMAT).ok()?.and_utc();
+                Some(Snapshot {
+                    id: format!("{}@{}", dir, time.format(TIME_FORMAT)),
+                    time,
+                    paths: vec![Path::new(&config.restic_root()).join(dir).display().to_string()],
+                    tags: vec![HOARDER_TAG.to_owned()],
+                })
+            })
+            .collect())
+    }
+
+    async fn restore(
+        &self,
+        config: &Config,
+        snapshot: &str,
+        path: &Path,
+        target: Option<&str>,
+        dry_run: bool,
+    ) -> Result<String, SerializableError> {
+        let (dir, inner) = Self::split(config, path);
+        if !snapshot.starts_with(&format!("{}@", dir)) {
+            return Err(SerializableError::backend(format!("snapshot {} isn't a copy of {}", snapshot, dir)));
+        }
+        let mut mounts = target
+            .iter()
+            .map(|t| DockerBinding::new_rw(t.to_string(), PathBuf::from(RESTORE_MOUNT)))
+            .collect();
+        let mut task = Self::rsync(config, &mut mounts)?;
+        task.args(["-a", "--out-format=%n"]).arg(format!("--exclude=/{}*", MARKER));
+        if dry_run {
+            task.arg("--dry-run");
+        }
+        // the contents of the directory are restored into the target
+        task.arg(format!("{}/", Self::remote(config, &[&dir, &inner])?)).arg(format!("{}/", RESTORE_MOUNT));
+        backend::run("rsync", Self::command(config, mounts, &task.to_shell())?, None).await
+    }
+
+    fn dump(&self, config: &Config, _snapshot: &str, path: &Path) -> Result<Command, SerializableError> {
+        let (dir, inner) = Self::split(config, path);
+        let mut mounts = vec![];
+        let mut task = Self::rsync(config, &mut mounts)?;
+        task.arg("-q").arg(Self::remote(config, &[&dir, &inner])?).arg("/tmp/dump");
+        Self::command(config, mounts, &format!("{} && cat /tmp/dump", task.to_shell()))
+    }
+
+    async fn stats(&self, config: &Config) -> Result<RepositoryStats, SerializableError> {
+        let mut mounts = vec![];
+        let mut task = Self::rsync(config, &mut mounts)?;
+        task.args(["--list-only", "-r", "--stats"]).arg(format!("{}/", Self::remote(config, &[])?));
+        let output = backend::run("rsync", Self::command(config, mounts, &task.to_shell())?, None).await?;
+        Ok(RepositoryStats {
+            snapshots: self.snapshots(config).await?.len() as u64,
+            stored_bytes: Self::total(&output, "Total file size:")
+                .ok_or_else(|| SerializableError::backend("unexpected rsync --stats output"))?,
+        })
+    }
+}
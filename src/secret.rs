@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+
+use log::{debug, info};
+use reqwest::Client;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_yaml::Value;
+
+use crate::{audit::{self, Audited}, SerializableError};
+
+/// A value kept out of logs and serialized configurations.
+#[derive(Clone, Default)]
+pub(crate) struct Secret(pub(crate) String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("***")
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let secret = String::deserialize(deserializer)?;
+        audit::secret(&secret);
+        Ok(Secret(secret))
+    }
+}
+
+/// How hoarder logs in to Vault, under `vault` in the configuration.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub(crate) struct VaultConfig {
+    /// address of the server, `VAULT_ADDR` if unset
+    #[serde(default)]
+    address: Option<String>,
+    /// namespace the secrets are in, `VAULT_NAMESPACE` if unset
+    #[serde(default)]
+    namespace: Option<String>,
+    /// file holding the token, `VAULT_TOKEN` if unset and no approle is set either
+    #[serde(default)]
+    token_file: Option<String>,
+    /// logs in with an AppRole instead of a token
+    #[serde(default)]
+    approle: Option<AppRole>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct AppRole {
+    role_id: String,
+    /// file holding the secret id
+    secret_id_file: String,
+    /// where the approle auth method is mounted
+    #[serde(default = "AppRole::default_mount")]
+    mount: String,
+}
+
+impl AppRole {
+    fn default_mount() -> String {
+        "approle".to_owned()
+    }
+}
+
+/// A logged in Vault client.
+struct Vault {
+    client: Client,
+    address: String,
+    namespace: Option<String>,
+    token: String,
+}
+
+impl Vault {
+    async fn login(config: &VaultConfig) -> Result<Self, SerializableError> {
+        let address = config.address.clone()
+            .or_else(|| std::env::var("VAULT_ADDR").ok())
+            .ok_or(SerializableError::config("vault secrets need vault.address or VAULT_ADDR to be set"))?;
+        let mut vault = Vault {
+            client: Client::new(),
+            address: address.trim_end_matches('/').to_owned(),
+            namespace: config.namespace.clone().or_else(|| std::env::var("VAULT_NAMESPACE").ok()),
+            token: String::new(),
+        };
+        vault.token = match (&config.approle, &config.token_file) {
+            (Some(approle), _) => {
+                let secret_id = std::fs::read_to_string(&approle.secret_id_file)?;
+                let body = serde_json::json!({ "role_id": approle.role_id, "secret_id": secret_id.trim() });
+                let response = vault.request(reqwest::Method::POST, &format!("auth/{}/login", approle.mount))
+                    .json(&body)
+                    .send()
+                    .await;
+                let response = vault.json(response, "approle login").await?;
+                response["auth"]["client_token"]
+                    .as_str()
+                    .ok_or(SerializableError::config("vault approle login returned no token"))?
+                    .to_owned()
+            }
+            (None, Some(file)) => std::fs::read_to_string(file)?.trim().to_owned(),
+            (None, None) => std::env::var("VAULT_TOKEN")
+                .map_err(|_| SerializableError::config("vault secrets need vault.token_file, vault.approle or VAULT_TOKEN to be set"))?,
+        };
+        Ok(vault)
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let mut request = self.client.request(method, format!("{}/v1/{}", self.address, path));
+        if !self.token.is_empty() {
+            request = request.header("X-Vault-Token", &self.token);
+        }
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        request
+    }
+
+    async fn json(&self, response: reqwest::Result<reqwest::Response>, what: &str) -> Result<serde_json::Value, SerializableError> {
+        let response = response.map_err(|e| SerializableError::config(format!("vault {} failed: {}", what, e)))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(SerializableError::config(format!("vault {} failed: {}", what, status)));
+        }
+        response.json().await.map_err(|e| SerializableError::config(format!("vault {} returned invalid json: {}", what, e)))
+    }
+
+    /// Reads `key` of the KV version 2 secret at `path` of the engine mounted at `mount`.
+    async fn read(&self, mount: &str, path: &str, key: &str) -> Result<String, SerializableError> {
+        let what = format!("read of {}/{}", mount, path);
+        let response = self.request(reqwest::Method::GET, &format!("{}/data/{}", mount, path)).send().await;
+        let response = self.json(response, &what).await?;
+        match &response["data"]["data"][key] {
+            serde_json::Value::String(value) => Ok(value.clone()),
+            serde_json::Value::Null => Err(SerializableError::config(format!("vault secret {}/{} has no key {}", mount, path, key))),
+            value => Ok(value.to_string()),
+        }
+    }
+}
+
+/// A reference to a secret, written in place of the value in the configuration. The cloud ones
+/// are read with the `aws` and `gcloud` clients, which use the identity of the instance.
+#[derive(Debug, PartialEq, Eq)]
+enum Reference<'a> {
+    /// `vault://<mount>/<path>#<key>`, a key of a KV version 2 secret
+    Vault { mount: &'a str, path: &'a str, key: &'a str },
+    /// `aws-sm://<secret id>[#<key>]`, an AWS Secrets Manager secret, or a key of it when it is a
+    /// json object
+    AwsSecretsManager { id: &'a str, key: Option<&'a str> },
+    /// `aws-ssm://<name>`, an AWS Systems Manager parameter, decrypted
+    AwsParameter { name: &'a str },
+    /// `gcp-sm://<project>/<secret>[/<version>][#<key>]`, a GCP Secret Manager secret, its latest
+    /// version by default
+    GcpSecretManager { project: &'a str, secret: &'a str, version: &'a str, key: Option<&'a str> },
+}
+
+impl<'a> Reference<'a> {
+    fn parse(value: &'a str) -> Option<Result<Self, String>> {
+        let (scheme, reference) = value.split_once("://")?;
+        let non_empty = |s: &&str| !s.is_empty();
+        let parsed = match scheme {
+            "vault" => reference
+                .split_once('#')
+                .and_then(|(secret, key)| secret.split_once('/').map(|(mount, path)| (mount, path, key)))
+                .filter(|(mount, path, key)| !mount.is_empty() && !path.is_empty() && !key.is_empty())
+                .map(|(mount, path, key)| Reference::Vault { mount, path, key })
+                .ok_or("vault://<mount>/<path>#<key>"),
+            "aws-sm" => {
+                let (id, key) = split_key(reference);
+                Some(id).filter(non_empty).map(|id| Reference::AwsSecretsManager { id, key }).ok_or("aws-sm://<secret id>[#<key>]")
+            }
+            "aws-ssm" => Some(reference).filter(non_empty).map(|name| Reference::AwsParameter { name }).ok_or("aws-ssm://<name>"),
+            "gcp-sm" => {
+                let (secret, key) = split_key(reference);
+                let mut parts = secret.split('/');
+                match (parts.next(), parts.next(), parts.next().unwrap_or("latest"), parts.next()) {
+                    (Some(project), Some(secret), version, None) if !project.is_empty() && !secret.is_empty() && !version.is_empty() => {
+                        Ok(Reference::GcpSecretManager { project, secret, version, key })
+                    }
+                    _ => Err("gcp-sm://<project>/<secret>[/<version>][#<key>]"),
+                }
+            }
+            _ => return None,
+        };
+        Some(parsed.map_err(|form| format!("{} isn't a {} reference", value, form)))
+    }
+}
+
+/// Splits the `#<key>` off a reference.
+fn split_key(reference: &str) -> (&str, Option<&str>) {
+    match reference.split_once('#') {
+        Some((secret, key)) => (secret, Some(key)),
+        None => (reference, None),
+    }
+}
+
+/// Runs the cloud client `program` to print a secret, reading `key` of it when set.
+async fn cloud_secret(program: &str, args: &[&str], reference: &str, key: Option<&str>) -> Result<String, SerializableError> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .audited_output()
+        .await
+        .map_err(|e| SerializableError::config(format!("failed to run {} to read {}: {}", program, reference, e)))?;
+    if !output.status.success() {
+        return Err(SerializableError::config(format!(
+            "{} failed to read {}: {}",
+            program,
+            reference,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )));
+    }
+    let secret = String::from_utf8(output.stdout)
+        .map_err(|_| SerializableError::config(format!("{} isn't valid utf-8", reference)))?;
+    let secret = secret.strip_suffix('\n').unwrap_or(&secret);
+    let Some(key) = key else {
+        return Ok(secret.to_owned());
+    };
+    let object: serde_json::Value = serde_json::from_str(secret)
+        .map_err(|_| SerializableError::config(format!("{} isn't a json object, it has no key {}", reference, key)))?;
+    match &object[key] {
+        serde_json::Value::String(value) => Ok(value.clone()),
+        serde_json::Value::Null => Err(SerializableError::config(format!("{} has no key {}", reference, key))),
+        value => Ok(value.to_string()),
+    }
+}
+
+/// The strings of `value` that reference secrets.
+fn references(value: &Value, found: &mut Vec<String>) {
+    match value {
+        Value::String(s) if Reference::parse(s).is_some() => found.push(s.clone()),
+        Value::Sequence(sequence) => sequence.iter().for_each(|v| references(v, found)),
+        Value::Mapping(mapping) => mapping.values().for_each(|v| references(v, found)),
+        Value::Tagged(tagged) => references(&tagged.value, found),
+        _ => (),
+    }
+}
+
+fn replace(value: &mut Value, resolved: &HashMap<String, String>) {
+    match value {
+        Value::String(s) => if let Some(secret) = resolved.get(s) {
+            *s = secret.clone();
+        },
+        Value::Sequence(sequence) => sequence.iter_mut().for_each(|v| replace(v, resolved)),
+        Value::Mapping(mapping) => mapping.values_mut().for_each(|v| replace(v, resolved)),
+        Value::Tagged(tagged) => replace(&mut tagged.value, resolved),
+        _ => (),
+    }
+}
+
+/// Replaces the secret references of the configuration `text` with the secrets, logging in to
+/// the stores with the configuration's own settings. Returns none when there is no reference.
+pub(crate) async fn resolve(text: &str) -> Result<Option<String>, SerializableError> {
+    let mut value: Value = serde_yaml::from_str(text)
+        .map_err(|e| SerializableError::config(format!("failed to parse config file: {}", e)))?;
+    let mut found = vec![];
+    references(&value, &mut found);
+    if found.is_empty() {
+        return Ok(None);
+    }
+    found.sort();
+    found.dedup();
+    let vault_config: VaultConfig = match value.get("vault") {
+        Some(vault) => serde_yaml::from_value(vault.clone())
+            .map_err(|e| SerializableError::config(format!("failed to parse the vault section: {}", e)))?,
+        None => VaultConfig::default(),
+    };
+    let mut vault = None;
+    let mut resolved = HashMap::new();
+    for reference in found {
+        let secret = match Reference::parse(&reference).expect("only references are collected") {
+            Ok(Reference::Vault { mount, path, key }) => {
+                let vault = match &mut vault {
+                    Some(vault) => vault,
+                    None => vault.insert(Vault::login(&vault_config).await?),
+                };
+                vault.read(mount, path, key).await?
+            }
+            Ok(Reference::AwsSecretsManager { id, key }) => {
+                let args = ["secretsmanager", "get-secret-value", "--secret-id", id, "--query", "SecretString", "--output", "text"];
+                cloud_secret("aws", &args, &reference, key).await?
+            }
+            Ok(Reference::AwsParameter { name }) => {
+                let args = ["ssm", "get-parameter", "--name", name, "--with-decryption", "--query", "Parameter.Value", "--output", "text"];
+                cloud_secret("aws", &args, &reference, None).await?
+            }
+            Ok(Reference::GcpSecretManager { project, secret, version, key }) => {
+                let secret = format!("--secret={}", secret);
+                let project = format!("--project={}", project);
+                let args = ["secrets", "versions", "access", version, secret.as_str(), project.as_str()];
+                cloud_secret("gcloud", &args, &reference, key).await?
+            }
+            Err(e) => return Err(SerializableError::config(e)),
+        };
+        debug!("resolved {}", reference);
+        audit::secret(&secret);
+        resolved.insert(reference, secret);
+    }
+    info!("resolved {} secret references", resolved.len());
+    replace(&mut value, &resolved);
+    serde_yaml::to_string(&value)
+        .map(Some)
+        .map_err(|e| SerializableError::config(format!("failed to write the resolved configuration: {}", e)))
+}
+
+#[test]
+fn test_secret_references() {
+    assert_eq!(
+        Reference::parse("vault://secret/hoarder/restic#password"),
+        Some(Ok(Reference::Vault { mount: "secret", path: "hoarder/restic", key: "password" })),
+    );
+    assert!(matches!(Reference::parse("vault://secret#password"), Some(Err(_))));
+    assert_eq!(Reference::parse("https://example.com/#x"), None);
+    assert_eq!(
+        Reference::parse("aws-sm://prod/hoarder#password"),
+        Some(Ok(Reference::AwsSecretsManager { id: "prod/hoarder", key: Some("password") })),
+    );
+    assert_eq!(Reference::parse("aws-ssm:///hoarder/password"), Some(Ok(Reference::AwsParameter { name: "/hoarder/password" })));
+    assert_eq!(
+        Reference::parse("gcp-sm://acme/restic"),
+        Some(Ok(Reference::GcpSecretManager { project: "acme", secret: "restic", version: "latest", key: None })),
+    );
+    assert!(matches!(Reference::parse("gcp-sm://acme"), Some(Err(_))));
+
+    let mut value: Value = serde_yaml::from_str("restic_password: vault://secret/r#p\nhooks:\n  notifiers:\n    - type: slack\n      webhook: vault://secret/s#url\n").unwrap();
+    let mut found = vec![];
+    references(&value, &mut found);
+    assert_eq!(found, ["vault://secret/r#p", "vault://secret/s#url"]);
+    replace(&mut value, &HashMap::from([("vault://secret/r#p".to_owned(), "hunter2".to_owned())]));
+    assert_eq!(value["restic_password"], "hunter2");
+    assert_eq!(format!("{:?}", Secret("hunter2".to_owned())), "***");
+}
@@ -1,10 +1,175 @@
+use std::path::PathBuf;
+
 use serde::{Deserialize, Serialize};
 
-use crate::archive::ArchiveOptions;
+use crate::{archive::{ArchiveInput, ArchiveOptions}, backend::BackendType, config::Config, docker::PathExclude, skip::SkipIf, DockerInputType, SerializableError, ShellTask};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct Service {
     pub(crate) name: String,
     pub(crate) archives: Vec<ArchiveOptions>,
     pub(crate) compose_project: Option<String>,
+    /// compose file of the project, used to bring it back up after a restore
+    #[serde(default)]
+    pub(crate) compose_file: Option<PathBuf>,
+    /// maximum age in seconds of the last successful backup before the service is reported as stale
+    #[serde(default)]
+    pub(crate) max_age: Option<u64>,
+    /// minimum number of snapshots of the service taken in the last 7 days, checked against the
+    /// repository by `hoarder status`
+    #[serde(default)]
+    pub(crate) min_snapshots_7d: Option<u32>,
+    /// maximum age in seconds of the service's latest snapshot in the repository, checked by
+    /// `hoarder status`
+    #[serde(default)]
+    pub(crate) max_snapshot_age: Option<u64>,
+    /// backends the service is backed up to, one after the other, only the configured backend if
+    /// unset
+    #[serde(default)]
+    pub(crate) backends: Option<Vec<BackendType>>,
+    /// tasks run before the service's archives are staged, like turning on a maintenance mode
+    #[serde(default)]
+    pub(crate) before: Vec<ServiceTask>,
+    /// tasks run once the service is backed up, or failed to be, undoing the `before` tasks
+    #[serde(default)]
+    pub(crate) after: Vec<ServiceTask>,
+    /// whether the service is backed up, keeping a parked service configured
+    #[serde(default = "Service::default_enabled")]
+    pub(crate) enabled: bool,
+    /// when the service is left out of a run
+    #[serde(default)]
+    pub(crate) skip_if: Option<SkipIf>,
+    /// services backed up before this one, like the one whose maintenance mode covers it
+    #[serde(default)]
+    pub(crate) depends_on: Vec<String>,
+    /// whether each archive is backed up without crossing into the file systems mounted inside
+    /// it, like restic's `--one-file-system`
+    #[serde(default)]
+    pub(crate) one_file_system: bool,
+}
+
+/// A task run inside a compose service of the service's project.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ServiceTask {
+    /// compose service the task runs in
+    pub(crate) service: String,
+    pub(crate) task: ShellTask,
+    /// user the task runs as, the container's if unset
+    #[serde(default)]
+    pub(crate) user: Option<String>,
+}
+
+impl Service {
+    pub(crate) fn default_enabled() -> bool {
+        true
+    }
+
+    pub(crate) fn backends(&self, config: &Config) -> Vec<BackendType> {
+        self.backends.clone().unwrap_or_else(|| vec![config.backend()])
+    }
+}
+
+/// Puts a service together in code, checking its archives as they are added.
+pub(crate) struct ServiceBuilder {
+    service: Service,
+    problems: Vec<String>,
+}
+
+#[allow(dead_code)]
+impl ServiceBuilder {
+    pub(crate) fn new(name: impl ToString) -> Self {
+        Self {
+            service: Service {
+                name: name.to_string(),
+                archives: vec![],
+                compose_project: None,
+                compose_file: None,
+                max_age: None,
+                min_snapshots_7d: None,
+                max_snapshot_age: None,
+                backends: None,
+                before: vec![],
+                after: vec![],
+                enabled: Service::default_enabled(),
+                skip_if: None,
+                depends_on: vec![],
+                one_file_system: false,
+            },
+            problems: vec![],
+        }
+    }
+
+    pub(crate) fn compose_project(mut self, compose_project: impl ToString) -> Self {
+        self.service.compose_project = Some(compose_project.to_string());
+        self
+    }
+
+    pub(crate) fn compose_file(mut self, compose_file: impl Into<PathBuf>) -> Self {
+        self.service.compose_file = Some(compose_file.into());
+        self
+    }
+
+    pub(crate) fn max_age(mut self, max_age: u64) -> Self {
+        self.service.max_age = Some(max_age);
+        self
+    }
+
+    pub(crate) fn min_snapshots_7d(mut self, min_snapshots_7d: u32) -> Self {
+        self.service.min_snapshots_7d = Some(min_snapshots_7d);
+        self
+    }
+
+    pub(crate) fn max_snapshot_age(mut self, max_snapshot_age: u64) -> Self {
+        self.service.max_snapshot_age = Some(max_snapshot_age);
+        self
+    }
+
+    pub(crate) fn backend(mut self, backend: BackendType) -> Self {
+        let backends = self.service.backends.get_or_insert_default();
+        if backends.contains(&backend) {
+            self.problems.push(format!("backend {} is added more than once", backend.name()));
+        } else {
+            backends.push(backend);
+        }
+        self
+    }
+
+    /// Adds an archive, with its options set as if it was loaded from yaml.
+    pub(crate) fn archive(mut self, name: impl ToString, input: DockerInputType) -> Self {
+        let name = name.to_string();
+        if self.service.archives.iter().any(|a| a.name == name) {
+            self.problems.push(format!("archive {} is added more than once", name));
+        }
+        self.service.archives.push(ArchiveOptions::new(name, ArchiveInput::Docker(input)));
+        self
+    }
+
+    pub(crate) fn named_volume(self, name: impl ToString, volume: impl ToString, exclude: Vec<PathBuf>) -> Self {
+        let filter = (!exclude.is_empty()).then(|| PathExclude::new(exclude));
+        self.archive(name, DockerInputType::ComposeNamedVolume { name: volume.to_string(), filter })
+    }
+
+    pub(crate) fn bound_volume(self, name: impl ToString, service: impl ToString, path: impl Into<PathBuf>, exclude: Vec<PathBuf>) -> Self {
+        let filter = (!exclude.is_empty()).then(|| PathExclude::new(exclude));
+        self.archive(name, DockerInputType::ComposeBoundVolume { service: service.to_string(), path: path.into(), filter })
+    }
+
+    pub(crate) fn exec_stdout(self, name: impl ToString, service: impl ToString, task: ShellTask, ext: impl ToString) -> Self {
+        self.archive(name, DockerInputType::ExecStdout { service: service.to_string(), task, ext: ext.to_string(), restore_task: None, user: None })
+    }
+
+    pub(crate) fn depends_on(mut self, service: impl ToString) -> Self {
+        self.service.depends_on.push(service.to_string());
+        self
+    }
+
+    pub(crate) fn build(self) -> Result<Service, SerializableError> {
+        if self.service.archives.is_empty() {
+            return Err(SerializableError::config(format!("{}: service has no archives", self.service.name)));
+        }
+        if !self.problems.is_empty() {
+            return Err(SerializableError::config(format!("{}: {}", self.service.name, self.problems.join("; "))));
+        }
+        Ok(self.service)
+    }
 }
@@ -0,0 +1,113 @@
+//! Services and archives left out of a run: disabled, or skipped by a condition checked when the
+//! run is planned.
+
+use std::{path::PathBuf, process::Stdio};
+
+use chrono::{Datelike, Local, Weekday};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::{audit::Audited, service::Service, ShellTask};
+
+/// When a service or an archive is skipped, any condition being enough.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub(crate) struct SkipIf {
+    /// command run on the host, skipping when it exits successfully
+    #[serde(default)]
+    pub(crate) command: Option<ShellTask>,
+    /// file whose existence skips, like a flag dropped during a maintenance
+    #[serde(default)]
+    pub(crate) file: Option<PathBuf>,
+    /// days of the week skipped, like `[sat, sun]`
+    #[serde(default)]
+    pub(crate) days: Vec<Weekday>,
+}
+
+impl SkipIf {
+    /// Why the condition skips today, if it does.
+    pub(crate) async fn reason(&self, today: Weekday) -> Option<String> {
+        if self.days.contains(&today) {
+            return Some(format!("skipped on {}", today));
+        }
+        if let Some(file) = self.file.as_ref().filter(|f| f.exists()) {
+            return Some(format!("{} exists", file.display()));
+        }
+        let task = self.command.as_ref()?;
+        let command = task.get_args().into_iter().collect::<Vec<_>>();
+        let (program, args) = command.split_first()?;
+        let status = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .audited_status()
+            .await;
+        match status {
+            Ok(status) if status.success() => Some(format!("`{}` succeeded", command.join(" "))),
+            Ok(_) => None,
+            Err(e) => {
+                // backing up is the safe side
+                warn!("failed to run the skip_if command {}, not skipping: {}", program, e);
+                None
+            }
+        }
+    }
+}
+
+/// Leaves out the disabled services and archives, and the ones whose `skip_if` holds. Services
+/// left with no archives are left out as well.
+pub(crate) async fn apply(services: &mut Vec<Service>) {
+    let today = Local::now().weekday();
+    let mut kept = vec![];
+    for mut service in services.drain(..) {
+        if !service.enabled {
+            info!("{}: disabled, skipping", service.name);
+            continue;
+        }
+        if let Some(reason) = match &service.skip_if {
+            Some(skip_if) => skip_if.reason(today).await,
+            None => None,
+        } {
+            info!("{}: {}, skipping", service.name, reason);
+            continue;
+        }
+        let mut archives = vec![];
+        for archive in service.archives.drain(..) {
+            if !archive.enabled {
+                info!("{}: {}: disabled, skipping", service.name, archive.name);
+                continue;
+            }
+            if let Some(reason) = match &archive.skip_if {
+                Some(skip_if) => skip_if.reason(today).await,
+                None => None,
+            } {
+                info!("{}: {}: {}, skipping", service.name, archive.name, reason);
+                continue;
+            }
+            archives.push(archive);
+        }
+        if archives.is_empty() {
+            info!("{}: every archive is skipped, skipping", service.name);
+            continue;
+        }
+        service.archives = archives;
+        kept.push(service);
+    }
+    *services = kept;
+}
+
+#[tokio::test]
+async fn test_skip_if() {
+    let skip_if = SkipIf { days: vec![Weekday::Sat, Weekday::Sun], ..Default::default() };
+    assert!(skip_if.reason(Weekday::Sun).await.is_some());
+    assert!(skip_if.reason(Weekday::Mon).await.is_none());
+
+    let skip_if = SkipIf { command: Some(ShellTask::new("true")), ..Default::default() };
+    assert!(skip_if.reason(Weekday::Mon).await.is_some());
+    let skip_if = SkipIf { command: Some(ShellTask::new("false")), file: Some(PathBuf::from("/nonexistent")), ..Default::default() };
+    assert!(skip_if.reason(Weekday::Mon).await.is_none());
+
+    let days: SkipIf = serde_yaml::from_str("days: [sat, Sunday]").unwrap();
+    assert_eq!(days.days, [Weekday::Sat, Weekday::Sun]);
+}
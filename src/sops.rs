@@ -0,0 +1,46 @@
+use std::{path::Path, process::{Command, Stdio}};
+
+use log::info;
+use serde_yaml::Value;
+
+use crate::{audit::Pending, SerializableError};
+
+/// Whether `text` is a yaml document encrypted by sops, which keeps its metadata under a top level
+/// `sops` key, whether every value or only some of them are encrypted.
+pub(crate) fn is_encrypted(text: &str) -> bool {
+    let Ok(Value::Mapping(mapping)) = serde_yaml::from_str::<Value>(text) else {
+        return false;
+    };
+    matches!(mapping.get("sops"), Some(Value::Mapping(sops)) if sops.contains_key("mac"))
+}
+
+/// Decrypts the configuration at `path` with the sops binary, which finds the keys as it always
+/// does: `SOPS_AGE_KEY_FILE` or `SOPS_AGE_KEY` for age, the gpg agent, or the cloud KMS credentials.
+pub(crate) fn decrypt(path: &Path) -> Result<String, SerializableError> {
+    info!("decrypting {} with sops", path.display());
+    let mut command = Command::new("sops");
+    command
+        .args(["--decrypt", "--input-type", "yaml", "--output-type", "yaml"])
+        .arg(path)
+        .stdin(Stdio::null());
+    let pending = Pending::start(&command);
+    let output = command.output();
+    pending.finish(output.as_ref().map(|o| o.status));
+    let output = output.map_err(|e| SerializableError::config(format!("{} is encrypted with sops, which failed to run: {}", path.display(), e)))?;
+    if !output.status.success() {
+        return Err(SerializableError::config(format!(
+            "sops failed to decrypt {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )));
+    }
+    String::from_utf8(output.stdout)
+        .map_err(|e| SerializableError::config(format!("sops decrypted {} to invalid utf-8: {}", path.display(), e)))
+}
+
+#[test]
+fn test_sops_is_encrypted() {
+    assert!(is_encrypted("restic_host: ENC[AES256_GCM,data:aGk=,type:str]\nsops:\n  mac: ENC[AES256_GCM,data:eA==,type:str]\n  version: 3.9.0\n"));
+    assert!(!is_encrypted("restic_host: host\nservices: []\n"));
+    assert!(!is_encrypted("sops: enabled\n"));
+}
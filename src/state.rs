@@ -0,0 +1,324 @@
+use std::{collections::BTreeMap, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{audit::CommandRecord, verify::VerifyRecord, ArchiveFailure, SerializableError};
+
+static STATE_FILE: &str = "state.json";
+
+/// Data persisted between runs, stored as json inside the state directory.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct State {
+    #[serde(skip)]
+    path: PathBuf,
+    /// per archive state, keyed by `service/archive`
+    #[serde(default)]
+    archives: BTreeMap<String, ArchiveState>,
+    /// per service state
+    #[serde(default)]
+    services: BTreeMap<String, ServiceState>,
+    /// duration in seconds of the last complete run
+    pub(crate) last_run_secs: Option<f64>,
+    /// progress of the run in progress, or of the last one if it was interrupted, keyed by
+    /// `service/archive`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    progress: BTreeMap<String, ArchiveProgress>,
+    /// finished runs, oldest first
+    #[serde(default)]
+    runs: Vec<RunRecord>,
+    /// when each deep check of the repositories last ran, keyed by backend and check
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) last_checks: BTreeMap<String, DateTime<Utc>>,
+    /// the last restore test
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_verify: Option<VerifyRecord>,
+}
+
+/// How far an archive got during a run, so that an interrupted run can be resumed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub(crate) enum ArchiveProgress {
+    /// the dump was written to the intermediate directory
+    Dumped { file: PathBuf },
+    /// the archive is part of a restic snapshot
+    Uploaded,
+}
+
+/// The outcome of a run, as kept in the history.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RunRecord {
+    pub(crate) started_at: DateTime<Utc>,
+    /// duration in seconds of the whole run
+    #[serde(default)]
+    pub(crate) duration_secs: f64,
+    pub(crate) status: RunStatus,
+    /// the error that ended the run, if any
+    #[serde(default)]
+    pub(crate) error: Option<String>,
+    /// sha256 of the configuration file the run used
+    #[serde(default)]
+    pub(crate) config_hash: Option<String>,
+    /// why the run fell back to another backend, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) degraded: Option<String>,
+    /// problems that weren't about a single archive's data, like drift
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) warnings: Vec<String>,
+    /// per service results
+    #[serde(default)]
+    pub(crate) services: BTreeMap<String, ServiceRecord>,
+    /// external commands the run executed, with the secrets redacted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) commands: Vec<CommandRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RunStatus {
+    /// the run hasn't finished yet
+    Running,
+    /// every archive was backed up
+    Success,
+    /// the run completed, but some archives failed
+    Partial,
+    /// the run completed on the fallback backend, as the configured one couldn't be reached
+    Degraded,
+    /// an error stopped the run
+    Failed,
+    /// the run was stopped by a signal
+    Interrupted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub(crate) struct ServiceRecord {
+    /// id of the restic snapshot holding the service
+    pub(crate) snapshot_id: Option<String>,
+    /// bytes restic added to the repository
+    pub(crate) data_added: Option<u64>,
+    /// duration in seconds of the restic upload
+    pub(crate) upload_secs: Option<f64>,
+    /// excludes passed to restic
+    #[serde(default)]
+    pub(crate) excludes: Option<Vec<String>>,
+    /// case-insensitive excludes passed to restic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) iexcludes: Option<Vec<String>>,
+    /// per archive results
+    #[serde(default)]
+    pub(crate) archives: BTreeMap<String, ArchiveRecord>,
+    /// per backend results, keyed by backend name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) backends: BTreeMap<String, BackendRecord>,
+}
+
+/// The outcome of a service's backup to one of its backends.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub(crate) struct BackendRecord {
+    pub(crate) snapshot_id: Option<String>,
+    /// bytes the backend added to its repository
+    pub(crate) data_added: Option<u64>,
+    /// duration in seconds of the upload
+    pub(crate) upload_secs: Option<f64>,
+    /// why the backup failed
+    pub(crate) error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ArchiveRecord {
+    pub(crate) status: ArchiveStatus,
+    /// size in bytes of the dump, volumes have none
+    pub(crate) bytes: Option<u64>,
+    /// duration in seconds of the dump
+    pub(crate) dump_secs: Option<f64>,
+    /// why the archive failed
+    pub(crate) error: Option<String>,
+    /// id of the restic snapshot holding the archive
+    #[serde(default)]
+    pub(crate) snapshot_id: Option<String>,
+    /// where the archive's data comes from: the dumped command, the volume or the bound path
+    #[serde(default)]
+    pub(crate) source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ArchiveStatus {
+    /// the archive is ready in the intermediate directory or mounted, but wasn't uploaded
+    Staged,
+    /// the archive is part of a restic snapshot
+    Uploaded,
+    /// the archive couldn't be backed up
+    Failed,
+}
+
+impl RunRecord {
+    pub(crate) fn start(config_hash: impl ToString) -> Self {
+        Self {
+            started_at: Utc::now(),
+            duration_secs: 0.0,
+            status: RunStatus::Running,
+            error: None,
+            config_hash: Some(config_hash.to_string()),
+            degraded: None,
+            warnings: vec![],
+            services: BTreeMap::new(),
+            commands: vec![],
+        }
+    }
+
+    /// Identifies the run in the history, derived from when it started.
+    pub(crate) fn id(&self) -> String {
+        self.started_at.format("%Y%m%d-%H%M%S").to_string()
+    }
+
+    pub(crate) fn service_mut(&mut self, service: &str) -> &mut ServiceRecord {
+        self.services.entry(service.to_owned()).or_default()
+    }
+
+    pub(crate) fn archive_mut(&mut self, service: &str, archive: &str) -> &mut ArchiveRecord {
+        self.service_mut(service)
+            .archives
+            .entry(archive.to_owned())
+            .or_insert(ArchiveRecord { status: ArchiveStatus::Staged, bytes: None, dump_secs: None, error: None, snapshot_id: None, source: None })
+    }
+
+    /// Fills in the status and duration of the run from its result.
+    pub(crate) fn finish(&mut self, result: &Result<Vec<ArchiveFailure>, SerializableError>) {
+        self.duration_secs = (Utc::now() - self.started_at).as_seconds_f64();
+        match result {
+            Ok(failed) => {
+                self.status = match (&self.degraded, failed.is_empty()) {
+                    (Some(_), _) => RunStatus::Degraded,
+                    (None, true) => RunStatus::Success,
+                    (None, false) => RunStatus::Partial,
+                };
+                for failure in failed {
+                    // failures outside of the plan, like the manifest's, are not about an
+                    // archive's data
+                    let archive = self.services
+                        .get_mut(&failure.service)
+                        .and_then(|s| s.archives.get_mut(&failure.archive));
+                    match archive {
+                        Some(archive) => {
+                            archive.status = ArchiveStatus::Failed;
+                            archive.error = Some(failure.error.to_string());
+                        }
+                        None => self.warnings.push(failure.to_string()),
+                    }
+                }
+            }
+            Err(e) => {
+                self.status = match e {
+                    SerializableError::Interrupted { .. } | SerializableError::Cancelled => RunStatus::Interrupted,
+                    _ => RunStatus::Failed,
+                };
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub(crate) struct ArchiveState {
+    /// sha256 of the last dump written to the intermediate directory
+    pub(crate) hash: Option<String>,
+    /// size in bytes of the last dump, as written to disk
+    #[serde(default)]
+    pub(crate) size: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub(crate) struct ServiceState {
+    /// duration in seconds of the last restic upload
+    pub(crate) upload_secs: Option<f64>,
+    /// when every archive of the service was last backed up
+    #[serde(default)]
+    pub(crate) last_success: Option<DateTime<Utc>>,
+}
+
+impl State {
+    pub(crate) fn load(dir: impl AsRef<Path>) -> Result<Self, SerializableError> {
+        let path = dir.as_ref().join(STATE_FILE);
+        let mut state: State = match std::fs::read(&path) {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .map_err(|e| SerializableError::state(format!("failed to parse {}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("no state found at {}, starting from scratch", path.display());
+                State::default()
+            }
+            Err(e) => return Err(SerializableError::state(format!("failed to read {}: {}", path.display(), e))),
+        };
+        state.path = path;
+        Ok(state)
+    }
+
+    /// Atomically writes the state back to its file.
+    pub(crate) fn save(&self) -> Result<(), SerializableError> {
+        let write = || -> std::io::Result<()> {
+            if let Some(dir) = self.path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let tmp = self.path.with_extension("json.tmp");
+            std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+            std::fs::rename(&tmp, &self.path)
+        };
+        write().map_err(|e| SerializableError::state(format!("failed to write {}: {}", self.path.display(), e)))
+    }
+
+    fn key(service: &str, archive: &str) -> String {
+        format!("{}/{}", service, archive)
+    }
+
+    pub(crate) fn archive(&self, service: &str, archive: &str) -> Option<&ArchiveState> {
+        self.archives.get(&Self::key(service, archive))
+    }
+
+    pub(crate) fn archive_mut(&mut self, service: &str, archive: &str) -> &mut ArchiveState {
+        self.archives.entry(Self::key(service, archive)).or_default()
+    }
+
+    pub(crate) fn service(&self, service: &str) -> Option<&ServiceState> {
+        self.services.get(service)
+    }
+
+    pub(crate) fn service_mut(&mut self, service: &str) -> &mut ServiceState {
+        self.services.entry(service.to_owned()).or_default()
+    }
+
+    pub(crate) fn progress(&self, service: &str, archive: &str) -> Option<&ArchiveProgress> {
+        self.progress.get(&Self::key(service, archive))
+    }
+
+    pub(crate) fn set_progress(&mut self, service: &str, archive: &str, progress: ArchiveProgress) {
+        self.progress.insert(Self::key(service, archive), progress);
+    }
+
+    pub(crate) fn has_progress(&self) -> bool {
+        !self.progress.is_empty()
+    }
+
+    pub(crate) fn clear_progress(&mut self) {
+        self.progress.clear();
+    }
+
+    /// Appends a finished run to the history, dropping the oldest ones beyond `keep`.
+    pub(crate) fn push_run(&mut self, run: RunRecord, keep: usize) {
+        self.runs.push(run);
+        let excess = self.runs.len().saturating_sub(keep);
+        self.runs.drain(..excess);
+    }
+
+    pub(crate) fn runs(&self) -> &[RunRecord] {
+        &self.runs
+    }
+
+    /// The most recent run that went through, even if some archives failed.
+    pub(crate) fn last_completed_run(&self) -> Option<&RunRecord> {
+        self.runs
+            .iter()
+            .rev()
+            .find(|r| matches!(r.status, RunStatus::Success | RunStatus::Partial))
+    }
+}
@@ -0,0 +1,142 @@
+use std::{collections::BTreeMap, path::Path};
+
+use chrono::{DateTime, Duration, Local, Utc};
+use indicatif::{HumanBytes, HumanDuration};
+use log::warn;
+
+use crate::{backend::{self, Snapshot}, config::Config, restore, service::Service, state::{ArchiveStatus, State}, theme::{self, Tone}, ArchiveFailure, SerializableError};
+
+/// Prints an overview of every configured service: its last successful backup, its last snapshot
+/// in the repository, what the last run added and what failed, then the size of the repository.
+/// Returns the services whose last successful backup is older than their max_age, and the ones
+/// whose snapshots in the repository miss their thresholds.
+pub(crate) async fn show(services: &[Service], config: &Config, offline: bool) -> Result<Vec<ArchiveFailure>, SerializableError> {
+    let state = State::load(config.state_dir()?)?;
+    // every service is checked against the backend it is restored from
+    let used = backend::used(config, services);
+    let mut repositories = BTreeMap::new();
+    for kind in used.iter().filter(|_| !offline) {
+        match backend::open_type(*kind).snapshots(config).await {
+            Ok(snapshots) => {
+                repositories.insert(*kind, snapshots);
+            }
+            Err(e) => warn!("{}: failed to list the repository's snapshots: {}", kind.name(), e),
+        }
+    }
+    let now = Utc::now();
+    let ago = |time: DateTime<Utc>| HumanDuration((now - time).to_std().unwrap_or_default());
+    let mut stale = vec![];
+    for service in services {
+        if !service.enabled {
+            println!("{}: {}", service.name, theme::paint(Tone::Dim, "disabled"));
+            continue;
+        }
+        let last_success = state.service(&service.name).and_then(|s| s.last_success);
+        let verdict = match service.max_age {
+            Some(max_age) if last_success.is_none_or(|l| (now - l).num_seconds() > max_age as i64) => {
+                stale.push(ArchiveFailure::new(&service.name, "*", SerializableError::Stale {
+                    last_success: last_success.map(|l| l.to_rfc3339()),
+                    max_age,
+                }));
+                "STALE"
+            }
+            Some(_) => "ok",
+            None => "no max_age",
+        };
+        let path = Path::new(&config.restic_root()).join(&service.name);
+        let snapshots = repositories.get(&backend::primary(config, service));
+        let missed = snapshots.map(|s| missed_thresholds(service, s, &path, now)).unwrap_or_default();
+        let verdict = if missed.is_empty() { verdict } else { "STALE" };
+        let tone = match verdict {
+            "STALE" => Tone::Bad,
+            "ok" => Tone::Good,
+            _ => Tone::Dim,
+        };
+        println!("{}: {}", service.name, theme::paint(tone, verdict));
+
+        match last_success {
+            Some(l) => println!("    last success   {} ({} ago)", l.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"), ago(l)),
+            None => println!("    last success   never"),
+        }
+        if let Some(snapshots) = snapshots {
+            match restore::latest_snapshot(snapshots, &path) {
+                Some(s) => println!("    last snapshot  {} ({} ago)", &s.id[..s.id.len().min(8)], ago(s.time)),
+                None => println!("    last snapshot  none"),
+            }
+        }
+        for problem in missed {
+            println!("    snapshots      {}", problem);
+            stale.push(ArchiveFailure::new(&service.name, "*", SerializableError::snapshot_threshold(problem)));
+        }
+        let last_run = state.runs().iter().rev().find_map(|r| r.services.get(&service.name));
+        if let Some(record) = last_run {
+            if let Some(data_added) = record.data_added {
+                println!("    data added     {}", HumanBytes(data_added));
+            }
+            for (archive, record) in record.archives.iter().filter(|(_, a)| a.status == ArchiveStatus::Failed) {
+                println!("    failed         {}: {}", archive, record.error.as_deref().unwrap_or("unknown error"));
+            }
+        }
+    }
+    for kind in used.iter().filter(|_| !offline) {
+        match backend::open_type(*kind).stats(config).await {
+            Ok(stats) => println!("{} repository: {} snapshots, {} stored", kind.name(), stats.snapshots, HumanBytes(stats.stored_bytes)),
+            Err(e) => warn!("{}: failed to read the repository's stats: {}", kind.name(), e),
+        }
+    }
+    Ok(stale)
+}
+
+/// Checks the snapshots of `path` against the service's `min_snapshots_7d` and
+/// `max_snapshot_age`, catching a service that stopped producing snapshots while its runs still
+/// report success.
+fn missed_thresholds(service: &Service, snapshots: &[Snapshot], path: &Path, now: DateTime<Utc>) -> Vec<String> {
+    let mut missed = vec![];
+    let times = snapshots.iter()
+        .filter(|s| s.covers(path))
+        .map(|s| s.time)
+        .collect::<Vec<_>>();
+    if let Some(min) = service.min_snapshots_7d {
+        let recent = times.iter().filter(|t| now - **t <= Duration::days(7)).count();
+        if recent < min as usize {
+            missed.push(format!("{} snapshots in the last 7 days, expected at least {}", recent, min));
+        }
+    }
+    if let Some(max_age) = service.max_snapshot_age {
+        match times.iter().max() {
+            Some(latest) if (now - *latest).num_seconds() > max_age as i64 => {
+                missed.push(format!("latest snapshot is from {}, max snapshot age is {}s", latest.to_rfc3339(), max_age));
+            }
+            Some(_) => {}
+            None => missed.push(format!("no snapshot in the repository, max snapshot age is {}s", max_age)),
+        }
+    }
+    missed
+}
+
+#[test]
+fn test_missed_thresholds() {
+    use crate::service::ServiceBuilder;
+
+    let now = Utc::now();
+    let snapshot = |days: i64, path: &str| Snapshot {
+        id: "0".to_owned(),
+        time: now - Duration::days(days),
+        paths: vec![path.to_owned()],
+        tags: vec![],
+    };
+    let snapshots = [snapshot(2, "/restic/app"), snapshot(9, "/restic/app"), snapshot(0, "/restic/other")];
+    let service = ServiceBuilder::new("app")
+        .named_volume("data", "data", vec![])
+        .min_snapshots_7d(2)
+        .max_snapshot_age(86400)
+        .build()
+        .unwrap();
+    let path = Path::new("/restic/app");
+    assert_eq!(missed_thresholds(&service, &snapshots, path, now).len(), 2);
+    assert!(missed_thresholds(&service, &snapshots, path, now - Duration::days(2)).is_empty());
+    assert_eq!(missed_thresholds(&service, &[], path, now), [
+        "0 snapshots in the last 7 days, expected at least 2",
+        "no snapshot in the repository, max snapshot age is 86400s",
+    ]);
+}
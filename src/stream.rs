@@ -0,0 +1,318 @@
+use std::{collections::VecDeque, future::Future, io, path::{Path, PathBuf}, pin::Pin, task::{ready, Context, Poll}, time::Duration};
+
+use indicatif::{HumanBytes, ProgressBar};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream}, task::JoinHandle, time::{Instant, Sleep}};
+use tokio_util::io::SyncIoBridge;
+
+use crate::archive::DUMP_HEAD_LEN;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct CompressionOptions {
+    /// zstd compression level
+    #[serde(default = "CompressionOptions::default_level")]
+    pub(crate) level: i32,
+    /// number of zstd worker threads, defaults to the available parallelism
+    pub(crate) workers: Option<u32>,
+}
+
+impl CompressionOptions {
+    fn default_level() -> i32 {
+        zstd::DEFAULT_COMPRESSION_LEVEL
+    }
+
+    fn workers(&self) -> u32 {
+        self.workers.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get() as u32))
+    }
+}
+
+/// Returns a writer whose data is zstd-compressed into `file` by a dedicated blocking thread, so
+/// that compression doesn't slow down whoever is feeding the writer.
+///
+/// The writer must be shut down to signal the end of the stream, after which the returned handle
+/// completes once everything has been compressed and synced to disk.
+pub(crate) fn zstd_writer(
+    file: std::fs::File,
+    options: &CompressionOptions,
+    buffer_size: usize,
+) -> io::Result<(DuplexStream, JoinHandle<io::Result<()>>)> {
+    let (writer, reader) = tokio::io::duplex(buffer_size);
+    let mut encoder = zstd::stream::write::Encoder::new(file, options.level)?;
+    encoder.multithread(options.workers())?;
+    let mut reader = SyncIoBridge::new(reader);
+    let handle = tokio::task::spawn_blocking(move || {
+        std::io::copy(&mut reader, &mut encoder)?;
+        encoder.finish()?.sync_all()
+    });
+    Ok((writer, handle))
+}
+
+/// A dump being written next to its final destination, removed when dropped unless committed.
+pub(crate) struct PartialFile {
+    path: PathBuf,
+    committed: bool,
+}
+
+impl PartialFile {
+    pub(crate) fn new(destination: &Path) -> Self {
+        let mut name = destination.file_name().unwrap_or_default().to_os_string();
+        name.push(".partial");
+        Self { path: destination.with_file_name(name), committed: false }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the completed dump to its final destination.
+    pub(crate) fn commit(mut self, destination: &Path) -> io::Result<()> {
+        std::fs::rename(&self.path, destination)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            match std::fs::remove_file(&self.path) {
+                Ok(()) => debug!("removed partial dump {}", self.path.display()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => warn!("failed to remove partial dump {}: {}", self.path.display(), e),
+            }
+        }
+    }
+}
+
+/// AsyncWrite wrapper that keeps track of the bytes going through it and reports them on a progress bar,
+/// optionally hashing them along the way
+pub(crate) struct CountingWriter<W> {
+    inner: W,
+    bytes_written: u64,
+    bar: ProgressBar,
+    hasher: Option<Sha256>,
+    /// first bytes written, to check the magic bytes of a dump
+    head: Vec<u8>,
+}
+
+impl<W> CountingWriter<W> {
+    pub(crate) fn new(inner: W, bar: ProgressBar) -> Self {
+        Self { inner, bytes_written: 0, bar, hasher: None, head: vec![] }
+    }
+
+    pub(crate) fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    fn advance(&mut self, written: &[u8]) {
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(written);
+        }
+        let missing = DUMP_HEAD_LEN.saturating_sub(self.head.len()).min(written.len());
+        self.head.extend_from_slice(&written[..missing]);
+        self.bytes_written += written.len() as u64;
+        self.bar.set_position(self.bytes_written);
+        self.bar.set_message(format!("{}", HumanBytes(self.bytes_written)));
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.advance(&buf[..n]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// AsyncWrite wrapper that keeps the average write rate under a limit in bytes per second
+pub(crate) struct ThrottledWriter<W> {
+    inner: W,
+    bytes_per_sec: u64,
+    bytes_written: u64,
+    start: Option<Instant>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> ThrottledWriter<W> {
+    pub(crate) fn new(inner: W, bytes_per_sec: u64) -> Self {
+        Self { inner, bytes_per_sec: bytes_per_sec.max(1), bytes_written: 0, start: None, delay: None }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ThrottledWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        let start = *self.start.get_or_insert_with(Instant::now);
+        // never hand more than a second worth of data to the inner writer at once
+        let len = buf.len().min(self.bytes_per_sec as usize);
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..len]))?;
+        self.bytes_written += n as u64;
+        let due = start + Duration::from_secs_f64(self.bytes_written as f64 / self.bytes_per_sec as f64);
+        if due > Instant::now() {
+            self.delay = Some(Box::pin(tokio::time::sleep_until(due)));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Copies a dump stream into its output, showing the amount of data copied on a spinner.
+pub(crate) struct SpinnerWriter<R> {
+    input: BufReader<R>,
+    output: CountingWriter<Box<dyn AsyncWrite + Unpin + Send>>,
+}
+
+impl<R: AsyncRead + Unpin> SpinnerWriter<R> {
+    /// `buffer_size` is the size of the reads from `input`; every read is handed to `output` as a
+    /// single write, so there is no need for a second buffer on the output side.
+    pub(crate) fn new(input: R, output: Box<dyn AsyncWrite + Unpin + Send>, buffer_size: usize, bar: ProgressBar) -> Self {
+        Self {
+            input: BufReader::with_capacity(buffer_size, input),
+            output: CountingWriter::new(output, bar),
+        }
+    }
+
+    /// Hash the copied data, retrievable with [SpinnerWriter::hash] once the copy is done.
+    pub(crate) fn hashed(mut self) -> Self {
+        self.output.hasher = Some(Sha256::new());
+        self
+    }
+
+    /// Hex encoded sha256 of the copied data, if hashing was requested.
+    pub(crate) fn hash(&mut self) -> Option<String> {
+        self.output.hasher.take().map(|h| hex::encode(h.finalize()))
+    }
+
+    /// The first bytes of the copied data.
+    pub(crate) fn head(&self) -> &[u8] {
+        &self.output.head
+    }
+
+    pub(crate) async fn write_all(&mut self) -> io::Result<u64> {
+        tokio::io::copy_buf(&mut self.input, &mut self.output).await?;
+        self.output.shutdown().await?;
+        Ok(self.output.bytes_written())
+    }
+}
+
+/// Lines of a command's stderr kept in memory, for its failure.
+static STDERR_TAIL: usize = 20;
+/// Longest stderr line read at once, longer ones are split, so a stderr without newlines can't
+/// grow without bound.
+static STDERR_LINE_MAX: usize = 8 << 10;
+
+/// Copies `stderr` to the file `log` as it is written, or only drains it without one. Returns the
+/// number of lines and the last [`STDERR_TAIL`] of them. Failing to write the log only stops the
+/// log.
+pub(crate) async fn capture_stderr(stderr: impl AsyncRead + Unpin, log: Option<&Path>) -> io::Result<(usize, Vec<String>)> {
+    let mut file = match log {
+        Some(log) => Some(tokio::fs::File::create(log).await?),
+        None => None,
+    };
+    // the child blocks once the pipe is full, it is drained to the end whatever happens to the log
+    let mut stderr = BufReader::new(stderr).take(u64::MAX);
+    let mut tail = VecDeque::with_capacity(STDERR_TAIL);
+    let mut count = 0;
+    let mut line = vec![];
+    loop {
+        stderr.set_limit(STDERR_LINE_MAX as u64);
+        if stderr.read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+        if let Some(f) = &mut file
+            && let Err(e) = f.write_all(&line).await
+        {
+            warn!("failed to write stderr to {}, only draining it: {}", log.expect("a log is open").display(), e);
+            file = None;
+        }
+        if tail.len() == STDERR_TAIL {
+            tail.pop_front();
+        }
+        tail.push_back(String::from_utf8_lossy(&line).trim_end().to_owned());
+        count += 1;
+        line.clear();
+    }
+    if let Some(f) = &mut file
+        && let Err(e) = f.flush().await
+    {
+        warn!("failed to write stderr to {}: {}", log.expect("a log is open").display(), e);
+    }
+    Ok((count, tail.into()))
+}
+
+/// Drains the stderr of `child` in the background while its other pipes are streamed, so a chatty
+/// child can't block on a full pipe. The returned future gives back its last lines once it exits.
+pub(crate) fn drain_stderr(child: &mut tokio::process::Child) -> impl Future<Output = String> + use<> {
+    let reader = child.stderr.take().map(|stderr| tokio::spawn(async move { capture_stderr(stderr, None).await }));
+    async move {
+        match reader {
+            Some(reader) => reader.await.ok().and_then(Result::ok).map(|(_, tail)| tail.join("\n")).unwrap_or_default(),
+            None => String::new(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_capture_stderr() {
+    let stderr = (0..30).map(|i| format!("line {}\n", i)).collect::<String>() + "no newline";
+    let log = std::env::temp_dir().join(format!("hoarder-stderr-{}.log", std::process::id()));
+    let (count, tail) = capture_stderr(stderr.as_bytes(), Some(&log)).await.unwrap();
+    assert_eq!(count, 31);
+    assert_eq!(tail.len(), STDERR_TAIL);
+    assert_eq!(tail.first().unwrap(), "line 11");
+    assert_eq!(tail.last().unwrap(), "no newline");
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), stderr);
+    std::fs::remove_file(&log).unwrap();
+
+    // a full disk only stops the log, and a line without end is read in pieces
+    let stderr = "x".repeat(STDERR_LINE_MAX * 2 + 1);
+    let (count, tail) = capture_stderr(stderr.as_bytes(), Some(Path::new("/dev/full"))).await.unwrap();
+    assert_eq!(count, 3);
+    assert_eq!(tail.first().unwrap().len(), STDERR_LINE_MAX);
+}
+
+/// Run with `cargo test -- --ignored bench_spinner_writer` and RUST_LOG=debug to compare buffer
+/// sizes.
+#[tokio::test]
+#[ignore]
+async fn bench_spinner_writer_buffer_sizes() {
+    let _ = pretty_env_logger::try_init();
+    const SIZE: u64 = 128 << 20;
+    for buffer_size in [10 << 10, 64 << 10, 1 << 20, 4 << 20] {
+        let input = tokio::io::repeat(0xaa).take(SIZE);
+        let mut writer = SpinnerWriter::new(input, Box::new(tokio::io::sink()), buffer_size, ProgressBar::hidden());
+        let start = std::time::Instant::now();
+        let written = writer.write_all().await.unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(written, SIZE);
+        debug!(
+            "buffer {:>10}: {} in {:?} ({}/s)",
+            HumanBytes(buffer_size as u64).to_string(),
+            HumanBytes(written),
+            elapsed,
+            HumanBytes((written as f64 / elapsed.as_secs_f64()) as u64),
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+
+use serde_yaml::Value;
+
+use crate::{
+    archive::ArchiveInput,
+    config::Config,
+    import::KnownDatabase,
+    inspect::{self, Container},
+    service::Service,
+    DockerInputType,
+    SerializableError,
+};
+
+/// A database container that no archive dumps.
+struct Finding<'a> {
+    /// compose service running the database
+    service: &'a str,
+    image: &'a str,
+    database: KnownDatabase,
+    /// archives copying its data raw, as `service/archive`
+    raw: Vec<String>,
+}
+
+/// The archives of `services` copying a mount of `container`, run by compose `service` of `project`.
+fn raw_copies(services: &[Service], project: &str, service: &str, container: &Container) -> Vec<String> {
+    let mut copies = vec![];
+    for configured in services {
+        let configured_project = configured.compose_project.as_ref().unwrap_or(&configured.name);
+        for archive in &configured.archives {
+            let ArchiveInput::Docker(input) = &archive.input else {
+                continue;
+            };
+            let copied = container.mounts.iter().any(|mount| match input {
+                DockerInputType::ComposeNamedVolume { name, .. } => {
+                    mount.name.as_deref() == Some(format!("{}_{}", configured_project, name).as_str())
+                }
+                DockerInputType::Volume { name, .. } => mount.name.as_ref() == Some(name),
+                DockerInputType::ComposeBoundVolume { service: bound, path, .. } => {
+                    configured_project == project
+                        && bound == service
+                        && (mount.destination.starts_with(path) || path.starts_with(&mount.destination))
+                }
+                DockerInputType::ExecStdout { .. } => false,
+            });
+            if copied {
+                copies.push(format!("{}/{}", configured.name, archive.name));
+            }
+        }
+    }
+    copies
+}
+
+/// The database containers of compose `project` that none of `services` dumps.
+fn findings<'a>(services: &[Service], project: &str, containers: &'a [Container]) -> Vec<Finding<'a>> {
+    let mut findings = vec![];
+    for container in containers {
+        let Some(service) = container.service() else {
+            continue;
+        };
+        let Some(database) = KnownDatabase::detect(&container.config.image) else {
+            continue;
+        };
+        let dumped = services
+            .iter()
+            .filter(|s| s.compose_project.as_ref().unwrap_or(&s.name) == project)
+            .flat_map(|s| &s.archives)
+            .any(|a| matches!(&a.input, ArchiveInput::Docker(DockerInputType::ExecStdout { service: dumped, .. }) if dumped == service));
+        if !dumped {
+            findings.push(Finding {
+                service,
+                image: &container.config.image,
+                database,
+                raw: raw_copies(services, project, service, container),
+            });
+        }
+    }
+    findings
+}
+
+/// Looks for databases of the configured compose projects, or of every one with `all`, that are
+/// not dumped, printing the archives to add for them. Returns how many were found.
+pub(crate) async fn suggest(config: &Config, services: &[Service], all: bool) -> Result<usize, SerializableError> {
+    let running = inspect::compose_projects(config).await?;
+    let mut projects = match all {
+        true => running.into_iter().collect::<Vec<_>>(),
+        false => services
+            .iter()
+            .map(|s| s.compose_project.clone().unwrap_or(s.name.clone()))
+            .filter(|p| running.contains(p))
+            .collect(),
+    };
+    projects.sort();
+    projects.dedup();
+    let mut suggestions: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    let mut found = 0;
+    for project in projects {
+        let containers = inspect::project_containers(config, &project).await?;
+        for finding in findings(services, &project, &containers) {
+            found += 1;
+            match finding.raw.as_slice() {
+                [] => println!("{}: {} runs {} and isn't backed up", project, finding.service, finding.image),
+                raw => println!(
+                    "{}: {} runs {} and its data is only copied raw by {}, which a running database can leave inconsistent",
+                    project,
+                    finding.service,
+                    finding.image,
+                    raw.join(", "),
+                ),
+            }
+            let target = services
+                .iter()
+                .find(|s| s.compose_project.as_ref().unwrap_or(&s.name) == &project)
+                .map(|s| s.name.clone())
+                .unwrap_or(project.clone());
+            suggestions.entry(target).or_default().push(finding.database.archive(finding.service, finding.image));
+        }
+    }
+    for (service, archives) in suggestions {
+        let yaml = serde_yaml::to_string(&Value::Sequence(archives))
+            .map_err(|e| SerializableError::config(format!("failed to write the suggested archives: {}", e)))?;
+        println!();
+        println!("# to add to the archives of service {}:", service);
+        print!("{}", yaml);
+    }
+    if found == 0 {
+        println!("every database found is dumped");
+    }
+    Ok(found)
+}
+
+#[test]
+fn test_suggest_findings() {
+    let containers: Vec<Container> = serde_json::from_str(r#"[
+        {"Config": {"Image": "postgres:16", "Labels": {"com.docker.compose.service": "db"}},
+         "Mounts": [{"Type": "volume", "Name": "app_pgdata", "Source": "/x", "Destination": "/var/lib/postgresql/data"}]},
+        {"Config": {"Image": "redis:7", "Labels": {"com.docker.compose.service": "cache"}},
+         "Mounts": [{"Type": "bind", "Source": "/srv/redis", "Destination": "/data"}]},
+        {"Config": {"Image": "mariadb:11", "Labels": {"com.docker.compose.service": "mysql"}}, "Mounts": []},
+        {"Config": {"Image": "nginx", "Labels": {"com.docker.compose.service": "web"}}, "Mounts": []}
+    ]"#).unwrap();
+    let services: Vec<Service> = serde_yaml::from_str(r#"
+        - name: app
+          archives:
+            - name: pgdata
+              input: !Docker
+                docker_type: ComposeNamedVolume
+                name: pgdata
+            - name: mysql
+              input: !Docker
+                docker_type: ExecStdout
+                service: mysql
+                task: [mariadb-dump, --all-databases]
+                ext: sql
+    "#).unwrap();
+    let findings = findings(&services, "app", &containers);
+    assert_eq!(findings.len(), 2);
+    assert_eq!(findings[0].service, "db");
+    assert_eq!(findings[0].raw, ["app/pgdata"]);
+    assert_eq!(findings[1].database, KnownDatabase::Redis);
+    assert!(findings[1].raw.is_empty());
+}
@@ -0,0 +1,419 @@
+use std::{collections::BTreeMap, path::{Path, PathBuf}, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use indicatif::ProgressBar;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncRead, process::Command};
+
+use crate::{
+    backend::{self, BackendType, BackupBackend, BackupRequest, BackupSummary, RepositoryStats, Retention, Snapshot, HOARDER_TAG},
+    config::Config,
+    credentials::Credentials,
+    DockerBinding,
+    DockerSubcommand,
+    SerializableError,
+    ShellTask,
+};
+
+static TARBALL_IMAGE: &str = "alpine";
+static TARBALL_CONTAINER_NAME: &str = "hoarder-tarball";
+/// Installs the tools the scripts need, unless the image already provides them.
+static SETUP: &str = "command -v aws >/dev/null && command -v zstd >/dev/null && tar --version 2>/dev/null | grep -q GNU \
+    || apk add --no-cache aws-cli tar zstd >/dev/null";
+/// Where the aws credentials file is mounted inside the containers.
+static CREDENTIALS_MOUNT: &str = "/root/.aws/credentials";
+/// Where the restore target is mounted inside the containers.
+static RESTORE_MOUNT: &str = "/restore";
+static EXTENSION: &str = ".tar.zst";
+static TIME_FORMAT: &str = "%Y-%m-%dT%H%M%SZ";
+
+/// Settings of the tarball backend.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct TarballConfig {
+    /// bucket the archives are uploaded to
+    pub(crate) bucket: String,
+    /// prefix of every key, the archives are stored at `prefix/host/directory/time.tar.zst`
+    pub(crate) prefix: Option<String>,
+    /// endpoint of S3 compatible storage, AWS if unset
+    pub(crate) endpoint: Option<String>,
+    pub(crate) region: Option<String>,
+    /// storage class of the uploaded archives
+    pub(crate) storage_class: Option<String>,
+    /// aws credentials file, credentials can also be passed with the AWS_* environment variables
+    pub(crate) credentials_file: Option<String>,
+    /// zstd compression level, 3 by default
+    pub(crate) compression_level: Option<u8>,
+    /// size of the parts of multipart uploads, 64MB by default, an upload having at most 10000
+    pub(crate) part_size: Option<String>,
+    /// image the archives are made and uploaded in, it needs the aws cli, GNU tar and zstd, which
+    /// are installed on alpine images missing them
+    pub(crate) image: Option<String>,
+    /// name of the long running container
+    pub(crate) container_name: Option<String>,
+}
+
+impl TarballConfig {
+    fn image(&self) -> String {
+        self.image.clone().unwrap_or(TARBALL_IMAGE.to_owned())
+    }
+
+    fn container_name(&self) -> String {
+        self.container_name.clone().unwrap_or(TARBALL_CONTAINER_NAME.to_owned())
+    }
+
+    /// Prefix of the keys of the archives of `host`.
+    fn host_prefix(&self, host: &str) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{}/{}/", prefix.trim_matches('/'), host),
+            None => format!("{}/", host),
+        }
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("s3://{}/{}", self.bucket, key)
+    }
+
+    /// Starts an aws cli task, reaching the configured endpoint.
+    fn aws(&self, args: &[&str]) -> ShellTask {
+        let mut task = ShellTask::new("aws");
+        task.args(args);
+        if let Some(endpoint) = &self.endpoint {
+            task.arg("--endpoint-url").arg(endpoint);
+        }
+        if let Some(region) = &self.region {
+            task.arg("--region").arg(region);
+        }
+        task
+    }
+}
+
+/// An object of `aws s3api list-objects-v2`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct S3Object {
+    key: String,
+    size: u64,
+}
+
+/// Backs up each directory under the backup root as a `tar.zst` archive, uploaded to S3 compatible
+/// storage with multipart uploads and SHA256 checksums. Keys are never overwritten, and are grouped
+/// by host and directory, so that lifecycle rules can be scoped to either.
+pub(crate) struct Tarball;
+
+impl Tarball {
+    fn settings(config: &Config) -> Result<&TarballConfig, SerializableError> {
+        config.tarball().ok_or(SerializableError::config("the tarball backend needs a tarball section"))
+    }
+
+    /// Credentials of the containers, as `docker run` options along with the values they pass by
+    /// name, adding the credentials file to `mounts`.
+    fn environment(config: &Config, mounts: &mut Vec<DockerBinding>) -> Result<(Vec<String>, Credentials), SerializableError> {
+        if let Some(file) = &Self::settings(config)?.credentials_file {
+            mounts.push(DockerBinding::new_ro(file.clone(), PathBuf::from(CREDENTIALS_MOUNT)));
+        }
+        let credentials = Credentials::new(config, BackendType::Tarball, &["AWS_"]);
+        Ok((credentials.options(), credentials))
+    }
+
+    /// Builds a command running `script` in a throwaway container.
+    fn command(config: &Config, mut mounts: Vec<DockerBinding>, script: &str) -> Result<Command, SerializableError> {
+        let (env, credentials) = Self::environment(config, &mut mounts)?;
+        let mut command = config.docker_command_with_context(DockerSubcommand::run(
+            Self::settings(config)?.image(),
+            mounts,
+            ["--rm", "--entrypoint", "sh"].into_iter().map(str::to_owned).chain(env).collect(),
+            vec!["-c".to_owned(), format!("{}; set -o pipefail; {}", SETUP, script)],
+        )).into_command();
+        command.envs(credentials.vars());
+        Ok(command)
+    }
+
+    /// Builds a command running `script` in the long running container.
+    fn exec(config: &Config, script: &str, stdin: bool) -> Result<Command, SerializableError> {
+        let mut task = ShellTask::new("sh");
+        task.arg("-c").arg(format!("set -o pipefail; {}", script));
+        Ok(config.docker_command_with_context(DockerSubcommand::exec(
+            Self::settings(config)?.container_name(),
+            task,
+            if stdin { vec!["-i"] } else { vec![] },
+        )).into_command())
+    }
+
+    fn key(config: &Config, dir: &str) -> Result<String, SerializableError> {
+        Ok(format!(
+            "{}{}/{}{}",
+            Self::settings(config)?.host_prefix(&config.restic_host()?),
+            dir,
+            Utc::now().format(TIME_FORMAT),
+            EXTENSION,
+        ))
+    }
+
+    /// Splits a path under the backup root into its directory there and the archive member of the
+    /// path inside of it.
+    fn split(config: &Config, path: &Path) -> (String, String) {
+        let relative = path.strip_prefix(config.restic_root()).unwrap_or(path);
+        let mut components = relative.components();
+        let dir = components.next().map(|c| c.as_os_str().to_string_lossy().into_owned()).unwrap_or_default();
+        let inner = components.as_path();
+        (dir, match inner.as_os_str().is_empty() {
+            true => String::new(),
+            false => format!("./{}", inner.display()),
+        })
+    }
+
+    /// Extracts the `inner` member of an archive read from stdin into `dir`, its contents right in
+    /// `dir` like the other backends restore them.
+    fn extract_task(dir: &str, inner: &str) -> ShellTask {
+        let mut tar = ShellTask::new("tar");
+        tar.args(["-C", dir, "-xf", "-"]);
+        if !inner.is_empty() {
+            // the members are stored as ./<inner>/..., `.` included
+            tar.arg(format!("--strip-components={}", Path::new(inner).components().count()));
+            tar.arg(inner);
+        }
+        tar
+    }
+
+    /// A script writing an archive of the `source` directory to `key`, or just measuring it in a
+    /// dry run.
+    /// The script uploading `request` as a tarball, and the key of the tarball.
+    fn backup_script(config: &Config, request: &BackupRequest) -> Result<(String, String), SerializableError> {
+        let (dir, _) = Self::split(config, &request.path);
+        let key = Self::key(config, &dir)?;
+        let excludes = request.excludes
+            .iter()
+            .map(|e| format!("./{}", Path::new(e).strip_prefix(&request.path).unwrap_or(Path::new(e)).display()))
+            .collect::<Vec<_>>();
+        let script = Self::upload_script(config, &request.path.display().to_string(), &excludes, &key)?;
+        Ok((script, key))
+    }
+
+    fn upload_script(config: &Config, source: &str, excludes: &[String], key: &str) -> Result<String, SerializableError> {
+        let settings = Self::settings(config)?;
+        let mut tar = ShellTask::new("tar");
+        tar.arg("-C").arg(source).arg("--anchored");
+        for exclude in excludes {
+            tar.arg(format!("--exclude={}", exclude));
+        }
+        tar.args(["-cf", "-", "."]);
+        let mut zstd = ShellTask::new("zstd");
+        zstd.args(["-q", "-T0"]).arg(format!("-{}", settings.compression_level.unwrap_or(3)));
+        let upload = match config.dry_run() {
+            true => {
+                let mut count = ShellTask::new("wc");
+                count.arg("-c");
+                count
+            }
+            false => {
+                let mut upload = settings.aws(&["s3", "cp", "--no-progress", "--checksum-algorithm", "SHA256"]);
+                if let Some(class) = &settings.storage_class {
+                    upload.arg("--storage-class").arg(class);
+                }
+                upload.arg("-").arg(settings.url(key));
+                upload
+            }
+        };
+        Ok(format!("{} | {} | {}", tar.to_shell(), zstd.to_shell(), upload.to_shell()))
+    }
+
+    /// Runs an upload `script` writing `key`, reporting the size of the archive.
+    async fn upload(config: &Config, script: String, key: String, input: Option<Box<dyn AsyncRead + Send + Unpin>>) -> Result<BackupSummary, SerializableError> {
+        let settings = Self::settings(config)?;
+        let stdin = input.is_some();
+        let output = backend::run("tarball", Self::exec(config, &script, stdin)?, input).await?;
+        if config.dry_run() {
+            info!("dry run, the archive would have been {} bytes", output.trim());
+            return Ok(BackupSummary::default());
+        }
+        let mut head = settings.aws(&["s3api", "head-object", "--output", "json", "--bucket"]);
+        head.arg(&settings.bucket).arg("--key").arg(&key);
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Head {
+            content_length: u64,
+        }
+        let head: Head = serde_json::from_str(&backend::run("tarball", Self::exec(config, &head.to_shell(), false)?, None).await?)?;
+        Ok(BackupSummary { snapshot_id: Some(key), data_added: Some(head.content_length) })
+    }
+
+    /// Every archive of this host, with the directory it holds.
+    async fn objects(config: &Config) -> Result<Vec<(S3Object, Snapshot)>, SerializableError> {
+        #[derive(Deserialize, Default)]
+        #[serde(rename_all = "PascalCase")]
+        struct List {
+            #[serde(default)]
+            contents: Vec<S3Object>,
+        }
+        let settings = Self::settings(config)?;
+        let prefix = settings.host_prefix(&config.restic_host()?);
+        let mut task = settings.aws(&["s3api", "list-objects-v2", "--output", "json", "--bucket"]);
+        task.arg(&settings.bucket).arg("--prefix").arg(&prefix);
+        let output = backend::run("tarball", Self::command(config, vec![], &task.to_shell())?, None).await?;
+        // the aws cli prints nothing when no key matches
+        let list: List = match output.trim().is_empty() {
+            true => List::default(),
+            false => serde_json::from_str(&output)?,
+        };
+        Ok(list.contents
+            .into_iter()
+            .filter_map(|o| {
+                let (dir, name) = o.key.strip_prefix(&prefix)?.split_once('/')?;
+                let time = NaiveDateTime::parse_from_str(name.strip_suffix(EXTENSION)?, TIME_FORMAT).ok()?.and_utc();
+                let snapshot = Snapshot {
+                    id: o.key.clone(),
+                    time,
+                    paths: vec![Path::new(&config.restic_root()).join(dir).display().to_string()],
+                    tags: vec![HOARDER_TAG.to_owned()],
+                };
+                Some((o, snapshot))
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl BackupBackend for Tarball {
+    async fn prepare(&self, config: &Config, mut mounts: Vec<DockerBinding>) -> Result<(), SerializableError> {
+        let settings = Self::settings(config)?;
+        let (env, credentials) = Self::environment(config, &mut mounts)?;
+        let options = ["--init", "--entrypoint", "sleep"].into_iter().map(str::to_owned).chain(env).collect();
+        backend::start_container_with_env(config, &settings.container_name(), &settings.image(), mounts, options, vec!["infinity"], credentials.into_vars()).await?;
+        let mut part_size = ShellTask::new("aws");
+        part_size.args(["configure", "set", "default.s3.multipart_chunksize"])
+            .arg(settings.part_size.as_deref().unwrap_or("64MB"));
+        backend::run("tarball", Self::exec(config, &format!("{}; {}", SETUP, part_size.to_shell()), false)?, None).await?;
+        Ok(())
+    }
+
+    async fn teardown(&self, config: &Config) -> Result<bool, SerializableError> {
+        backend::stop_container(config, &Self::settings(config)?.container_name()).await
+    }
+
+    async fn backup(
+        &self,
+        config: &Config,
+        request: BackupRequest,
+        bar: &ProgressBar,
+        _previous: Option<Duration>,
+    ) -> Result<BackupSummary, SerializableError> {
+        if config.dry_run() {
+            warn!("running in dry run mode, not actually uploading");
+        }
+        let (script, key) = Self::backup_script(config, &request)?;
+        bar.set_message("uploading");
+        Self::upload(config, script, key, None).await
+    }
+
+    fn plan_commands(&self, config: &Config, request: &BackupRequest) -> Result<Vec<Command>, SerializableError> {
+        let (script, _) = Self::backup_script(config, request)?;
+        Ok(vec![Self::exec(config, &script, false)?])
+    }
+
+    async fn forget(&self, config: &Config, retention: &Retention) -> Result<(), SerializableError> {
+        if retention.rules().is_empty() {
+            return Err(SerializableError::config("retention must keep something, set at least one keep_* rule"));
+        }
+        let settings = Self::settings(config)?;
+        let mut by_dir = BTreeMap::<String, Vec<Snapshot>>::new();
+        for (_, snapshot) in Self::objects(config).await? {
+            by_dir.entry(snapshot.paths.concat()).or_default().push(snapshot);
+        }
+        for snapshots in by_dir.values() {
+            let kept = retention.keep(&snapshots.iter().map(|s| s.time).collect::<Vec<_>>());
+            for (_, snapshot) in snapshots.iter().enumerate().filter(|(i, _)| !kept.contains(i)) {
+                info!("removing {}", snapshot.id);
+                if config.dry_run() {
+                    continue;
+                }
+                let mut task = settings.aws(&["s3", "rm"]);
+                task.arg(settings.url(&snapshot.id));
+                backend::run("tarball", Self::command(config, vec![], &task.to_shell())?, None).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn snapshots(&self, config: &Config) -> Result<Vec<Snapshot>, SerializableError> {
+        Ok(Self::objects(config).await?.into_iter().map(|(_, s)| s).collect())
+    }
+
+    async fn restore(
+        &self,
+        config: &Config,
+        snapshot: &str,
+        path: &Path,
+        target: Option<&str>,
+        dry_run: bool,
+    ) -> Result<String, SerializableError> {
+        let settings = Self::settings(config)?;
+        let (_, inner) = Self::split(config, path);
+        let mut download = settings.aws(&["s3", "cp", "--no-progress"]);
+        download.arg(settings.url(snapshot)).arg("-");
+        let (tar, mounts) = match dry_run {
+            true => {
+                let mut tar = ShellTask::new("tar");
+                tar.args(["-tf", "-"]);
+                if !inner.is_empty() {
+                    tar.arg(&inner);
+                }
+                (tar, vec![])
+            }
+            false => (
+                Self::extract_task(RESTORE_MOUNT, &inner),
+                target.iter().map(|t| DockerBinding::new_rw(t.to_string(), PathBuf::from(RESTORE_MOUNT))).collect(),
+            ),
+        };
+        let script = format!("{} | zstd -dcq | {}", download.to_shell(), tar.to_shell());
+        backend::run("tarball", Self::command(config, mounts, &script)?, None).await
+    }
+
+    fn dump(&self, config: &Config, snapshot: &str, path: &Path) -> Result<Command, SerializableError> {
+        let settings = Self::settings(config)?;
+        let (_, inner) = Self::split(config, path);
+        let mut download = settings.aws(&["s3", "cp", "--no-progress"]);
+        download.arg(settings.url(snapshot)).arg("-");
+        let mut tar = ShellTask::new("tar");
+        tar.args(["-xOf", "-"]).arg(inner);
+        Self::command(config, vec![], &format!("{} | zstd -dcq | {}", download.to_shell(), tar.to_shell()))
+    }
+
+    async fn stats(&self, config: &Config) -> Result<RepositoryStats, SerializableError> {
+        let objects = Self::objects(config).await?;
+        Ok(RepositoryStats {
+            snapshots: objects.len() as u64,
+            stored_bytes: objects.iter().map(|(o, _)| o.size).sum(),
+        })
+    }
+}
+
+#[test]
+fn test_extract_task() {
+    let dir = std::env::temp_dir().join(format!("hoarder-tarball-{}", std::process::id()));
+    let (source, target) = (dir.join("source"), dir.join("target"));
+    std::fs::create_dir_all(source.join("data/nested")).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(source.join("data/nested/file"), "data").unwrap();
+    std::fs::write(source.join("other"), "other").unwrap();
+    let archive = dir.join("archive.tar");
+    let status = std::process::Command::new("tar")
+        .arg("-C").arg(&source).arg("-cf").arg(&archive).arg(".")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let (_, inner) = Tarball::split(&Config::default(), &Path::new(&Config::default().restic_root()).join("app/data"));
+    let task = Tarball::extract_task(&target.to_string_lossy(), &inner);
+    let mut args = task.get_args().into_iter();
+    let status = std::process::Command::new(args.next().unwrap())
+        .args(args)
+        .stdin(std::fs::File::open(&archive).unwrap())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(std::fs::read_to_string(target.join("nested/file")).unwrap(), "data");
+    assert!(!target.join("data").exists() && !target.join("other").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
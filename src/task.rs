@@ -1,30 +1,103 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// A command and its arguments, written in yaml either as a list or as a single command line
+/// split like a shell would.
+#[derive(Serialize, Debug, Clone)]
 #[serde(transparent)]
 pub(crate) struct ShellTask {
     _args: Vec<String>,
 }
 
+impl<'de> Deserialize<'de> for ShellTask {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Written {
+            List(Vec<String>),
+            Line(String),
+        }
+
+        match Written::deserialize(deserializer)? {
+            Written::List(args) => Ok(Self { _args: args }),
+            Written::Line(line) => Self::autosplit(&line).map_err(serde::de::Error::custom),
+        }
+    }
+}
+
 impl ShellTask {
     pub(crate) fn new(initial: impl ToString) -> Self {
         Self { _args: vec![initial.to_string()] }
     }
 
-    pub(crate) fn autosplit(args: impl ToString) -> Self {
-        let args = args.to_string();
-        if args.contains('"') {
-            panic!("autosplit can't be used on a string containing quoted arguments!");
+    /// Splits `line` into arguments like a POSIX shell, without expanding anything: single quotes
+    /// keep everything literally, double quotes only let a backslash escape `"`, `\`, `$` and
+    /// `` ` ``, and a backslash outside of quotes escapes any character.
+    pub(crate) fn autosplit(line: &str) -> Result<Self, String> {
+        let mut args = vec![];
+        // none between arguments, so that `""` still makes an empty one
+        let mut current: Option<String> = None;
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => args.extend(current.take()),
+                '\'' => {
+                    let arg = current.get_or_insert_default();
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => arg.push(c),
+                            None => return Err(format!("unterminated single quote in {:?}", line)),
+                        }
+                    }
+                }
+                '"' => {
+                    let arg = current.get_or_insert_default();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some(c @ ('"' | '\\' | '$' | '`')) => arg.push(c),
+                                Some('\n') => (),
+                                Some(c) => {
+                                    arg.push('\\');
+                                    arg.push(c);
+                                }
+                                None => return Err(format!("unterminated double quote in {:?}", line)),
+                            },
+                            Some(c) => arg.push(c),
+                            None => return Err(format!("unterminated double quote in {:?}", line)),
+                        }
+                    }
+                }
+                '\\' => match chars.next() {
+                    // a line continuation
+                    Some('\n') => (),
+                    Some(c) => current.get_or_insert_default().push(c),
+                    None => return Err(format!("trailing backslash in {:?}", line)),
+                },
+                c => current.get_or_insert_default().push(c),
+            }
         }
-        Self {
-            _args: args.split_whitespace().map(|arg| arg.to_string()).collect(),
+        args.extend(current);
+        if args.is_empty() {
+            return Err("the task is empty".to_owned());
         }
+        Ok(Self { _args: args })
     }
 
     pub(crate) fn get_args(&self) -> impl IntoIterator<Item = &str> {
         self._args.iter().map(|arg| arg.as_str())
     }
 
+    /// The task as a shell command line, every argument single quoted.
+    pub(crate) fn to_shell(&self) -> String {
+        self._args
+            .iter()
+            .map(|arg| format!("'{}'", arg.replace('\'', r"'\''")))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
     pub(crate) fn arg(&mut self, arg: impl ToString) -> &mut Self {
         self._args.push(arg.to_string());
         self
@@ -35,3 +108,24 @@ impl ShellTask {
         self
     }
 }
+
+#[test]
+fn test_autosplit() {
+    let args = |line: &str| ShellTask::autosplit(line).map(|t| t._args);
+    assert_eq!(args("pg_dump  -U postgres\tapp").unwrap(), ["pg_dump", "-U", "postgres", "app"]);
+    assert_eq!(args(r#"pg_dump --exclude-table="foo bar" app"#).unwrap(), ["pg_dump", "--exclude-table=foo bar", "app"]);
+    assert_eq!(args(r#"sh -c 'echo "$HOME" | tr a b'"#).unwrap(), ["sh", "-c", r#"echo "$HOME" | tr a b"#]);
+    assert_eq!(args(r#"echo "a \"quoted\" \$word \n" it\'s"#).unwrap(), ["echo", r#"a "quoted" $word \n"#, "it's"]);
+    assert_eq!(args(r#"echo '' "" a""b 'c'"d"e"#).unwrap(), ["echo", "", "", "ab", "cde"]);
+    assert_eq!(args("echo a\\ b \\\nc").unwrap(), ["echo", "a b", "c"]);
+    assert!(args("echo 'unterminated").is_err());
+    assert!(args(r#"echo "unterminated\""#).is_err());
+    assert!(args("echo \\").is_err());
+    assert!(args("  ").is_err());
+
+    let task: ShellTask = serde_yaml::from_str(r#"pg_dump --exclude-table="foo bar""#).unwrap();
+    assert_eq!(task._args, ["pg_dump", "--exclude-table=foo bar"]);
+    let task: ShellTask = serde_yaml::from_str("[pg_dump, --exclude-table=foo bar]").unwrap();
+    assert_eq!(task._args, ["pg_dump", "--exclude-table=foo bar"]);
+    assert!(serde_yaml::from_str::<ShellTask>("pg_dump 'foo").is_err());
+}
@@ -0,0 +1,162 @@
+//! Colors of the terminal output: the logs, the progress bars and the tables.
+
+use std::{io::Write, sync::Mutex};
+
+use console::{Style, StyledObject};
+use log::Level;
+use serde::{Deserialize, Serialize};
+
+/// When the terminal output is colored.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ColorMode {
+    /// on terminals, unless NO_COLOR is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        <Self as clap::ValueEnum>::from_str(value, false).ok()
+    }
+}
+
+/// The colors standing for outcomes and log levels.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Theme {
+    /// green for success, yellow for warnings and red for failures
+    #[default]
+    Default,
+    /// blue for success, yellow for warnings and bold orange for failures, told apart without
+    /// red and green
+    Colorblind,
+    /// no colors, failures and warnings in bold
+    Mono,
+}
+
+impl Theme {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        <Self as clap::ValueEnum>::from_str(value, false).ok()
+    }
+
+    /// The style of `tone`, as the dotted names of indicatif templates.
+    fn dotted(self, tone: Tone) -> &'static str {
+        match (self, tone) {
+            (Theme::Default, Tone::Good) => "green",
+            (Theme::Default, Tone::Warn) => "yellow",
+            (Theme::Default, Tone::Bad) => "red",
+            (Theme::Default, Tone::Accent) => "cyan",
+            (Theme::Colorblind, Tone::Good) => "blue",
+            (Theme::Colorblind, Tone::Warn) => "yellow",
+            (Theme::Colorblind, Tone::Bad) => "208.bold",
+            (Theme::Colorblind, Tone::Accent) => "cyan",
+            (Theme::Mono, Tone::Good | Tone::Accent) => "",
+            (Theme::Mono, Tone::Warn | Tone::Bad) => "bold",
+            (_, Tone::Dim) => "dim",
+        }
+    }
+}
+
+/// What a piece of output stands for, colored by the theme.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Tone {
+    Good,
+    Warn,
+    Bad,
+    Accent,
+    Dim,
+}
+
+static THEME: Mutex<Theme> = Mutex::new(Theme::Default);
+
+fn theme() -> Theme {
+    *THEME.lock().unwrap()
+}
+
+/// Colors the output with `theme`, when `mode` says so. `auto` leaves it to the terminal
+/// detection, which already respects NO_COLOR.
+pub(crate) fn apply(mode: ColorMode, theme: Theme) {
+    *THEME.lock().unwrap() = theme;
+    let enabled = match mode {
+        ColorMode::Auto => return,
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+    };
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+}
+
+/// Whether NO_COLOR asks for no colors, see <https://no-color.org>.
+pub(crate) fn no_color_env() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty())
+}
+
+/// `text` styled as `tone`, for stdout.
+pub(crate) fn paint<D>(tone: Tone, text: D) -> StyledObject<D> {
+    Style::from_dotted_str(theme().dotted(tone)).apply_to(text)
+}
+
+/// `text` styled as `tone`, for stderr.
+fn paint_stderr<D>(tone: Tone, text: D) -> StyledObject<D> {
+    Style::from_dotted_str(theme().dotted(tone)).for_stderr().apply_to(text)
+}
+
+/// `{key}` in an indicatif template, styled as `tone`.
+fn placeholder(key: &str, tone: Tone) -> String {
+    match theme().dotted(tone) {
+        "" => format!("{{{}}}", key),
+        style => format!("{{{}:.{}}}", key, style),
+    }
+}
+
+/// Template of the upload progress bars.
+pub(crate) fn bar_template() -> String {
+    format!(
+        "{}: [{}] {{percent}}% {{bytes}}/{{total_bytes}} {{msg}}",
+        placeholder("prefix", Tone::Accent),
+        placeholder("wide_bar", Tone::Good),
+    )
+}
+
+/// Template of the spinners.
+pub(crate) fn spinner_template() -> String {
+    format!("{} {}: {{msg}} ({{elapsed}})", placeholder("spinner", Tone::Good), placeholder("prefix", Tone::Accent))
+}
+
+/// Logs to stderr like pretty_env_logger, with the levels colored by the theme, filtered with
+/// RUST_LOG.
+pub(crate) fn init_logger() {
+    pretty_env_logger::env_logger::Builder::from_default_env()
+        .format(|f, record| {
+            let tone = match record.level() {
+                Level::Error => Tone::Bad,
+                Level::Warn => Tone::Warn,
+                Level::Info => Tone::Good,
+                Level::Debug => Tone::Accent,
+                Level::Trace => Tone::Dim,
+            };
+            writeln!(
+                f,
+                " {} {} > {}",
+                paint_stderr(tone, format!("{:<5}", record.level())),
+                Style::new().bold().for_stderr().apply_to(record.target()),
+                record.args(),
+            )
+        })
+        .init();
+}
+
+#[test]
+fn test_templates() {
+    use indicatif::ProgressStyle;
+
+    for theme in [Theme::Default, Theme::Colorblind, Theme::Mono] {
+        apply(ColorMode::Auto, theme);
+        assert!(ProgressStyle::with_template(&bar_template()).is_ok());
+        assert!(ProgressStyle::with_template(&spinner_template()).is_ok());
+    }
+    apply(ColorMode::Auto, Theme::Default);
+}
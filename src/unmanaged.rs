@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{archive::{ArchiveInput, ArchiveOptions}, config::Config, inspect, service::Service, DockerInputType, SerializableError};
+
+/// Name of the service the unmanaged volumes are backed up under.
+pub(crate) static UNMANAGED_SERVICE: &str = "_unmanaged";
+
+/// Backs up every named volume no service covers, so that new volumes are never left out.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub(crate) struct UnmanagedConfig {
+    /// volumes left out, `*` matching any run of characters
+    #[serde(default)]
+    pub(crate) ignore: Vec<String>,
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters.
+fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        // no `*` at all
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Anonymous volumes are named after 64 hex digits, nobody expects them to be backed up.
+fn is_anonymous(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The volumes the archives of `services` back up.
+fn covered(services: &[Service]) -> HashSet<String> {
+    let mut covered = HashSet::new();
+    for service in services {
+        let project = service.compose_project.clone().unwrap_or(service.name.clone());
+        for archive in &service.archives {
+            match &archive.input {
+                ArchiveInput::Docker(DockerInputType::ComposeNamedVolume { name, .. }) => {
+                    covered.insert(format!("{}_{}", project, name));
+                }
+                ArchiveInput::Docker(DockerInputType::Volume { name, .. }) => {
+                    covered.insert(name.clone());
+                }
+                _ => (),
+            }
+        }
+    }
+    covered
+}
+
+/// Adds the service backing up the named volumes none of `services` covers, when enabled.
+pub(crate) async fn add(config: &Config, services: &mut Vec<Service>) -> Result<(), SerializableError> {
+    let Some(unmanaged) = config.unmanaged() else {
+        return Ok(());
+    };
+    let covered = covered(services);
+    let mut volumes = inspect::volumes(config).await?
+        .into_iter()
+        .filter(|v| !is_anonymous(v) && !covered.contains(v) && !unmanaged.ignore.iter().any(|p| matches(p, v)))
+        .collect::<Vec<_>>();
+    if volumes.is_empty() {
+        info!("every named volume is covered by a service");
+        return Ok(());
+    }
+    volumes.sort();
+    warn!("{} volumes aren't covered by any service, backing them up under {}:", volumes.len(), UNMANAGED_SERVICE);
+    for volume in &volumes {
+        warn!("- {}", volume);
+    }
+    services.push(Service {
+        name: UNMANAGED_SERVICE.to_owned(),
+        archives: volumes
+            .into_iter()
+            .map(|name| ArchiveOptions::new(name.clone(), ArchiveInput::Docker(DockerInputType::Volume { name, filter: None })))
+            .collect(),
+        compose_project: None,
+        compose_file: None,
+        max_age: None,
+        min_snapshots_7d: None,
+        max_snapshot_age: None,
+        backends: None,
+        before: vec![],
+        after: vec![],
+        enabled: Service::default_enabled(),
+        skip_if: None,
+        depends_on: vec![],
+        one_file_system: false,
+    });
+    Ok(())
+}
+
+#[test]
+fn test_unmanaged_matches() {
+    assert!(matches("scratch", "scratch"));
+    assert!(!matches("scratch", "scratch2"));
+    assert!(matches("*_cache", "app_cache"));
+    assert!(matches("tmp*", "tmp"));
+    assert!(matches("a*b*c", "axxbyyc"));
+    assert!(!matches("a*b*c", "axxcyyb"));
+    assert!(is_anonymous(&"0123456789abcdef".repeat(4)));
+    assert!(!is_anonymous("db_data"));
+}
@@ -0,0 +1,344 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    io::Read,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    archive::ArchiveInput,
+    audit::Audited,
+    backend::{self, BackendType, BackupBackend, Snapshot},
+    config::Config,
+    dump_file,
+    inspect,
+    metrics,
+    notify::{Dispatcher, Notification},
+    restic,
+    restore,
+    service::Service,
+    state::State,
+    stream,
+    DockerInputType,
+    SerializableError,
+    ShellTask,
+};
+
+/// How often the restores are tested, under `verify` in the configuration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct VerifyConfig {
+    /// seconds between two restore tests, one is run after a backup once due
+    pub(crate) interval: u64,
+    /// how many files of the archive are compared
+    #[serde(default = "VerifyConfig::default_files")]
+    pub(crate) files: usize,
+}
+
+impl VerifyConfig {
+    pub(crate) fn default_files() -> usize {
+        10
+    }
+}
+
+/// The outcome of a restore test, as kept in the state and sent to the hooks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct VerifyRecord {
+    pub(crate) at: DateTime<Utc>,
+    pub(crate) service: String,
+    pub(crate) archive: String,
+    /// snapshot the files were restored from
+    #[serde(default)]
+    pub(crate) snapshot: Option<String>,
+    /// how many files were restored and compared
+    #[serde(default)]
+    pub(crate) compared: usize,
+    /// files whose restored content differs from the live one
+    #[serde(default)]
+    pub(crate) mismatched: Vec<String>,
+    /// why the test couldn't be carried out
+    #[serde(default)]
+    pub(crate) error: Option<String>,
+}
+
+impl VerifyRecord {
+    pub(crate) fn ok(&self) -> bool {
+        self.error.is_none() && self.mismatched.is_empty()
+    }
+}
+
+/// Where the live data of an archive is.
+#[derive(Debug)]
+enum Source {
+    /// a named volume
+    Volume(String),
+    /// a directory of the host bound in a compose service
+    Bound { project: String, service: String, path: PathBuf },
+    /// a dump named `file`, as left in the intermediate directory at `host`
+    Dump { file: String, host: PathBuf },
+}
+
+/// An archive that can be tested.
+#[derive(Debug)]
+pub(crate) struct Target {
+    service: String,
+    /// the backend the service's snapshots are read from
+    backend: BackendType,
+    archive: String,
+    source: Source,
+}
+
+/// The archives that can be tested, plugin ones aside, and dumps too when the intermediate directory
+/// is cleaned up after every run, optionally only `service`'s and only `archive`.
+pub(crate) fn targets(config: &Config, services: &[Service], service: Option<&str>, archive: Option<&str>) -> Result<Vec<Target>, SerializableError> {
+    if let Some(service) = service
+        && !services.iter().any(|s| s.name == service)
+    {
+        return Err(SerializableError::config(format!("service {} is not configured", service)));
+    }
+    let mut targets = vec![];
+    for s in services.iter().filter(|s| service.is_none_or(|service| s.name == service)) {
+        let project = s.compose_project.clone().unwrap_or(s.name.clone());
+        for a in s.archives.iter().filter(|a| archive.is_none_or(|archive| a.name == archive)) {
+            let ArchiveInput::Docker(input) = &a.input else {
+                continue;
+            };
+            let source = match input {
+                DockerInputType::ComposeNamedVolume { name, .. } => Source::Volume(format!("{}_{}", project, name)),
+                DockerInputType::Volume { name, .. } => Source::Volume(name.clone()),
+                DockerInputType::ComposeBoundVolume { service, path, .. } => Source::Bound {
+                    project: project.clone(),
+                    service: service.clone(),
+                    path: path.clone(),
+                },
+                // a dump is compared with the copy left in the intermediate directory
+                DockerInputType::ExecStdout { .. } if config.cleanup_intermediate() => continue,
+                DockerInputType::ExecStdout { ext, .. } => {
+                    let host = dump_file(&config.intermediate_path()?, &s.name, &a.name, ext, a.compression.is_some());
+                    let file = host.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                    Source::Dump { file, host }
+                }
+            };
+            targets.push(Target { service: s.name.clone(), backend: backend::primary(config, s), archive: a.name.clone(), source });
+        }
+    }
+    if let (Some(archive), true) = (archive, targets.is_empty()) {
+        return Err(SerializableError::config(format!("archive {} is not configured, or can't be tested", archive)));
+    }
+    Ok(targets)
+}
+
+/// Picks one of the targets at random, restores a sample of its files from the latest snapshot
+/// and compares them with the live data. Files modified since the snapshot are left out.
+pub(crate) async fn verify(config: &Config, mut targets: Vec<Target>, files: usize) -> Result<VerifyRecord, SerializableError> {
+    if targets.is_empty() {
+        return Err(SerializableError::config("no archive can be tested"));
+    }
+    let target = targets.swap_remove(RandomState::new().hash_one(Utc::now()) as usize % targets.len());
+    info!("{}: {}: testing a restore of {} files", target.service, target.archive, files);
+    let mut record = VerifyRecord {
+        at: Utc::now(),
+        service: target.service.clone(),
+        archive: target.archive.clone(),
+        snapshot: None,
+        compared: 0,
+        mismatched: vec![],
+        error: None,
+    };
+    if let Err(e) = compare(config, &target, files, &mut record).await {
+        record.error = Some(e.to_string());
+    }
+    match (&record.error, record.mismatched.is_empty()) {
+        (Some(e), _) => warn!("{}: {}: the restore test failed: {}", record.service, record.archive, e),
+        (None, true) => info!("{}: {}: the {} restored files match", record.service, record.archive, record.compared),
+        (None, false) => warn!(
+            "{}: {}: {} of the {} restored files don't match: {}",
+            record.service,
+            record.archive,
+            record.mismatched.len(),
+            record.compared,
+            record.mismatched.join(", "),
+        ),
+    }
+    Ok(record)
+}
+
+async fn compare(config: &Config, target: &Target, files: usize, record: &mut VerifyRecord) -> Result<(), SerializableError> {
+    let service_path = Path::new(&config.restic_root()).join(&target.service);
+    let backend = backend::open_type(target.backend);
+    let snapshots = backend.snapshots(config).await?;
+    let snapshot = restore::latest_snapshot(&snapshots, &service_path)
+        .ok_or_else(|| SerializableError::backend(format!("no snapshot of {}", target.service)))?;
+    record.snapshot = Some(snapshot.id.clone());
+
+    let live = match &target.source {
+        Source::Dump { file, host } => vec![(file.clone(), dump_hash(host, snapshot)?)],
+        Source::Volume(volume) => live_hashes(config, volume, snapshot, files).await?,
+        Source::Bound { project, service, path } => {
+            let host_path = inspect::bound_volume(config, project, service, path).await?
+                .ok_or_else(|| SerializableError::config(format!("{} is not a bound volume of service {}", path.display(), service)))?;
+            live_hashes(config, &host_path, snapshot, files).await?
+        }
+    };
+    if live.is_empty() {
+        return Err(SerializableError::restic("no file predates the snapshot, nothing to compare"));
+    }
+    for (file, hash) in live {
+        let path = match &target.source {
+            Source::Dump { .. } => service_path.join(&file),
+            _ => service_path.join(&target.archive).join(&file),
+        };
+        record.compared += 1;
+        if snapshot_hash(config, backend.as_ref(), &snapshot.id, &path).await? != hash {
+            record.mismatched.push(file);
+        }
+    }
+    Ok(())
+}
+
+/// sha256 of the dump left in the intermediate directory, which must be the one in the snapshot.
+fn dump_hash(path: &Path, snapshot: &Snapshot) -> Result<String, SerializableError> {
+    let metadata = std::fs::metadata(path).map_err(|e| SerializableError::config(format!(
+        "{} isn't in the intermediate directory, nothing to compare it with: {}",
+        path.display(),
+        e,
+    )))?;
+    if metadata.modified().map(DateTime::<Utc>::from)? > snapshot.time {
+        return Err(SerializableError::config(format!("{} was dumped again since the snapshot", path.display())));
+    }
+    let mut hasher = Sha256::new();
+    let mut file = std::fs::File::open(path)?;
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(hex::encode(hasher.finalize())),
+            n => hasher.update(&buf[..n]),
+        }
+    }
+}
+
+/// sha256 of up to `files` random files of `source`, a volume or host directory, that weren't
+/// modified since the snapshot, keyed by their path relative to it.
+async fn live_hashes(config: &Config, source: &str, snapshot: &Snapshot, files: usize) -> Result<Vec<(String, String)>, SerializableError> {
+    let minutes = (Utc::now() - snapshot.time).num_minutes();
+    let script = format!(
+        "cd {} && find . -type f -mmin +{} | shuf -n {} | while IFS= read -r f; do sha256sum \"$f\"; done",
+        restic::RESTORE_MOUNT,
+        minutes,
+        files,
+    );
+    let mut task = ShellTask::new("sh");
+    task.args(["-c", &script]);
+    let output = restic::query(config, vec![restic::restore_mount(source, true)], task).await?;
+    Ok(output
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(hash, file)| (file.trim_start_matches("./").to_owned(), hash.to_owned()))
+        .collect())
+}
+
+/// sha256 of `path` in `snapshot`, streamed out of the repository.
+async fn snapshot_hash(config: &Config, backend: &dyn BackupBackend, snapshot: &str, path: &Path) -> Result<String, SerializableError> {
+    let mut command = backend.dump(config, snapshot, path)?;
+    command.stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let mut handle = command.audited_spawn()
+        .map_err(|e| SerializableError::docker(&command, None, Some(e.to_string())))?;
+    let mut stdout = handle.stdout.take()
+        .ok_or(SerializableError::restic("no stdout found in restic dump output"))?;
+    let stderr = stream::drain_stderr(&mut handle);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; config.io_buffer_size()];
+    let read = async {
+        loop {
+            let n = stdout.read(&mut buf).await?;
+            if n == 0 {
+                return Ok::<_, std::io::Error>(());
+            }
+            hasher.update(&buf[..n]);
+        }
+    }.await;
+    let status = handle.wait().await?;
+    if !status.success() {
+        return Err(SerializableError::docker(&command, status.code(), Some(stderr.await)));
+    }
+    read?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Keeps `record` as the last restore test, and in the metrics if they are written.
+pub(crate) fn save(config: &Config, record: &VerifyRecord) -> Result<(), SerializableError> {
+    let mut state = State::load(config.state_dir()?)?;
+    state.last_verify = Some(record.clone());
+    state.save()?;
+    if let Some(dir) = config.metrics_dir() {
+        metrics::write_verify(&dir, record)?;
+    }
+    Ok(())
+}
+
+/// Whether a scheduled restore test is due.
+pub(crate) fn due(config: &Config, schedule: &VerifyConfig) -> Result<bool, SerializableError> {
+    let state = State::load(config.state_dir()?)?;
+    Ok(state.last_verify.as_ref().is_none_or(|last| (Utc::now() - last.at).num_seconds() >= schedule.interval as i64))
+}
+
+/// Runs a restore test after a backup if one is due, keeping it and running the hooks with it.
+pub(crate) async fn scheduled(config: &Config, targets: Vec<Target>, notifier: &Dispatcher) {
+    let Some(schedule) = config.verify() else {
+        return;
+    };
+    match due(config, schedule) {
+        Ok(true) => (),
+        Ok(false) => return,
+        Err(e) => {
+            warn!("failed to check whether a restore test is due: {}", e);
+            return;
+        }
+    }
+    let record = match verify(config, targets, schedule.files).await {
+        Ok(record) => record,
+        Err(e) => {
+            warn!("failed to test a restore: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = save(config, &record) {
+        warn!("failed to keep the restore test: {}", e);
+    }
+    if let Err(e) = notifier.notify(Notification::Verify { record: &record }).await {
+        error!("{}", e);
+    }
+}
+
+#[test]
+fn test_verify_targets() {
+    let config: Config = serde_yaml::from_str("intermediate_path: /tmp/hoarder").unwrap();
+    let services: Vec<Service> = serde_yaml::from_str(r#"
+        - name: app
+          compose_project: prod
+          archives:
+            - name: data
+              input: !Docker
+                docker_type: ComposeNamedVolume
+                name: data
+            - name: db
+              input: !Docker
+                docker_type: ExecStdout
+                service: db
+                task: [pg_dumpall]
+                ext: sql
+              compression: {}
+    "#).unwrap();
+    let all = targets(&config, &services, None, None).unwrap();
+    assert!(matches!(&all[0].source, Source::Volume(volume) if volume == "prod_data"));
+    assert!(matches!(&all[1].source, Source::Dump { file, host } if file == "db.sql.zst" && host == Path::new("/tmp/hoarder/app/db.sql.zst")));
+    assert_eq!(targets(&config, &services, Some("app"), Some("db")).unwrap().len(), 1);
+    assert!(targets(&config, &services, Some("web"), None).is_err());
+}